@@ -1,13 +1,14 @@
+use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::io::{BufRead, BufReader, Read, Seek};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
 
-use crate::bencode::{BencodeKey, BencodeParsable, BencodeType};
+use crate::bencode::{BencodeKey, BencodeParsable, BencodeType, BencodeValue};
 
 fn consume_next_byte<R: Read + Seek>(buf_reader: &mut BufReader<R>) {
     buf_reader.read_exact(&mut [0u8]).unwrap();
 }
 
-/**
+/*
  * @TODO make sure that we have the minimum mandatory data according to torrent files BEP
  */
 
@@ -64,10 +65,8 @@ fn decode_bytes<R: Read + Seek>(buf_reader: &mut BufReader<R>) -> Vec<u8> {
 
     let mut data_value = vec![0u8; data_length];
 
-    let read_res = buf_reader.read(&mut data_value);
-
-    if read_res.is_err() {
-        panic!("error reading in string {}", read_res.unwrap_err())
+    if let Err(e) = buf_reader.read_exact(&mut data_value) {
+        panic!("error reading in string {}", e)
     }
 
     return data_value;
@@ -108,6 +107,7 @@ fn decode_integer<R: Read + Seek>(buf_reader: &mut BufReader<R>) -> usize {
     parse_string_to_usize(String::from_utf8(int_buf).unwrap())
 }
 
+#[allow(dead_code)]
 /**
  * Will panic if the next type is None (buffer EOF) or if the type is not the expected one
  */
@@ -177,6 +177,54 @@ fn decode_nested_list_string<R: Read + Seek>(buf_reader: &mut BufReader<R>) -> V
     nested
 }
 
+/**
+ * Consume the next value whatever its type (nested values included) without decoding it.
+ * Used for values we do not support, so the rest of the data can still be parsed.
+ */
+pub fn skip_next_value<R: Read + Seek>(buf_reader: &mut BufReader<R>) {
+    let next_type = match extract_next_type(buf_reader) {
+        None => return,
+        Some(t) => t,
+    };
+
+    match next_type {
+        BencodeType::String => {
+            decode_bytes(buf_reader);
+        }
+        BencodeType::Integer => {
+            //not using decode_integer as skipped integers may be negative
+            let mut int_buf = Vec::new();
+            buf_reader.read_until(b'e', &mut int_buf).unwrap();
+        }
+        BencodeType::List | BencodeType::Dictionary => {
+            consume_next_byte(buf_reader);
+            loop {
+                if extract_next_type(buf_reader) == Some(BencodeType::Terminator) {
+                    consume_next_byte(buf_reader);
+                    break;
+                }
+                skip_next_value(buf_reader);
+            }
+        }
+        BencodeType::Terminator => panic!("cannot skip a terminator"),
+    }
+}
+
+/**
+ * Bytes of the next value, consumed without being decoded
+ */
+fn read_raw_value<R: Read + Seek>(buf_reader: &mut BufReader<R>) -> Vec<u8> {
+    let start = buf_reader.stream_position().unwrap();
+    skip_next_value(buf_reader);
+    let end = buf_reader.stream_position().unwrap();
+
+    let mut raw_value = vec![0u8; (end - start) as usize];
+    buf_reader.seek(SeekFrom::Start(start)).unwrap();
+    buf_reader.read_exact(&mut raw_value).unwrap();
+
+    return raw_value;
+}
+
 pub fn decode_dictionary<P: BencodeParsable + Debug>(
     target: &mut P,
     buf_reader: &mut BufReader<P::R>,
@@ -220,6 +268,13 @@ pub fn decode_dictionary<P: BencodeParsable + Debug>(
 
         let unwrapped_current_key = current_key.unwrap();
 
+        if unwrapped_current_key.is_raw_value() {
+            let raw_value = read_raw_value(buf_reader);
+            target.on_raw_value(unwrapped_current_key.clone(), raw_value);
+            current_key = None;
+            continue;
+        }
+
         match next_type {
             BencodeType::String => {
                 //in reality BencodeType::String work for both string & raw bytes
@@ -235,6 +290,15 @@ pub fn decode_dictionary<P: BencodeParsable + Debug>(
                 target.on_dictionary(unwrapped_current_key.clone(), buf_reader);
             }
             BencodeType::List => {
+                if !unwrapped_current_key.is_list_of_strings()
+                    && !unwrapped_current_key.is_nested_list_string()
+                {
+                    println!("skipping unsupported list for {:?}", unwrapped_current_key);
+                    skip_next_value(buf_reader);
+                    current_key = None;
+                    continue;
+                }
+
                 consume_next_byte(buf_reader); // consume 'l'
                 match () {
                     _ if unwrapped_current_key.is_list_of_strings() => {
@@ -263,3 +327,115 @@ pub fn decode_dictionary<P: BencodeParsable + Debug>(
 
     return;
 }
+
+//peer messages are small, anything nested deeper than this is malformed or hostile
+const MAX_VALUE_DEPTH: usize = 32;
+
+/**
+ * Decode the value starting at the beginning of untrusted bytes (extension messages...).
+ * Unlike the streaming decoder above it never panics, it returns the value with the amount of bytes it used
+ * since some messages carry raw data after it.
+ */
+pub fn decode_value(data: &[u8]) -> Result<(BencodeValue, usize), String> {
+    let mut position = 0;
+    let value = decode_value_at(data, &mut position, 0)?;
+
+    return Ok((value, position));
+}
+
+fn decode_value_at(
+    data: &[u8],
+    position: &mut usize,
+    depth: usize,
+) -> Result<BencodeValue, String> {
+    if depth > MAX_VALUE_DEPTH {
+        return Err("bencode value is nested too deeply".to_string());
+    }
+
+    match data.get(*position) {
+        None => return Err("unexpected end of bencode data".to_string()),
+        Some(b'i') => {
+            *position += 1;
+            let digits = read_until(data, position, b'e')?;
+            let value = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| digits.parse::<i64>().ok())
+                .ok_or_else(|| {
+                    format!(
+                        "invalid bencode integer {}",
+                        String::from_utf8_lossy(digits)
+                    )
+                })?;
+
+            return Ok(BencodeValue::Integer(value));
+        }
+        Some(b'l') => {
+            *position += 1;
+            let mut values = Vec::new();
+
+            while data.get(*position) != Some(&b'e') {
+                values.push(decode_value_at(data, position, depth + 1)?);
+            }
+            *position += 1;
+
+            return Ok(BencodeValue::List(values));
+        }
+        Some(b'd') => {
+            *position += 1;
+            let mut entries = BTreeMap::new();
+
+            while data.get(*position) != Some(&b'e') {
+                let key = match decode_value_at(data, position, depth + 1)? {
+                    BencodeValue::Bytes(key) => key,
+                    _ => return Err("bencode dictionary key is not a string".to_string()),
+                };
+                let value = decode_value_at(data, position, depth + 1)?;
+                entries.insert(key, value);
+            }
+            *position += 1;
+
+            return Ok(BencodeValue::Dictionary(entries));
+        }
+        Some(b'0'..=b'9') => {
+            let digits = read_until(data, position, b':')?;
+            let length = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| digits.parse::<usize>().ok())
+                .ok_or_else(|| {
+                    format!(
+                        "invalid bencode string length {}",
+                        String::from_utf8_lossy(digits)
+                    )
+                })?;
+
+            let end = position
+                .checked_add(length)
+                .filter(|end| *end <= data.len())
+                .ok_or_else(|| "bencode string goes past the end of data".to_string())?;
+            let value = data[*position..end].to_vec();
+            *position = end;
+
+            return Ok(BencodeValue::Bytes(value));
+        }
+        Some(byte) => return Err(format!("unexpected byte {byte:#04x} in bencode data")),
+    }
+}
+
+/**
+ * Returns the bytes up to the terminator and moves past it
+ */
+fn read_until<'a>(
+    data: &'a [u8],
+    position: &mut usize,
+    terminator: u8,
+) -> Result<&'a [u8], String> {
+    let start = *position;
+    let length = data[start..]
+        .iter()
+        .position(|byte| *byte == terminator)
+        .ok_or_else(|| "unexpected end of bencode data".to_string())?;
+
+    *position = start + length + 1;
+
+    return Ok(&data[start..start + length]);
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+/**
+ * Owned bencode value, used to build the data we send (tracker replies, ...).
+ * Dictionary keys are kept in a BTreeMap so they are encoded in the sorted order required by the spec.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum BencodeValue {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BencodeValue>),
+    Dictionary(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+impl BencodeValue {
+    pub fn dictionary<K: AsRef<[u8]>>(entries: Vec<(K, BencodeValue)>) -> Self {
+        BencodeValue::Dictionary(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_ref().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        match self {
+            Self::Dictionary(entries) => entries.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Self::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            Self::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_dictionary(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            Self::Dictionary(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+
+        return buf;
+    }
+
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Integer(value) => {
+                buf.push(b'i');
+                buf.extend_from_slice(value.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            Self::Bytes(value) => encode_bytes(value, buf),
+            Self::List(values) => {
+                buf.push(b'l');
+                values.iter().for_each(|value| value.encode_into(buf));
+                buf.push(b'e');
+            }
+            Self::Dictionary(entries) => {
+                buf.push(b'd');
+                entries.iter().for_each(|(key, value)| {
+                    encode_bytes(key, buf);
+                    value.encode_into(buf);
+                });
+                buf.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(value: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(value.len().to_string().as_bytes());
+    buf.push(b':');
+    buf.extend_from_slice(value);
+}
+
+impl From<&str> for BencodeValue {
+    fn from(value: &str) -> Self {
+        BencodeValue::Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(value: String) -> Self {
+        BencodeValue::Bytes(value.into_bytes())
+    }
+}
+
+impl From<&[u8]> for BencodeValue {
+    fn from(value: &[u8]) -> Self {
+        BencodeValue::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(value: Vec<u8>) -> Self {
+        BencodeValue::Bytes(value)
+    }
+}
+
+impl From<i64> for BencodeValue {
+    fn from(value: i64) -> Self {
+        BencodeValue::Integer(value)
+    }
+}
+
+impl From<usize> for BencodeValue {
+    fn from(value: usize) -> Self {
+        BencodeValue::Integer(value as i64)
+    }
+}
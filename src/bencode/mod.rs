@@ -1,12 +1,13 @@
-use core::panic;
 use std::{
     fmt::Debug,
-    io::{BufRead, BufReader, Read, Seek},
+    io::{BufReader, Read, Seek},
 };
 
 mod decode;
+mod encode;
 
-pub use decode::decode_dictionary;
+pub use decode::{decode_dictionary, decode_value, skip_next_value};
+pub use encode::BencodeValue;
 
 #[derive(Debug, PartialEq)]
 pub enum BencodeType {
@@ -47,6 +48,11 @@ pub trait BencodeKey {
         false
     }
 
+    //values of mixed types, handed over still bencoded
+    fn is_raw_value(&self) -> bool {
+        false
+    }
+
     fn from_str(s: &str) -> Self;
     fn as_str(&self) -> &str;
     fn is_unsupported_key(&self) -> bool;
@@ -80,6 +86,10 @@ pub trait BencodeParsable: Sized + Debug {
         );
     }
 
+    fn on_raw_value(&mut self, key: Self::Key, _value: Vec<u8>) {
+        println!("on_raw_value throwing away data for {}", key.as_str());
+    }
+
     fn on_dictionary(&mut self, key: Self::Key, buf_reader: &mut BufReader<Self::R>) {
         println!("on_dictionary throwing away data for {}", key.as_str());
         decode_dictionary(self, buf_reader);
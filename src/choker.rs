@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rand::seq::IndexedRandom;
+
+use crate::peer_registry::{PeerCommand, PeerStats};
+use crate::torrent_context::TorrentContext;
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+const CHOKER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/**
+ * Tit-for-tat choking: the upload slots go to the interested peers we download the most from
+ * (or upload the most to once seeding), plus one optimistic unchoke rotated every 30 seconds
+ * so new peers get a chance to prove themselves.
+ */
+#[derive(Debug)]
+pub struct Choker {
+    upload_slots: usize,
+    optimistic: Option<SocketAddr>,
+    optimistic_since: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(upload_slots: usize) -> Self {
+        Choker {
+            upload_slots,
+            optimistic: None,
+            optimistic_since: None,
+        }
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    /**
+     * Peers that should be unchoked, every other peer is choked
+     */
+    pub fn rechoke(
+        &mut self,
+        peers: &[(SocketAddr, PeerStats)],
+        seeding: bool,
+        now: Instant,
+    ) -> HashSet<SocketAddr> {
+        let mut unchoked = HashSet::new();
+
+        if self.upload_slots == 0 {
+            self.optimistic = None;
+            return unchoked;
+        }
+
+        let mut interested: Vec<&(SocketAddr, PeerStats)> = peers
+            .iter()
+            .filter(|(_, stats)| stats.peer_interested)
+            .collect();
+
+        let rate = |stats: &PeerStats| {
+            if seeding {
+                stats.upload_rate
+            } else {
+                stats.download_rate
+            }
+        };
+        //while downloading, peers snubbing us come last whatever their past rate
+        let snubbing = |stats: &PeerStats| !seeding && stats.snubbed;
+        interested.sort_by(|(_, a), (_, b)| {
+            snubbing(a)
+                .cmp(&snubbing(b))
+                .then(rate(b).total_cmp(&rate(a)))
+        });
+
+        //one slot is kept for the optimistic unchoke
+        let regular_slots = self.upload_slots.saturating_sub(1).max(1);
+        for (peer, _) in interested.iter().take(regular_slots) {
+            unchoked.insert(*peer);
+        }
+
+        if unchoked.len() >= self.upload_slots {
+            return unchoked;
+        }
+
+        let optimistic_is_valid = match (self.optimistic, self.optimistic_since) {
+            (Some(peer), Some(since)) => {
+                now.duration_since(since) < OPTIMISTIC_UNCHOKE_INTERVAL
+                    && !unchoked.contains(&peer)
+                    && interested.iter().any(|(candidate, _)| *candidate == peer)
+            }
+            _ => false,
+        };
+
+        if !optimistic_is_valid {
+            let candidates: Vec<SocketAddr> = interested
+                .iter()
+                .map(|(peer, _)| *peer)
+                .filter(|peer| !unchoked.contains(peer) && Some(*peer) != self.optimistic)
+                .collect();
+
+            //keep the current one when it is the only candidate left
+            let next = match candidates.choose(&mut rand::rng()) {
+                Some(peer) => Some(*peer),
+                None => self
+                    .optimistic
+                    .filter(|peer| interested.iter().any(|(candidate, _)| candidate == peer))
+                    .filter(|peer| !unchoked.contains(peer)),
+            };
+
+            if next != self.optimistic {
+                self.optimistic_since = Some(now);
+            }
+            self.optimistic = next;
+        }
+
+        if let Some(peer) = self.optimistic {
+            unchoked.insert(peer);
+        }
+
+        return unchoked;
+    }
+}
+
+/**
+ * Run the choker on the connections of the context until stopped
+ */
+pub fn spawn_choker(context: TorrentContext, stopped: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut choker = Choker::new(context.config.upload_slots);
+        let mut last_rechoke: Option<Instant> = None;
+
+        while !stopped.load(Ordering::Relaxed) {
+            let rechoke_requested = context.peer_registry.lock().unwrap().take_rechoke_request();
+            let rechoke_due = match last_rechoke {
+                Some(at) => at.elapsed() >= RECHOKE_INTERVAL,
+                None => true,
+            };
+
+            if rechoke_requested || rechoke_due {
+                let seeding = context.is_download_complete();
+                let peer_registry = context.peer_registry.lock().unwrap();
+                let peers = peer_registry.stats();
+                let unchoked = choker.rechoke(&peers, seeding, Instant::now());
+
+                debug!(
+                    "rechoke: {} unchoked out of {} peers, optimistic {:?}",
+                    unchoked.len(),
+                    peers.len(),
+                    choker.optimistic()
+                );
+
+                for (peer, stats) in peers {
+                    let should_choke = !unchoked.contains(&peer);
+
+                    if should_choke != stats.am_choking {
+                        let command = if should_choke {
+                            PeerCommand::Choke
+                        } else {
+                            PeerCommand::Unchoke
+                        };
+                        peer_registry.send(&peer, command);
+                    }
+                }
+
+                last_rechoke = Some(Instant::now());
+            }
+
+            thread::sleep(CHOKER_POLL_INTERVAL);
+        }
+    })
+}
@@ -1 +1,330 @@
-pub static PEER_ID: &str = "-TR3000-abcdefghijkl";
+use std::env;
+use std::time::Duration;
+
+use log::warn;
+use rand::Rng;
+use rand::distr::Alphanumeric;
+
+use crate::connection_handler::mse::EncryptionPolicy;
+
+//client and version in the Azureus style, the 12 bytes after it are random
+pub static PEER_ID_PREFIX: &str = "-TR3000-";
+
+/**
+ * Client wide settings, shared by the tracker announces and the peer connections.
+ * Every field can be overridden through the environment (or the .env file), see ClientConfig::from_env
+ */
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub peer_id: [u8; 20],
+
+    //port we announce to trackers and listen on for incoming peers
+    pub port: u16,
+
+    //amount of peers asked to the tracker, tracker default when None (usually 50)
+    pub numwant: Option<usize>,
+
+    //random value identifying us across IP changes, generated once per session
+    pub key: String,
+
+    //explicit address to announce, trackers use the request source address when None
+    pub ip: Option<String>,
+
+    pub compact: bool,
+    pub no_peer_id: bool,
+
+    pub tracker_connect_timeout: Duration,
+
+    //whole request timeout, covers slow trackers that accept the connection but never answer
+    pub tracker_timeout: Duration,
+
+    //bounds of the amount of block requests kept in flight per peer
+    pub min_request_queue: usize,
+    pub max_request_queue: usize,
+
+    //time a peer has to send a requested block, then the block is asked to other peers
+    //and the peer is considered snubbing us
+    pub request_timeout: Duration,
+
+    //peers we upload to at the same time, including the optimistic unchoke. 0 disables uploads
+    pub upload_slots: usize,
+
+    //incoming and outgoing peer connections, also the incoming handshakes pending at once
+    pub max_connections: usize,
+
+    //bandwidth caps in bytes per second, 0 for unlimited. They can be changed at runtime through
+    //the BandwidthLimit of each level: TorrentContext::global_bandwidth, TorrentContext::bandwidth
+    //and PeerCommand::SetRateLimits
+    pub upload_limit: u64,
+    pub download_limit: u64,
+    pub per_torrent_upload_limit: u64,
+    pub per_torrent_download_limit: u64,
+    pub per_peer_upload_limit: u64,
+    pub per_peer_download_limit: u64,
+
+    pub peer_connect_timeout: Duration,
+
+    //time a peer has to send its whole handshake
+    pub handshake_timeout: Duration,
+
+    //a keep-alive is sent when we had nothing else to send for this long
+    pub keep_alive_interval: Duration,
+
+    //peers sending nothing at all for this long, not even keep-alives, are disconnected
+    pub peer_idle_timeout: Duration,
+
+    //keep the metadata downloaded for a magnet link as a .torrent next to the download
+    pub save_magnet_torrent: bool,
+
+    //message stream encryption of the connections we open, plaintext by default so peers
+    //without MSE support are reached at the first attempt
+    pub outgoing_encryption: EncryptionPolicy,
+
+    //message stream encryption accepted from peers connecting to us
+    pub incoming_encryption: EncryptionPolicy,
+
+    //accept uTP connections on the UDP port and try uTP before TCP when connecting to peers
+    pub utp: bool,
+
+    //run a DHT node (BEP 5) to find peers without trackers, on its own UDP port
+    //since uTP already uses the peer port
+    pub dht: bool,
+    pub dht_port: u16,
+
+    //host:port of the nodes used to join the DHT when the saved routing table is empty
+    pub dht_bootstrap_nodes: Vec<String>,
+
+    //routing table kept across sessions, not saved when None
+    pub dht_state_file: Option<String>,
+
+    //announce non-private torrents on the local network (BEP 14) and connect to the peers
+    //announcing them there
+    pub lsd: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            peer_id: generate_peer_id(),
+            port: 6881,
+            numwant: None,
+            key: generate_key(),
+            ip: None,
+            compact: true,
+            no_peer_id: true,
+            tracker_connect_timeout: Duration::from_secs(10),
+            tracker_timeout: Duration::from_secs(30),
+            min_request_queue: 4,
+            max_request_queue: 250,
+            request_timeout: Duration::from_secs(60),
+            upload_slots: 4,
+            max_connections: 50,
+            upload_limit: 0,
+            download_limit: 0,
+            per_torrent_upload_limit: 0,
+            per_torrent_download_limit: 0,
+            per_peer_upload_limit: 0,
+            per_peer_download_limit: 0,
+            peer_connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
+            keep_alive_interval: Duration::from_secs(120),
+            peer_idle_timeout: Duration::from_secs(180),
+            save_magnet_torrent: false,
+            outgoing_encryption: EncryptionPolicy::Disabled,
+            incoming_encryption: EncryptionPolicy::Preferred,
+            utp: true,
+            dht: true,
+            dht_port: 6882,
+            dht_bootstrap_nodes: vec![
+                String::from("router.bittorrent.com:6881"),
+                String::from("dht.transmissionbt.com:6881"),
+                String::from("router.utorrent.com:6881"),
+            ],
+            dht_state_file: Some(String::from("./downloads/dht.dat")),
+            lsd: true,
+        }
+    }
+}
+
+impl ClientConfig {
+    /**
+     * Default configuration overridden by the TORRENT_* environment variables
+     */
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Some(peer_id) = env_var("TORRENT_PEER_ID") {
+            match <[u8; 20]>::try_from(peer_id.as_bytes()) {
+                Ok(peer_id) => config.peer_id = peer_id,
+                Err(_) => warn!("TORRENT_PEER_ID must be 20 bytes long, ignoring {peer_id}"),
+            }
+        }
+
+        if let Some(port) = env_parse("TORRENT_PORT") {
+            config.port = port;
+        }
+
+        if let Some(numwant) = env_parse("TORRENT_NUMWANT") {
+            config.numwant = Some(numwant);
+        }
+
+        if let Some(key) = env_var("TORRENT_KEY") {
+            config.key = key;
+        }
+
+        config.ip = env_var("TORRENT_IP");
+
+        if let Some(compact) = env_parse("TORRENT_COMPACT") {
+            config.compact = compact;
+        }
+
+        if let Some(no_peer_id) = env_parse("TORRENT_NO_PEER_ID") {
+            config.no_peer_id = no_peer_id;
+        }
+
+        if let Some(secs) = env_parse("TORRENT_TRACKER_CONNECT_TIMEOUT") {
+            config.tracker_connect_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = env_parse("TORRENT_TRACKER_TIMEOUT") {
+            config.tracker_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(min_request_queue) = env_parse("TORRENT_MIN_REQUEST_QUEUE") {
+            config.min_request_queue = min_request_queue;
+        }
+
+        if let Some(max_request_queue) = env_parse("TORRENT_MAX_REQUEST_QUEUE") {
+            config.max_request_queue = max_request_queue;
+        }
+
+        if let Some(secs) = env_parse("TORRENT_REQUEST_TIMEOUT") {
+            config.request_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(upload_slots) = env_parse("TORRENT_UPLOAD_SLOTS") {
+            config.upload_slots = upload_slots;
+        }
+
+        if let Some(max_connections) = env_parse("TORRENT_MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+
+        if let Some(limit) = env_parse("TORRENT_UPLOAD_LIMIT") {
+            config.upload_limit = limit;
+        }
+
+        if let Some(limit) = env_parse("TORRENT_DOWNLOAD_LIMIT") {
+            config.download_limit = limit;
+        }
+
+        if let Some(limit) = env_parse("TORRENT_PER_TORRENT_UPLOAD_LIMIT") {
+            config.per_torrent_upload_limit = limit;
+        }
+
+        if let Some(limit) = env_parse("TORRENT_PER_TORRENT_DOWNLOAD_LIMIT") {
+            config.per_torrent_download_limit = limit;
+        }
+
+        if let Some(limit) = env_parse("TORRENT_PER_PEER_UPLOAD_LIMIT") {
+            config.per_peer_upload_limit = limit;
+        }
+
+        if let Some(limit) = env_parse("TORRENT_PER_PEER_DOWNLOAD_LIMIT") {
+            config.per_peer_download_limit = limit;
+        }
+
+        if let Some(secs) = env_parse("TORRENT_PEER_CONNECT_TIMEOUT") {
+            config.peer_connect_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = env_parse("TORRENT_HANDSHAKE_TIMEOUT") {
+            config.handshake_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = env_parse("TORRENT_KEEP_ALIVE_INTERVAL") {
+            config.keep_alive_interval = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = env_parse("TORRENT_PEER_IDLE_TIMEOUT") {
+            config.peer_idle_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(save_magnet_torrent) = env_parse("TORRENT_SAVE_MAGNET_TORRENT") {
+            config.save_magnet_torrent = save_magnet_torrent;
+        }
+
+        if let Some(policy) = env_parse("TORRENT_OUTGOING_ENCRYPTION") {
+            config.outgoing_encryption = policy;
+        }
+
+        if let Some(policy) = env_parse("TORRENT_INCOMING_ENCRYPTION") {
+            config.incoming_encryption = policy;
+        }
+
+        if let Some(utp) = env_parse("TORRENT_UTP") {
+            config.utp = utp;
+        }
+
+        if let Some(dht) = env_parse("TORRENT_DHT") {
+            config.dht = dht;
+        }
+
+        if let Some(dht_port) = env_parse("TORRENT_DHT_PORT") {
+            config.dht_port = dht_port;
+        }
+
+        if let Some(nodes) = env_var("TORRENT_DHT_BOOTSTRAP_NODES") {
+            config.dht_bootstrap_nodes = nodes
+                .split(',')
+                .map(|node| node.trim().to_string())
+                .filter(|node| !node.is_empty())
+                .collect();
+        }
+
+        if let Some(path) = env_var("TORRENT_DHT_STATE_FILE") {
+            config.dht_state_file = Some(path);
+        }
+
+        if let Some(lsd) = env_parse("TORRENT_LSD") {
+            config.lsd = lsd;
+        }
+
+        return config;
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = env_var(name)?;
+
+    match value.parse::<T>() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!("cannot parse {name}={value}, using default");
+            None
+        }
+    }
+}
+
+fn generate_key() -> String {
+    format!("{:08X}", rand::rng().random::<u32>())
+}
+
+/**
+ * New for every session, two instances of the client must not take each other for themselves
+ */
+fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..PEER_ID_PREFIX.len()].copy_from_slice(PEER_ID_PREFIX.as_bytes());
+
+    let mut rng = rand::rng();
+    for byte in &mut peer_id[PEER_ID_PREFIX.len()..] {
+        *byte = rng.sample(Alphanumeric);
+    }
+
+    return peer_id;
+}
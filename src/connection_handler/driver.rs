@@ -0,0 +1,238 @@
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{self, MissedTickBehavior};
+
+use super::mse::Rc4;
+use super::peer_stream::{PeerStream, Transport};
+use super::{ConnectionHandler, READ_CHUNK_SIZE, TICK_INTERVAL};
+use crate::rate_limiter::{Allowance, Direction};
+use crate::utp::UtpStream;
+
+//uploads are queued while less than this waits to be written, a slow peer does not make us buffer pieces
+const WRITE_BUFFER_LOW_WATER: usize = 64 * 1024;
+
+//time the last messages get to leave once the connection is closed
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+ * Transport registered with the runtime
+ */
+enum AsyncTransport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncTransport {
+    fn new(transport: Transport) -> io::Result<Self> {
+        match transport {
+            Transport::Tcp(stream) => {
+                stream.set_nonblocking(true)?;
+                Ok(AsyncTransport::Tcp(TcpStream::from_std(stream)?))
+            }
+            Transport::Utp(stream) => Ok(AsyncTransport::Utp(stream)),
+        }
+    }
+}
+
+impl AsyncRead for AsyncTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncTransport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            AsyncTransport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncTransport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            AsyncTransport::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncTransport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            AsyncTransport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncTransport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            AsyncTransport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/**
+ * Without limits the instant is already there, no timer is involved
+ */
+async fn wait_until(at: Instant) {
+    if at > Instant::now() {
+        time::sleep_until(at.into()).await;
+    }
+}
+
+impl ConnectionHandler {
+    /**
+     * Messages queued by the protocol, encrypted in the order they were queued
+     */
+    fn drain_write_buffer(&mut self, encryptor: Option<&mut Rc4>, out: &mut Vec<u8>) {
+        let mut queued = self.take_write_buffer();
+
+        if let Some(encryptor) = encryptor {
+            encryptor.apply(&mut queued);
+        }
+
+        out.extend_from_slice(&queued);
+    }
+
+    /**
+     * Bandwidth this connection draws from: its own, the torrent's and the client's
+     */
+    fn allowance(&self, direction: Direction) -> Allowance {
+        let levels = vec![
+            Arc::clone(&self.bandwidth),
+            Arc::clone(&self.context.bandwidth),
+            Arc::clone(&self.context.global_bandwidth),
+        ];
+
+        return Allowance::new(direction, levels);
+    }
+
+    /**
+     * Handle the received data then verify the pieces it completed, those are verified even
+     * when the peer broke the protocol afterwards
+     */
+    async fn receive(&mut self, data: &[u8]) -> Result<(), String> {
+        let handled = self.on_data(data);
+        let verified = self.verify_completed_pieces().await;
+
+        return handled.and(verified);
+    }
+
+    /**
+     * Connection task once the handshake is done, for both directions. Reading, writing and the
+     * ticks run concurrently: the protocol code only queues messages and never waits on the socket.
+     */
+    pub(crate) async fn run(&mut self, stream: PeerStream) {
+        let (transport, mut encryptor, mut decryptor, pending) = stream.into_parts();
+
+        if encryptor.is_some() {
+            self.log_debug("connection is encrypted");
+        }
+        self.utp = matches!(transport, Transport::Utp(_));
+        if self.utp {
+            self.log_debug("connection runs over uTP");
+        }
+
+        let transport = match AsyncTransport::new(transport) {
+            Ok(transport) => transport,
+            Err(e) => {
+                self.log_err(format!("cannot register connection: {e}").as_str());
+                return;
+            }
+        };
+        let (mut reader, mut writer) = tokio::io::split(transport);
+
+        self.start();
+        if let Err(e) = self.receive(&pending).await {
+            self.log_err(e.as_str());
+            return;
+        }
+
+        let mut ticker = time::interval(TICK_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut buf = vec![0u8; READ_CHUNK_SIZE];
+        let mut out = Vec::new();
+
+        //reads and writes wait for their share of the bandwidth, a limited download
+        //leaves the data in the socket so the peer slows down
+        let mut download = self.allowance(Direction::Download);
+        let mut upload = self.allowance(Direction::Upload);
+
+        loop {
+            //pending uploads are served as the previous ones leave
+            while out.len() + self.write_buffer.len() < WRITE_BUFFER_LOW_WATER
+                && self.serve_next_upload().await
+            {}
+            self.drain_write_buffer(encryptor.as_mut(), &mut out);
+
+            let (read_len, read_at) = download.next(READ_CHUNK_SIZE);
+            let (write_len, write_at) = upload.next(out.len());
+
+            tokio::select! {
+                read = async {
+                    wait_until(read_at).await;
+                    reader.read(&mut buf[..read_len]).await
+                } => match read {
+                    Ok(0) => {
+                        self.log_err("peer closed the connection");
+                        return;
+                    }
+                    Ok(read) => {
+                        download.consume(read);
+                        if let Some(decryptor) = decryptor.as_mut() {
+                            decryptor.apply(&mut buf[..read]);
+                        }
+                        if let Err(e) = self.receive(&buf[..read]).await {
+                            self.log_err(e.as_str());
+                            break;
+                        }
+                        if !self.step() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        self.log_err(format!("stream read error: {e}").as_str());
+                        return;
+                    }
+                },
+                written = async {
+                    wait_until(write_at).await;
+                    writer.write(&out[..write_len]).await
+                }, if write_len > 0 => match written {
+                    Ok(written) => {
+                        upload.consume(written);
+                        out.drain(..written);
+                    }
+                    Err(e) => {
+                        self.log_err(format!("stream write error: {e}").as_str());
+                        return;
+                    }
+                },
+                _ = ticker.tick() => {
+                    if !self.step() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        //we don't care about errors, the peer may already be gone
+        self.drain_write_buffer(encryptor.as_mut(), &mut out);
+        let _ = time::timeout(CLOSE_TIMEOUT, async {
+            writer.write_all(&out).await?;
+            writer.shutdown().await
+        })
+        .await;
+    }
+}
@@ -0,0 +1,287 @@
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+use super::ConnectionHandler;
+use super::message::PeerMessage;
+use crate::piece_picker::{BlockRequest, bitfield_has_piece};
+
+//BEP 6: third bit from the right of the reserved bytes
+pub const FAST_EXTENSION_BYTE: usize = 7;
+pub const FAST_EXTENSION_BIT: u8 = 0x04;
+
+//pieces a choked peer may still request from us
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+//suggestions kept per peer, the others are ignored
+const MAX_SUGGESTED_PIECES: usize = 32;
+
+pub fn supports_fast_extension(reserved: &[u8; 8]) -> bool {
+    reserved[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT != 0
+}
+
+/**
+ * Canonical allowed fast set of BEP 6, the same for every peer of a /24 so reconnecting does not give more pieces.
+ * Only defined for IPv4, IPv6 peers get none.
+ */
+pub fn allowed_fast_set(
+    info_hash: &[u8; 20],
+    ip: IpAddr,
+    pieces_amount: usize,
+    count: usize,
+) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return Vec::new(),
+        },
+    };
+
+    let count = count.min(pieces_amount);
+    let mut allowed = Vec::with_capacity(count);
+
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+
+    while allowed.len() < count {
+        x = Sha1::digest(&x).to_vec();
+
+        for chunk in x.chunks_exact(4) {
+            if allowed.len() == count {
+                break;
+            }
+
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % pieces_amount as u32;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+
+    return allowed;
+}
+
+fn set_piece(bitfield: &mut [u8], piece_index: usize) {
+    bitfield[piece_index / 8] |= 1 << (7 - piece_index % 8);
+}
+
+impl ConnectionHandler {
+    /**
+     * First message after the handshake: with the fast extension have all / have none replace the bitfield
+     */
+    pub(crate) fn send_availability(&mut self) {
+        if !self.fast_extension {
+            self.send_bitfield();
+            return;
+        }
+
+        //remaining leaves the skipped pieces out, what we hold must be counted piece by piece
+        let pieces_amount = self.torrent_file.pieces_amount;
+        let held = {
+            let piece_picker = self.piece_picker.lock().unwrap();
+            (0..pieces_amount)
+                .filter(|piece_index| piece_picker.has(*piece_index))
+                .count()
+        };
+
+        if held == pieces_amount {
+            self.log_debug("Sending have all");
+            self.send(&PeerMessage::HaveAll);
+        } else if held == 0 {
+            self.log_debug("Sending have none");
+            self.send(&PeerMessage::HaveNone);
+        } else {
+            self.send_bitfield();
+        }
+    }
+
+    /**
+     * Tell the peer which of our pieces it can download while choked
+     */
+    pub(crate) fn send_allowed_fast(&mut self) {
+        if !self.fast_extension || self.torrent_file.pieces_amount == 0 {
+            return;
+        }
+
+        let allowed = allowed_fast_set(
+            &self.torrent_file.info_hash,
+            self.peer.ip(),
+            self.torrent_file.pieces_amount,
+            ALLOWED_FAST_COUNT,
+        );
+
+        for piece_index in allowed {
+            if !self.piece_picker.lock().unwrap().has(piece_index as usize) {
+                continue;
+            }
+
+            self.our_allowed_fast.insert(piece_index);
+            self.log_debug(format!("sending allowed fast: {piece_index}").as_str());
+            self.send(&PeerMessage::AllowedFast { index: piece_index });
+        }
+    }
+
+    /**
+     * Without the fast extension a dropped request is simply not answered
+     */
+    pub(crate) fn reject_request(&mut self, block: BlockRequest) {
+        if self.fast_extension {
+            self.log_debug(format!("rejecting request {:?}", block).as_str());
+            self.send(&PeerMessage::RejectRequest {
+                index: block.piece,
+                begin: block.offset,
+                length: block.length,
+            });
+        }
+    }
+
+    /**
+     * We choked the peer: its queued requests are dropped, with the fast extension they are
+     * rejected one by one except the allowed fast ones which are still served
+     */
+    pub(crate) fn reject_queued_uploads(&mut self) {
+        let queued: Vec<BlockRequest> = self.upload_queue.drain(..).collect();
+
+        for block in queued {
+            if self.fast_extension && self.our_allowed_fast.contains(&block.piece) {
+                self.upload_queue.push_back(block);
+            } else {
+                self.reject_request(block);
+            }
+        }
+    }
+
+    pub(crate) fn is_allowed_fast(&self, piece_index: u32) -> bool {
+        self.fast_extension && self.our_allowed_fast.contains(&piece_index)
+    }
+
+    /**
+     * Suggest piece, have all, have none, reject request and allowed fast, only valid once negotiated
+     */
+    pub(crate) fn handle_fast_message(&mut self, msg: PeerMessage) -> Result<(), String> {
+        if !self.fast_extension {
+            return Err(format!(
+                "received {:?} without fast extension support",
+                msg.message_type()
+            ));
+        }
+
+        let pieces_amount = self.torrent_file.pieces_amount;
+        let known_piece = |piece_index: u32| -> Result<u32, String> {
+            if piece_index as usize >= pieces_amount {
+                return Err(format!(
+                    "received {:?} for unknown piece {piece_index}",
+                    msg.message_type()
+                ));
+            }
+
+            return Ok(piece_index);
+        };
+
+        match msg {
+            PeerMessage::HaveAll | PeerMessage::HaveNone => {
+                let mut bitfield = vec![0u8; pieces_amount.div_ceil(8)];
+
+                if msg == PeerMessage::HaveAll {
+                    (0..pieces_amount)
+                        .for_each(|piece_index| set_piece(&mut bitfield, piece_index));
+                }
+
+                self.handle_bitfield(&bitfield)?;
+            }
+            PeerMessage::SuggestPiece { index } => {
+                let piece_index = known_piece(index)?;
+
+                if self.suggested_pieces.len() < MAX_SUGGESTED_PIECES
+                    && !self.piece_picker.lock().unwrap().has(piece_index as usize)
+                {
+                    self.suggested_pieces.insert(piece_index);
+                }
+            }
+            PeerMessage::AllowedFast { index } => {
+                let piece_index = known_piece(index)?;
+                self.peer_allowed_fast.insert(piece_index);
+            }
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                self.handle_reject(BlockRequest {
+                    piece: index,
+                    offset: begin,
+                    length,
+                });
+            }
+            _ => {}
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * The block goes back to the picker for another peer, and the piece is not asked to this peer
+     * again until it unchokes us
+     */
+    fn handle_reject(&mut self, block: BlockRequest) {
+        if !self.request_queue.remove(&block) {
+            self.log_debug(format!("peer rejected {:?} we did not request", block).as_str());
+            return;
+        }
+
+        self.log_debug(format!("peer rejected {:?}", block).as_str());
+
+        self.piece_picker
+            .lock()
+            .unwrap()
+            .release_block(self.peer, &block);
+        self.rejected_pieces.insert(block.piece);
+    }
+
+    /**
+     * Peer bitfield restricted to the pieces we may request from it: only the allowed fast ones
+     * while it chokes us, never the ones it rejected
+     */
+    pub(crate) fn requestable_bitfield(&self) -> Option<Vec<u8>> {
+        let peer_bitfield = self.peer_bitfield.as_ref()?;
+
+        if self.peer_unchoked && self.rejected_pieces.is_empty() {
+            return Some(peer_bitfield.clone());
+        }
+
+        return Some(self.restricted_bitfield(peer_bitfield, |piece_index| {
+            (self.peer_unchoked || self.peer_allowed_fast.contains(&piece_index))
+                && !self.rejected_pieces.contains(&piece_index)
+        }));
+    }
+
+    /**
+     * Requestable pieces the peer suggested, None when there are none
+     */
+    pub(crate) fn suggested_bitfield(&self, requestable: &[u8]) -> Option<Vec<u8>> {
+        if self.suggested_pieces.is_empty() {
+            return None;
+        }
+
+        return Some(self.restricted_bitfield(requestable, |piece_index| {
+            self.suggested_pieces.contains(&piece_index)
+        }));
+    }
+
+    fn restricted_bitfield<F: Fn(u32) -> bool>(&self, bitfield: &[u8], keep: F) -> Vec<u8> {
+        let mut restricted = vec![0u8; bitfield.len()];
+
+        for piece_index in 0..self.torrent_file.pieces_amount {
+            if bitfield_has_piece(bitfield, piece_index) && keep(piece_index as u32) {
+                set_piece(&mut restricted, piece_index);
+            }
+        }
+
+        return restricted;
+    }
+
+    pub(crate) fn can_request(&self) -> bool {
+        self.peer_bitfield.is_some() && (self.peer_unchoked || !self.peer_allowed_fast.is_empty())
+    }
+}
@@ -1,119 +1,162 @@
-use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
 
 use sha1::{Digest, Sha1};
+use tokio::task;
 
 use super::ConnectionHandler;
-use super::message::{MessageType, REQUEST_PIECE_SIZE};
-
-impl ConnectionHandler<'_> {
-    pub(crate) fn handle_new_piece(&mut self, raw_msg: &[u8]) {
-        let piece_index = u32::from_be_bytes(raw_msg[0..4].try_into().unwrap());
-        let offset_inside_piece = u32::from_be_bytes(raw_msg[4..8].try_into().unwrap());
-
-        let current_piece_index = self.current_piece.as_ref().unwrap().index;
+use super::message::PeerMessage;
+use crate::extension::{self, EXTENDED_HANDSHAKE_ID, ExtendedHandshake};
+use crate::peer_registry::PeerCommand;
+use crate::piece_picker::{BlockRequest, BlockResult, Piece, bitfield_has_piece};
+
+//requests above this size are refused, most clients never ask for more than 16KiB
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+//requests of a peer we keep queued, advertised as reqq in the extended handshake
+pub(crate) const MAX_UPLOAD_QUEUE: usize = 250;
+
+impl ConnectionHandler {
+    pub(crate) fn handle_new_piece(
+        &mut self,
+        piece_index: u32,
+        offset_inside_piece: u32,
+        block_data: &[u8],
+    ) -> Result<(), String> {
+        let block = BlockRequest {
+            piece: piece_index,
+            offset: offset_inside_piece,
+            length: block_data.len() as u32,
+        };
 
-        if current_piece_index != piece_index {
-            self.log_err(
+        if !self.request_queue.on_block_received(&block) {
+            //blocks cancelled in endgame may still arrive
+            self.log_debug(
                 format!(
-                    "expected piece index {} but received {piece_index}",
-                    current_piece_index
+                    "received block we are not waiting for: piece {piece_index} offset {offset_inside_piece} length {}",
+                    block_data.len()
                 )
                 .as_str(),
             );
-            return;
+            return Ok(());
         }
 
-        let block_data = &raw_msg[8..];
+        self.log_debug(
+            format!(
+                "received {} bytes for piece index {piece_index} & offset {offset_inside_piece}",
+                block_data.len(),
+            )
+            .as_str(),
+        );
+
+        let block_result = self
+            .piece_picker
+            .lock()
+            .unwrap()
+            .on_block_received(self.peer, &block, block_data);
 
-        let current_piece = self.current_piece.as_mut().unwrap();
+        match block_result {
+            BlockResult::Rejected => {
+                self.log_err(
+                    format!("invalid block for piece {piece_index} offset {offset_inside_piece}")
+                        .as_str(),
+                );
+                return Ok(());
+            }
+            BlockResult::Duplicate => {
+                self.log_debug("block was already received from another peer");
+                return Ok(());
+            }
+            BlockResult::Accepted { cancel, completed } => {
+                {
+                    let peer_registry = self.peer_registry.lock().unwrap();
+                    for peer in cancel {
+                        peer_registry.send(&peer, PeerCommand::Cancel(block));
+                    }
+                }
 
-        let min_required_length = (offset_inside_piece as usize) + block_data.len();
-        if current_piece.data.len() < min_required_length {
-            current_piece.data.resize(min_required_length, 0);
+                if let Some(piece) = completed {
+                    self.completed_pieces.push(piece);
+                }
+            }
         }
 
-        current_piece.data[(offset_inside_piece as usize)..min_required_length]
-            .copy_from_slice(&block_data);
+        return Ok(());
+    }
 
-        current_piece.received_offsets.insert(offset_inside_piece);
-        current_piece.missing_data -= block_data.len();
+    /**
+     * Hash the completed pieces and write the good ones on a blocking thread, the runtime
+     * threads keep serving the other connections meanwhile
+     */
+    pub(crate) async fn verify_completed_pieces(&mut self) -> Result<(), String> {
+        let mut banned = Vec::new();
 
-        /*
-         * We shadow current_piece with a non-mut ref because otherwise we cannot
-         * borrow self as non-mutable below. Removing the shadowing does not allow us to compile
-         */
+        //on an error the pieces left are aborted with the connection
+        while let Some(piece) = self.completed_pieces.pop() {
+            banned.extend(self.verify_piece(piece).await?);
+        }
 
-        let current_piece = self.current_piece.as_ref().unwrap();
+        return self.ban_peers(&banned);
+    }
 
-        self.log_debug(
-                format!(
-                    "received {} bytes for piece index {piece_index} & offset {offset_inside_piece} missing data: {}",
-                    block_data.len(),
-                    self.current_piece.as_ref().unwrap().missing_data
-                )
-                .as_str(),
-        );
+    async fn verify_piece(&mut self, piece: Piece) -> Result<Vec<IpAddr>, String> {
+        let piece_index = piece.index as usize;
+        let expected_hash =
+            self.torrent_file.info.pieces[(piece_index * 20)..(piece_index * 20) + 20].to_vec();
+        let piece_offset = piece_index * self.torrent_file.info.piece_length;
+        let file_handler = Arc::clone(&self.file_handler);
 
-        if current_piece.missing_data > 0 {
-            let max_offset_index_in_piece =
-                (self.torrent_file.info.piece_length as u32).div_ceil(REQUEST_PIECE_SIZE);
+        let verified = task::spawn_blocking(move || {
+            let calculated_hash = Sha1::digest(piece.data.as_slice()).to_vec();
+            let hashes_match = calculated_hash == expected_hash;
 
-            let next_offset = (0..max_offset_index_in_piece).find_map(|offset_index| {
-                let offset = offset_index * REQUEST_PIECE_SIZE;
-                if current_piece.received_offsets.contains(&offset) {
-                    None
-                } else {
-                    Some(offset)
-                }
-            });
+            let mut total_written_bytes = 0;
+            if hashes_match {
+                let mut file_handler = file_handler.lock().unwrap();
+                file_handler.write_piece_to_file(piece_offset, &piece.data);
+                file_handler.set_have(piece_index);
 
-            if next_offset.is_none() {
-                panic!(
-                    "searched next offset but found none for piece index: {piece_index} received offsets: {:?}",
-                    current_piece.received_offsets
-                );
+                total_written_bytes = file_handler.written_bytes;
             }
 
-            self.request_piece(piece_index, next_offset.unwrap());
-            return;
-        }
-
-        let hash_data = Sha1::new()
-            .chain_update(self.current_piece.as_ref().unwrap().data.as_slice())
-            .finalize();
-        let calculated_hash = hash_data.as_slice();
-
-        let expected_hash = &self.torrent_file.info.pieces
-            [(piece_index as usize * 20)..(piece_index as usize * 20) + 20];
+            return (piece, calculated_hash, expected_hash, total_written_bytes);
+        })
+        .await;
 
+        let (piece, calculated_hash, expected_hash, total_written_bytes) = match verified {
+            Ok(verified) => verified,
+            Err(e) => {
+                self.piece_picker.lock().unwrap().abort(piece_index);
+                return Err(format!("verifying piece {piece_index} failed: {e}"));
+            }
+        };
         let hashes_match = calculated_hash == expected_hash;
 
         self.log_debug(
             format!(
                 "Piece index {} is done.\nDownloaded: {}\nExpected:   {}\nmatch: {hashes_match}",
                 piece_index,
-                hex::encode(calculated_hash),
-                hex::encode(expected_hash)
+                hex::encode(&calculated_hash),
+                hex::encode(&expected_hash)
             )
             .as_str(),
         );
 
-        let total_written_bytes = {
-            let mut file_handler = self.file_handler.lock().unwrap();
-            let mut total_written_bytes = 0;
+        let banned = {
+            let mut piece_picker = self.piece_picker.lock().unwrap();
             if hashes_match {
-                file_handler.write_piece_to_file(
-                    piece_index as usize * self.torrent_file.info.piece_length,
-                    &current_piece.data,
-                );
-
-                total_written_bytes += file_handler.written_bytes;
+                piece_picker.hash_passed(&piece)
+            } else {
+                piece_picker.hash_failed(&piece)
             }
-            total_written_bytes
         };
 
         if hashes_match {
-            self.send_have(piece_index);
+            self.send_have(piece.index);
+            self.peer_registry
+                .lock()
+                .unwrap()
+                .broadcast(&self.peer, PeerCommand::Have(piece.index));
         }
 
         self.log_debug(
@@ -123,73 +166,287 @@ impl ConnectionHandler<'_> {
             )
             .as_str(),
         );
-        self.current_piece = None;
+
+        return Ok(banned);
     }
 
-    pub(crate) fn handle_request_piece(&mut self, raw_msg: &[u8]) {
-        let piece_index = u32::from_be_bytes(raw_msg[0..4].try_into().unwrap());
-        let offset_inside_piece = u32::from_be_bytes(raw_msg[4..8].try_into().unwrap());
-        let length = u32::from_be_bytes(raw_msg[8..].try_into().unwrap());
+    /**
+     * Peers that sent corrupt data are not connected to again and their connections are closed,
+     * this one included
+     */
+    fn ban_peers(&mut self, banned: &[IpAddr]) -> Result<(), String> {
+        if banned.is_empty() {
+            return Ok(());
+        }
 
-        let start_index =
-            self.torrent_file.info.piece_length as u32 * piece_index + offset_inside_piece;
+        {
+            let mut peer_pool = self.context.peer_pool.lock().unwrap();
+            for ip in banned {
+                peer_pool.ban(*ip);
+            }
+        }
 
-        let requested_data = self
-            .file_handler
-            .lock()
-            .unwrap()
-            .get_data_from_file(start_index as u64, length as usize);
+        let reason = String::from("banned for sending corrupt data");
+        {
+            let peer_registry = self.peer_registry.lock().unwrap();
+            for peer in peer_registry.peers() {
+                if peer != self.peer && banned.contains(&peer.ip()) {
+                    peer_registry.send(&peer, PeerCommand::Disconnect(reason.clone()));
+                }
+            }
+        }
+
+        if banned.contains(&self.peer.ip()) {
+            return Err(reason);
+        }
+
+        return Ok(());
+    }
+
+    pub(crate) fn handle_request_piece(&mut self, block: BlockRequest) {
+        if self.am_choking && !self.is_allowed_fast(block.piece) {
+            self.log_debug(format!("refusing request {:?} from choked peer", block).as_str());
+            self.reject_request(block);
+            return;
+        }
+
+        //the block must stay inside its piece, the last piece is usually shorter
+        let piece_index = block.piece as usize;
+        let piece_start = piece_index * self.torrent_file.info.piece_length;
+        let piece_size = self
+            .torrent_file
+            .info
+            .piece_length
+            .min(self.torrent_file.info.length.saturating_sub(piece_start));
+
+        if block.length > MAX_REQUEST_LENGTH
+            || piece_index >= self.torrent_file.pieces_amount
+            || block.offset as usize + block.length as usize > piece_size
+        {
+            self.log_err(format!("received invalid request {:?}", block).as_str());
+            self.reject_request(block);
+            return;
+        }
+
+        if !self.piece_picker.lock().unwrap().has(block.piece as usize) {
+            self.log_debug(format!("refusing request {:?} for a piece we miss", block).as_str());
+            self.reject_request(block);
+            return;
+        }
+
+        if self.upload_queue.len() >= MAX_UPLOAD_QUEUE {
+            self.log_debug(format!("upload queue is full, dropping request {:?}", block).as_str());
+            self.reject_request(block);
+            return;
+        }
 
-        let body_len = 1 + 4 + 4 + requested_data.len(); // id + index + begin + block
-        let mut piece_msg = vec![0u8; 4 + body_len];
-        piece_msg[0..4].copy_from_slice(&(body_len as u32).to_be_bytes());
-        piece_msg[4] = MessageType::Piece.to_byte();
-        piece_msg[5..9].copy_from_slice(&piece_index.to_be_bytes());
-        piece_msg[9..13].copy_from_slice(&offset_inside_piece.to_be_bytes());
-        piece_msg[13..].copy_from_slice(&requested_data);
+        self.upload_queue.push_back(block);
+    }
+
+    pub(crate) fn handle_cancel(&mut self, block: BlockRequest) {
+        let queued = self.upload_queue.len();
+        self.upload_queue
+            .retain(|queued_block| *queued_block != block);
+
+        //with the fast extension every request gets an answer, a cancelled one too
+        if self.upload_queue.len() < queued {
+            self.reject_request(block);
+        }
 
         self.log_debug(
             format!(
-                "sending piece {piece_index} offset {offset_inside_piece} length {}",
-                requested_data.len()
+                "peer cancelled {:?}, dropped {} queued upload",
+                block,
+                queued - self.upload_queue.len()
             )
             .as_str(),
         );
+    }
+
+    /**
+     * Queue the next block the peer requested, false when there is none.
+     * The block is read on a blocking thread.
+     */
+    pub(crate) async fn serve_next_upload(&mut self) -> bool {
+        let BlockRequest {
+            piece: piece_index,
+            offset: offset_inside_piece,
+            length,
+        } = match self.upload_queue.pop_front() {
+            Some(block) => block,
+            None => return false,
+        };
+
+        let start_index = piece_index as usize * self.torrent_file.info.piece_length
+            + offset_inside_piece as usize;
+        let file_handler = Arc::clone(&self.file_handler);
+
+        let read = task::spawn_blocking(move || {
+            let mut file_handler = file_handler.lock().unwrap();
+            let requested_data =
+                file_handler.get_data_from_file(start_index as u64, length as usize);
+            file_handler.uploaded_bytes += requested_data.len();
+
+            return requested_data;
+        })
+        .await;
+
+        let requested_data = match read {
+            Ok(requested_data) => requested_data,
+            Err(e) => {
+                self.log_err(format!("reading piece {piece_index} failed: {e}").as_str());
+                return false;
+            }
+        };
+
+        let uploaded = requested_data.len();
+
+        self.log_debug(
+            format!("sending piece {piece_index} offset {offset_inside_piece} length {uploaded}")
+                .as_str(),
+        );
+
+        self.send(&PeerMessage::Piece {
+            index: piece_index,
+            begin: offset_inside_piece,
+            block: requested_data,
+        });
 
-        self.stream_mut().write_all(&piece_msg).unwrap();
-        self.stream_mut().flush().unwrap();
+        self.upload_rate.add(uploaded);
+        return true;
     }
 
-    pub fn handle_bitfield(&mut self, raw_msg: &[u8]) {
-        let bitfield_vec = raw_msg.to_vec();
+    /**
+     * BEP 3: a bitfield of the wrong size is a protocol error, the connection is dropped
+     */
+    pub fn handle_bitfield(&mut self, raw_msg: &[u8]) -> Result<(), String> {
         let required_bitfield_length = self.torrent_file.pieces_amount.div_ceil(8);
-        if bitfield_vec.len() != required_bitfield_length {
-            self.log_err(
-                format!(
-                    "sent a bitfield of length {} while torrent needs {}",
-                    bitfield_vec.len(),
-                    required_bitfield_length
-                )
-                .as_str(),
-            );
+        if raw_msg.len() != required_bitfield_length {
+            return Err(format!(
+                "sent a bitfield of length {} while torrent needs {}",
+                raw_msg.len(),
+                required_bitfield_length
+            ));
         }
-        self.peer_bitfield = Some(bitfield_vec);
 
-        let mut has_missing_pieces = false;
-
-        for piece_index in 0..self.torrent_file.pieces_amount {
-            if !self.has_piece(piece_index) {
-                has_missing_pieces = true;
+        let bitfield_vec = raw_msg.to_vec();
+        {
+            let mut piece_picker = self.piece_picker.lock().unwrap();
+            if let Some(previous_bitfield) = &self.peer_bitfield {
+                piece_picker.remove_peer_bitfield(previous_bitfield);
             }
+            piece_picker.add_peer_bitfield(&bitfield_vec);
         }
 
-        self.peer_has_missing_pieces = has_missing_pieces;
+        self.peer_missing_pieces = (0..self.torrent_file.pieces_amount)
+            .filter(|piece_index| !bitfield_has_piece(&bitfield_vec, *piece_index))
+            .count();
+        self.peer_bitfield = Some(bitfield_vec);
+
+        return Ok(());
     }
 
-    pub fn handle_have(&mut self, raw_msg: &[u8]) {
-        let piece_index = u32::from_be_bytes(raw_msg.try_into().unwrap());
+    pub fn handle_have(&mut self, piece_index: u32) {
         self.log_debug(format!("peer has new piece {piece_index}").as_str());
 
+        if piece_index as usize >= self.torrent_file.pieces_amount {
+            self.log_err(format!("have for unknown piece {piece_index}").as_str());
+            return;
+        }
+
+        if self.peer_bitfield.is_none() {
+            //peers having no piece may skip the bitfield message
+            self.peer_bitfield = Some(vec![0u8; self.torrent_file.pieces_amount.div_ceil(8)]);
+        }
+
+        if self.has_piece(piece_index as usize) {
+            return;
+        }
+
         self.update_peer_bitfield(piece_index, true);
+        self.peer_missing_pieces = self.peer_missing_pieces.saturating_sub(1);
+        self.piece_picker
+            .lock()
+            .unwrap()
+            .peer_has(piece_index as usize);
+    }
+
+    pub(crate) fn send_extended_handshake(&mut self) {
+        let base = ExtendedHandshake {
+            v: Some(extension::client_version()),
+            p: Some(self.config.port),
+            reqq: Some(MAX_UPLOAD_QUEUE),
+            yourip: Some(self.peer.ip()),
+            ..ExtendedHandshake::default()
+        };
+        let handshake = self.extensions.our_handshake(base, &self.context);
+
+        self.log_debug(format!("sending extended handshake {:?}", handshake.m).as_str());
+
+        self.send_extended(EXTENDED_HANDSHAKE_ID, handshake.to_bytes());
+    }
+
+    pub(crate) fn send_extended(&mut self, extended_id: u8, payload: Vec<u8>) {
+        self.send(&PeerMessage::Extended {
+            id: extended_id,
+            payload,
+        });
+    }
+
+    pub(crate) fn send_extended_messages(&mut self, messages: Vec<(u8, Vec<u8>)>) {
+        for (extended_id, payload) in messages {
+            self.send_extended(extended_id, payload);
+        }
+    }
+
+    /**
+     * Extended handshake or message of a negotiated extension
+     */
+    pub(crate) fn handle_extended(
+        &mut self,
+        extended_id: u8,
+        payload: &[u8],
+    ) -> Result<(), String> {
+        if !extension::supports_extension_protocol(&self.peer_reserved) {
+            return Err(String::from(
+                "received extended message without extension protocol support",
+            ));
+        }
+
+        if extended_id != EXTENDED_HANDSHAKE_ID {
+            if self.peer_extended_handshake.is_none() {
+                return Err(String::from(
+                    "received extended message before the extended handshake",
+                ));
+            }
+
+            let messages =
+                self.extensions
+                    .on_message(extended_id, payload, self.peer, &self.context)?;
+            self.send_extended_messages(messages);
+            return Ok(());
+        }
+
+        let handshake = ExtendedHandshake::parse(payload)?;
+
+        self.log_debug(
+            format!(
+                "extended handshake: client {:?}, extensions {:?}, port {:?}, reqq {:?}, sees us as {:?}",
+                handshake.v, handshake.m, handshake.p, handshake.reqq, handshake.yourip
+            )
+            .as_str(),
+        );
+
+        if let Some(reqq) = handshake.reqq {
+            self.request_queue.limit_depth(reqq);
+        }
+
+        let messages = self
+            .extensions
+            .on_peer_handshake(&handshake, self.peer, &self.context);
+        self.peer_extended_handshake = Some(handshake);
+        self.send_extended_messages(messages);
+
+        return Ok(());
     }
 }
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use log::debug;
+use std::time::{Duration, Instant};
+
+use super::fast::{FAST_EXTENSION_BIT, FAST_EXTENSION_BYTE, supports_fast_extension};
+use super::mse::{self, EncryptionPolicy};
+use super::peer_stream::{PeerStream, Transport};
+use crate::client::ClientConfig;
+use crate::extension::{
+    EXTENSION_PROTOCOL_BIT, EXTENSION_PROTOCOL_BYTE, supports_extension_protocol,
+};
+use crate::utp::UtpSocket;
+
+pub const HANDSHAKE_LENGTH: usize = 68;
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Connect(io::Error),
+    Io(io::Error),
+    Timeout,
+    ConnectionClosed,
+    InvalidProtocol,
+    InfoHashMismatch,
+
+    //we connected to ourselves, usually through an address announced by the tracker
+    SelfConnection,
+
+    //the MSE handshake failed or the encryption policies of both sides do not match
+    Encryption(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "cannot connect: {e}"),
+            Self::Io(e) => write!(f, "handshake i/o error: {e}"),
+            Self::Timeout => write!(f, "handshake timed out"),
+            Self::ConnectionClosed => write!(f, "connection closed during handshake"),
+            Self::InvalidProtocol => write!(f, "not a BitTorrent handshake"),
+            Self::InfoHashMismatch => write!(f, "info hash does not match"),
+            Self::SelfConnection => write!(f, "connected to ourselves"),
+            Self::Encryption(e) => write!(f, "encryption handshake failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+fn io_error(e: io::Error) -> HandshakeError {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => HandshakeError::Timeout,
+        ErrorKind::UnexpectedEof => HandshakeError::ConnectionClosed,
+        _ => HandshakeError::Io(e),
+    }
+}
+
+/**
+ * <pstrlen=19><pstr><8 reserved bytes><info hash><peer id>
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    //extension bits (BEP 10, BEP 6...)
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0u8; 8];
+        reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+        reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
+
+        Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        supports_extension_protocol(&self.reserved)
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        supports_fast_extension(&self.reserved)
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut buf = [0u8; HANDSHAKE_LENGTH];
+
+        buf[0] = PROTOCOL.len() as u8;
+        buf[1..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..68].copy_from_slice(&self.peer_id);
+
+        return buf;
+    }
+
+    pub fn parse(buf: &[u8; HANDSHAKE_LENGTH]) -> Result<Self, HandshakeError> {
+        if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+
+        return Ok(Handshake {
+            reserved: buf[20..28].try_into().unwrap(),
+            info_hash: buf[28..48].try_into().unwrap(),
+            peer_id: buf[48..68].try_into().unwrap(),
+        });
+    }
+
+    /**
+     * Checks the handshake of a peer is for our torrent and does not come from ourselves
+     */
+    pub fn validate(
+        &self,
+        info_hash: &[u8; 20],
+        our_peer_id: &[u8; 20],
+    ) -> Result<(), HandshakeError> {
+        if self.info_hash != *info_hash {
+            return Err(HandshakeError::InfoHashMismatch);
+        }
+
+        if self.peer_id == *our_peer_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+
+        return Ok(());
+    }
+}
+
+fn connect_tcp(peer: SocketAddr, timeout: Duration) -> Result<TcpStream, HandshakeError> {
+    TcpStream::connect_timeout(&peer, timeout).map_err(HandshakeError::Connect)
+}
+
+/**
+ * uTP first when we have a uTP socket, TCP for the peers not answering it
+ */
+fn connect_transport(
+    peer: SocketAddr,
+    utp: Option<&UtpSocket>,
+    timeout: Duration,
+) -> Result<Transport, HandshakeError> {
+    if let Some(utp) = utp {
+        match utp.connect(peer, timeout) {
+            Ok(stream) => return Ok(Transport::Utp(stream)),
+            Err(e) => debug!("[{peer}] cannot connect over uTP ({e}), trying TCP"),
+        }
+    }
+
+    return Ok(Transport::Tcp(connect_tcp(peer, timeout)?));
+}
+
+/**
+ * Open a connection to the peer, encrypted according to the outgoing policy.
+ * When encryption is only preferred, a peer failing the MSE handshake is connected to again in plaintext.
+ */
+pub fn connect(
+    peer: SocketAddr,
+    info_hash: &[u8; 20],
+    config: &ClientConfig,
+    utp: Option<&UtpSocket>,
+) -> Result<PeerStream, HandshakeError> {
+    let policy = config.outgoing_encryption;
+    let stream = connect_transport(peer, utp, config.peer_connect_timeout)?;
+
+    if policy == EncryptionPolicy::Disabled {
+        return Ok(PeerStream::plaintext(stream));
+    }
+
+    match mse::initiate(stream, info_hash, policy, config.handshake_timeout) {
+        Ok(stream) => return Ok(stream),
+        Err(e) if policy == EncryptionPolicy::Preferred => {
+            debug!("[{peer}] {e}, retrying in plaintext");
+        }
+        Err(e) => return Err(e),
+    }
+
+    let stream = connect_transport(peer, utp, config.peer_connect_timeout)?;
+    return Ok(PeerStream::plaintext(stream));
+}
+
+pub fn send_handshake(
+    stream: &mut PeerStream,
+    handshake: &Handshake,
+) -> Result<(), HandshakeError> {
+    stream.write_all(&handshake.to_bytes()).map_err(io_error)
+}
+
+/**
+ * Read the 68 bytes of the handshake, failing if they did not all arrive before the deadline
+ */
+pub fn read_handshake(
+    stream: &mut PeerStream,
+    deadline: Duration,
+) -> Result<Handshake, HandshakeError> {
+    let started_at = Instant::now();
+    let mut buf = [0u8; HANDSHAKE_LENGTH];
+    let mut received = 0;
+
+    //a read timeout alone would let a peer sending one byte at a time go on forever
+    while received < HANDSHAKE_LENGTH {
+        let remaining = deadline.saturating_sub(started_at.elapsed());
+        if remaining.is_zero() {
+            return Err(HandshakeError::Timeout);
+        }
+
+        stream.set_read_timeout(Some(remaining)).map_err(io_error)?;
+
+        match stream.read(&mut buf[received..]) {
+            Ok(0) => return Err(HandshakeError::ConnectionClosed),
+            Ok(read) => received += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(io_error(e)),
+        }
+    }
+
+    return Handshake::parse(&buf);
+}
@@ -1,20 +1,25 @@
-use std::collections::HashSet;
+//largest message we accept, a bitfield of a huge torrent or a 16KiB block fit easily
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
 
-pub const REQUEST_PIECE_SIZE: u32 = 16u32 * 1024u32;
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MessageType {
     //https://wiki.theory.org/BitTorrentSpecification
-    Choke = 0,         // (choke): Peer notifies that it will not send data.
-    Unchoke = 1,       // (unchoke): Peer notifies that it will send data.
-    Interested = 2,    // (interested): Peer expresses interest in obtaining data.
-    NotInterested = 3, // (not interested): Peer expresses no interest in data.
-    Have = 4,          // (have): Tells peers that a piece has been downloaded.
+    Choke = 0,          // (choke): Peer notifies that it will not send data.
+    Unchoke = 1,        // (unchoke): Peer notifies that it will send data.
+    Interested = 2,     // (interested): Peer expresses interest in obtaining data.
+    NotInterested = 3,  // (not interested): Peer expresses no interest in data.
+    Have = 4,           // (have): Tells peers that a piece has been downloaded.
     Bitfield = 5, // (bitfield): Sent immediately after handshake to show all pieces a peer has.
     Request = 6,  // (request): Requests a block of data.
     Piece = 7,    // (piece): Delivers a block of data.
     Cancel = 8,   // (cancel): Cancels a previously sent request.
     Port = 9,     // (port): Used for DHT tracker connectivity.
+    SuggestPiece = 13, // (suggest piece): BEP 6, a piece the peer would like us to download.
+    HaveAll = 14, // (have all): BEP 6, replaces a full bitfield.
+    HaveNone = 15, // (have none): BEP 6, replaces an empty bitfield.
+    RejectRequest = 16, // (reject request): BEP 6, a request that will not be served.
+    AllowedFast = 17, // (allowed fast): BEP 6, a piece that can be requested while choked.
+    Extended = 20, // (extended): BEP 10 extension message, the first payload byte is the extended id.
 }
 
 impl MessageType {
@@ -30,6 +35,12 @@ impl MessageType {
             7 => Some(Self::Piece),
             8 => Some(Self::Cancel),
             9 => Some(Self::Port),
+            13 => Some(Self::SuggestPiece),
+            14 => Some(Self::HaveAll),
+            15 => Some(Self::HaveNone),
+            16 => Some(Self::RejectRequest),
+            17 => Some(Self::AllowedFast),
+            20 => Some(Self::Extended),
             _ => None,
         }
     }
@@ -46,20 +57,292 @@ impl MessageType {
             Self::Piece => 7,
             Self::Cancel => 8,
             Self::Port => 9,
+            Self::SuggestPiece => 13,
+            Self::HaveAll => 14,
+            Self::HaveNone => 15,
+            Self::RejectRequest => 16,
+            Self::AllowedFast => 17,
+            Self::Extended => 20,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct Piece {
-    pub index: u32,
-    pub data: Vec<u8>,
-    pub received_offsets: HashSet<u32>,
-    pub missing_data: usize,
+/**
+ * Peer wire message with its fields parsed. On the wire: <4 bytes BE length><1 byte id><payload>,
+ * a zero length being a keep-alive.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    Bitfield {
+        bitfield: Vec<u8>,
+    },
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port {
+        port: u16,
+    },
+    SuggestPiece {
+        index: u32,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast {
+        index: u32,
+    },
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    //ids we don't know, from an extension we did not negotiate. They are skipped, not fatal
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+fn read_u32(body: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(body[offset..offset + 4].try_into().unwrap())
 }
 
-#[derive(Debug)]
-pub struct Message {
-    pub msg_type: Option<MessageType>, //None for keep-alive msg as they have 0 length
-    pub data: Vec<u8>,
+impl PeerMessage {
+    /**
+     * None for keep-alives and unknown messages
+     */
+    pub fn message_type(&self) -> Option<MessageType> {
+        let msg_type = match self {
+            Self::KeepAlive | Self::Unknown { .. } => return None,
+            Self::Choke => MessageType::Choke,
+            Self::Unchoke => MessageType::Unchoke,
+            Self::Interested => MessageType::Interested,
+            Self::NotInterested => MessageType::NotInterested,
+            Self::Have { .. } => MessageType::Have,
+            Self::Bitfield { .. } => MessageType::Bitfield,
+            Self::Request { .. } => MessageType::Request,
+            Self::Piece { .. } => MessageType::Piece,
+            Self::Cancel { .. } => MessageType::Cancel,
+            Self::Port { .. } => MessageType::Port,
+            Self::SuggestPiece { .. } => MessageType::SuggestPiece,
+            Self::HaveAll => MessageType::HaveAll,
+            Self::HaveNone => MessageType::HaveNone,
+            Self::RejectRequest { .. } => MessageType::RejectRequest,
+            Self::AllowedFast { .. } => MessageType::AllowedFast,
+            Self::Extended { .. } => MessageType::Extended,
+        };
+
+        return Some(msg_type);
+    }
+
+    /**
+     * Append the full wire message, length prefix included
+     */
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let id = match (self, self.message_type()) {
+            (Self::Unknown { id, .. }, _) => *id,
+            (_, Some(msg_type)) => msg_type.to_byte(),
+            (_, None) => {
+                out.extend_from_slice(&0u32.to_be_bytes());
+                return;
+            }
+        };
+
+        //the length is known once the payload is written
+        let start = out.len();
+        out.extend_from_slice(&[0u8; 4]);
+        out.push(id);
+
+        match self {
+            Self::Have { index } | Self::SuggestPiece { index } | Self::AllowedFast { index } => {
+                out.extend_from_slice(&index.to_be_bytes());
+            }
+            Self::Bitfield { bitfield } => out.extend_from_slice(bitfield),
+            Self::Request {
+                index,
+                begin,
+                length,
+            }
+            | Self::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | Self::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                out.extend_from_slice(&index.to_be_bytes());
+                out.extend_from_slice(&begin.to_be_bytes());
+                out.extend_from_slice(&length.to_be_bytes());
+            }
+            Self::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                out.extend_from_slice(&index.to_be_bytes());
+                out.extend_from_slice(&begin.to_be_bytes());
+                out.extend_from_slice(block);
+            }
+            Self::Port { port } => out.extend_from_slice(&port.to_be_bytes()),
+            Self::Extended { id, payload } => {
+                out.push(*id);
+                out.extend_from_slice(payload);
+            }
+            Self::Unknown { payload, .. } => out.extend_from_slice(payload),
+            _ => {}
+        }
+
+        let length = (out.len() - start - 4) as u32;
+        out[start..start + 4].copy_from_slice(&length.to_be_bytes());
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.encode(&mut bytes);
+        return bytes;
+    }
+
+    /**
+     * Message from its id and payload, without the length prefix. An empty payload is a keep-alive.
+     * Known messages with the wrong length are an error.
+     */
+    pub fn parse(payload: &[u8]) -> Result<Self, String> {
+        let (id, body) = match payload.split_first() {
+            Some((id, body)) => (*id, body),
+            None => return Ok(Self::KeepAlive),
+        };
+
+        let msg_type = match MessageType::from_byte(id) {
+            Some(msg_type) => msg_type,
+            None => {
+                return Ok(Self::Unknown {
+                    id,
+                    payload: body.to_vec(),
+                });
+            }
+        };
+
+        let valid_length = match msg_type {
+            MessageType::Choke
+            | MessageType::Unchoke
+            | MessageType::Interested
+            | MessageType::NotInterested
+            | MessageType::HaveAll
+            | MessageType::HaveNone => body.is_empty(),
+            MessageType::Have | MessageType::SuggestPiece | MessageType::AllowedFast => {
+                body.len() == 4
+            }
+            MessageType::Request | MessageType::Cancel | MessageType::RejectRequest => {
+                body.len() == 12
+            }
+            MessageType::Port => body.len() == 2,
+            MessageType::Piece => body.len() >= 8,
+            MessageType::Extended => !body.is_empty(),
+            MessageType::Bitfield => true,
+        };
+        if !valid_length {
+            return Err(format!("received {:?} of length {}", msg_type, body.len()));
+        }
+
+        let message = match msg_type {
+            MessageType::Choke => Self::Choke,
+            MessageType::Unchoke => Self::Unchoke,
+            MessageType::Interested => Self::Interested,
+            MessageType::NotInterested => Self::NotInterested,
+            MessageType::Have => Self::Have {
+                index: read_u32(body, 0),
+            },
+            MessageType::Bitfield => Self::Bitfield {
+                bitfield: body.to_vec(),
+            },
+            MessageType::Request => Self::Request {
+                index: read_u32(body, 0),
+                begin: read_u32(body, 4),
+                length: read_u32(body, 8),
+            },
+            MessageType::Piece => Self::Piece {
+                index: read_u32(body, 0),
+                begin: read_u32(body, 4),
+                block: body[8..].to_vec(),
+            },
+            MessageType::Cancel => Self::Cancel {
+                index: read_u32(body, 0),
+                begin: read_u32(body, 4),
+                length: read_u32(body, 8),
+            },
+            MessageType::Port => Self::Port {
+                port: u16::from_be_bytes([body[0], body[1]]),
+            },
+            MessageType::SuggestPiece => Self::SuggestPiece {
+                index: read_u32(body, 0),
+            },
+            MessageType::HaveAll => Self::HaveAll,
+            MessageType::HaveNone => Self::HaveNone,
+            MessageType::RejectRequest => Self::RejectRequest {
+                index: read_u32(body, 0),
+                begin: read_u32(body, 4),
+                length: read_u32(body, 8),
+            },
+            MessageType::AllowedFast => Self::AllowedFast {
+                index: read_u32(body, 0),
+            },
+            MessageType::Extended => Self::Extended {
+                id: body[0],
+                payload: body[1..].to_vec(),
+            },
+        };
+
+        return Ok(message);
+    }
+
+    /**
+     * Take the next full message out of the buffer, None until enough bytes arrived.
+     * A length above max_message_size is an error: the connection cannot be trusted anymore.
+     */
+    pub fn decode(buf: &mut Vec<u8>, max_message_size: usize) -> Result<Option<Self>, String> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
+
+        if length > max_message_size {
+            return Err(format!("message of {length} bytes is too large"));
+        }
+
+        if buf.len() < 4 + length {
+            return Ok(None);
+        }
+
+        let message = Self::parse(&buf[4..4 + length]);
+        buf.drain(0..4 + length);
+
+        return message.map(Some);
+    }
 }
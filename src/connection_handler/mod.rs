@@ -1,55 +1,165 @@
+mod driver;
+pub mod fast;
 mod handlers;
-mod message;
+pub mod handshake;
+pub mod message;
+pub mod mse;
+pub mod peer_stream;
+mod request_queue;
 
-use std::cmp::min;
 use std::collections::{HashSet, VecDeque};
-use std::io::{ErrorKind, Read, Write};
-use std::net::TcpStream;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use log::{debug, error, info, warn};
+use tokio::task;
 
+use crate::client::ClientConfig;
+use crate::dht::{self, DHT_BIT, DHT_BYTE};
+use crate::extension::{self, ExtendedHandshake, ExtensionRegistry};
 use crate::file_handler::FileHandler;
-
+use crate::peer_registry::{PeerCommand, PeerRegistry, PeerStats};
+use crate::piece_picker::{BlockRequest, Piece, PiecePicker, bitfield_has_piece};
+use crate::rate_limiter::BandwidthLimit;
+use crate::rate_meter::RateMeter;
+use crate::runtime;
+use crate::torrent_context::TorrentContext;
 use crate::torrent_file::TorrentFile;
-use crate::tracker::get_handshake_data;
-use message::{Message, MessageType, Piece, REQUEST_PIECE_SIZE};
+use handshake::{Handshake, HandshakeError};
+use message::{MAX_MESSAGE_SIZE, PeerMessage};
+use peer_stream::PeerStream;
+use request_queue::RequestQueue;
+
+//the message loop wakes up at least this often to handle commands from other threads
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+//time a leecher has to become interested before a complete client drops it
+const INTEREST_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+const READ_CHUNK_SIZE: usize = 32 * 1024;
 
-pub struct ConnectionHandler<'a> {
-    peer: &'a str,
+pub struct ConnectionHandler {
+    peer: SocketAddr,
     torrent_file: Arc<TorrentFile>,
     file_handler: Arc<Mutex<FileHandler>>,
-    connected: bool, //success TCP connection + validated info hash
+    piece_picker: Arc<Mutex<PiecePicker>>,
+    peer_registry: Arc<Mutex<PeerRegistry>>,
+    commands: Receiver<PeerCommand>,
+    config: Arc<ClientConfig>,
+    connected: bool, //success TCP connection + validated handshake
     peer_interested: bool,
     peer_unchoked: bool,
-    current_piece: Option<Piece>,
-    next_downloadable_pieces: VecDeque<usize>,
-    peer_has_missing_pieces: bool,
+    am_interested: bool,
+
+    //only the choker unchokes peers
+    am_choking: bool,
+
+    request_queue: RequestQueue,
+
+    //we initiated the connection, so the peer address is the one it listens on
+    outgoing: bool,
+
+    //blocks the peer requested, served one at a time so a cancel can still drop them
+    upload_queue: VecDeque<BlockRequest>,
+    upload_rate: RateMeter,
+    //pieces whose last block arrived, hashed and written off the runtime by verify_completed_pieces
+    completed_pieces: Vec<Piece>,
+    //limits of this peer alone, the torrent and global ones come from the context
+    bandwidth: Arc<BandwidthLimit>,
+    connected_at: Instant,
+    //pieces absent from the peer bitfield, kept up to date by its bitfield and haves
+    peer_missing_pieces: usize,
+
+    //for the keep-alive and idle timers
+    last_sent_at: Instant,
+    last_received_at: Instant,
 
     peer_bitfield: Option<Vec<u8>>,
-    stream: Option<TcpStream>,
+
+    //messages waiting to be written by the connection task
+    write_buffer: Vec<u8>,
+    utp: bool,
+
+    //from the peer handshake
+    peer_id: Option<[u8; 20]>,
+    peer_reserved: [u8; 8],
+
+    //fast extension (BEP 6), supported by both sides
+    fast_extension: bool,
+    //pieces the peer can request while we choke it
+    our_allowed_fast: HashSet<u32>,
+    //pieces we can request while the peer chokes us
+    peer_allowed_fast: HashSet<u32>,
+    suggested_pieces: HashSet<u32>,
+    //pieces the peer rejected, not requested again until it unchokes us
+    rejected_pieces: HashSet<u32>,
+
+    //extension protocols, only used if the peer supports BEP 10
+    context: TorrentContext,
+    extensions: ExtensionRegistry,
+    peer_extended_handshake: Option<ExtendedHandshake>,
+
+    //received bytes not forming a full message yet
+    read_buffer: Vec<u8>,
 }
 
-impl<'a> ConnectionHandler<'a> {
-    pub fn new(
-        peer: &'a str,
-        torrent_file: Arc<TorrentFile>,
-        file_handler: Arc<Mutex<FileHandler>>,
-    ) -> Self {
+impl ConnectionHandler {
+    pub fn new(peer: SocketAddr, context: &TorrentContext) -> Self {
+        let config = Arc::clone(&context.config);
+        let request_queue = RequestQueue::new(config.min_request_queue, config.max_request_queue);
+        let commands = context.peer_registry.lock().unwrap().register(peer);
+        let bandwidth = Arc::new(BandwidthLimit::new(
+            config.per_peer_upload_limit,
+            config.per_peer_download_limit,
+        ));
+
+        let mut extensions = ExtensionRegistry::new();
+        for factory in &context.extensions {
+            extensions.register(factory());
+        }
+
         ConnectionHandler {
             peer,
             connected: false,
             peer_interested: false,
             peer_unchoked: false,
+            am_interested: false,
+            am_choking: true,
             peer_bitfield: None,
-            stream: None,
-            torrent_file,
-            file_handler,
-            current_piece: None,
-            next_downloadable_pieces: VecDeque::new(),
-
-            //setting this to true at ini because we don't know
-            peer_has_missing_pieces: true,
+            write_buffer: Vec::new(),
+            utp: false,
+            peer_id: None,
+            peer_reserved: [0u8; 8],
+            fast_extension: false,
+            our_allowed_fast: HashSet::new(),
+            peer_allowed_fast: HashSet::new(),
+            suggested_pieces: HashSet::new(),
+            rejected_pieces: HashSet::new(),
+            context: context.clone(),
+            extensions,
+            peer_extended_handshake: None,
+            torrent_file: Arc::clone(&context.torrent_file),
+            file_handler: Arc::clone(&context.file_handler),
+            piece_picker: Arc::clone(&context.piece_picker),
+            peer_registry: Arc::clone(&context.peer_registry),
+            commands,
+            config,
+            request_queue,
+            outgoing: false,
+            upload_queue: VecDeque::new(),
+            upload_rate: RateMeter::default(),
+            completed_pieces: Vec::new(),
+            bandwidth,
+            connected_at: Instant::now(),
+            last_sent_at: Instant::now(),
+            last_received_at: Instant::now(),
+            read_buffer: Vec::new(),
+
+            //every piece until the peer tells us otherwise
+            peer_missing_pieces: context.torrent_file.pieces_amount,
         }
     }
 
@@ -69,42 +179,146 @@ impl<'a> ConnectionHandler<'a> {
         warn!("[{}] {}", self.peer, msg);
     }
 
-    fn log_payload(&self, data: &[u8]) {
-        if data.len() < 100 {
-            self.log_debug(format!("payload: {:?}", data).as_str());
-        } else {
-            self.log_debug(
-                format!("payload: {:?}...{} elements", &data[0..32], data.len()).as_str(),
+    /**
+     * Next block to request, chosen by the piece picker shared with the other connections
+     */
+    fn next_block_to_request(&mut self) -> Option<BlockRequest> {
+        let requestable = self.requestable_bitfield()?;
+
+        //suggested pieces first, they are probably in the peer cache
+        if let Some(suggested) = self.suggested_bitfield(&requestable) {
+            let block = self
+                .piece_picker
+                .lock()
+                .unwrap()
+                .pick_block(self.peer, &suggested);
+
+            if block.is_some() {
+                return block;
+            }
+            self.suggested_pieces.clear();
+        }
+
+        let block = self
+            .piece_picker
+            .lock()
+            .unwrap()
+            .pick_block(self.peer, &requestable);
+
+        if block.is_none() {
+            self.on_nothing_to_pick();
+        }
+
+        return block;
+    }
+
+    /**
+     * Keep as many requests in flight as the request queue wants
+     */
+    fn fill_request_queue(&mut self) {
+        let desired_depth = self.request_queue.desired_depth();
+
+        while self.request_queue.len() < desired_depth {
+            let block = match self.next_block_to_request() {
+                Some(block) => block,
+                None => break,
+            };
+
+            self.request_piece(block);
+            self.request_queue.push(block);
+        }
+    }
+
+    /**
+     * Requests the peer did not answer in time are cancelled, their blocks go back to the picker
+     * with the data of the piece received so far. Returns whether some expired.
+     */
+    fn expire_requests(&mut self) -> bool {
+        let was_snubbed = self.request_queue.is_snubbed();
+        let expired = self.request_queue.take_expired(self.config.request_timeout);
+        if expired.is_empty() {
+            return false;
+        }
+
+        if !was_snubbed {
+            self.log_info(
+                format!("peer snubbed us, {} requests timed out", expired.len()).as_str(),
             );
         }
+
+        for block in &expired {
+            self.send_cancel(*block);
+        }
+
+        let mut piece_picker = self.piece_picker.lock().unwrap();
+        for block in expired {
+            piece_picker.release_block(self.peer, &block);
+        }
+
+        return true;
     }
 
-    fn start_new_piece(&mut self, piece_index: u32) {
-        let is_last_piece = piece_index as usize == self.torrent_file.pieces_amount - 1;
-        let piece_data_length = if is_last_piece {
-            self.torrent_file.info.length
-                - (piece_index as usize * self.torrent_file.info.piece_length)
-        } else {
-            self.torrent_file.info.piece_length
-        };
+    /**
+     * The peer dropped all our requests (choke), their blocks can be requested to other peers
+     */
+    fn reset_outstanding_requests(&mut self) {
+        let mut piece_picker = self.piece_picker.lock().unwrap();
 
-        self.current_piece = Some(Piece {
-            index: piece_index,
-            data: Vec::with_capacity(self.torrent_file.info.piece_length),
-            received_offsets: HashSet::new(),
-            missing_data: piece_data_length,
-        });
+        for block in self.request_queue.clear() {
+            piece_picker.release_block(self.peer, &block);
+        }
+    }
+
+    /**
+     * An error means the connection must be closed
+     */
+    fn handle_commands(&mut self) -> Result<(), String> {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                PeerCommand::Cancel(block) => {
+                    if self.request_queue.remove(&block) {
+                        self.send_cancel(block);
+                    }
+                }
+                PeerCommand::Choke => {
+                    if !self.am_choking {
+                        self.send_intention(PeerMessage::Choke);
+                        //choking discards every pending request of the peer
+                        self.reject_queued_uploads();
+                    }
+                }
+                PeerCommand::Unchoke => {
+                    if self.am_choking {
+                        self.send_intention(PeerMessage::Unchoke);
+                    }
+                }
+                PeerCommand::Have(piece_index) => self.send_have(piece_index),
+                PeerCommand::Disconnect(reason) => return Err(reason),
+                PeerCommand::SetRateLimits { upload, download } => {
+                    self.bandwidth.upload.set_rate(upload);
+                    self.bandwidth.download.set_rate(download);
+                }
+            }
+        }
 
-        self.log_debug(format!("Starting new piece {:?}", self.current_piece).as_str());
+        return Ok(());
     }
 
-    fn stream_mut(&mut self) -> &mut TcpStream {
-        assert!(
-            self.stream.is_some(),
-            "stream_mut: stream is not instantiated!"
-        );
+    fn send_raw(&mut self, raw_msg: &[u8]) {
+        self.write_buffer.extend_from_slice(raw_msg);
+        self.last_sent_at = Instant::now();
+    }
+
+    fn send(&mut self, msg: &PeerMessage) {
+        msg.encode(&mut self.write_buffer);
+        self.last_sent_at = Instant::now();
+    }
 
-        return self.stream.as_mut().unwrap();
+    /**
+     * Messages queued since the last call, in the order they must be sent
+     */
+    pub(crate) fn take_write_buffer(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.write_buffer)
     }
 
     pub fn has_piece(&mut self, piece: usize) -> bool {
@@ -121,381 +335,477 @@ impl<'a> ConnectionHandler<'a> {
             Some(bitfield) => bitfield,
         };
 
-        let piece_bitfield_index = piece.div_euclid(8);
-        let piece_bit_pos = 7 - piece % 8;
-
-        if peer_bitfield.len() - 1 < piece_bitfield_index {
-            self.log_err(
-                format!(
-                    "has bitfield of length {} but trying to reach index {piece_bitfield_index}",
-                    peer_bitfield.len()
-                )
-                .as_str(),
-            );
-        }
-
-        let pieces_in_index = peer_bitfield[piece_bitfield_index];
-
-        return ((pieces_in_index >> piece_bit_pos) & 0b1) == 1;
+        return bitfield_has_piece(peer_bitfield, piece);
     }
 
-    fn send_intention(&mut self, msg_type: MessageType) {
-        let mut raw_msg = [0u8; 5];
-
-        raw_msg[0..4].copy_from_slice(&1u32.to_be_bytes());
-        raw_msg[4] = msg_type.to_byte();
-
-        self.log_debug(format!("Sending intention: {:?}", msg_type).as_str());
+    /**
+     * Messages without payload, our choking and interest follow what we tell the peer
+     */
+    fn send_intention(&mut self, msg: PeerMessage) {
+        self.log_debug(format!("Sending intention: {:?}", msg).as_str());
+
+        match msg {
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            _ => {}
+        }
 
-        self.stream_mut().write_all(&raw_msg).unwrap();
+        self.send(&msg);
     }
 
     fn send_bitfield(&mut self) {
-        let mut raw_msg = vec![0u8; 4 + 1 + self.file_handler.lock().unwrap().bitfield.len()];
-
-        raw_msg[0..4].copy_from_slice(
-            &(1u32 + self.file_handler.lock().unwrap().bitfield.len() as u32).to_be_bytes(),
-        );
-
-        raw_msg[4] = MessageType::Bitfield.to_byte();
-
-        raw_msg[5..].copy_from_slice(&self.file_handler.lock().unwrap().bitfield);
+        let bitfield = self.file_handler.lock().unwrap().bitfield.clone();
 
         self.log_debug("Sending bitfield");
 
-        self.stream_mut().write_all(&raw_msg).unwrap();
+        self.send(&PeerMessage::Bitfield { bitfield });
     }
 
     fn send_have(&mut self, piece_index: u32) {
-        let mut raw_msg = [0u8; 4 + 1 + 4];
-
-        raw_msg[0..4].copy_from_slice(&(1u32 + 4u32).to_be_bytes());
-        raw_msg[4] = MessageType::Have.to_byte();
-        raw_msg[5..9].copy_from_slice(&piece_index.to_be_bytes());
-
         self.log_debug(format!("sending have: {piece_index}").as_str());
 
-        self.stream_mut().write_all(&raw_msg).unwrap();
+        self.send(&PeerMessage::Have { index: piece_index });
     }
 
-    fn request_piece(&mut self, piece: u32, offset: u32) {
-        let mut raw_msg = [0u8; 17];
-
-        //4 bytes BE => payload length
-        raw_msg[0..4].copy_from_slice(&13u32.to_be_bytes());
-
-        //1 byte => message type
-        raw_msg[4] = MessageType::Request.to_byte();
-
-        //4 bytes BE => piece index
-        raw_msg[5..9].copy_from_slice(&piece.to_be_bytes());
-
-        //4 bytes BE => offset inside the piece
-        raw_msg[9..13].copy_from_slice(&offset.to_be_bytes());
-
-        //4 bytes BE => amount of bytes to request
-        // raw_msg[13..17]
-        //     .copy_from_slice(&(self.torrent_file.info.piece_length as u32).to_be_bytes());
-
-        let req_size = min(
-            self.current_piece.as_ref().unwrap().missing_data as u32,
-            REQUEST_PIECE_SIZE,
+    fn request_piece(&mut self, block: BlockRequest) {
+        self.log_debug(
+            format!(
+                "sending request msg for piece index {} offset {} length {} ({} in flight)",
+                block.piece,
+                block.offset,
+                block.length,
+                self.request_queue.len() + 1
+            )
+            .as_str(),
         );
 
-        raw_msg[13..17].copy_from_slice(&(req_size).to_be_bytes());
-
-        self.log_debug(format!("sending request msg for piece index {piece} offset {offset} length {req_size}\n{:?}", raw_msg).as_str());
-
-        if self.current_piece.is_none() {
-            self.start_new_piece(piece);
-        }
-
-        //self.waiting_for_piece = Some(piece);
-
-        self.stream_mut().write_all(&raw_msg).unwrap();
+        self.send(&PeerMessage::Request {
+            index: block.piece,
+            begin: block.offset,
+            length: block.length,
+        });
     }
 
-    pub fn connect(&mut self) {
-        //handshakes are 68 bytes long
-
-        self.log_info("Connecting to peer");
-        let mut stream = match TcpStream::connect(self.peer) {
-            Ok(stream) => {
-                self.connected = true;
-                stream
-            }
-            Err(e) => {
-                self.log_err(format!("Could not initiate TCP connection with {}", e).as_str());
-                return;
-            }
-        };
-
-        let handshake_data = get_handshake_data(&self.torrent_file.info_hash);
+    fn send_cancel(&mut self, block: BlockRequest) {
         self.log_debug(
             format!(
-                "sending handshake data {:?}",
-                String::from_utf8_lossy(&handshake_data)
+                "sending cancel msg for piece index {} offset {} length {}",
+                block.piece, block.offset, block.length
             )
             .as_str(),
         );
-        stream.write_all(&handshake_data).unwrap();
 
-        let mut handshake_response = [0u8; 68];
-        let received_data = stream.read(&mut handshake_response);
-
-        if received_data.is_err() {
-            self.log_err(format!("closed connection {}", received_data.unwrap_err()).as_str());
+        self.send(&PeerMessage::Cancel {
+            index: block.piece,
+            begin: block.offset,
+            length: block.length,
+        });
+    }
 
-            self.connected = false;
-            return;
+    fn our_handshake(&self) -> Handshake {
+        let mut handshake = Handshake::new(self.torrent_file.info_hash, self.config.peer_id);
+        if self.context.dht.is_some() {
+            handshake.reserved[DHT_BYTE] |= DHT_BIT;
         }
 
+        return handshake;
+    }
+
+    fn on_peer_handshake(&mut self, peer_handshake: &Handshake) -> Result<(), HandshakeError> {
+        peer_handshake.validate(&self.torrent_file.info_hash, &self.config.peer_id)?;
+
         self.log_debug(
             format!(
-                "received handshake response: {:?}",
-                String::from_utf8_lossy(&handshake_response)
+                "peer id: {} | reserved: {}",
+                String::from_utf8_lossy(&peer_handshake.peer_id),
+                hex::encode(peer_handshake.reserved)
             )
             .as_str(),
         );
 
-        let peer_id = &handshake_response[48..68];
-        let info_hash = &handshake_response[28..48];
+        self.peer_id = Some(peer_handshake.peer_id);
+        self.peer_reserved = peer_handshake.reserved;
+        self.fast_extension = peer_handshake.supports_fast_extension()
+            && self.our_handshake().supports_fast_extension();
+        self.connected = true;
 
-        let info_hash_match = self.torrent_file.info_hash.eq(info_hash);
+        return Ok(());
+    }
 
-        self.log_debug(
-            format!(
-                "peer id: {} | info hash match: {}",
-                String::from_utf8_lossy(peer_id),
-                info_hash_match
-            )
-            .as_str(),
-        );
+    /**
+     * Connect to the peer, exchange handshakes and run the message loop until the connection ends.
+     * Blocking facade over connect_async.
+     */
+    pub fn connect(&mut self) -> Result<(), HandshakeError> {
+        runtime::block_on(self.connect_async())
+    }
 
-        self.stream = Some(stream);
-        self.send_bitfield();
-        if self.file_handler.lock().unwrap().needed_pieces.len() > 0 {
-            self.send_intention(MessageType::Interested);
-        } else {
-            self.send_intention(MessageType::NotInterested);
-        }
+    pub async fn connect_async(&mut self) -> Result<(), HandshakeError> {
+        self.log_info("Connecting to peer");
+        self.outgoing = true;
 
-        if self.file_handler.lock().unwrap().needed_pieces.len() < self.torrent_file.pieces_amount {
-            //we already have some pieces to we can unchoke
-            self.send_intention(MessageType::Unchoke);
-        }
+        //connecting and the handshakes are short, they keep their blocking implementation
+        let peer = self.peer;
+        let info_hash = self.torrent_file.info_hash;
+        let config = Arc::clone(&self.config);
+        let utp_socket = self.context.utp_socket.clone();
+        let our_handshake = self.our_handshake();
+
+        let (stream, peer_handshake) = task::spawn_blocking(move || {
+            let mut stream = handshake::connect(peer, &info_hash, &config, utp_socket.as_deref())?;
+            handshake::send_handshake(&mut stream, &our_handshake)?;
+            let peer_handshake = handshake::read_handshake(&mut stream, config.handshake_timeout)?;
+
+            return Ok::<_, HandshakeError>((stream, peer_handshake));
+        })
+        .await
+        .map_err(|e| HandshakeError::Io(io::Error::other(e)))??;
 
-        self.run_message_loop();
+        self.on_peer_handshake(&peer_handshake)?;
 
-        self.log_info("Exiting thread");
-        //stream.shutdown(Shutdown::Both).unwrap();
+        self.run(stream).await;
+        return Ok(());
     }
 
-    fn await_next_msg(&mut self) -> Result<Message, String> {
-        //4 first bytes is the payload length
-        let mut payload_length_raw = [0u8; 4];
-        match self.stream_mut().read_exact(&mut payload_length_raw) {
-            Ok(()) => {}
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    return Err(String::from("peer closed the connection"));
-                } else {
-                    return Err(format!("stream read error: {}", e));
-                }
-            }
-        }
+    /**
+     * Serve a peer that connected to us, its handshake was already read by the listener.
+     * Blocking facade over accept_async.
+     */
+    pub fn accept(
+        &mut self,
+        stream: PeerStream,
+        peer_handshake: &Handshake,
+    ) -> Result<(), HandshakeError> {
+        runtime::block_on(self.accept_async(stream, peer_handshake))
+    }
 
-        let payload_length = u32::from_be_bytes(payload_length_raw);
+    pub async fn accept_async(
+        &mut self,
+        stream: PeerStream,
+        peer_handshake: &Handshake,
+    ) -> Result<(), HandshakeError> {
+        self.log_info("Accepted connection from peer");
+        self.on_peer_handshake(peer_handshake)?;
 
-        if payload_length == 0 {
-            self.log_debug("received keep-alive");
-            return Ok(Message {
-                msg_type: None,
-                data: Vec::new(),
-            });
-        }
+        //goes out first, encrypted like the rest when the connection is
+        let our_handshake = self.our_handshake().to_bytes();
+        self.send_raw(&our_handshake);
 
-        let mut full_payload = vec![0u8; payload_length as usize];
-        self.stream_mut().read_exact(&mut full_payload).unwrap();
+        self.run(stream).await;
+        return Ok(());
+    }
 
-        let msg_id = full_payload[0];
+    pub fn peer_id(&self) -> Option<[u8; 20]> {
+        self.peer_id
+    }
 
-        let msg_type = match MessageType::from_byte(msg_id) {
-            None => {
-                return Err(format!("unknown message type: {}", msg_id));
-            }
-            Some(m) => m,
-        };
+    pub fn peer_reserved(&self) -> [u8; 8] {
+        self.peer_reserved
+    }
 
-        return Ok(Message {
-            msg_type: Some(msg_type),
-            data: full_payload,
-        });
+    /**
+     * Last extended handshake of the peer, None if it does not support the extension protocol
+     */
+    pub fn peer_extended_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer_extended_handshake.as_ref()
     }
 
-    fn run_message_loop(&mut self) {
-        loop {
-            let new_msg = self.await_next_msg();
+    /**
+     * First messages once the handshake is done, for both directions
+     */
+    fn start(&mut self) {
+        //the handshake may have taken a while, the idle timer starts now
+        self.last_received_at = Instant::now();
+        self.send_availability();
+        self.send_allowed_fast();
+        if extension::supports_extension_protocol(&self.peer_reserved) {
+            self.send_extended_handshake();
+        }
+        if let Some(dht) = &self.context.dht
+            && dht::supports_dht(&self.peer_reserved)
+        {
+            let port = dht.local_addr().port();
+            self.send(&PeerMessage::Port { port });
+        }
+        if self.piece_picker.lock().unwrap().remaining() > 0 {
+            self.send_intention(PeerMessage::Interested);
+        } else {
+            self.send_intention(PeerMessage::NotInterested);
+        }
+    }
 
-            if new_msg.is_err() {
-                self.log_err(format!("{}", new_msg.unwrap_err()).as_str());
-                return;
-            }
+    /**
+     * Bytes received from the peer, every full message is handled right away
+     */
+    fn on_data(&mut self, data: &[u8]) -> Result<(), String> {
+        self.read_buffer.extend_from_slice(data);
+        if !data.is_empty() {
+            self.last_received_at = Instant::now();
+        }
 
-            let new_msg = new_msg.unwrap();
-
-            if new_msg.data.len() == 0 {
-                self.log_debug("received keep-alive msg");
-                /*
-                 * Currently sending KA message in response to a KA message as
-                 * we are running sync single thread.
-                 * Should implement own KA timer with multi thread implem
-                 */
-                self.send_keep_alive();
-                continue;
-            }
+        while let Some(msg) = PeerMessage::decode(&mut self.read_buffer, MAX_MESSAGE_SIZE)? {
+            self.handle_message(msg)?;
+        }
 
-            let msg_type = new_msg.msg_type.unwrap();
+        return Ok(());
+    }
+
+    /**
+     * An error means the peer broke the protocol and the connection must be dropped
+     */
+    fn handle_message(&mut self, msg: PeerMessage) -> Result<(), String> {
+        if let Some(msg_type) = msg.message_type() {
             self.log_debug(format!("received message type: {:?}", msg_type).as_str());
-            self.log_payload(&new_msg.data);
-
-            match msg_type {
-                MessageType::Choke => self.peer_unchoked = false,
-                MessageType::Unchoke => self.peer_unchoked = true,
-                MessageType::Interested => self.peer_interested = true,
-                MessageType::NotInterested => self.peer_interested = false,
-
-                MessageType::Have => {
-                    assert!(
-                        new_msg.data.len() == 5,
-                        "received HAVE msg with length of {}",
-                        new_msg.data.len()
-                    );
-                    self.handle_have(&new_msg.data[1..]);
-                }
-                MessageType::Bitfield => {
-                    self.handle_bitfield(&new_msg.data[1..]);
-                }
-                MessageType::Request => {
-                    self.handle_request_piece(&new_msg.data[1..]);
-                }
-                MessageType::Piece => {
-                    let truncated_payload = &new_msg.data[1..];
-                    self.handle_new_piece(truncated_payload);
+        }
+
+        match msg {
+            PeerMessage::KeepAlive => self.log_debug("received keep-alive msg"),
+            PeerMessage::Choke => {
+                self.peer_unchoked = false;
+
+                //with the fast extension the peer rejects each dropped request explicitly
+                if !self.fast_extension {
+                    self.reset_outstanding_requests();
                 }
-                MessageType::Cancel => {}
-                MessageType::Port => {}
             }
-
-            if !self.connected {
-                /*
-                 * Should not happen because we connect on top of
-                 * the loop, and return in case of disconnect.
-                 * So most likely a bug if came here
-                 */
-                self.log_err("peer is not connected");
-                break;
+            PeerMessage::Unchoke => {
+                self.peer_unchoked = true;
+                self.rejected_pieces.clear();
             }
-
-            if self.peer_bitfield.is_some()
-                && self.current_piece.is_none()
-                && (!self.peer_interested
-                    && self.file_handler.lock().unwrap().written_bytes
-                        == self.torrent_file.info.length)
-            {
-                self.log_info(
-                    "dropping connection as we downloaded all and peer is not interested",
+            PeerMessage::Interested => self.peer_interested = true,
+            PeerMessage::NotInterested => self.peer_interested = false,
+
+            PeerMessage::Have { index } => self.handle_have(index),
+            PeerMessage::Bitfield { bitfield } => self.handle_bitfield(&bitfield)?,
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                self.handle_request_piece(BlockRequest {
+                    piece: index,
+                    offset: begin,
+                    length,
+                });
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => self.handle_new_piece(index, begin, &block)?,
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                self.handle_cancel(BlockRequest {
+                    piece: index,
+                    offset: begin,
+                    length,
+                });
+            }
+            PeerMessage::Port { port } => {
+                self.log_debug(format!("received port msg, DHT node on port {port}").as_str());
+                if let Some(dht) = &self.context.dht {
+                    dht.add_node(SocketAddr::new(self.peer.ip(), port));
+                }
+            }
+            PeerMessage::SuggestPiece { .. }
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::RejectRequest { .. }
+            | PeerMessage::AllowedFast { .. } => self.handle_fast_message(msg)?,
+            PeerMessage::Extended { id, payload } => self.handle_extended(id, &payload)?,
+            PeerMessage::Unknown { id, payload } => {
+                self.log_debug(
+                    format!(
+                        "ignoring unknown message id {id} of {} bytes",
+                        payload.len()
+                    )
+                    .as_str(),
                 );
-                break;
             }
+        }
 
-            if self.peer_unchoked && self.peer_bitfield.is_some() && self.current_piece.is_none() {
-                //plan new next pieces available from peer if the inner buffer is empty
-                self.plan_next_pieces();
-
-                if self.next_downloadable_pieces.len() > 0 {
-                    let next_piece = self.next_downloadable_pieces.pop_front().unwrap();
-                    self.start_new_piece(next_piece as u32);
-                    self.request_piece(next_piece as u32, 0u32);
-                }
+        return Ok(());
+    }
 
-                if self.peer_has_missing_pieces {}
+    /**
+     * Share the connection state with the choker
+     */
+    fn publish_stats(&mut self) {
+        let listen_addr = if self.outgoing {
+            Some(self.peer)
+        } else {
+            self.peer_extended_handshake
+                .as_ref()
+                .and_then(|handshake| handshake.p)
+                .filter(|port| *port != 0)
+                .map(|port| SocketAddr::new(self.peer.ip(), port))
+        };
 
-                //self.download_next_piece();
-            }
-        }
+        let stats = PeerStats {
+            peer_interested: self.peer_interested,
+            am_choking: self.am_choking,
+            download_rate: self.request_queue.download_rate.rate(),
+            upload_rate: self.upload_rate.rate(),
+            connected_at: self.connected_at,
+            listen_addr,
+            seed: self.peer_bitfield.is_some() && self.peer_missing_pieces == 0,
+            utp: self.utp,
+            snubbed: self.request_queue.is_snubbed(),
+        };
 
-        //we don't unwrap because it will panic if already closed
-        let _ = self.stream_mut().shutdown(std::net::Shutdown::Both);
+        self.peer_registry
+            .lock()
+            .unwrap()
+            .update_stats(&self.peer, stats);
     }
 
-    pub fn plan_next_pieces(&mut self) {
-        if self.next_downloadable_pieces.len() > 0 {
-            return;
+    /**
+     * Work done after every batch of received data and on every tick of the connection task.
+     * Returns false when the connection should be closed.
+     */
+    fn step(&mut self) -> bool {
+        if let Err(e) = self.handle_commands() {
+            self.log_err(e.as_str());
+            return false;
         }
 
-        let mut not_available_pieces = HashSet::<usize>::new();
+        if let Err(e) = self.check_timers() {
+            self.log_err(e.as_str());
+            return false;
+        }
 
-        loop {
-            let next_needed_piece = self.file_handler.lock().unwrap().needed_pieces.pop_front();
+        if self.peer_extended_handshake.is_some() {
+            let messages = self.extensions.on_tick(self.peer, &self.context);
+            self.send_extended_messages(messages);
+        }
 
-            if next_needed_piece.is_none() {
-                break;
-            }
+        if !self.connected {
+            //the task only runs once the handshake is validated, so most likely a bug
+            self.log_err("peer is not connected");
+            return false;
+        }
 
-            let next_needed_piece = next_needed_piece.unwrap();
+        /*
+         * Once we have everything, seeds are useless and leechers get a moment
+         * to tell us they are interested (they usually send it after our bitfield)
+         */
+        let peer_is_useless = self.peer_missing_pieces == 0
+            || (!self.peer_interested && self.connected_at.elapsed() >= INTEREST_GRACE_PERIOD);
+
+        if self.peer_bitfield.is_some()
+            && self.request_queue.len() == 0
+            && self.upload_queue.is_empty()
+            && peer_is_useless
+            && self.file_handler.lock().unwrap().written_bytes == self.torrent_file.info.length
+        {
+            self.log_info("dropping connection as we downloaded all and peer is not interested");
+            return false;
+        }
 
-            if !self.has_piece(next_needed_piece) {
-                not_available_pieces.insert(next_needed_piece);
-                continue;
-            }
+        //the released blocks are left to the other peers until the next step
+        let expired = self.expire_requests();
+        if !expired && self.can_request() {
+            self.fill_request_queue();
+        }
 
-            self.next_downloadable_pieces.push_back(next_needed_piece);
+        self.publish_stats();
+        return true;
+    }
 
-            if self.next_downloadable_pieces.len() > 4 {
-                break;
-            }
+    /**
+     * Keep-alive when we have been silent for a while, and the end of the connection when the peer has
+     */
+    fn check_timers(&mut self) -> Result<(), String> {
+        let idle = self.last_received_at.elapsed();
+        if idle >= self.config.peer_idle_timeout {
+            return Err(format!("peer sent nothing for {}s", idle.as_secs()));
         }
 
-        not_available_pieces.iter().for_each(|piece| {
-            self.file_handler
-                .lock()
-                .unwrap()
-                .needed_pieces
-                .push_front(*piece);
-        });
-        self.log_debug(
-            format!(
-                "putting back {} pieces that peer does not have\n{:?}",
-                not_available_pieces.len(),
-                not_available_pieces
-            )
-            .as_str(),
-        );
+        if self.last_sent_at.elapsed() >= self.config.keep_alive_interval {
+            self.send_keep_alive();
+        }
+
+        return Ok(());
+    }
 
-        if self.file_handler.lock().unwrap().needed_pieces.len() == 0 {
-            self.log_info(format!("Done downloading all torrent pieces",).as_str());
-            self.send_intention(MessageType::NotInterested);
-        } else if self.next_downloadable_pieces.len() == 0 {
+    /**
+     * The picker has no piece for this peer, either we are done or the peer has nothing we need
+     */
+    fn on_nothing_to_pick(&mut self) {
+        let remaining = self.piece_picker.lock().unwrap().remaining();
+
+        if remaining == 0 {
+            if self.am_interested {
+                self.log_info("Done downloading all torrent pieces");
+                self.send_intention(PeerMessage::NotInterested);
+            }
+        } else if self.request_queue.len() == 0 {
             self.log_debug("cannot download any more pieces from peer");
         }
     }
 
     pub fn update_peer_bitfield(&mut self, piece_index: u32, available: bool) {
-        let bitfield = self.peer_bitfield.as_mut().unwrap();
-
         let index_in_bitfield = piece_index.div_euclid(8) as usize;
         let bit_offset = 7 - (piece_index % 8);
 
+        let byte = match self
+            .peer_bitfield
+            .as_mut()
+            .and_then(|bitfield| bitfield.get_mut(index_in_bitfield))
+        {
+            Some(byte) => byte,
+            None => {
+                self.log_err(
+                    format!("piece {piece_index} is outside of the peer bitfield").as_str(),
+                );
+                return;
+            }
+        };
+
         if available {
-            bitfield[index_in_bitfield] |= 1 << bit_offset;
+            *byte |= 1 << bit_offset;
         } else {
-            bitfield[index_in_bitfield] &= !(1 << bit_offset);
+            *byte &= !(1 << bit_offset);
         }
     }
 
     pub fn send_keep_alive(&mut self) {
         self.log_debug("Sending keep-alive msg");
-        self.stream_mut().write_all(&0u32.to_be_bytes()).unwrap();
+        self.send(&PeerMessage::KeepAlive);
+    }
+}
+
+impl Drop for ConnectionHandler {
+    /**
+     * Give the outstanding blocks back to the picker, forget the peer availability
+     * and unregister the connection. Also runs when the connection thread panics
+     */
+    fn drop(&mut self) {
+        if let Ok(mut peer_registry) = self.peer_registry.lock() {
+            peer_registry.unregister(&self.peer);
+        }
+
+        let mut piece_picker = match self.piece_picker.lock() {
+            Ok(piece_picker) => piece_picker,
+            Err(_) => return,
+        };
+
+        for block in self.request_queue.clear() {
+            piece_picker.release_block(self.peer, &block);
+        }
+
+        //the task stopped before they were verified, they are downloaded again
+        for piece in &self.completed_pieces {
+            piece_picker.abort(piece.index as usize);
+        }
+
+        if let Some(bitfield) = &self.peer_bitfield {
+            piece_picker.remove_peer_bitfield(bitfield);
+        }
     }
 }
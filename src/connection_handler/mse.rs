@@ -0,0 +1,498 @@
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+
+use super::handshake::{HandshakeError, PROTOCOL};
+use super::peer_stream::{PeerStream, Transport};
+
+//Message Stream Encryption: 768 bit Diffie-Hellman group with generator 2
+const DH_PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const DH_GENERATOR: u32 = 2;
+const DH_KEY_LENGTH: usize = 96;
+
+//random padding after the public keys, and the most a peer may send before a synchronisation point
+const MAX_PADDING: usize = 512;
+
+//verification constant, 8 zero bytes found encrypted in the stream
+const VC: [u8; 8] = [0u8; 8];
+
+//the first bytes of the RC4 keystream are weak and dropped
+const RC4_DISCARD: usize = 1024;
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/**
+ * What we accept for a connection: plaintext only, both with RC4 preferred, or RC4 only
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    Disabled,
+    #[default]
+    Preferred,
+    Forced,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "disabled" => Ok(Self::Disabled),
+            "preferred" => Ok(Self::Preferred),
+            "forced" => Ok(Self::Forced),
+            _ => Err(format!("unknown encryption policy {value}")),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "disabled"),
+            Self::Preferred => write!(f, "preferred"),
+            Self::Forced => write!(f, "forced"),
+        }
+    }
+}
+
+impl EncryptionPolicy {
+    /**
+     * crypto_provide field of the initiator
+     */
+    pub fn crypto_provide(&self) -> u32 {
+        match self {
+            Self::Forced => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /**
+     * crypto_select field of the receiver, None when nothing provided is acceptable
+     */
+    pub fn crypto_select(&self, provided: u32) -> Option<u32> {
+        if provided & CRYPTO_RC4 != 0 {
+            return Some(CRYPTO_RC4);
+        }
+
+        if provided & CRYPTO_PLAINTEXT != 0 && *self != Self::Forced {
+            return Some(CRYPTO_PLAINTEXT);
+        }
+
+        return None;
+    }
+}
+
+/**
+ * RC4 keystream, the same operation encrypts and decrypts
+ */
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+
+    /**
+     * Keystream of a direction: SHA1("keyA" or "keyB", S, SKEY) with the first 1024 bytes dropped
+     */
+    fn for_direction(name: &[u8], secret: &[u8], skey: &[u8; 20]) -> Self {
+        let mut rc4 = Rc4::new(&hash(&[name, secret, skey]));
+        rc4.apply(&mut [0u8; RC4_DISCARD]);
+        return rc4;
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    return hasher.finalize().into();
+}
+
+fn xor(left: &[u8; 20], right: &[u8; 20]) -> [u8; 20] {
+    let mut result = [0u8; 20];
+    for (index, value) in result.iter_mut().enumerate() {
+        *value = left[index] ^ right[index];
+    }
+    return result;
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut rng = rand::rng();
+    let length = rng.random_range(0..=MAX_PADDING);
+    return (0..length).map(|_| rng.random()).collect();
+}
+
+fn encryption_error(reason: &str) -> HandshakeError {
+    HandshakeError::Encryption(reason.to_string())
+}
+
+/**
+ * Our Diffie-Hellman key pair for one connection
+ */
+struct KeyPair {
+    private_key: BigUint,
+    public_key: [u8; DH_KEY_LENGTH],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        //160 bits are enough for the private key
+        let private_key = BigUint::from_bytes_be(&rand::rng().random::<[u8; 20]>());
+        let public_key = BigUint::from(DH_GENERATOR).modpow(&private_key, &prime());
+
+        KeyPair {
+            private_key,
+            public_key: to_key_bytes(&public_key),
+        }
+    }
+
+    /**
+     * S, the shared secret, refusing the trivial public keys that would make it predictable
+     */
+    fn shared_secret(&self, peer_public_key: &[u8]) -> Result<[u8; DH_KEY_LENGTH], HandshakeError> {
+        let prime = prime();
+        let peer_public_key = BigUint::from_bytes_be(peer_public_key);
+
+        if peer_public_key <= BigUint::from(1u32) || peer_public_key >= &prime - 1u32 {
+            return Err(encryption_error("invalid public key"));
+        }
+
+        return Ok(to_key_bytes(
+            &peer_public_key.modpow(&self.private_key, &prime),
+        ));
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(DH_PRIME.as_bytes(), 16).unwrap()
+}
+
+/**
+ * Keys are sent as 96 big endian bytes, left padded with zeros
+ */
+fn to_key_bytes(value: &BigUint) -> [u8; DH_KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; DH_KEY_LENGTH];
+    key[DH_KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    return key;
+}
+
+/**
+ * Reads the handshake with a deadline, keeping what was received past the current step
+ */
+struct HandshakeReader<'a> {
+    stream: &'a mut Transport,
+    buffer: Vec<u8>,
+    started_at: Instant,
+    deadline: Duration,
+}
+
+impl<'a> HandshakeReader<'a> {
+    fn new(stream: &'a mut Transport, deadline: Duration) -> Self {
+        HandshakeReader {
+            stream,
+            buffer: Vec::new(),
+            started_at: Instant::now(),
+            deadline,
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), HandshakeError> {
+        self.stream.write_all(data).map_err(HandshakeError::Io)
+    }
+
+    /**
+     * Read more data, at least one byte
+     */
+    fn receive(&mut self) -> Result<(), HandshakeError> {
+        let mut chunk = [0u8; 1024];
+
+        loop {
+            let remaining = self.deadline.saturating_sub(self.started_at.elapsed());
+            if remaining.is_zero() {
+                return Err(HandshakeError::Timeout);
+            }
+
+            self.stream
+                .set_read_timeout(Some(remaining))
+                .map_err(HandshakeError::Io)?;
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(HandshakeError::ConnectionClosed),
+                Ok(read) => {
+                    self.buffer.extend_from_slice(&chunk[..read]);
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Err(HandshakeError::Timeout);
+                }
+                Err(e) => return Err(HandshakeError::Io(e)),
+            }
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<Vec<u8>, HandshakeError> {
+        while self.buffer.len() < length {
+            self.receive()?;
+        }
+
+        return Ok(self.buffer.drain(..length).collect());
+    }
+
+    fn take_decrypted(&mut self, length: usize, rc4: &mut Rc4) -> Result<Vec<u8>, HandshakeError> {
+        let mut data = self.take(length)?;
+        rc4.apply(&mut data);
+        return Ok(data);
+    }
+
+    fn take_u16(&mut self, rc4: &mut Rc4) -> Result<usize, HandshakeError> {
+        let data = self.take_decrypted(2, rc4)?;
+        return Ok(u16::from_be_bytes([data[0], data[1]]) as usize);
+    }
+
+    /**
+     * Skip the padding of the peer up to the pattern, which is consumed too
+     */
+    fn synchronize(&mut self, pattern: &[u8]) -> Result<(), HandshakeError> {
+        loop {
+            if let Some(position) = self
+                .buffer
+                .windows(pattern.len())
+                .position(|window| window == pattern)
+            {
+                self.buffer.drain(..position + pattern.len());
+                return Ok(());
+            }
+
+            if self.buffer.len() >= MAX_PADDING + pattern.len() {
+                return Err(encryption_error("synchronisation point not found"));
+            }
+
+            self.receive()?;
+        }
+    }
+
+    /**
+     * What the peer already sent after the handshake, decrypted if the stream is encrypted
+     */
+    fn into_pending(self, rc4: Option<&mut Rc4>) -> Vec<u8> {
+        let mut pending = self.buffer;
+        if let Some(rc4) = rc4 {
+            rc4.apply(&mut pending);
+        }
+        return pending;
+    }
+}
+
+/**
+ * Start the handshake on a connection we opened, the info hash is the shared key (SKEY)
+ */
+pub fn initiate(
+    stream: impl Into<Transport>,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+    deadline: Duration,
+) -> Result<PeerStream, HandshakeError> {
+    let mut stream = stream.into();
+    let keys = KeyPair::generate();
+    let crypto_provide = policy.crypto_provide();
+    let mut reader = HandshakeReader::new(&mut stream, deadline);
+
+    //1 A->B: Ya, PadA
+    let mut message = keys.public_key.to_vec();
+    message.extend_from_slice(&random_padding());
+    reader.send(&message)?;
+
+    //2 B->A: Yb, PadB
+    let secret = keys.shared_secret(&reader.take(DH_KEY_LENGTH)?)?;
+
+    let mut encryptor = Rc4::for_direction(b"keyA", &secret, info_hash);
+    let mut decryptor = Rc4::for_direction(b"keyB", &secret, info_hash);
+
+    //3 A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    //ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), without PadC nor IA
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend_from_slice(&xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&crypto_provide.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encryptor.apply(&mut encrypted);
+    message.extend_from_slice(&encrypted);
+    reader.send(&message)?;
+
+    //4 B->A: ENCRYPT(VC, crypto_select, len(padD), padD), PadB ends at the encrypted VC
+    let mut encrypted_vc = VC;
+    decryptor.apply(&mut encrypted_vc);
+    reader.synchronize(&encrypted_vc)?;
+
+    let crypto_select = u32::from_be_bytes(
+        reader
+            .take_decrypted(4, &mut decryptor)?
+            .try_into()
+            .unwrap(),
+    );
+    let pad_length = reader.take_u16(&mut decryptor)?;
+    if pad_length > MAX_PADDING {
+        return Err(encryption_error("padding is too long"));
+    }
+    reader.take_decrypted(pad_length, &mut decryptor)?;
+
+    if crypto_select.count_ones() != 1 || crypto_select & crypto_provide == 0 {
+        return Err(encryption_error(
+            "peer selected a method we did not provide",
+        ));
+    }
+
+    if crypto_select == CRYPTO_RC4 {
+        let pending = reader.into_pending(Some(&mut decryptor));
+        return Ok(PeerStream::new(
+            stream,
+            Some((encryptor, decryptor)),
+            pending,
+        ));
+    }
+
+    let pending = reader.into_pending(None);
+    return Ok(PeerStream::new(stream, None, pending));
+}
+
+/**
+ * Serve a connection opened by a peer, plaintext or encrypted depending on its first bytes.
+ * The info hashes are the torrents we serve, one of them must be the shared key of the peer.
+ */
+pub fn accept(
+    stream: impl Into<Transport>,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+    deadline: Duration,
+) -> Result<PeerStream, HandshakeError> {
+    let mut stream = stream.into();
+    let mut reader = HandshakeReader::new(&mut stream, deadline);
+    let start = reader.take(1 + PROTOCOL.len())?;
+
+    if start[0] as usize == PROTOCOL.len() && start[1..] == PROTOCOL[..] {
+        if policy == EncryptionPolicy::Forced {
+            return Err(encryption_error("plaintext connections are refused"));
+        }
+
+        //the rest of the BitTorrent handshake is read by the caller
+        let mut pending = start;
+        pending.extend(reader.into_pending(None));
+        return Ok(PeerStream::new(stream, None, pending));
+    }
+
+    if policy == EncryptionPolicy::Disabled {
+        return Err(HandshakeError::InvalidProtocol);
+    }
+
+    //1 A->B: Ya, PadA
+    let mut peer_public_key = start;
+    peer_public_key.extend_from_slice(&reader.take(DH_KEY_LENGTH - peer_public_key.len())?);
+
+    let keys = KeyPair::generate();
+    let secret = keys.shared_secret(&peer_public_key)?;
+
+    //2 B->A: Yb, PadB
+    let mut message = keys.public_key.to_vec();
+    message.extend_from_slice(&random_padding());
+    reader.send(&message)?;
+
+    //3 A->B: HASH('req1', S) ends PadA
+    reader.synchronize(&hash(&[b"req1", &secret]))?;
+
+    let obfuscated: [u8; 20] = reader.take(20)?.try_into().unwrap();
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| xor(&hash(&[b"req2", *info_hash]), &req3) == obfuscated)
+        .ok_or(HandshakeError::InfoHashMismatch)?;
+
+    let mut decryptor = Rc4::for_direction(b"keyA", &secret, &info_hash);
+    let mut encryptor = Rc4::for_direction(b"keyB", &secret, &info_hash);
+
+    if reader.take_decrypted(VC.len(), &mut decryptor)? != VC {
+        return Err(encryption_error("invalid verification constant"));
+    }
+
+    let crypto_provide = u32::from_be_bytes(
+        reader
+            .take_decrypted(4, &mut decryptor)?
+            .try_into()
+            .unwrap(),
+    );
+    let pad_length = reader.take_u16(&mut decryptor)?;
+    if pad_length > MAX_PADDING {
+        return Err(encryption_error("padding is too long"));
+    }
+    reader.take_decrypted(pad_length, &mut decryptor)?;
+
+    //initial payload, usually the BitTorrent handshake
+    let initial_payload_length = reader.take_u16(&mut decryptor)?;
+    let mut pending = reader.take_decrypted(initial_payload_length, &mut decryptor)?;
+
+    let crypto_select = policy
+        .crypto_select(crypto_provide)
+        .ok_or_else(|| encryption_error("no acceptable crypto method provided"))?;
+
+    debug!("encrypted handshake done, provided {crypto_provide:#x} selected {crypto_select:#x}");
+
+    //4 B->A: ENCRYPT(VC, crypto_select, len(padD), padD), without padD
+    let mut message = VC.to_vec();
+    message.extend_from_slice(&crypto_select.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    encryptor.apply(&mut message);
+    reader.send(&message)?;
+
+    if crypto_select == CRYPTO_RC4 {
+        pending.extend(reader.into_pending(Some(&mut decryptor)));
+        return Ok(PeerStream::new(
+            stream,
+            Some((encryptor, decryptor)),
+            pending,
+        ));
+    }
+
+    pending.extend(reader.into_pending(None));
+    return Ok(PeerStream::new(stream, None, pending));
+}
@@ -0,0 +1,193 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use super::mse::Rc4;
+use crate::utp::UtpStream;
+
+/**
+ * Byte stream the peer protocol runs over
+ */
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Transport::Tcp(stream) => stream.peer_addr(),
+            Transport::Utp(stream) => stream.peer_addr(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.set_read_timeout(timeout),
+            Transport::Utp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Transport::Utp(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.shutdown(how),
+            Transport::Utp(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl From<TcpStream> for Transport {
+    fn from(stream: TcpStream) -> Self {
+        Transport::Tcp(stream)
+    }
+}
+
+impl From<UtpStream> for Transport {
+    fn from(stream: UtpStream) -> Self {
+        Transport::Utp(stream)
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.read(buf),
+            Transport::Utp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.write(buf),
+            Transport::Utp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.flush(),
+            Transport::Utp(stream) => stream.flush(),
+        }
+    }
+}
+
+/**
+ * TCP or uTP stream to a peer, RC4 encrypted in both directions when the MSE handshake selected it.
+ * Everything after the MSE handshake (BitTorrent handshake included) goes through it.
+ */
+pub struct PeerStream {
+    stream: Transport,
+
+    //None when the connection is plaintext
+    encryptor: Option<Rc4>,
+    decryptor: Option<Rc4>,
+
+    //already decrypted bytes received with the end of the MSE handshake
+    pending: Vec<u8>,
+}
+
+impl PeerStream {
+    pub fn plaintext(stream: impl Into<Transport>) -> Self {
+        PeerStream {
+            stream: stream.into(),
+            encryptor: None,
+            decryptor: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub(crate) fn new(stream: Transport, ciphers: Option<(Rc4, Rc4)>, pending: Vec<u8>) -> Self {
+        let (encryptor, decryptor) = match ciphers {
+            Some((encryptor, decryptor)) => (Some(encryptor), Some(decryptor)),
+            None => (None, None),
+        };
+
+        PeerStream {
+            stream,
+            encryptor,
+            decryptor,
+            pending,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(self.stream, Transport::Utp(_))
+    }
+
+    /**
+     * Transport, encryptor, decryptor and pending bytes, for the connection task doing its own I/O
+     */
+    pub(crate) fn into_parts(self) -> (Transport, Option<Rc4>, Option<Rc4>, Vec<u8>) {
+        (self.stream, self.encryptor, self.decryptor, self.pending)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let read = buf.len().min(self.pending.len());
+            buf[..read].copy_from_slice(&self.pending[..read]);
+            self.pending.drain(..read);
+            return Ok(read);
+        }
+
+        let read = self.stream.read(buf)?;
+
+        if let Some(decryptor) = self.decryptor.as_mut() {
+            decryptor.apply(&mut buf[..read]);
+        }
+
+        return Ok(read);
+    }
+}
+
+impl Write for PeerStream {
+    /**
+     * Always writes the whole buffer: the keystream moves forward as soon as the data is encrypted
+     */
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.encryptor.as_mut() {
+            Some(encryptor) => {
+                let mut encrypted = buf.to_vec();
+                encryptor.apply(&mut encrypted);
+                self.stream.write_all(&encrypted)?;
+            }
+            None => self.stream.write_all(buf)?,
+        }
+
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
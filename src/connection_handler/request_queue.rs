@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::piece_picker::{BlockRequest, REQUEST_PIECE_SIZE};
+use crate::rate_meter::RateMeter;
+
+//amount of round trip samples used to estimate the base (unqueued) RTT
+const RTT_SAMPLES: usize = 32;
+
+/*
+ * The queue is sized to the bandwidth delay product computed with the lowest recent RTT,
+ * with some headroom so the depth keeps growing while the link is not saturated.
+ * Once it is, the RTT increases from queueing but the base RTT does not, so the depth settles.
+ */
+const QUEUE_HEADROOM: f64 = 1.5;
+
+#[derive(Debug)]
+pub struct OutstandingRequest {
+    pub block: BlockRequest,
+    pub requested_at: Instant,
+}
+
+/**
+ * Blocks requested to a peer and not received yet. Each request is a lease on the block:
+ * past the request timeout it is given back so another peer can be asked.
+ */
+#[derive(Debug)]
+pub struct RequestQueue {
+    outstanding: VecDeque<OutstandingRequest>,
+    rtt_samples: VecDeque<Duration>,
+    pub download_rate: RateMeter,
+    min_depth: usize,
+    max_depth: usize,
+
+    //a request timed out and no block arrived since, the peer only gets one request at a time
+    snubbed: bool,
+}
+
+impl RequestQueue {
+    pub fn new(min_depth: usize, max_depth: usize) -> Self {
+        RequestQueue {
+            outstanding: VecDeque::new(),
+            rtt_samples: VecDeque::with_capacity(RTT_SAMPLES),
+            download_rate: RateMeter::default(),
+            min_depth: min_depth.max(1),
+            max_depth: max_depth.max(min_depth.max(1)),
+            snubbed: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn push(&mut self, block: BlockRequest) {
+        self.outstanding.push_back(OutstandingRequest {
+            block,
+            requested_at: Instant::now(),
+        });
+    }
+
+    /**
+     * Called when a block arrives, returns false if we never asked for it
+     */
+    pub fn on_block_received(&mut self, block: &BlockRequest) -> bool {
+        let position = match self.outstanding.iter().position(|r| r.block == *block) {
+            Some(position) => position,
+            None => return false,
+        };
+
+        let request = self.outstanding.remove(position).unwrap();
+
+        if self.rtt_samples.len() == RTT_SAMPLES {
+            self.rtt_samples.pop_front();
+        }
+        self.rtt_samples.push_back(request.requested_at.elapsed());
+        self.download_rate.add(block.length as usize);
+        self.snubbed = false;
+
+        return true;
+    }
+
+    /**
+     * Drop a request we cancelled, returns false if it was not outstanding
+     */
+    pub fn remove(&mut self, block: &BlockRequest) -> bool {
+        match self.outstanding.iter().position(|r| r.block == *block) {
+            Some(position) => {
+                self.outstanding.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Forget every outstanding request (peer choked us), returns them so blocks can be requested again
+     */
+    pub fn clear(&mut self) -> Vec<BlockRequest> {
+        self.outstanding.drain(..).map(|r| r.block).collect()
+    }
+
+    /**
+     * Requests older than the timeout, they are dropped and the peer is considered snubbing us
+     */
+    pub fn take_expired(&mut self, timeout: Duration) -> Vec<BlockRequest> {
+        let mut expired = Vec::new();
+
+        self.outstanding.retain(|request| {
+            if request.requested_at.elapsed() < timeout {
+                return true;
+            }
+
+            expired.push(request.block);
+            return false;
+        });
+
+        if !expired.is_empty() {
+            self.snubbed = true;
+        }
+
+        return expired;
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    /**
+     * The peer told us how many requests it accepts (reqq of the extended handshake)
+     */
+    pub fn limit_depth(&mut self, limit: usize) {
+        self.max_depth = self.max_depth.min(limit.max(1));
+        self.min_depth = self.min_depth.min(self.max_depth);
+    }
+
+    pub fn base_rtt(&self) -> Option<Duration> {
+        self.rtt_samples.iter().min().copied()
+    }
+
+    /**
+     * Amount of requests we want to keep in flight
+     */
+    pub fn desired_depth(&mut self) -> usize {
+        if self.snubbed {
+            return 1;
+        }
+
+        let base_rtt = match self.base_rtt() {
+            Some(rtt) => rtt,
+            None => return self.min_depth,
+        };
+
+        let bandwidth_delay_product = self.download_rate.rate() * base_rtt.as_secs_f64();
+        let depth = (bandwidth_delay_product * QUEUE_HEADROOM / REQUEST_PIECE_SIZE as f64).ceil();
+
+        return (depth as usize).clamp(self.min_depth, self.max_depth);
+    }
+}
@@ -0,0 +1,329 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use crate::bencode::{BencodeValue, decode_value};
+
+pub type NodeId = [u8; 20];
+
+//20 bytes of node id, 4 bytes of IPv4 address and 2 bytes of port
+pub const COMPACT_NODE_LENGTH: usize = 26;
+const COMPACT_PEER_LENGTH: usize = 6;
+
+//BEP 5 error codes
+pub const GENERIC_ERROR: i64 = 201;
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        //the peer port is the source port of the query, for peers behind a NAT
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    //answered with a METHOD_UNKNOWN error
+    Unknown {
+        method: String,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &str {
+        match self {
+            Self::Ping => "ping",
+            Self::FindNode { .. } => "find_node",
+            Self::GetPeers { .. } => "get_peers",
+            Self::AnnouncePeer { .. } => "announce_peer",
+            Self::Unknown { method } => method,
+        }
+    }
+}
+
+/**
+ * Every response shares the same dictionary, the keys set depend on the query
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub nodes: Vec<NodeInfo>,
+    //peers of the info hash, get_peers only
+    pub values: Vec<SocketAddr>,
+    //to send back with announce_peer, get_peers only
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query { sender: NodeId, query: Query },
+    Response { sender: NodeId, response: Response },
+    Error { code: i64, message: String },
+}
+
+/**
+ * KRPC message (BEP 5): a bencoded dictionary sent in a single UDP datagram
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    //chosen by the querying node and echoed in the answer
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+}
+
+pub fn compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut compact = Vec::new();
+
+    for node in nodes {
+        //the compact format only has room for IPv4 nodes
+        if let IpAddr::V4(ip) = node.addr.ip() {
+            compact.extend_from_slice(&node.id);
+            compact.extend_from_slice(&ip.octets());
+            compact.extend_from_slice(&node.addr.port().to_be_bytes());
+        }
+    }
+
+    return compact;
+}
+
+pub fn parse_compact_nodes(compact: &[u8]) -> Result<Vec<NodeInfo>, String> {
+    if !compact.len().is_multiple_of(COMPACT_NODE_LENGTH) {
+        return Err(format!("compact nodes of length {}", compact.len()));
+    }
+
+    let nodes = compact
+        .chunks_exact(COMPACT_NODE_LENGTH)
+        .map(|chunk| NodeInfo {
+            id: chunk[0..20].try_into().unwrap(),
+            addr: parse_compact_peer(&chunk[20..]),
+        })
+        .collect();
+
+    return Ok(nodes);
+}
+
+fn compact_peer(addr: &SocketAddr) -> Option<Vec<u8>> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut compact = ip.octets().to_vec();
+            compact.extend_from_slice(&addr.port().to_be_bytes());
+            Some(compact)
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+fn parse_compact_peer(chunk: &[u8]) -> SocketAddr {
+    let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+    let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+
+    SocketAddr::new(IpAddr::V4(ip), port)
+}
+
+fn node_id(value: Option<&BencodeValue>, key: &str) -> Result<NodeId, String> {
+    value
+        .and_then(|value| value.as_bytes())
+        .and_then(|bytes| <[u8; 20]>::try_from(bytes).ok())
+        .ok_or_else(|| format!("missing or invalid {key}"))
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries = vec![("t", BencodeValue::Bytes(self.transaction_id.clone()))];
+
+        match &self.body {
+            Body::Query { sender, query } => {
+                let mut arguments = vec![("id", BencodeValue::from(sender.as_slice()))];
+
+                match query {
+                    Query::Ping | Query::Unknown { .. } => {}
+                    Query::FindNode { target } => {
+                        arguments.push(("target", BencodeValue::from(target.as_slice())));
+                    }
+                    Query::GetPeers { info_hash } => {
+                        arguments.push(("info_hash", BencodeValue::from(info_hash.as_slice())));
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        arguments.push(("info_hash", BencodeValue::from(info_hash.as_slice())));
+                        arguments.push(("port", BencodeValue::Integer(*port as i64)));
+                        arguments.push(("token", BencodeValue::from(token.as_slice())));
+                        if *implied_port {
+                            arguments.push(("implied_port", BencodeValue::Integer(1)));
+                        }
+                    }
+                }
+
+                entries.push(("y", BencodeValue::from("q")));
+                entries.push(("q", BencodeValue::from(query.method())));
+                entries.push(("a", BencodeValue::dictionary(arguments)));
+            }
+            Body::Response { sender, response } => {
+                let mut values = vec![("id", BencodeValue::from(sender.as_slice()))];
+
+                if !response.nodes.is_empty() {
+                    values.push(("nodes", BencodeValue::from(compact_nodes(&response.nodes))));
+                }
+                if !response.values.is_empty() {
+                    let peers = response
+                        .values
+                        .iter()
+                        .filter_map(compact_peer)
+                        .map(BencodeValue::Bytes)
+                        .collect();
+                    values.push(("values", BencodeValue::List(peers)));
+                }
+                if let Some(token) = &response.token {
+                    values.push(("token", BencodeValue::from(token.as_slice())));
+                }
+
+                entries.push(("y", BencodeValue::from("r")));
+                entries.push(("r", BencodeValue::dictionary(values)));
+            }
+            Body::Error { code, message } => {
+                entries.push(("y", BencodeValue::from("e")));
+                entries.push((
+                    "e",
+                    BencodeValue::List(vec![
+                        BencodeValue::Integer(*code),
+                        BencodeValue::from(message.as_str()),
+                    ]),
+                ));
+            }
+        }
+
+        return BencodeValue::dictionary(entries).encode();
+    }
+
+    /**
+     * Datagrams come from anyone, nothing is trusted
+     */
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let (value, _) = decode_value(data)?;
+
+        let transaction_id = value
+            .get("t")
+            .and_then(|t| t.as_bytes())
+            .ok_or_else(|| String::from("missing transaction id"))?
+            .to_vec();
+
+        let body = match value.get("y").and_then(|y| y.as_str()) {
+            Some("q") => parse_query(&value)?,
+            Some("r") => parse_response(&value)?,
+            Some("e") => {
+                let error = value.get("e").and_then(|e| e.as_list()).unwrap_or(&[]);
+                Body::Error {
+                    code: error
+                        .first()
+                        .and_then(|code| code.as_integer())
+                        .unwrap_or(GENERIC_ERROR),
+                    message: error
+                        .get(1)
+                        .and_then(|message| message.as_str())
+                        .unwrap_or_default()
+                        .to_string(),
+                }
+            }
+            _ => return Err(String::from("unknown message type")),
+        };
+
+        return Ok(Message {
+            transaction_id,
+            body,
+        });
+    }
+}
+
+fn parse_query(value: &BencodeValue) -> Result<Body, String> {
+    let method = value
+        .get("q")
+        .and_then(|q| q.as_str())
+        .ok_or_else(|| String::from("missing query method"))?;
+    let arguments = value
+        .get("a")
+        .ok_or_else(|| String::from("missing query arguments"))?;
+    let sender = node_id(arguments.get("id"), "id")?;
+
+    let query = match method {
+        "ping" => Query::Ping,
+        "find_node" => Query::FindNode {
+            target: node_id(arguments.get("target"), "target")?,
+        },
+        "get_peers" => Query::GetPeers {
+            info_hash: node_id(arguments.get("info_hash"), "info_hash")?,
+        },
+        "announce_peer" => Query::AnnouncePeer {
+            info_hash: node_id(arguments.get("info_hash"), "info_hash")?,
+            port: arguments
+                .get("port")
+                .and_then(|port| port.as_integer())
+                .and_then(|port| u16::try_from(port).ok())
+                .ok_or_else(|| String::from("missing or invalid port"))?,
+            implied_port: arguments
+                .get("implied_port")
+                .and_then(|implied_port| implied_port.as_integer())
+                .is_some_and(|implied_port| implied_port != 0),
+            token: arguments
+                .get("token")
+                .and_then(|token| token.as_bytes())
+                .ok_or_else(|| String::from("missing token"))?
+                .to_vec(),
+        },
+        method => Query::Unknown {
+            method: method.to_string(),
+        },
+    };
+
+    return Ok(Body::Query { sender, query });
+}
+
+fn parse_response(value: &BencodeValue) -> Result<Body, String> {
+    let values = value
+        .get("r")
+        .ok_or_else(|| String::from("missing response values"))?;
+    let sender = node_id(values.get("id"), "id")?;
+
+    let nodes = match values.get("nodes").and_then(|nodes| nodes.as_bytes()) {
+        Some(nodes) => parse_compact_nodes(nodes)?,
+        None => Vec::new(),
+    };
+
+    let peers = values
+        .get("values")
+        .and_then(|peers| peers.as_list())
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|peer| peer.as_bytes())
+        .filter(|peer| peer.len() == COMPACT_PEER_LENGTH)
+        .map(parse_compact_peer)
+        .collect();
+
+    let token = values
+        .get("token")
+        .and_then(|token| token.as_bytes())
+        .map(|token| token.to_vec());
+
+    return Ok(Body::Response {
+        sender,
+        response: Response {
+            nodes,
+            values: peers,
+            token,
+        },
+    });
+}
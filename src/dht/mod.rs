@@ -0,0 +1,616 @@
+pub mod krpc;
+pub mod routing_table;
+mod storage;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::network::canonical_peer_addr;
+use crate::peer_pool::PeerPool;
+use krpc::{Body, METHOD_UNKNOWN, Message, NodeId, NodeInfo, PROTOCOL_ERROR, Query, Response};
+use routing_table::{K, RoutingTable, distance};
+use storage::Storage;
+
+pub use storage::MAX_STORED_TORRENTS;
+
+//BEP 5: last bit of the reserved bytes, the peer answers the Port message
+pub const DHT_BYTE: usize = 7;
+pub const DHT_BIT: u8 = 0x01;
+
+//queries in flight during a lookup
+const ALPHA: usize = 3;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+//how often the socket thread checks the pending queries
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+const MAX_DATAGRAM_SIZE: usize = 4096;
+
+//peers sent in a get_peers answer, it must fit in a datagram
+const MAX_VALUES: usize = 100;
+
+//BEP 5 asks to announce again every 15 minutes, stored peers expire after 30
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const ANNOUNCE_LOOP_TICK: Duration = Duration::from_secs(1);
+
+pub fn supports_dht(reserved: &[u8; 8]) -> bool {
+    reserved[DHT_BYTE] & DHT_BIT != 0
+}
+
+/**
+ * host:port strings (bootstrap nodes, the nodes key of torrents) to IPv4 addresses, the only ones
+ * our DHT talks to
+ */
+pub fn resolve_nodes(nodes: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+
+    for node in nodes {
+        match node.to_socket_addrs() {
+            Ok(resolved) => addrs.extend(resolved.filter(|addr| addr.is_ipv4())),
+            Err(e) => debug!("cannot resolve DHT node {node}: {e}"),
+        }
+    }
+
+    return addrs;
+}
+
+struct Reply {
+    transaction_id: Vec<u8>,
+    result: Result<(NodeId, Response), String>,
+}
+
+struct PendingQuery {
+    addr: SocketAddr,
+    sent_at: Instant,
+    replies: Sender<Reply>,
+}
+
+/**
+ * Nodes answering a lookup, with the peers they gave for get_peers
+ */
+#[derive(Debug, Default)]
+struct LookupResult {
+    //closest first, with the token to announce to them
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    id: NodeId,
+    table: Mutex<RoutingTable>,
+    storage: Mutex<Storage>,
+    //by transaction id
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction: AtomicU16,
+    stopped: AtomicBool,
+}
+
+impl Inner {
+    fn send(&self, message: &Message, addr: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(&message.encode(), addr)?;
+        return Ok(());
+    }
+
+    /**
+     * The reply, or the timeout, is sent to the channel
+     */
+    fn send_query(&self, addr: SocketAddr, query: Query, replies: &Sender<Reply>) -> Vec<u8> {
+        let transaction_id = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+
+        self.pending.lock().unwrap().insert(
+            transaction_id.clone(),
+            PendingQuery {
+                addr,
+                sent_at: Instant::now(),
+                replies: replies.clone(),
+            },
+        );
+
+        let message = Message {
+            transaction_id: transaction_id.clone(),
+            body: Body::Query {
+                sender: self.id,
+                query,
+            },
+        };
+
+        //failing right away or timing out is the same for the caller
+        if let Err(e) = self.send(&message, addr) {
+            debug!("[{addr}] cannot send DHT query: {e}");
+        }
+
+        return transaction_id;
+    }
+
+    fn handle_datagram(&self, datagram: &[u8], from: SocketAddr) {
+        let message = match Message::parse(datagram) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("[{from}] invalid DHT message: {e}");
+                return;
+            }
+        };
+
+        match message.body {
+            Body::Query { sender, query } => {
+                self.handle_query(message.transaction_id, sender, query, from)
+            }
+            Body::Response { sender, response } => {
+                self.reply(message.transaction_id, from, Ok((sender, response)));
+            }
+            Body::Error {
+                code,
+                message: text,
+            } => {
+                self.reply(
+                    message.transaction_id,
+                    from,
+                    Err(format!("error {code}: {text}")),
+                );
+            }
+        }
+    }
+
+    /**
+     * Hands an answer to the query waiting for it, answers from another address are ignored.
+     * Only the nodes answering our queries join the routing table, unsolicited responses
+     * could carry any node id.
+     */
+    fn reply(
+        &self,
+        transaction_id: Vec<u8>,
+        from: SocketAddr,
+        result: Result<(NodeId, Response), String>,
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        let query = match pending.get(&transaction_id) {
+            Some(query) if query.addr == from => pending.remove(&transaction_id).unwrap(),
+            _ => return,
+        };
+        drop(pending);
+
+        if let Ok((sender, _)) = &result {
+            self.table.lock().unwrap().insert(NodeInfo {
+                id: *sender,
+                addr: from,
+            });
+        }
+
+        let _ = query.replies.send(Reply {
+            transaction_id,
+            result,
+        });
+    }
+
+    fn handle_query(
+        &self,
+        transaction_id: Vec<u8>,
+        sender: NodeId,
+        query: Query,
+        from: SocketAddr,
+    ) {
+        self.table.lock().unwrap().insert(NodeInfo {
+            id: sender,
+            addr: from,
+        });
+
+        let body = match query {
+            Query::Ping => Ok(Response::default()),
+            Query::FindNode { target } => Ok(Response {
+                nodes: self.table.lock().unwrap().closest(&target, K),
+                ..Response::default()
+            }),
+            Query::GetPeers { info_hash } => {
+                let mut storage = self.storage.lock().unwrap();
+                Ok(Response {
+                    nodes: self.table.lock().unwrap().closest(&info_hash, K),
+                    values: storage.peers(&info_hash, MAX_VALUES),
+                    token: Some(storage.token(from.ip())),
+                })
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                let mut storage = self.storage.lock().unwrap();
+                if storage.is_valid_token(from.ip(), &token) {
+                    let port = if implied_port { from.port() } else { port };
+                    storage.add_peer(info_hash, SocketAddr::new(from.ip(), port));
+                    Ok(Response::default())
+                } else {
+                    Err((PROTOCOL_ERROR, "bad token"))
+                }
+            }
+            Query::Unknown { method } => {
+                debug!("[{from}] unknown DHT query {method}");
+                Err((METHOD_UNKNOWN, "method unknown"))
+            }
+        };
+
+        let body = match body {
+            Ok(response) => Body::Response {
+                sender: self.id,
+                response,
+            },
+            Err((code, message)) => Body::Error {
+                code,
+                message: message.to_string(),
+            },
+        };
+
+        let message = Message {
+            transaction_id,
+            body,
+        };
+        if let Err(e) = self.send(&message, from) {
+            debug!("[{from}] cannot answer DHT query: {e}");
+        }
+    }
+
+    /**
+     * Queries without an answer in time fail, their node gets closer to being replaced
+     */
+    fn expire_queries(&self) {
+        let expired: Vec<(Vec<u8>, PendingQuery)> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ids: Vec<Vec<u8>> = pending
+                .iter()
+                .filter(|(_, query)| query.sent_at.elapsed() >= QUERY_TIMEOUT)
+                .map(|(id, _)| id.clone())
+                .collect();
+
+            ids.into_iter()
+                .filter_map(|id| pending.remove(&id).map(|query| (id, query)))
+                .collect()
+        };
+
+        for (transaction_id, query) in expired {
+            self.table.lock().unwrap().mark_failed(query.addr);
+            let _ = query.replies.send(Reply {
+                transaction_id,
+                result: Err(String::from("query timed out")),
+            });
+        }
+    }
+
+    /**
+     * Iterative lookup: ask the closest nodes we know about the target, then the closer ones they
+     * tell us about, until the K closest nodes found have all answered or failed.
+     * The seeds are asked first whatever their id, for bootstrapping.
+     */
+    fn lookup(&self, target: NodeId, get_peers: bool, seeds: &[SocketAddr]) -> LookupResult {
+        let (sender, replies) = mpsc::channel();
+        let query = || {
+            if get_peers {
+                Query::GetPeers { info_hash: target }
+            } else {
+                Query::FindNode { target }
+            }
+        };
+
+        let mut candidates = self.table.lock().unwrap().closest(&target, K);
+        let mut queried: HashSet<SocketAddr> = HashSet::new();
+        let mut in_flight: HashMap<Vec<u8>, SocketAddr> = HashMap::new();
+        let mut result = LookupResult::default();
+
+        for seed in seeds {
+            if queried.insert(*seed) {
+                in_flight.insert(self.send_query(*seed, query(), &sender), *seed);
+            }
+        }
+
+        loop {
+            candidates.sort_by_key(|node| distance(&node.id, &target));
+
+            while in_flight.len() < ALPHA {
+                let next = candidates
+                    .iter()
+                    .take(K)
+                    .find(|node| !queried.contains(&node.addr))
+                    .copied();
+
+                match next {
+                    Some(node) => {
+                        queried.insert(node.addr);
+                        in_flight.insert(self.send_query(node.addr, query(), &sender), node.addr);
+                    }
+                    None => break,
+                }
+            }
+
+            if in_flight.is_empty() {
+                break;
+            }
+
+            //the socket thread answers every query, with a timeout at worst
+            let reply = match replies.recv_timeout(QUERY_TIMEOUT * 2) {
+                Ok(reply) => reply,
+                Err(_) => break,
+            };
+            let addr = match in_flight.remove(&reply.transaction_id) {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            match reply.result {
+                Ok((id, response)) => {
+                    let node = NodeInfo { id, addr };
+                    if !candidates.iter().any(|candidate| candidate.addr == addr) {
+                        candidates.push(node);
+                    }
+
+                    for found in response.nodes {
+                        let known = candidates
+                            .iter()
+                            .any(|candidate| candidate.addr == found.addr);
+                        if !known && found.id != self.id && found.addr != self.local_addr {
+                            candidates.push(found);
+                        }
+                    }
+
+                    for peer in response.values {
+                        if !result.peers.contains(&peer) {
+                            result.peers.push(peer);
+                        }
+                    }
+
+                    result.closest.push((node, response.token));
+                }
+                Err(e) => {
+                    debug!("[{addr}] DHT query failed: {e}");
+                    candidates.retain(|candidate| candidate.addr != addr);
+                }
+            }
+        }
+
+        //queries still in flight answer to nobody
+        let mut pending = self.pending.lock().unwrap();
+        for transaction_id in in_flight.keys() {
+            pending.remove(transaction_id);
+        }
+        drop(pending);
+
+        result
+            .closest
+            .sort_by_key(|(node, _)| distance(&node.id, &target));
+        result.closest.truncate(K);
+
+        return result;
+    }
+}
+
+/**
+ * Mainline DHT node (BEP 5) over its own UDP socket, IPv4 only. A thread answers the queries
+ * of other nodes and hands the answers to our lookups, which run on the calling thread.
+ */
+pub struct Dht {
+    inner: Arc<Inner>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Dht {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Dht::new(UdpSocket::bind(addr)?, RoutingTable::new(rand::random()))
+    }
+
+    /**
+     * The table gives our id, a saved table keeps it across sessions
+     */
+    pub fn new(socket: UdpSocket, table: RoutingTable) -> io::Result<Self> {
+        socket.set_read_timeout(Some(TICK_INTERVAL))?;
+
+        let inner = Arc::new(Inner {
+            local_addr: socket.local_addr()?,
+            socket,
+            id: table.id(),
+            table: Mutex::new(table),
+            storage: Mutex::new(Storage::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            stopped: AtomicBool::new(false),
+        });
+
+        let handle = {
+            let inner = Arc::clone(&inner);
+            thread::spawn(move || run_socket(inner))
+        };
+
+        Ok(Dht {
+            inner,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.inner.table.lock().unwrap().nodes()
+    }
+
+    /**
+     * Fill the routing table by looking up our own id through the given nodes and the known ones.
+     * Returns the size of the table.
+     */
+    pub fn bootstrap(&self, nodes: &[SocketAddr]) -> usize {
+        let seeds: Vec<SocketAddr> = nodes
+            .iter()
+            .map(|node| canonical_peer_addr(*node))
+            .collect();
+        self.inner.lookup(self.inner.id, false, &seeds);
+
+        let size = self.inner.table.lock().unwrap().len();
+        info!("DHT bootstrapped with {size} nodes");
+
+        return size;
+    }
+
+    /**
+     * A node heard of outside the DHT (Port message of a peer), it joins the table if it answers
+     */
+    pub fn add_node(&self, addr: SocketAddr) {
+        //nobody waits for the answer, the socket thread adds the node to the table
+        let (sender, _) = mpsc::channel();
+        self.inner
+            .send_query(canonical_peer_addr(addr), Query::Ping, &sender);
+    }
+
+    /**
+     * The K nodes closest to the target
+     */
+    pub fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        self.inner
+            .lookup(target, false, &[])
+            .closest
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect()
+    }
+
+    pub fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let peers = self.inner.lookup(info_hash, true, &[]).peers;
+        debug!(
+            "DHT gave {} peers for {}",
+            peers.len(),
+            hex::encode(info_hash)
+        );
+
+        return peers;
+    }
+
+    /**
+     * Tell the nodes closest to the info hash we are a peer accepting connections on the port.
+     * Returns the peers found on the way.
+     */
+    pub fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.inner.lookup(info_hash, true, &[]);
+        let (sender, replies) = mpsc::channel();
+
+        let mut in_flight = 0;
+        for (node, token) in lookup.closest {
+            let token = match token {
+                Some(token) => token,
+                None => continue,
+            };
+
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port: false,
+                token,
+            };
+            self.inner.send_query(node.addr, query, &sender);
+            in_flight += 1;
+        }
+
+        let mut announced = 0;
+        for _ in 0..in_flight {
+            match replies.recv_timeout(QUERY_TIMEOUT * 2) {
+                Ok(reply) if reply.result.is_ok() => announced += 1,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        debug!(
+            "announced {} to {announced} DHT nodes",
+            hex::encode(info_hash)
+        );
+
+        return lookup.peers;
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), String> {
+        self.inner.table.lock().unwrap().save(path)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.stopped.load(Ordering::Relaxed)
+    }
+
+    /**
+     * The socket thread ends, lookups in progress fail
+     */
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.stop();
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run_socket(inner: Arc<Inner>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut last_tick = Instant::now();
+
+    while !inner.stopped.load(Ordering::Relaxed) {
+        match inner.socket.recv_from(&mut buf) {
+            Ok((read, from)) => inner.handle_datagram(&buf[..read], canonical_peer_addr(from)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            //ICMP errors of earlier datagrams show up here on some systems
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            Err(e) => {
+                warn!("DHT socket error: {e}");
+                thread::sleep(TICK_INTERVAL);
+            }
+        }
+
+        if last_tick.elapsed() >= TICK_INTERVAL {
+            inner.expire_queries();
+            last_tick = Instant::now();
+        }
+    }
+}
+
+/**
+ * Announce the torrent every ANNOUNCE_INTERVAL and feed the peers found to the pool,
+ * until the DHT is stopped
+ */
+pub fn spawn_announce_loop(
+    dht: Arc<Dht>,
+    info_hash: [u8; 20],
+    port: u16,
+    peer_pool: Arc<Mutex<PeerPool>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut next_announce = Instant::now();
+
+        while !dht.is_stopped() {
+            if Instant::now() >= next_announce {
+                let peers = dht.announce(info_hash, port);
+                peer_pool.lock().unwrap().add_peers(peers);
+                next_announce = Instant::now() + ANNOUNCE_INTERVAL;
+            }
+
+            thread::sleep(ANNOUNCE_LOOP_TICK);
+        }
+    })
+}
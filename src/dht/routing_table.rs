@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::bencode::{BencodeValue, decode_value};
+
+use super::krpc::{NodeId, NodeInfo, compact_nodes, parse_compact_nodes};
+
+//nodes per bucket, also the amount of closest nodes returned by lookups
+pub const K: usize = 8;
+
+//queries a node can leave unanswered before it is replaced by a newly seen one
+const MAX_FAILURES: u32 = 2;
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; 20];
+    for (index, byte) in distance.iter_mut().enumerate() {
+        *byte = a[index] ^ b[index];
+    }
+
+    return distance;
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/**
+ * Kademlia routing table: one bucket per length of the prefix shared with our id, so we know
+ * many nodes close to us and a few far away. Nodes already known are kept over new ones
+ * as long as they answer, long lived nodes are the most likely to stay.
+ */
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        RoutingTable {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /**
+     * Length of the prefix shared with our id, None for our own id
+     */
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+
+        let mut leading_zeros = 0;
+        for byte in distance {
+            if byte != 0 {
+                return Some(leading_zeros + byte.leading_zeros() as usize);
+            }
+            leading_zeros += 8;
+        }
+
+        return None;
+    }
+
+    /**
+     * Returns whether the node is in the table
+     */
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let bucket = match self.bucket_index(&node.id) {
+            Some(index) => &mut self.buckets[index],
+            None => return false,
+        };
+
+        let entry = Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+
+        if let Some(known) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            *known = entry;
+            return true;
+        }
+
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+
+        if let Some(bad) = bucket.iter_mut().find(|entry| entry.is_bad()) {
+            *bad = entry;
+            return true;
+        }
+
+        return false;
+    }
+
+    /**
+     * A query to this address timed out
+     */
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /**
+     * Good nodes closest to the target, closest first
+     */
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect();
+
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+
+        return nodes;
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /**
+     * When a node was last heard of, None if it is not in the table
+     */
+    pub fn last_seen(&self, id: &NodeId) -> Option<Instant> {
+        self.buckets
+            .iter()
+            .flatten()
+            .find(|entry| entry.node.id == *id)
+            .map(|entry| entry.last_seen)
+    }
+
+    /**
+     * Our id and the nodes we know, so the next session does not start from the bootstrap nodes
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        BencodeValue::dictionary(vec![
+            ("id", BencodeValue::from(self.id.as_slice())),
+            ("nodes", BencodeValue::from(compact_nodes(&self.nodes()))),
+        ])
+        .encode()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let (value, _) = decode_value(bytes)?;
+
+        let id = value
+            .get("id")
+            .and_then(|id| id.as_bytes())
+            .and_then(|id| <[u8; 20]>::try_from(id).ok())
+            .ok_or_else(|| String::from("routing table has no valid id"))?;
+        let nodes = value
+            .get("nodes")
+            .and_then(|nodes| nodes.as_bytes())
+            .ok_or_else(|| String::from("routing table has no nodes"))?;
+
+        let mut table = RoutingTable::new(id);
+        for node in parse_compact_nodes(nodes)? {
+            table.insert(node);
+        }
+
+        return Ok(table);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)
+                .map_err(|e| format!("cannot create {}: {e}", directory.display()))?;
+        }
+
+        std::fs::write(path, self.to_bytes())
+            .map_err(|e| format!("cannot save routing table {}: {e}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("cannot read routing table {}: {e}", path.display()))?;
+
+        return Self::from_bytes(&bytes);
+    }
+}
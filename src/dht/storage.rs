@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+
+//a token stays valid for one to two rotations
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const TOKEN_LENGTH: usize = 8;
+
+//announced peers are forgotten unless they announce again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 500;
+
+//tokens are not bound to an info hash, a node could otherwise announce any amount of them
+pub const MAX_STORED_TORRENTS: usize = 1000;
+
+/**
+ * What other nodes stored on us: the peers announced for each info hash,
+ * and the secrets of the tokens we hand out with get_peers
+ */
+#[derive(Debug)]
+pub(super) struct Storage {
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    rotated_at: Instant,
+    peers: HashMap<[u8; 20], Vec<(SocketAddr, Instant)>>,
+}
+
+impl Storage {
+    pub(super) fn new() -> Self {
+        Storage {
+            secret: rand::random(),
+            previous_secret: rand::random(),
+            rotated_at: Instant::now(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /**
+     * Expired peers are dropped on every rotation too, with the info hashes left without peers
+     */
+    fn rotate_secret(&mut self) {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION {
+            self.previous_secret = self.secret;
+            self.secret = rand::random();
+            self.rotated_at = Instant::now();
+
+            self.peers.retain(|_, peers| {
+                peers.retain(|(_, announced_at)| announced_at.elapsed() < PEER_TTL);
+                !peers.is_empty()
+            });
+        }
+    }
+
+    fn token_with(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(secret);
+
+        return hasher.finalize()[..TOKEN_LENGTH].to_vec();
+    }
+
+    /**
+     * Proof the node asked us from this address before announcing
+     */
+    pub(super) fn token(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate_secret();
+        return Self::token_with(&self.secret, ip);
+    }
+
+    pub(super) fn is_valid_token(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate_secret();
+        return token == Self::token_with(&self.secret, ip).as_slice()
+            || token == Self::token_with(&self.previous_secret, ip).as_slice();
+    }
+
+    /**
+     * Announces for new info hashes are ignored once MAX_STORED_TORRENTS are stored
+     */
+    pub(super) fn add_peer(&mut self, info_hash: [u8; 20], peer: SocketAddr) {
+        if self.peers.len() >= MAX_STORED_TORRENTS && !self.peers.contains_key(&info_hash) {
+            return;
+        }
+
+        let peers = self.peers.entry(info_hash).or_default();
+        let now = Instant::now();
+
+        if let Some(known) = peers.iter_mut().find(|(known, _)| *known == peer) {
+            known.1 = now;
+        } else if peers.len() < MAX_PEERS_PER_TORRENT {
+            peers.push((peer, now));
+        }
+    }
+
+    pub(super) fn peers(&mut self, info_hash: &[u8; 20], max: usize) -> Vec<SocketAddr> {
+        let peers = match self.peers.get_mut(info_hash) {
+            Some(peers) => peers,
+            None => return Vec::new(),
+        };
+
+        peers.retain(|(_, announced_at)| announced_at.elapsed() < PEER_TTL);
+
+        return peers.iter().take(max).map(|(peer, _)| *peer).collect();
+    }
+}
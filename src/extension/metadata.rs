@@ -0,0 +1,218 @@
+use log::debug;
+use sha1::{Digest, Sha1};
+
+use super::{ExtendedHandshake, ExtensionContext, ExtensionHandler};
+use crate::bencode::{BencodeValue, decode_value};
+use crate::torrent_context::TorrentContext;
+
+pub const UT_METADATA: &str = "ut_metadata";
+
+//the info dictionary is exchanged in pieces of 16KiB, the last one may be shorter
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+//a peer announcing more than this is lying or broken
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/**
+ * ut_metadata message: a bencoded dictionary, followed by the piece data for Data messages
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            Self::Request { piece } => (0, piece),
+            Self::Data { piece, .. } => (1, piece),
+            Self::Reject { piece } => (2, piece),
+        };
+
+        let mut entries = vec![
+            ("msg_type", BencodeValue::Integer(msg_type)),
+            ("piece", BencodeValue::from(*piece)),
+        ];
+        if let Self::Data { total_size, .. } = self {
+            entries.push(("total_size", BencodeValue::from(*total_size)));
+        }
+
+        let mut bytes = BencodeValue::dictionary(entries).encode();
+        if let Self::Data { data, .. } = self {
+            bytes.extend_from_slice(data);
+        }
+
+        return bytes;
+    }
+
+    pub fn parse(payload: &[u8]) -> Result<Self, String> {
+        let (value, used) = decode_value(payload)?;
+
+        let integer = |key: &str| {
+            value
+                .get(key)
+                .and_then(|value| value.as_integer())
+                .and_then(|value| usize::try_from(value).ok())
+                .ok_or_else(|| format!("ut_metadata message has no valid {key}"))
+        };
+        let piece = integer("piece")?;
+
+        match integer("msg_type")? {
+            0 => return Ok(Self::Request { piece }),
+            1 => {
+                return Ok(Self::Data {
+                    piece,
+                    total_size: integer("total_size")?,
+                    data: payload[used..].to_vec(),
+                });
+            }
+            2 => return Ok(Self::Reject { piece }),
+            msg_type => return Err(format!("unknown ut_metadata message type {msg_type}")),
+        }
+    }
+}
+
+pub fn metadata_pieces_amount(metadata_size: usize) -> usize {
+    metadata_size.div_ceil(METADATA_PIECE_SIZE)
+}
+
+/**
+ * Info dictionary being downloaded, checked against the info hash once every piece arrived
+ */
+#[derive(Debug)]
+pub struct MetadataDownload {
+    info_hash: [u8; 20],
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataDownload {
+    pub fn new(info_hash: [u8; 20], size: usize) -> Result<Self, String> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(format!("invalid metadata size {size}"));
+        }
+
+        return Ok(MetadataDownload {
+            info_hash,
+            size,
+            pieces: vec![None; metadata_pieces_amount(size)],
+        });
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn missing_pieces(&self) -> Vec<usize> {
+        (0..self.pieces.len())
+            .filter(|piece| self.pieces[*piece].is_none())
+            .collect()
+    }
+
+    pub fn add_piece(&mut self, piece: usize, data: Vec<u8>) -> Result<(), String> {
+        if piece >= self.pieces.len() {
+            return Err(format!("metadata piece {piece} is out of range"));
+        }
+
+        let expected_length = if piece == self.pieces.len() - 1 {
+            self.size - piece * METADATA_PIECE_SIZE
+        } else {
+            METADATA_PIECE_SIZE
+        };
+
+        if data.len() != expected_length {
+            return Err(format!(
+                "metadata piece {piece} has {} bytes instead of {expected_length}",
+                data.len()
+            ));
+        }
+
+        self.pieces[piece] = Some(data);
+        return Ok(());
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|piece| piece.is_some())
+    }
+
+    /**
+     * The assembled info dictionary, an error if it does not match the info hash.
+     * The pieces are dropped either way so they can be downloaded again from another peer.
+     */
+    pub fn finish(&mut self) -> Result<Vec<u8>, String> {
+        if !self.is_complete() {
+            return Err(String::from("metadata is not complete"));
+        }
+
+        let metadata: Vec<u8> = self
+            .pieces
+            .iter_mut()
+            .flat_map(|piece| piece.take().unwrap())
+            .collect();
+
+        let hash: [u8; 20] = Sha1::digest(&metadata).into();
+        if hash != self.info_hash {
+            return Err(String::from("metadata does not match the info hash"));
+        }
+
+        return Ok(metadata);
+    }
+}
+
+/**
+ * Serves our info dictionary to the peers downloading it
+ */
+#[derive(Debug, Default)]
+pub struct UtMetadata {}
+
+impl ExtensionHandler for UtMetadata {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn on_our_handshake(&self, handshake: &mut ExtendedHandshake, context: &TorrentContext) {
+        let metadata_size = context.torrent_file.info_bytes.len();
+
+        if metadata_size > 0 {
+            handshake.metadata_size = Some(metadata_size);
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8], context: &mut ExtensionContext) -> Result<(), String> {
+        let piece = match MetadataMessage::parse(payload)? {
+            MetadataMessage::Request { piece } => piece,
+
+            //we already have the metadata
+            _ => return Ok(()),
+        };
+
+        let metadata = &context.torrent.torrent_file.info_bytes;
+        let start = piece.saturating_mul(METADATA_PIECE_SIZE);
+
+        let reply = if start < metadata.len() {
+            let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+
+            MetadataMessage::Data {
+                piece,
+                total_size: metadata.len(),
+                data: metadata[start..end].to_vec(),
+            }
+        } else {
+            debug!("[{}] rejecting metadata piece {piece}", context.peer);
+            MetadataMessage::Reject { piece }
+        };
+
+        context.send(reply.to_bytes());
+        return Ok(());
+    }
+}
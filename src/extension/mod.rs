@@ -0,0 +1,363 @@
+pub mod metadata;
+pub mod pex;
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use log::debug;
+
+use crate::bencode::{BencodeValue, decode_value};
+use crate::connection_handler::message::PeerMessage;
+use crate::torrent_context::TorrentContext;
+
+//BEP 10: bit 20 from the right of the reserved bytes
+pub const EXTENSION_PROTOCOL_BYTE: usize = 5;
+pub const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+//message id of every extension message, the first payload byte is the extended id
+pub const EXTENDED_MESSAGE_ID: u8 = 20;
+
+//extended id of the extended handshake, the other ids are negotiated
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+pub fn client_version() -> String {
+    format!("rust-torrent {}", env!("CARGO_PKG_VERSION"))
+}
+
+pub fn supports_extension_protocol(reserved: &[u8; 8]) -> bool {
+    reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+}
+
+/**
+ * Full wire message: <length><20><extended id><payload>
+ */
+pub fn extended_message(extended_id: u8, payload: &[u8]) -> Vec<u8> {
+    PeerMessage::Extended {
+        id: extended_id,
+        payload: payload.to_vec(),
+    }
+    .to_bytes()
+}
+
+/**
+ * Bencoded dictionary sent as extended message 0 right after the handshake (and possibly again later
+ * to update the supported extensions). Every key is optional.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtendedHandshake {
+    //extension name => extended id the sender wants to receive it with, 0 disables it
+    pub m: BTreeMap<String, u8>,
+
+    //client name and version
+    pub v: Option<String>,
+
+    //listen port of the sender, useful for incoming connections whose source port is random
+    pub p: Option<u16>,
+
+    //amount of outstanding requests the sender accepts
+    pub reqq: Option<usize>,
+
+    //our ip as seen by the sender
+    pub yourip: Option<IpAddr>,
+
+    //size of the info dictionary (BEP 9)
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let m = self
+            .m
+            .iter()
+            .map(|(name, id)| (name.as_str(), BencodeValue::Integer(*id as i64)))
+            .collect();
+        let mut entries = vec![("m", BencodeValue::dictionary(m))];
+
+        if let Some(v) = &self.v {
+            entries.push(("v", BencodeValue::from(v.as_str())));
+        }
+        if let Some(p) = self.p {
+            entries.push(("p", BencodeValue::Integer(p as i64)));
+        }
+        if let Some(reqq) = self.reqq {
+            entries.push(("reqq", BencodeValue::from(reqq)));
+        }
+        if let Some(yourip) = self.yourip {
+            let bytes = match yourip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            entries.push(("yourip", BencodeValue::from(bytes)));
+        }
+        if let Some(metadata_size) = self.metadata_size {
+            entries.push(("metadata_size", BencodeValue::from(metadata_size)));
+        }
+
+        return BencodeValue::dictionary(entries).encode();
+    }
+
+    /**
+     * Parse the payload of a received extended handshake, invalid optional keys are ignored
+     */
+    pub fn parse(payload: &[u8]) -> Result<Self, String> {
+        let (value, _) = decode_value(payload)?;
+
+        if value.as_dictionary().is_none() {
+            return Err(String::from("extended handshake is not a dictionary"));
+        }
+
+        let mut m = BTreeMap::new();
+        if let Some(entries) = value.get("m").and_then(|m| m.as_dictionary()) {
+            for (name, id) in entries {
+                let id = id.as_integer().and_then(|id| u8::try_from(id).ok());
+
+                if let (Ok(name), Some(id)) = (String::from_utf8(name.clone()), id) {
+                    m.insert(name, id);
+                }
+            }
+        }
+
+        let positive = |key: &str| {
+            value
+                .get(key)
+                .and_then(|value| value.as_integer())
+                .and_then(|value| usize::try_from(value).ok())
+        };
+
+        let yourip = value
+            .get("yourip")
+            .and_then(|ip| ip.as_bytes())
+            .and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()))),
+                16 => Some(IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(ip).unwrap(),
+                ))),
+                _ => None,
+            });
+
+        return Ok(ExtendedHandshake {
+            m,
+            v: value.get("v").and_then(|v| v.as_str()).map(String::from),
+            p: positive("p").and_then(|p| u16::try_from(p).ok()),
+            reqq: positive("reqq"),
+            yourip,
+            metadata_size: positive("metadata_size"),
+        });
+    }
+}
+
+/**
+ * What an extension can see and do while handling an event of its connection
+ */
+pub struct ExtensionContext<'a> {
+    pub peer: SocketAddr,
+    pub torrent: &'a TorrentContext,
+
+    //payloads to send as messages of the extension
+    outbox: Vec<Vec<u8>>,
+}
+
+impl<'a> ExtensionContext<'a> {
+    pub fn new(peer: SocketAddr, torrent: &'a TorrentContext) -> Self {
+        ExtensionContext {
+            peer,
+            torrent,
+            outbox: Vec::new(),
+        }
+    }
+
+    /**
+     * Queue a message of the extension, it is dropped if the peer does not support it
+     */
+    pub fn send(&mut self, payload: Vec<u8>) {
+        self.outbox.push(payload);
+    }
+}
+
+/**
+ * An extension protocol (ut_metadata, ut_pex...), one instance lives in every connection
+ */
+pub trait ExtensionHandler: Send {
+    //name advertised in the m dictionary
+    fn name(&self) -> &'static str;
+
+    /**
+     * Fill the extension specific keys of our extended handshake (metadata_size...)
+     */
+    fn on_our_handshake(&self, _handshake: &mut ExtendedHandshake, _context: &TorrentContext) {}
+
+    /**
+     * Called for every extended handshake of the peer, even if it does not support this extension
+     */
+    fn on_peer_handshake(
+        &mut self,
+        _handshake: &ExtendedHandshake,
+        _context: &mut ExtensionContext,
+    ) {
+    }
+
+    /**
+     * A message of the extension arrived, an error drops the connection
+     */
+    fn on_message(&mut self, payload: &[u8], context: &mut ExtensionContext) -> Result<(), String>;
+
+    /**
+     * Called on every iteration of the message loop, for periodic messages
+     */
+    fn on_tick(&mut self, _context: &mut ExtensionContext) {}
+}
+
+/**
+ * Creates the handler of an extension for a new connection
+ */
+pub type ExtensionFactory = std::sync::Arc<dyn Fn() -> Box<dyn ExtensionHandler> + Send + Sync>;
+
+/**
+ * Extensions of a connection. We receive messages with the ids we advertised (position in the registry + 1)
+ * and send them with the ids the peer advertised.
+ */
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    peer_ids: HashMap<String, u8>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) {
+        self.handlers.push(handler);
+    }
+
+    /**
+     * Id the peer must use to send us messages of the extension
+     */
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.handlers
+            .iter()
+            .position(|handler| handler.name() == name)
+            .map(|position| position as u8 + 1)
+    }
+
+    /**
+     * Id we must use to send messages of the extension, None if the peer does not support it
+     */
+    pub fn peer_id(&self, name: &str) -> Option<u8> {
+        self.peer_ids.get(name).copied()
+    }
+
+    pub fn our_handshake(
+        &self,
+        base: ExtendedHandshake,
+        context: &TorrentContext,
+    ) -> ExtendedHandshake {
+        let mut handshake = base;
+
+        for (position, handler) in self.handlers.iter().enumerate() {
+            handshake
+                .m
+                .insert(handler.name().to_string(), position as u8 + 1);
+            handler.on_our_handshake(&mut handshake, context);
+        }
+
+        return handshake;
+    }
+
+    /**
+     * Returns the (extended id, payload) messages to send
+     */
+    pub fn on_peer_handshake(
+        &mut self,
+        handshake: &ExtendedHandshake,
+        peer: SocketAddr,
+        torrent: &TorrentContext,
+    ) -> Vec<(u8, Vec<u8>)> {
+        //a later handshake only updates the extensions it mentions
+        for (name, id) in &handshake.m {
+            if *id == 0 {
+                self.peer_ids.remove(name);
+            } else {
+                self.peer_ids.insert(name.clone(), *id);
+            }
+        }
+
+        return self.dispatch(peer, torrent, |handler, context| {
+            handler.on_peer_handshake(handshake, context)
+        });
+    }
+
+    pub fn on_message(
+        &mut self,
+        extended_id: u8,
+        payload: &[u8],
+        peer: SocketAddr,
+        torrent: &TorrentContext,
+    ) -> Result<Vec<(u8, Vec<u8>)>, String> {
+        let position = match (extended_id as usize).checked_sub(1) {
+            Some(position) if position < self.handlers.len() => position,
+            _ => {
+                debug!("[{peer}] ignoring message of unknown extension {extended_id}");
+                return Ok(Vec::new());
+            }
+        };
+
+        let handler = &mut self.handlers[position];
+        let mut context = ExtensionContext::new(peer, torrent);
+
+        handler
+            .on_message(payload, &mut context)
+            .map_err(|e| format!("{}: {e}", handler.name()))?;
+
+        return Ok(self.take_outbox(position, context));
+    }
+
+    pub fn on_tick(&mut self, peer: SocketAddr, torrent: &TorrentContext) -> Vec<(u8, Vec<u8>)> {
+        return self.dispatch(peer, torrent, |handler, context| handler.on_tick(context));
+    }
+
+    fn dispatch<F>(
+        &mut self,
+        peer: SocketAddr,
+        torrent: &TorrentContext,
+        mut event: F,
+    ) -> Vec<(u8, Vec<u8>)>
+    where
+        F: FnMut(&mut dyn ExtensionHandler, &mut ExtensionContext),
+    {
+        let mut messages = Vec::new();
+
+        for position in 0..self.handlers.len() {
+            let mut context = ExtensionContext::new(peer, torrent);
+
+            event(self.handlers[position].as_mut(), &mut context);
+            messages.extend(self.take_outbox(position, context));
+        }
+
+        return messages;
+    }
+
+    fn take_outbox(&self, position: usize, context: ExtensionContext) -> Vec<(u8, Vec<u8>)> {
+        let name = self.handlers[position].name();
+
+        let peer_id = match self.peer_id(name) {
+            Some(id) => id,
+            None => {
+                if !context.outbox.is_empty() {
+                    debug!(
+                        "[{}] peer does not support {name}, dropping messages",
+                        context.peer
+                    );
+                }
+                return Vec::new();
+            }
+        };
+
+        return context
+            .outbox
+            .into_iter()
+            .map(|payload| (peer_id, payload))
+            .collect();
+    }
+}
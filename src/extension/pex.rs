@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use super::{ExtendedHandshake, ExtensionContext, ExtensionHandler};
+use crate::bencode::{BencodeValue, decode_value};
+
+pub const UT_PEX: &str = "ut_pex";
+
+//BEP 11: a peer must not send more than one message a minute
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+//added and dropped peers in a single message
+pub const MAX_PEX_PEERS: usize = 50;
+
+//messages arriving faster than this are ignored
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+pub const PEX_FLAG_ENCRYPTION: u8 = 0x01;
+pub const PEX_FLAG_SEED: u8 = 0x02;
+pub const PEX_FLAG_UTP: u8 = 0x04;
+pub const PEX_FLAG_HOLEPUNCH: u8 = 0x08;
+pub const PEX_FLAG_CONNECTABLE: u8 = 0x10;
+
+/**
+ * Peers connected (added) and disconnected (dropped) since the previous message, with a flags byte per added peer
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (added, added_flags): (Vec<_>, Vec<u8>) = self
+            .added
+            .iter()
+            .filter(|(peer, _)| peer.is_ipv4())
+            .copied()
+            .unzip();
+        let (added6, added6_flags): (Vec<_>, Vec<u8>) = self
+            .added
+            .iter()
+            .filter(|(peer, _)| peer.is_ipv6())
+            .copied()
+            .unzip();
+        let dropped = self.dropped.iter().filter(|peer| peer.is_ipv4());
+        let dropped6 = self.dropped.iter().filter(|peer| peer.is_ipv6());
+
+        return BencodeValue::dictionary(vec![
+            ("added", BencodeValue::from(compact_peers(added.iter()))),
+            ("added.f", BencodeValue::from(added_flags)),
+            ("added6", BencodeValue::from(compact_peers(added6.iter()))),
+            ("added6.f", BencodeValue::from(added6_flags)),
+            ("dropped", BencodeValue::from(compact_peers(dropped))),
+            ("dropped6", BencodeValue::from(compact_peers(dropped6))),
+        ])
+        .encode();
+    }
+
+    pub fn parse(payload: &[u8]) -> Result<Self, String> {
+        let (value, _) = decode_value(payload)?;
+
+        if value.as_dictionary().is_none() {
+            return Err(String::from("ut_pex message is not a dictionary"));
+        }
+
+        let bytes = |key: &str| {
+            value
+                .get(key)
+                .and_then(|value| value.as_bytes())
+                .unwrap_or_default()
+        };
+
+        let with_flags = |peers: Vec<SocketAddr>, flags: &[u8]| {
+            peers
+                .into_iter()
+                .enumerate()
+                .map(|(index, peer)| (peer, flags.get(index).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut added = with_flags(parse_peers(bytes("added"), 6), bytes("added.f"));
+        added.extend(with_flags(
+            parse_peers(bytes("added6"), 18),
+            bytes("added6.f"),
+        ));
+
+        let mut dropped = parse_peers(bytes("dropped"), 6);
+        dropped.extend(parse_peers(bytes("dropped6"), 18));
+
+        return Ok(PexMessage { added, dropped });
+    }
+}
+
+/**
+ * Addresses as ip + port
+ */
+fn compact_peers<'a, I: Iterator<Item = &'a SocketAddr>>(peers: I) -> Vec<u8> {
+    let mut compact = Vec::new();
+
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => compact.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => compact.extend_from_slice(&ip.octets()),
+        }
+        compact.extend_from_slice(&peer.port().to_be_bytes());
+    }
+
+    return compact;
+}
+
+/**
+ * 6 bytes per IPv4 peer, 18 per IPv6 peer
+ */
+fn parse_peers(compact: &[u8], size: usize) -> Vec<SocketAddr> {
+    compact
+        .chunks_exact(size)
+        .map(|chunk| {
+            let ip = if size == 6 {
+                IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&chunk[0..4]).unwrap()))
+            } else {
+                IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&chunk[0..16]).unwrap()))
+            };
+            let port = u16::from_be_bytes(chunk[size - 2..].try_into().unwrap());
+
+            SocketAddr::new(ip, port)
+        })
+        .collect()
+}
+
+/**
+ * Tells the peer about the other peers of the swarm we are connected to, and feeds the peers it knows to the pool
+ */
+#[derive(Debug)]
+pub struct UtPex {
+    interval: Duration,
+
+    //the peer advertised ut_pex
+    enabled: bool,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+
+    //what the peer currently knows from us
+    advertised: HashSet<SocketAddr>,
+}
+
+impl Default for UtPex {
+    fn default() -> Self {
+        Self::new(PEX_INTERVAL)
+    }
+}
+
+impl UtPex {
+    pub fn new(interval: Duration) -> Self {
+        UtPex {
+            interval,
+            enabled: false,
+            last_sent: None,
+            last_received: None,
+            advertised: HashSet::new(),
+        }
+    }
+
+    /**
+     * Listen addresses of our other connections with their flags
+     */
+    fn connected_peers(&self, context: &ExtensionContext) -> HashMap<SocketAddr, u8> {
+        let stats = context.torrent.peer_registry.lock().unwrap().stats();
+
+        return stats
+            .into_iter()
+            .filter(|(peer, _)| *peer != context.peer)
+            .filter_map(|(peer, stats)| {
+                let listen_addr = stats.listen_addr?;
+                let mut flags = 0;
+
+                //we managed to connect to it ourselves
+                if listen_addr == peer {
+                    flags |= PEX_FLAG_CONNECTABLE;
+                }
+                if stats.seed {
+                    flags |= PEX_FLAG_SEED;
+                }
+                if stats.utp {
+                    flags |= PEX_FLAG_UTP;
+                }
+
+                return Some((listen_addr, flags));
+            })
+            .collect();
+    }
+}
+
+impl ExtensionHandler for UtPex {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn on_peer_handshake(
+        &mut self,
+        handshake: &ExtendedHandshake,
+        _context: &mut ExtensionContext,
+    ) {
+        self.enabled = handshake.m.get(UT_PEX).is_some_and(|id| *id != 0);
+    }
+
+    fn on_message(&mut self, payload: &[u8], context: &mut ExtensionContext) -> Result<(), String> {
+        if let Some(last_received) = self.last_received
+            && last_received.elapsed() < MIN_RECEIVE_INTERVAL
+        {
+            debug!("[{}] ignoring ut_pex message sent too soon", context.peer);
+            return Ok(());
+        }
+        self.last_received = Some(Instant::now());
+
+        let message = PexMessage::parse(payload)?;
+        let peers = message
+            .added
+            .into_iter()
+            .take(MAX_PEX_PEERS)
+            .map(|(peer, _)| peer);
+
+        let added = context.torrent.peer_pool.lock().unwrap().add_peers(peers);
+        debug!("[{}] ut_pex: {added} new peers", context.peer);
+
+        return Ok(());
+    }
+
+    fn on_tick(&mut self, context: &mut ExtensionContext) {
+        if !self.enabled
+            || self
+                .last_sent
+                .is_some_and(|last_sent| last_sent.elapsed() < self.interval)
+        {
+            return;
+        }
+        self.last_sent = Some(Instant::now());
+
+        let connected = self.connected_peers(context);
+
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(peer, _)| !self.advertised.contains(peer))
+            .take(MAX_PEX_PEERS)
+            .map(|(peer, flags)| (*peer, *flags))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|peer| !connected.contains_key(peer))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return;
+        }
+
+        //the rest goes in the next message
+        self.advertised.extend(added.iter().map(|(peer, _)| *peer));
+        for peer in &dropped {
+            self.advertised.remove(peer);
+        }
+
+        context.send(PexMessage { added, dropped }.to_bytes());
+    }
+}
@@ -1,25 +1,20 @@
 use std::{
-    collections::VecDeque,
-    fs::{self, File},
+    fs,
     io::{Read, Seek, Write},
+    path::Path,
 };
 
-use log::{debug, info};
+use log::debug;
 use sha1::{Digest, Sha1};
 
-use crate::{torrent_file::TorrentFile, tracker_data::TrackerData};
+use crate::torrent_file::TorrentFile;
 use hex;
 
 pub struct FileHandler {
     file: fs::File,
     pub bitfield: Vec<u8>,
-
-    /*
-    Using VecDeque instead of Vector since we can/remove elements from the top
-    with O(1) complexity, while Vector needs to re-organize the whole vector everytime
-    */
-    pub needed_pieces: VecDeque<usize>,
     pub written_bytes: usize,
+    pub uploaded_bytes: usize,
 }
 
 impl FileHandler {
@@ -32,6 +27,10 @@ impl FileHandler {
         self.written_bytes += piece.len();
     }
 
+    pub fn set_have(&mut self, piece_index: usize) {
+        self.bitfield[piece_index / 8] |= 1 << (7 - piece_index % 8);
+    }
+
     pub fn get_data_from_file(&mut self, start_index: u64, length: usize) -> Vec<u8> {
         let mut buf = vec![0u8; length];
         self.file
@@ -55,7 +54,7 @@ fn get_local_file_bitfield(file: &mut fs::File, torrent_file: &TorrentFile) -> (
         let read = file.read(&mut buffer).unwrap();
         total_read += read;
 
-        hasher.write(&buffer[0..read]).unwrap();
+        hasher.update(&buffer[0..read]);
         let hashed = hasher.finalize_reset();
 
         let matching_part = torrent_file.info.pieces[(i * 20)..(i * 20 + 20)].as_ref();
@@ -95,8 +94,15 @@ fn get_local_file_bitfield(file: &mut fs::File, torrent_file: &TorrentFile) -> (
     return (bitfield, total_verified_bytes);
 }
 
-pub fn get_file_handler(torrent_file: &TorrentFile, _tracker_data: &TrackerData) -> FileHandler {
-    let path = format!("./downloads/{}", torrent_file.info.name);
+pub fn get_file_handler(torrent_file: &TorrentFile) -> FileHandler {
+    return get_file_handler_in(torrent_file, Path::new("./downloads"));
+}
+
+/**
+ * Open (or create) the torrent file inside the given directory and verify its pieces
+ */
+pub fn get_file_handler_in(torrent_file: &TorrentFile, directory: &Path) -> FileHandler {
+    let path = directory.join(&torrent_file.info.name);
 
     let exists_before_open = fs::exists(&path).unwrap();
 
@@ -104,6 +110,7 @@ pub fn get_file_handler(torrent_file: &TorrentFile, _tracker_data: &TrackerData)
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(path)
         .unwrap();
 
@@ -118,36 +125,10 @@ pub fn get_file_handler(torrent_file: &TorrentFile, _tracker_data: &TrackerData)
 
     println!("total pieces: {}", torrent_file.pieces_amount);
 
-    let needed_pieces =
-        bitfield
-            .iter()
-            .enumerate()
-            .fold(VecDeque::new(), |mut acc, (index, pieces_bits)| {
-                for shift in 0..=7 {
-                    let piece_index = index * 8 + shift;
-                    if piece_index >= torrent_file.pieces_amount {
-                        break;
-                    }
-                    /*
-                     * We shift left all the 8 pieces from the byte and apply
-                     * a mask cancelling all bits except the MSB. If the resulting
-                     * number is 0 then we need that piece, anything else than 0 is not needed.
-                     */
-                    let piece_is_needed = (pieces_bits << shift) & 0b10000000 == 0;
-
-                    if piece_is_needed {
-                        acc.push_back(piece_index);
-                    }
-                    println!("piece {} | needed: {piece_is_needed}", index * 8 + shift);
-                }
-
-                return acc;
-            });
-
     FileHandler {
         file: handler,
         bitfield,
-        needed_pieces,
         written_bytes: total_verified_bytes,
+        uploaded_bytes: 0,
     }
 }
@@ -1,7 +1,24 @@
 pub mod bencode;
+pub mod choker;
 pub mod client;
 pub mod connection_handler;
+pub mod dht;
+pub mod extension;
 pub mod file_handler;
+pub mod lsd;
+pub mod magnet;
+pub mod network;
+pub mod peer_listener;
+pub mod peer_pool;
+pub mod peer_registry;
+pub mod piece_picker;
+pub mod rate_limiter;
+pub mod rate_meter;
+pub mod runtime;
+pub mod torrent_context;
 pub mod torrent_file;
 pub mod tracker;
 pub mod tracker_data;
+pub mod tracker_manager;
+pub mod tracker_server;
+pub mod utp;
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::network::canonical_peer_addr;
+use crate::peer_pool::PeerPool;
+
+//BEP 14 multicast groups, both on the same port
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+//BEP 14 allows one announce per torrent and minute, we stay well below
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+//info hashes in a single announce, so it fits in an ethernet frame
+const MAX_INFO_HASHES: usize = 20;
+
+//how long the thread waits for datagrams before checking the announces due
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/**
+ * BT-SEARCH message (BEP 14): an HTTP like request multicast on the local network
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announce {
+    //port accepting peer connections, the address is the source of the datagram
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    //lets a client recognize its own announces looped back by the network
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    /**
+     * The host is the group the announce is sent to
+     */
+    pub fn encode(&self, host: SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {}\r\n",
+            self.port
+        );
+
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+
+        return message.into_bytes();
+    }
+
+    /**
+     * Datagrams come from anyone on the network, nothing is trusted
+     */
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let message =
+            std::str::from_utf8(data).map_err(|_| String::from("announce is not UTF-8"))?;
+        let mut lines = message.split("\r\n");
+
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(String::from("not a BT-SEARCH request"));
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(format!("invalid header line {line:?}")),
+            };
+
+            if name.eq_ignore_ascii_case("port") {
+                port = value.parse::<u16>().ok().filter(|port| *port != 0);
+            } else if name.eq_ignore_ascii_case("infohash") {
+                match hex::decode(value)
+                    .ok()
+                    .and_then(|hash| hash.try_into().ok())
+                {
+                    Some(info_hash) => info_hashes.push(info_hash),
+                    None => debug!("ignoring invalid LSD info hash {value:?}"),
+                }
+            } else if name.eq_ignore_ascii_case("cookie") {
+                cookie = Some(value.to_string());
+            }
+        }
+
+        let port = port.ok_or_else(|| String::from("missing or invalid port"))?;
+        if info_hashes.is_empty() {
+            return Err(String::from("no valid info hash"));
+        }
+
+        return Ok(Announce {
+            port,
+            info_hashes,
+            cookie,
+        });
+    }
+}
+
+struct Torrent {
+    peer_pool: Arc<Mutex<PeerPool>>,
+    next_announce: Instant,
+}
+
+struct Group {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+struct Inner {
+    groups: Vec<Group>,
+    //peer port we announce
+    port: u16,
+    cookie: String,
+    torrents: Mutex<HashMap<[u8; 20], Torrent>>,
+    stopped: AtomicBool,
+}
+
+impl Inner {
+    fn handle_datagram(&self, datagram: &[u8], from: SocketAddr) {
+        let announce = match Announce::parse(datagram) {
+            Ok(announce) => announce,
+            Err(e) => {
+                debug!("[{from}] invalid LSD announce: {e}");
+                return;
+            }
+        };
+
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return;
+        }
+
+        //keeps the scope of IPv6 link-local addresses
+        let mut peer = from;
+        peer.set_port(announce.port);
+        let torrents = self.torrents.lock().unwrap();
+
+        for info_hash in &announce.info_hashes {
+            if let Some(torrent) = torrents.get(info_hash) {
+                debug!("[{peer}] found by LSD for {}", hex::encode(info_hash));
+                torrent.peer_pool.lock().unwrap().add_peers([peer]);
+            }
+        }
+    }
+
+    /**
+     * Multicast the torrents due, on every group
+     */
+    fn announce_due(&self) {
+        let now = Instant::now();
+        let mut due = Vec::new();
+
+        for (info_hash, torrent) in self.torrents.lock().unwrap().iter_mut() {
+            if now >= torrent.next_announce {
+                due.push(*info_hash);
+                torrent.next_announce = now + ANNOUNCE_INTERVAL;
+            }
+        }
+
+        for info_hashes in due.chunks(MAX_INFO_HASHES) {
+            let announce = Announce {
+                port: self.port,
+                info_hashes: info_hashes.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+
+            for group in &self.groups {
+                //networks without a multicast route are only worth a debug line
+                if let Err(e) = group
+                    .socket
+                    .send_to(&announce.encode(group.addr), group.addr)
+                {
+                    debug!("cannot send LSD announce to {}: {e}", group.addr);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Local Service Discovery (BEP 14): the torrents are announced on the multicast groups of the
+ * local network, and the peers announcing them there are added to their pools. Every client of
+ * the machine joins the groups, our own announces come back and are told apart by their cookie.
+ */
+pub struct Lsd {
+    inner: Arc<Inner>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Lsd {
+    /**
+     * Joins the BEP 14 groups, the IPv6 one only when the host supports it
+     */
+    pub fn start(port: u16) -> io::Result<Self> {
+        Lsd::with_groups(
+            &[
+                SocketAddr::new(IpAddr::V4(LSD_IPV4_GROUP), LSD_PORT),
+                SocketAddr::new(IpAddr::V6(LSD_IPV6_GROUP), LSD_PORT),
+            ],
+            port,
+        )
+    }
+
+    /**
+     * Fails when none of the groups can be joined
+     */
+    pub fn with_groups(groups: &[SocketAddr], port: u16) -> io::Result<Self> {
+        let mut joined = Vec::new();
+        let mut last_error = None;
+
+        for addr in groups {
+            match join_group(*addr) {
+                Ok(socket) => joined.push(Group {
+                    socket,
+                    addr: *addr,
+                }),
+                Err(e) => {
+                    debug!("cannot join LSD group {addr}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if joined.is_empty() {
+            return Err(last_error
+                .unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no LSD group")));
+        }
+
+        //every socket waits in turn, a loop takes about one tick
+        for group in &joined {
+            group
+                .socket
+                .set_read_timeout(Some(TICK_INTERVAL / joined.len() as u32))?;
+        }
+
+        let inner = Arc::new(Inner {
+            groups: joined,
+            port,
+            cookie: hex::encode(rand::random::<[u8; 8]>()),
+            torrents: Mutex::new(HashMap::new()),
+            stopped: AtomicBool::new(false),
+        });
+
+        let handle = {
+            let inner = Arc::clone(&inner);
+            thread::spawn(move || run_sockets(inner))
+        };
+
+        info!(
+            "LSD listening on {}",
+            inner
+                .groups
+                .iter()
+                .map(|group| group.addr.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+
+        Ok(Lsd {
+            inner,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn cookie(&self) -> &str {
+        &self.inner.cookie
+    }
+
+    /**
+     * The torrent is announced right away then every ANNOUNCE_INTERVAL, the peers found go to
+     * the pool. Private torrents must not be added, their peers only come from their trackers.
+     */
+    pub fn add_torrent(&self, info_hash: [u8; 20], peer_pool: Arc<Mutex<PeerPool>>) {
+        self.inner.torrents.lock().unwrap().insert(
+            info_hash,
+            Torrent {
+                peer_pool,
+                next_announce: Instant::now(),
+            },
+        );
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.inner.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.stopped.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        self.stop();
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/**
+ * Socket bound to the port of the group and member of it. Other clients of the machine bind the
+ * same port, the address is reused and every one of them gets the multicast datagrams.
+ */
+fn join_group(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = match addr.ip() {
+        IpAddr::V4(group) => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port()).into())?;
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+            //announces stay on the local network
+            socket.set_multicast_ttl_v4(1)?;
+            socket
+        }
+        IpAddr::V6(group) => {
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_only_v6(true)?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), addr.port()).into())?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+            socket
+        }
+    };
+
+    return Ok(socket.into());
+}
+
+fn run_sockets(inner: Arc<Inner>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    while !inner.stopped.load(Ordering::Relaxed) {
+        inner.announce_due();
+
+        for group in &inner.groups {
+            match group.socket.recv_from(&mut buf) {
+                Ok((read, from)) => inner.handle_datagram(&buf[..read], canonical_peer_addr(from)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => {
+                    warn!("LSD socket error on {}: {e}", group.addr);
+                    thread::sleep(TICK_INTERVAL);
+                }
+            }
+        }
+    }
+}
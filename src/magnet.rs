@@ -0,0 +1,289 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::client::ClientConfig;
+use crate::connection_handler::handshake::{self, Handshake};
+use crate::connection_handler::message::PeerMessage;
+use crate::connection_handler::peer_stream::PeerStream;
+use crate::extension::metadata::{MetadataDownload, MetadataMessage, UT_METADATA};
+use crate::extension::{self, EXTENDED_HANDSHAKE_ID, ExtendedHandshake, extended_message};
+
+//time a peer has to send us the whole metadata
+const METADATA_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+//extended id we ask peers to use for ut_metadata messages
+const LOCAL_UT_METADATA_ID: u8 = 1;
+
+//largest message accepted while downloading the metadata, bitfields included
+const MAX_METADATA_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/**
+ * magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>&x.pe=<peer>
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self, String> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| String::from("not a magnet link"))?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for parameter in query.split('&') {
+            let (key, value) = match parameter.split_once('=') {
+                Some((key, value)) => (key, value),
+                None => continue,
+            };
+            let value = urlencoding::decode(value)
+                .map_err(|e| format!("invalid magnet parameter {key}: {e}"))?
+                .into_owned();
+
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => debug!("ignoring invalid magnet peer {value}"),
+                },
+                _ => {}
+            }
+        }
+
+        return Ok(MagnetLink {
+            info_hash: info_hash
+                .ok_or_else(|| String::from("magnet link has no btih info hash"))?,
+            display_name,
+            trackers,
+            peers,
+        });
+    }
+}
+
+/**
+ * 40 hex characters or 32 base32 characters
+ */
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], String> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|e| format!("invalid hex info hash: {e}"))?,
+        32 => decode_base32(hash).ok_or_else(|| format!("invalid base32 info hash {hash}"))?,
+        _ => return Err(format!("invalid info hash {hash}")),
+    };
+
+    return Ok(bytes.try_into().unwrap());
+}
+
+fn decode_base32(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for character in value.bytes() {
+        let digit = match character.to_ascii_uppercase() {
+            letter @ b'A'..=b'Z' => letter - b'A',
+            digit @ b'2'..=b'7' => digit - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | digit as u32;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    return Some(bytes);
+}
+
+/**
+ * Download the info dictionary from the first peer able to send it, checked against the info hash
+ */
+pub fn fetch_metadata(
+    info_hash: [u8; 20],
+    peers: &[SocketAddr],
+    config: &ClientConfig,
+) -> Result<Vec<u8>, String> {
+    for peer in peers {
+        match fetch_from_peer(*peer, info_hash, config) {
+            Ok(metadata) => {
+                info!("[{peer}] downloaded metadata of {} bytes", metadata.len());
+                return Ok(metadata);
+            }
+            Err(e) => debug!("[{peer}] cannot download metadata: {e}"),
+        }
+    }
+
+    return Err(format!(
+        "none of the {} peers sent the metadata",
+        peers.len()
+    ));
+}
+
+fn fetch_from_peer(
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    config: &ClientConfig,
+) -> Result<Vec<u8>, String> {
+    let mut stream =
+        handshake::connect(peer, &info_hash, config, None).map_err(|e| e.to_string())?;
+
+    handshake::send_handshake(&mut stream, &Handshake::new(info_hash, config.peer_id))
+        .map_err(|e| e.to_string())?;
+    let peer_handshake = handshake::read_handshake(&mut stream, config.handshake_timeout)
+        .map_err(|e| e.to_string())?;
+    peer_handshake
+        .validate(&info_hash, &config.peer_id)
+        .map_err(|e| e.to_string())?;
+
+    if !peer_handshake.supports_extension_protocol() {
+        return Err(String::from("peer does not support the extension protocol"));
+    }
+
+    let mut our_handshake = ExtendedHandshake {
+        v: Some(extension::client_version()),
+        p: Some(config.port),
+        ..ExtendedHandshake::default()
+    };
+    our_handshake
+        .m
+        .insert(UT_METADATA.to_string(), LOCAL_UT_METADATA_ID);
+    write(
+        &mut stream,
+        &extended_message(EXTENDED_HANDSHAKE_ID, &our_handshake.to_bytes()),
+    )?;
+
+    let started_at = Instant::now();
+    let mut download: Option<MetadataDownload> = None;
+
+    loop {
+        let remaining = METADATA_PEER_TIMEOUT.saturating_sub(started_at.elapsed());
+        if remaining.is_zero() {
+            return Err(String::from("timed out"));
+        }
+
+        let msg = read_message(&mut stream, remaining)?;
+
+        //everything but extension messages (bitfield, have...) is ignored
+        let (extended_id, payload) = match msg {
+            PeerMessage::Extended { id, payload } => (id, payload),
+            _ => continue,
+        };
+
+        if extended_id == EXTENDED_HANDSHAKE_ID {
+            let peer_extensions = ExtendedHandshake::parse(&payload)?;
+            let peer_ut_metadata_id = *peer_extensions
+                .m
+                .get(UT_METADATA)
+                .filter(|id| **id != 0)
+                .ok_or_else(|| String::from("peer does not support ut_metadata"))?;
+            let metadata_size = peer_extensions
+                .metadata_size
+                .ok_or_else(|| String::from("peer did not send the metadata size"))?;
+
+            let new_download = MetadataDownload::new(info_hash, metadata_size)?;
+
+            for piece in new_download.missing_pieces() {
+                let request = MetadataMessage::Request { piece }.to_bytes();
+                write(
+                    &mut stream,
+                    &extended_message(peer_ut_metadata_id, &request),
+                )?;
+            }
+
+            download = Some(new_download);
+            continue;
+        }
+
+        if extended_id != LOCAL_UT_METADATA_ID {
+            continue;
+        }
+
+        let download = download
+            .as_mut()
+            .ok_or_else(|| String::from("received metadata before the extended handshake"))?;
+
+        match MetadataMessage::parse(&payload)? {
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                if total_size != download.size() {
+                    return Err(format!(
+                        "metadata size changed from {} to {total_size}",
+                        download.size()
+                    ));
+                }
+
+                download.add_piece(piece, data)?;
+
+                if download.is_complete() {
+                    return download.finish();
+                }
+            }
+            MetadataMessage::Reject { piece } => {
+                return Err(format!("peer rejected metadata piece {piece}"));
+            }
+            MetadataMessage::Request { .. } => {}
+        }
+    }
+}
+
+fn write(stream: &mut PeerStream, raw_msg: &[u8]) -> Result<(), String> {
+    stream.write_all(raw_msg).map_err(|e| e.to_string())
+}
+
+/**
+ * Next message, keep-alives are skipped
+ */
+fn read_message(stream: &mut PeerStream, timeout: Duration) -> Result<PeerMessage, String> {
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+
+    loop {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).map_err(read_error)?;
+
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_METADATA_MESSAGE_SIZE {
+            return Err(format!("message of {length} bytes is too large"));
+        }
+        if length == 0 {
+            continue;
+        }
+
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload).map_err(read_error)?;
+
+        return PeerMessage::parse(&payload);
+    }
+}
+
+fn read_error(e: std::io::Error) -> String {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => String::from("timed out"),
+        ErrorKind::UnexpectedEof => String::from("peer closed the connection"),
+        _ => e.to_string(),
+    }
+}
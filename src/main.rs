@@ -1,53 +1,323 @@
-use std::{env::args, fs::File, process};
+use std::{env::args, fs::File, net::SocketAddr, path::Path, process, sync::Arc};
 
-use dotenvy;
-use log::{debug, error};
-use rust_torrent::{file_handler, torrent_file::TorrentFile, tracker};
+use log::{debug, error, info, warn};
+use rust_torrent::{
+    client::ClientConfig,
+    dht::{self, Dht, routing_table::RoutingTable},
+    file_handler,
+    lsd::Lsd,
+    magnet::{self, MagnetLink},
+    network,
+    peer_listener::PeerListener,
+    torrent_context::TorrentContext,
+    torrent_file::{self, TorrentFile},
+    tracker,
+    tracker_manager::{self, TrackerManager, TransferStats},
+    tracker_server::{TrackerServer, TrackerServerConfig},
+    utp::UtpSocket,
+};
 
 fn main() {
-    dotenvy::dotenv().unwrap();
+    //.env is optional, every setting has a default
+    let _ = dotenvy::dotenv();
     env_logger::init();
 
+    let config = Arc::new(ClientConfig::from_env());
+
     let args: Vec<String> = args().collect();
 
+    if args.get(1).map(String::as_str) == Some("tracker-server") {
+        run_tracker_server(&args[2..]);
+        return;
+    }
+
     if args.len() != 2 {
         panic!("No file path given in args")
     }
 
     let file_name = &args[1];
 
-    let file_res = File::open(file_name);
+    let dht = if config.dht { start_dht(&config) } else { None };
 
-    if file_res.is_err() {
-        let err = file_res.unwrap_err();
-        error!("Error opening file {}: {}", file_name, err);
+    let torrent = if file_name.starts_with("magnet:") {
+        Arc::new(torrent_from_magnet(file_name, &config, dht.as_deref()))
+    } else {
+        let file = match File::open(file_name) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Error opening file {}: {}", file_name, err);
+                process::exit(1);
+            }
+        };
+        Arc::new(TorrentFile::from(file))
+    };
+
+    if !torrent_file::is_safe_file_name(&torrent.info.name) {
+        error!("invalid file name in torrent: {:?}", torrent.info.name);
         process::exit(1);
     }
 
-    let file = file_res.unwrap();
-    let torrent = TorrentFile::from(file);
+    let mut context = TorrentContext::new(
+        Arc::clone(&torrent),
+        file_handler::get_file_handler(&torrent),
+        Arc::clone(&config),
+    );
+    let initial_written_bytes = context.file_handler.lock().unwrap().written_bytes;
+
+    let tracker_manager = match TrackerManager::new(Arc::clone(&torrent), Arc::clone(&config)) {
+        Ok(manager) => Arc::new(manager),
+        Err(err) => {
+            error!("cannot start tracker manager: {}", err);
+            process::exit(1)
+        }
+    };
+
+    debug!("{:?}", tracker_manager.statuses());
+
+    //uTP shares the port number of the TCP listener
+    if config.utp {
+        match network::bind_dual_stack_udp(config.port).and_then(UtpSocket::new) {
+            Ok(socket) => context.utp_socket = Some(Arc::new(socket)),
+            Err(err) => warn!("cannot bind uTP socket on port {}: {}", config.port, err),
+        }
+    }
+
+    let announce_handle = tracker_manager::spawn_announce_loop(
+        Arc::clone(&tracker_manager),
+        Arc::clone(&context.peer_pool),
+        Arc::clone(&context.file_handler),
+    );
 
-    let res_tracker_data = tracker::get_tracker_data(&torrent);
+    let torrent_dht = dht.as_ref().filter(|_| context.allows_untracked_peers());
+    let dht_announce_handle = torrent_dht.map(|dht| {
+        if !torrent.nodes.is_empty() {
+            dht.bootstrap(&dht::resolve_nodes(&torrent.nodes));
+        }
+        context.dht = Some(Arc::clone(dht));
 
-    if res_tracker_data.is_err() {
-        error!(
-            "error getting tracker data: {}",
-            res_tracker_data.unwrap_err()
-        );
-        process::exit(1)
+        dht::spawn_announce_loop(
+            Arc::clone(dht),
+            torrent.info_hash,
+            config.port,
+            Arc::clone(&context.peer_pool),
+        )
+    });
+
+    let lsd = if config.lsd && context.allows_untracked_peers() {
+        start_lsd(&config)
+    } else {
+        None
+    };
+    if let Some(lsd) = &lsd {
+        lsd.add_torrent(torrent.info_hash, Arc::clone(&context.peer_pool));
     }
 
-    let tracker_data = res_tracker_data.unwrap();
+    //without a listener we can still connect to peers ourselves
+    let peer_listener = match network::bind_dual_stack_listener(config.port) {
+        Ok(listener) => PeerListener::start(listener, vec![context.clone()]).ok(),
+        Err(err) => {
+            warn!("cannot listen for peers on port {}: {}", config.port, err);
+            None
+        }
+    };
 
-    debug!("{:?}", tracker_data);
+    let utp_listener = context
+        .utp_socket
+        .as_ref()
+        .and_then(|socket| PeerListener::start_utp(Arc::clone(socket), vec![context.clone()]).ok());
 
-    let file_handler = file_handler::get_file_handler(&torrent, &tracker_data);
+    tracker::run_connections(&context, Some(config.max_connections));
 
-    let handles = tracker::get_connections_handler(torrent, &tracker_data, file_handler, Some(50));
+    if let Some(peer_listener) = peer_listener {
+        peer_listener.stop();
+    }
+    if let Some(utp_listener) = utp_listener {
+        utp_listener.stop();
+    }
 
-    for handle in handles {
-        handle.join().unwrap();
+    let uploaded = context.file_handler.lock().unwrap().uploaded_bytes;
+    tracker_manager.stop(TransferStats {
+        uploaded,
+        downloaded: torrent.info.length - initial_written_bytes,
+        left: 0,
+    });
+    //a panicking announce thread must not take the client down on exit
+    if announce_handle.join().is_err() {
+        error!("tracker announce thread panicked");
     }
 
+    if let Some(dht) = dht {
+        if let Some(path) = &config.dht_state_file {
+            match dht.save(Path::new(path)) {
+                Ok(()) => debug!("saved DHT routing table to {path}"),
+                Err(err) => warn!("{}", err),
+            }
+        }
+        dht.stop();
+    }
+    if let Some(handle) = dht_announce_handle
+        && handle.join().is_err()
+    {
+        error!("DHT announce thread panicked");
+    }
+    //the socket thread is joined on drop
+    drop(lsd);
+
     println!("END");
 }
+
+fn start_lsd(config: &ClientConfig) -> Option<Lsd> {
+    match Lsd::start(config.port) {
+        Ok(lsd) => Some(lsd),
+        Err(err) => {
+            warn!("cannot start local service discovery: {}", err);
+            None
+        }
+    }
+}
+
+/**
+ * Join the DHT with the routing table of the previous session, or through the bootstrap nodes
+ */
+fn start_dht(config: &ClientConfig) -> Option<Arc<Dht>> {
+    let table = config
+        .dht_state_file
+        .as_ref()
+        .and_then(|path| RoutingTable::load(Path::new(path)).ok())
+        .unwrap_or_else(|| RoutingTable::new(rand::random()));
+
+    let socket = std::net::UdpSocket::bind(("0.0.0.0", config.dht_port));
+    let dht = match socket.and_then(|socket| Dht::new(socket, table)) {
+        Ok(dht) => dht,
+        Err(err) => {
+            warn!("cannot start DHT on port {}: {}", config.dht_port, err);
+            return None;
+        }
+    };
+
+    dht.bootstrap(&dht::resolve_nodes(&config.dht_bootstrap_nodes));
+
+    return Some(Arc::new(dht));
+}
+
+/**
+ * Download the info dictionary of a magnet link from the peers given by its trackers and the DHT
+ */
+fn torrent_from_magnet(link: &str, config: &Arc<ClientConfig>, dht: Option<&Dht>) -> TorrentFile {
+    let magnet_link = match MagnetLink::parse(link) {
+        Ok(magnet_link) => magnet_link,
+        Err(err) => {
+            error!("invalid magnet link: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let mut peers: Vec<SocketAddr> = magnet_link.peers.clone();
+    peers.extend(magnet_peers(&magnet_link, config));
+    if let Some(dht) = dht {
+        peers.extend(dht.get_peers(magnet_link.info_hash));
+    }
+
+    let metadata = match magnet::fetch_metadata(magnet_link.info_hash, &peers, config) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            error!("cannot download the torrent metadata: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let torrent = match TorrentFile::from_info_bytes(metadata, &magnet_link.trackers) {
+        Ok(torrent) => torrent,
+        Err(err) => {
+            error!("invalid torrent metadata: {}", err);
+            process::exit(1);
+        }
+    };
+
+    if config.save_magnet_torrent {
+        let path = Path::new("./downloads").join(format!("{}.torrent", torrent.info.name));
+
+        match torrent.save(&path) {
+            Ok(()) => info!("saved torrent file to {}", path.display()),
+            Err(err) => warn!("{}", err),
+        }
+    }
+
+    return torrent;
+}
+
+fn magnet_peers(magnet_link: &MagnetLink, config: &Arc<ClientConfig>) -> Vec<SocketAddr> {
+    if magnet_link.trackers.is_empty() {
+        return Vec::new();
+    }
+
+    //only what the trackers need to announce is known before the metadata
+    let torrent = TorrentFile {
+        announce: magnet_link.trackers[0].clone(),
+        announce_list: Some(
+            magnet_link
+                .trackers
+                .iter()
+                .map(|tracker| vec![tracker.clone()])
+                .collect(),
+        ),
+        info_hash: magnet_link.info_hash,
+        info_hash_str: hex::encode(magnet_link.info_hash),
+        ..TorrentFile::default()
+    };
+
+    match TrackerManager::new(Arc::new(torrent), Arc::clone(config)) {
+        //the size is unknown, any amount left makes the trackers send us seeds
+        Ok(manager) => manager.announce_due(TransferStats {
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+        }),
+        Err(err) => {
+            warn!("cannot announce magnet link: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+/**
+ * rust-torrent tracker-server [--http <addr>] [--udp <addr>] [--no-http] [--trust-ip]
+ */
+fn run_tracker_server(args: &[String]) {
+    let mut config = TrackerServerConfig::default();
+    let mut args_iter = args.iter();
+
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--http" | "--udp" => {
+                let addr = match args_iter.next().map(|value| value.parse()) {
+                    Some(Ok(addr)) => addr,
+                    _ => {
+                        error!("{arg} expects an address like 0.0.0.0:6969");
+                        process::exit(1)
+                    }
+                };
+
+                if arg == "--http" {
+                    config.http_addr = Some(addr);
+                } else {
+                    config.udp_addr = Some(addr);
+                }
+            }
+            "--no-http" => config.http_addr = None,
+            "--trust-ip" => config.trust_announced_ip = true,
+            _ => {
+                error!("unknown tracker-server argument {arg}");
+                process::exit(1)
+            }
+        }
+    }
+
+    match TrackerServer::start(config) {
+        Ok(server) => server.wait(),
+        Err(err) => {
+            error!("cannot start tracker server: {}", err);
+            process::exit(1)
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::OnceLock;
+
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/*
+ * Well-known public addresses only used to let the OS pick the outgoing interface.
+ * Connecting a UDP socket does not send any packet.
+ */
+static IPV4_PROBE_ADDR: &str = "8.8.8.8:80";
+static IPV6_PROBE_ADDR: &str = "[2001:4860:4860::8888]:80";
+
+fn local_address_towards(probe_addr: &str, bind_addr: &str) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(bind_addr).ok()?;
+    socket.connect(probe_addr).ok()?;
+    let local_ip = socket.local_addr().ok()?.ip();
+
+    if local_ip.is_unspecified() || local_ip.is_loopback() {
+        return None;
+    }
+
+    return Some(local_ip);
+}
+
+/**
+ * Address of the interface used to reach the IPv4 internet, if any. Probed the first time only.
+ */
+pub fn local_ipv4() -> Option<IpAddr> {
+    static LOCAL_IPV4: OnceLock<Option<IpAddr>> = OnceLock::new();
+    *LOCAL_IPV4.get_or_init(|| local_address_towards(IPV4_PROBE_ADDR, "0.0.0.0:0"))
+}
+
+/**
+ * Address of the interface used to reach the IPv6 internet, if any. Probed the first time only.
+ */
+pub fn local_ipv6() -> Option<IpAddr> {
+    static LOCAL_IPV6: OnceLock<Option<IpAddr>> = OnceLock::new();
+    *LOCAL_IPV6.get_or_init(|| local_address_towards(IPV6_PROBE_ADDR, "[::]:0"))
+}
+
+/**
+ * Bind a TCP listener accepting both IPv6 and IPv4 (as v4-mapped addresses) connections.
+ * Falls back to an IPv4 only listener on hosts without IPv6 support.
+ */
+pub fn bind_dual_stack_listener(port: u16) -> io::Result<TcpListener> {
+    match bind_ipv6_listener(port) {
+        Ok(listener) => Ok(listener),
+        Err(e) => {
+            debug!("cannot bind dual stack listener ({e}), falling back to IPv4");
+            TcpListener::bind(("0.0.0.0", port))
+        }
+    }
+}
+
+/**
+ * Bind a UDP socket receiving both IPv6 and IPv4 datagrams, or an IPv4 only one without IPv6 support
+ */
+pub fn bind_dual_stack_udp(port: u16) -> io::Result<UdpSocket> {
+    match bind_ipv6_udp(port) {
+        Ok(socket) => Ok(socket),
+        Err(e) => {
+            debug!("cannot bind dual stack UDP socket ({e}), falling back to IPv4");
+            UdpSocket::bind(("0.0.0.0", port))
+        }
+    }
+}
+
+fn bind_ipv6_udp(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+
+    return Ok(socket.into());
+}
+
+fn bind_ipv6_listener(port: u16) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    socket.listen(128)?;
+
+    return Ok(socket.into());
+}
+
+/**
+ * Peers accepted on a dual stack listener show up as ::ffff:a.b.c.d,
+ * converting them back so they compare equal to tracker provided addresses
+ */
+pub fn canonical_peer_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ipv4) => SocketAddr::new(IpAddr::V4(ipv4), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task;
+
+use crate::connection_handler::ConnectionHandler;
+use crate::connection_handler::handshake::{self, HandshakeError};
+use crate::connection_handler::mse::{self, EncryptionPolicy};
+use crate::connection_handler::peer_stream::Transport;
+use crate::network::canonical_peer_addr;
+use crate::runtime;
+use crate::torrent_context::TorrentContext;
+use crate::utp::UtpSocket;
+
+//peers connecting to us must send their handshake first
+const INCOMING_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+//how often the uTP listener checks whether it was stopped
+const UTP_ACCEPT_INTERVAL: Duration = Duration::from_millis(250);
+
+/**
+ * Accepts incoming peer connections and hands them to the torrent matching the handshake info hash.
+ * Every connection is served by a task of the runtime, like outgoing ones.
+ */
+pub struct PeerListener {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+
+    //a blocking TCP accept has to be woken up to stop
+    tcp: bool,
+}
+
+impl PeerListener {
+    pub fn start(listener: TcpListener, torrents: Vec<TorrentContext>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        info!("listening for peers on {local_addr}");
+
+        return Ok(PeerListener::spawn(
+            local_addr,
+            true,
+            torrents,
+            move |incoming, stopped| run_listener(listener, incoming, stopped),
+        ));
+    }
+
+    /**
+     * Accept uTP connections on the socket our outgoing uTP connections use
+     */
+    pub fn start_utp(socket: Arc<UtpSocket>, torrents: Vec<TorrentContext>) -> io::Result<Self> {
+        socket.listen();
+        let local_addr = socket.local_addr();
+        info!("listening for uTP peers on {local_addr}");
+
+        return Ok(PeerListener::spawn(
+            local_addr,
+            false,
+            torrents,
+            move |incoming, stopped| run_utp_listener(&socket, incoming, stopped),
+        ));
+    }
+
+    fn spawn(
+        local_addr: SocketAddr,
+        tcp: bool,
+        torrents: Vec<TorrentContext>,
+        run: impl FnOnce(Incoming, Arc<AtomicBool>) + Send + 'static,
+    ) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        //every torrent shares the client configuration
+        let (encryption, max_connections) = torrents
+            .first()
+            .map(|context| {
+                (
+                    context.config.incoming_encryption,
+                    context.config.max_connections,
+                )
+            })
+            .unwrap_or_default();
+
+        let torrents: HashMap<[u8; 20], TorrentContext> = torrents
+            .into_iter()
+            .map(|context| (context.torrent_file.info_hash, context))
+            .collect();
+
+        let handle = {
+            let stopped = Arc::clone(&stopped);
+            let incoming = Incoming {
+                torrents: Arc::new(torrents),
+                encryption,
+                handshakes: Arc::new(Semaphore::new(max_connections)),
+            };
+            thread::spawn(move || run(incoming, stopped))
+        };
+
+        PeerListener {
+            local_addr,
+            stopped,
+            handle: Some(handle),
+            tcp,
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        //wake up the blocking accept
+        if self.tcp {
+            let loopback = match self.local_addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            let _ = TcpStream::connect(SocketAddr::new(loopback, self.local_addr.port()));
+        }
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/**
+ * What the accept loops share with the connection tasks
+ */
+struct Incoming {
+    torrents: Arc<HashMap<[u8; 20], TorrentContext>>,
+    encryption: EncryptionPolicy,
+
+    //the handshakes run on the blocking pool before any connection limit applies,
+    //so only as many as the connection limit may be pending at once
+    handshakes: Arc<Semaphore>,
+}
+
+impl Incoming {
+    /**
+     * Serve the connection if a handshake slot is free, otherwise it is closed right away
+     */
+    fn spawn(&self, stream: Transport) {
+        let permit = match Arc::clone(&self.handshakes).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("dropping incoming connection, too many pending handshakes");
+                return;
+            }
+        };
+
+        runtime::spawn(handle_incoming(
+            stream,
+            Arc::clone(&self.torrents),
+            self.encryption,
+            permit,
+        ));
+    }
+}
+
+fn run_listener(listener: TcpListener, incoming: Incoming, stopped: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("cannot accept peer connection: {e}");
+                continue;
+            }
+        };
+
+        incoming.spawn(Transport::Tcp(stream));
+    }
+}
+
+fn run_utp_listener(socket: &UtpSocket, incoming: Incoming, stopped: Arc<AtomicBool>) {
+    while !stopped.load(Ordering::Relaxed) {
+        let stream = match socket.accept(UTP_ACCEPT_INTERVAL) {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => {
+                warn!("cannot accept uTP peer connection: {e}");
+                return;
+            }
+        };
+
+        incoming.spawn(Transport::Utp(stream));
+    }
+}
+
+async fn handle_incoming(
+    stream: Transport,
+    torrents: Arc<HashMap<[u8; 20], TorrentContext>>,
+    encryption: EncryptionPolicy,
+    handshake_permit: OwnedSemaphorePermit,
+) {
+    let peer = match stream.peer_addr() {
+        Ok(addr) => canonical_peer_addr(addr),
+        Err(_) => return,
+    };
+
+    //plaintext or encrypted, told apart by the first bytes. Both handshakes are short and blocking
+    let info_hashes: Vec<[u8; 20]> = torrents.keys().copied().collect();
+    let handshakes = task::spawn_blocking(move || {
+        let mut stream = mse::accept(stream, &info_hashes, encryption, INCOMING_HANDSHAKE_TIMEOUT)?;
+        let peer_handshake = handshake::read_handshake(&mut stream, INCOMING_HANDSHAKE_TIMEOUT)?;
+
+        return Ok::<_, HandshakeError>((stream, peer_handshake));
+    })
+    .await;
+    drop(handshake_permit);
+
+    let (stream, peer_handshake) = match handshakes {
+        Ok(Ok(handshakes)) => handshakes,
+        Ok(Err(e)) => {
+            debug!("[{peer}] dropping incoming connection: {e}");
+            return;
+        }
+        Err(e) => {
+            warn!("[{peer}] incoming handshake task failed: {e}");
+            return;
+        }
+    };
+
+    let info_hash = peer_handshake.info_hash;
+    let context = match torrents.get(&info_hash) {
+        Some(context) => context,
+        None => {
+            debug!(
+                "[{peer}] dropping incoming connection for unknown torrent {}",
+                hex::encode(info_hash)
+            );
+            return;
+        }
+    };
+
+    {
+        let mut peer_pool = context.peer_pool.lock().unwrap();
+
+        if peer_pool.connected_len() >= context.config.max_connections {
+            debug!("[{peer}] dropping incoming connection, too many connections");
+            return;
+        }
+
+        if peer_pool.is_banned(peer.ip()) {
+            debug!("[{peer}] dropping incoming connection, peer is banned");
+            return;
+        }
+
+        if !peer_pool.add_incoming(peer) {
+            debug!("[{peer}] dropping incoming connection, already connected");
+            return;
+        }
+    }
+
+    let mut connection_handler = ConnectionHandler::new(peer, context);
+    if let Err(e) = connection_handler
+        .accept_async(stream, &peer_handshake)
+        .await
+    {
+        debug!("[{peer}] dropping incoming connection: {e}");
+    }
+    drop(connection_handler);
+
+    context.peer_pool.lock().unwrap().release(peer);
+}
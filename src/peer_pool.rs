@@ -0,0 +1,106 @@
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+
+use log::debug;
+
+use crate::network::canonical_peer_addr;
+
+/**
+ * Peers discovered by any source (trackers, ...) waiting for a connection.
+ * A peer is either queued or connected, adding it again while it is known is a no-op.
+ * Banned addresses are never queued nor accepted again.
+ */
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    queued: VecDeque<SocketAddr>,
+    connected: HashSet<SocketAddr>,
+    banned: HashSet<IpAddr>,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Returns the amount of peers that were not known yet
+     */
+    pub fn add_peers<I: IntoIterator<Item = SocketAddr>>(&mut self, peers: I) -> usize {
+        let mut added = 0;
+
+        for peer in peers {
+            let peer = canonical_peer_addr(peer);
+
+            if peer.port() == 0
+                || self.banned.contains(&peer.ip())
+                || self.connected.contains(&peer)
+                || self.queued.contains(&peer)
+            {
+                continue;
+            }
+
+            self.queued.push_back(peer);
+            added += 1;
+        }
+
+        if added > 0 {
+            debug!("{added} new peers in pool ({} queued)", self.queued.len());
+        }
+
+        return added;
+    }
+
+    /**
+     * Pops the next peer to connect to, it stays known until released
+     */
+    pub fn next_peer(&mut self) -> Option<SocketAddr> {
+        let peer = self.queued.pop_front()?;
+        self.connected.insert(peer);
+
+        return Some(peer);
+    }
+
+    /**
+     * A peer connected to us, returns false if we already have a connection with it or it is banned
+     */
+    pub fn add_incoming(&mut self, peer: SocketAddr) -> bool {
+        let peer = canonical_peer_addr(peer);
+        if self.banned.contains(&peer.ip()) {
+            return false;
+        }
+        self.queued.retain(|queued_peer| *queued_peer != peer);
+
+        return self.connected.insert(peer);
+    }
+
+    /**
+     * Called once a connection is over, the peer can be queued again by the next discovery
+     */
+    pub fn release(&mut self, peer: SocketAddr) {
+        self.connected.remove(&canonical_peer_addr(peer));
+    }
+
+    /**
+     * The peer sent corrupt data, every port of its address is forgotten
+     */
+    pub fn ban(&mut self, ip: IpAddr) {
+        let ip = canonical_peer_addr(SocketAddr::new(ip, 0)).ip();
+
+        debug!("banning {ip}");
+        self.banned.insert(ip);
+        self.queued.retain(|peer| peer.ip() != ip);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned
+            .contains(&canonical_peer_addr(SocketAddr::new(ip, 0)).ip())
+    }
+
+    pub fn queued_len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn connected_len(&self) -> usize {
+        self.connected.len()
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Instant;
+
+use crate::piece_picker::BlockRequest;
+
+/**
+ * Instructions sent to a connection thread by the rest of the client,
+ * they are handled the next time its message loop wakes up
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerCommand {
+    //the block was received from another peer
+    Cancel(BlockRequest),
+
+    //decided by the choker
+    Choke,
+    Unchoke,
+
+    //the piece was verified through another connection, the peer must be told we have it
+    Have(u32),
+
+    //the connection must be closed, for the given reason
+    Disconnect(String),
+
+    //new bandwidth caps of this peer in bytes per second, 0 for unlimited
+    SetRateLimits { upload: u64, download: u64 },
+}
+
+/**
+ * State of a connection published by its thread for the rest of the client
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub peer_interested: bool,
+    pub am_choking: bool,
+
+    //bytes per second
+    pub download_rate: f64,
+    pub upload_rate: f64,
+
+    pub connected_at: Instant,
+
+    //address the peer accepts connections on, from the extended handshake for incoming connections
+    pub listen_addr: Option<SocketAddr>,
+
+    //the peer has every piece
+    pub seed: bool,
+
+    //the connection runs over uTP
+    pub utp: bool,
+
+    //the peer stopped answering our requests
+    pub snubbed: bool,
+}
+
+impl Default for PeerStats {
+    fn default() -> Self {
+        PeerStats {
+            peer_interested: false,
+            am_choking: true,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            connected_at: Instant::now(),
+            listen_addr: None,
+            seed: false,
+            utp: false,
+            snubbed: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RegisteredPeer {
+    commands: Sender<PeerCommand>,
+    stats: PeerStats,
+}
+
+/**
+ * Connections currently alive, used to reach the thread owning a peer
+ */
+#[derive(Debug, Default)]
+pub struct PeerRegistry {
+    peers: HashMap<SocketAddr, RegisteredPeer>,
+
+    //a connection asks the choker not to wait for the next round (peer interest changed...)
+    rechoke_requested: bool,
+}
+
+impl PeerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, peer: SocketAddr) -> Receiver<PeerCommand> {
+        let (sender, receiver) = mpsc::channel();
+        self.peers.insert(
+            peer,
+            RegisteredPeer {
+                commands: sender,
+                stats: PeerStats::default(),
+            },
+        );
+
+        return receiver;
+    }
+
+    pub fn unregister(&mut self, peer: &SocketAddr) {
+        if let Some(registered_peer) = self.peers.remove(peer)
+            && !registered_peer.stats.am_choking
+        {
+            //an upload slot is free
+            self.rechoke_requested = true;
+        }
+    }
+
+    /**
+     * Returns false if the peer is not connected anymore
+     */
+    pub fn send(&self, peer: &SocketAddr, command: PeerCommand) -> bool {
+        match self.peers.get(peer) {
+            Some(registered_peer) => registered_peer.commands.send(command).is_ok(),
+            None => false,
+        }
+    }
+
+    /**
+     * Every connection but the given one
+     */
+    pub fn broadcast(&self, except: &SocketAddr, command: PeerCommand) {
+        for (peer, registered_peer) in &self.peers {
+            if peer != except {
+                let _ = registered_peer.commands.send(command.clone());
+            }
+        }
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.keys().copied().collect()
+    }
+
+    pub fn update_stats(&mut self, peer: &SocketAddr, stats: PeerStats) {
+        if let Some(registered_peer) = self.peers.get_mut(peer) {
+            if registered_peer.stats.peer_interested != stats.peer_interested {
+                self.rechoke_requested = true;
+            }
+
+            registered_peer.stats = stats;
+        }
+    }
+
+    pub fn stats(&self) -> Vec<(SocketAddr, PeerStats)> {
+        self.peers
+            .iter()
+            .map(|(peer, registered_peer)| (*peer, registered_peer.stats.clone()))
+            .collect()
+    }
+
+    pub fn take_rechoke_request(&mut self) -> bool {
+        std::mem::take(&mut self.rechoke_requested)
+    }
+}
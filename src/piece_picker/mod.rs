@@ -0,0 +1,477 @@
+mod piece;
+mod smart_ban;
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+
+use rand::Rng;
+
+pub use piece::{BlockRequest, BlockState, Piece, REQUEST_PIECE_SIZE};
+pub use smart_ban::MAX_HASH_FAILURES;
+use smart_ban::SmartBan;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PiecePriority {
+    Skip, //never downloaded
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PieceState {
+    Needed,
+    InProgress,
+    Have,
+}
+
+/**
+ * Check a piece in a bitfield, bits are stored in MSB order
+ */
+pub fn bitfield_has_piece(bitfield: &[u8], piece_index: usize) -> bool {
+    match bitfield.get(piece_index / 8) {
+        Some(byte) => (byte >> (7 - piece_index % 8)) & 1 == 1,
+        None => false,
+    }
+}
+
+/**
+ * Piece with blocks requested or received, the data is shared by every connection
+ * so a piece can be completed by several peers
+ */
+#[derive(Debug)]
+struct DownloadingPiece {
+    piece: Piece,
+
+    //peers a block was requested to, more than one in endgame
+    requesters: Vec<Vec<SocketAddr>>,
+}
+
+#[derive(Debug)]
+pub enum BlockResult {
+    //not requested or does not belong to a downloading piece
+    Rejected,
+
+    //already received from another peer (endgame)
+    Duplicate,
+
+    Accepted {
+        //other peers the block was requested to, they should be sent a cancel
+        cancel: Vec<SocketAddr>,
+
+        //the piece is complete and must be verified, see hash_passed and hash_failed
+        completed: Option<Piece>,
+    },
+}
+
+/**
+ * Central piece selection shared by every connection.
+ * Keeps how many connected peers have each piece and hands out the rarest needed pieces first,
+ * ties are broken randomly so peers don't all start with the same pieces.
+ */
+#[derive(Debug)]
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    states: Vec<PieceState>,
+    priorities: Vec<PiecePriority>,
+    piece_length: usize,
+    total_length: usize,
+    downloading: BTreeMap<u32, DownloadingPiece>,
+    smart_ban: SmartBan,
+
+    //running counts so picking does not scan every piece, see count and uncount
+    wanted: usize,
+    remaining: usize,
+}
+
+impl PiecePicker {
+    /**
+     * Pieces set in our bitfield are already verified on disk
+     */
+    pub fn new(
+        piece_length: usize,
+        total_length: usize,
+        pieces_amount: usize,
+        our_bitfield: &[u8],
+    ) -> Self {
+        let states: Vec<PieceState> = (0..pieces_amount)
+            .map(|piece_index| {
+                if bitfield_has_piece(our_bitfield, piece_index) {
+                    PieceState::Have
+                } else {
+                    PieceState::Needed
+                }
+            })
+            .collect();
+
+        //every piece starts with the normal priority
+        let remaining = states
+            .iter()
+            .filter(|state| **state != PieceState::Have)
+            .count();
+
+        PiecePicker {
+            availability: vec![0; pieces_amount],
+            states,
+            priorities: vec![PiecePriority::Normal; pieces_amount],
+            piece_length,
+            total_length,
+            downloading: BTreeMap::new(),
+            smart_ban: SmartBan::default(),
+            wanted: remaining,
+            remaining,
+        }
+    }
+
+    pub fn pieces_amount(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn availability(&self, piece_index: usize) -> u32 {
+        self.availability[piece_index]
+    }
+
+    pub fn add_peer_bitfield(&mut self, bitfield: &[u8]) {
+        for piece_index in 0..self.pieces_amount() {
+            if bitfield_has_piece(bitfield, piece_index) {
+                self.availability[piece_index] += 1;
+            }
+        }
+    }
+
+    /**
+     * The peer disconnected, its pieces are no longer available from it
+     */
+    pub fn remove_peer_bitfield(&mut self, bitfield: &[u8]) {
+        for piece_index in 0..self.pieces_amount() {
+            if bitfield_has_piece(bitfield, piece_index) {
+                self.availability[piece_index] = self.availability[piece_index].saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn peer_has(&mut self, piece_index: usize) {
+        if let Some(availability) = self.availability.get_mut(piece_index) {
+            *availability += 1;
+        }
+    }
+
+    pub fn set_priority(&mut self, piece_index: usize, priority: PiecePriority) {
+        self.uncount(piece_index);
+        self.priorities[piece_index] = priority;
+        self.count(piece_index);
+    }
+
+    fn set_state(&mut self, piece_index: usize, state: PieceState) {
+        self.uncount(piece_index);
+        self.states[piece_index] = state;
+        self.count(piece_index);
+    }
+
+    fn is_remaining(&self, piece_index: usize) -> bool {
+        self.states[piece_index] != PieceState::Have
+            && self.priorities[piece_index] != PiecePriority::Skip
+    }
+
+    fn count(&mut self, piece_index: usize) {
+        if self.is_wanted(piece_index) {
+            self.wanted += 1;
+        }
+        if self.is_remaining(piece_index) {
+            self.remaining += 1;
+        }
+    }
+
+    /**
+     * Called before the state or priority of the piece changes, count adds it back after
+     */
+    fn uncount(&mut self, piece_index: usize) {
+        if self.is_wanted(piece_index) {
+            self.wanted -= 1;
+        }
+        if self.is_remaining(piece_index) {
+            self.remaining -= 1;
+        }
+    }
+
+    pub fn priority(&self, piece_index: usize) -> PiecePriority {
+        self.priorities[piece_index]
+    }
+
+    fn is_wanted(&self, piece_index: usize) -> bool {
+        self.states[piece_index] == PieceState::Needed
+            && self.priorities[piece_index] != PiecePriority::Skip
+    }
+
+    /**
+     * A piece that failed its hash check is downloaded again from other peers than the ones that
+     * sent the failed data, when some other peer has it
+     */
+    fn avoids(&self, piece_index: usize, peer: SocketAddr) -> bool {
+        match self.smart_ban.suspects(piece_index as u32) {
+            Some(suspects) => {
+                suspects.contains(&peer.ip())
+                    && self.availability[piece_index] as usize > suspects.len()
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Whether the peer has a piece we still want
+     */
+    pub fn is_interesting(&self, peer_bitfield: &[u8]) -> bool {
+        (0..self.pieces_amount()).any(|piece_index| {
+            (self.is_wanted(piece_index) || self.states[piece_index] == PieceState::InProgress)
+                && bitfield_has_piece(peer_bitfield, piece_index)
+        })
+    }
+
+    /**
+     * Pick the next piece to download from a peer and mark it in progress.
+     * Highest priority first, then the lowest availability.
+     */
+    pub fn pick(&mut self, peer_bitfield: &[u8]) -> Option<usize> {
+        self.pick_for(None, peer_bitfield)
+    }
+
+    fn pick_for(&mut self, peer: Option<SocketAddr>, peer_bitfield: &[u8]) -> Option<usize> {
+        let mut rng = rand::rng();
+        let mut best: Option<(PiecePriority, u32)> = None;
+        let mut picked = None;
+        let mut ties = 0u32;
+
+        for piece_index in 0..self.pieces_amount() {
+            if !self.is_wanted(piece_index)
+                || !bitfield_has_piece(peer_bitfield, piece_index)
+                || peer.is_some_and(|peer| self.avoids(piece_index, peer))
+            {
+                continue;
+            }
+
+            let priority = self.priorities[piece_index];
+            let availability = self.availability[piece_index];
+
+            let is_better = match best {
+                None => true,
+                Some((best_priority, best_availability)) => {
+                    priority > best_priority
+                        || (priority == best_priority && availability < best_availability)
+                }
+            };
+
+            if is_better {
+                best = Some((priority, availability));
+                picked = Some(piece_index);
+                ties = 1;
+            } else if best == Some((priority, availability)) {
+                //reservoir sampling, every tied piece has the same chance to be picked
+                ties += 1;
+                if rng.random_range(0..ties) == 0 {
+                    picked = Some(piece_index);
+                }
+            }
+        }
+
+        if let Some(piece_index) = picked {
+            self.set_state(piece_index, PieceState::InProgress);
+        }
+
+        return picked;
+    }
+
+    fn piece_size(&self, piece_index: usize) -> usize {
+        if piece_index == self.pieces_amount() - 1 {
+            self.total_length - piece_index * self.piece_length
+        } else {
+            self.piece_length
+        }
+    }
+
+    /**
+     * Next block to request from a peer: missing blocks of the downloading pieces first
+     * so they complete early, then a new piece. In endgame, blocks already requested
+     * from other peers are requested again.
+     */
+    pub fn pick_block(&mut self, peer: SocketAddr, peer_bitfield: &[u8]) -> Option<BlockRequest> {
+        let avoided: Vec<u32> = self
+            .downloading
+            .keys()
+            .copied()
+            .filter(|piece_index| self.avoids(*piece_index as usize, peer))
+            .collect();
+
+        for (piece_index, downloading) in self.downloading.iter_mut() {
+            if !bitfield_has_piece(peer_bitfield, *piece_index as usize)
+                || avoided.contains(piece_index)
+            {
+                continue;
+            }
+
+            if let Some(block_index) = downloading.piece.next_missing_block() {
+                return Some(downloading.request_block(block_index, peer));
+            }
+        }
+
+        if let Some(piece_index) = self.pick_for(Some(peer), peer_bitfield) {
+            let piece = Piece::new(piece_index as u32, self.piece_size(piece_index));
+            let mut downloading = DownloadingPiece {
+                requesters: vec![Vec::new(); piece.blocks.len()],
+                piece,
+            };
+            let block = downloading.request_block(0, peer);
+            self.downloading.insert(piece_index as u32, downloading);
+
+            return Some(block);
+        }
+
+        if !self.is_endgame() {
+            return None;
+        }
+
+        for (piece_index, downloading) in self.downloading.iter_mut() {
+            if !bitfield_has_piece(peer_bitfield, *piece_index as usize)
+                || avoided.contains(piece_index)
+            {
+                continue;
+            }
+
+            let block_index = (0..downloading.piece.blocks.len()).find(|block_index| {
+                downloading.piece.blocks[*block_index] == BlockState::Requested
+                    && !downloading.requesters[*block_index].contains(&peer)
+            });
+
+            if let Some(block_index) = block_index {
+                return Some(downloading.request_block(block_index, peer));
+            }
+        }
+
+        return None;
+    }
+
+    /**
+     * Every wanted piece is downloading and every missing block is requested
+     */
+    pub fn is_endgame(&self) -> bool {
+        !self.downloading.is_empty()
+            && self.wanted == 0
+            && self
+                .downloading
+                .values()
+                .all(|downloading| downloading.piece.next_missing_block().is_none())
+    }
+
+    pub fn on_block_received(
+        &mut self,
+        peer: SocketAddr,
+        block: &BlockRequest,
+        data: &[u8],
+    ) -> BlockResult {
+        let downloading = match self.downloading.get_mut(&block.piece) {
+            Some(downloading) => downloading,
+            None => return BlockResult::Rejected,
+        };
+
+        let block_index = (block.offset / REQUEST_PIECE_SIZE) as usize;
+
+        if downloading.piece.blocks.get(block_index) == Some(&BlockState::Received) {
+            return BlockResult::Duplicate;
+        }
+
+        if !downloading.piece.add_block(block.offset, data, peer) {
+            return BlockResult::Rejected;
+        }
+
+        let mut cancel = std::mem::take(&mut downloading.requesters[block_index]);
+        cancel.retain(|requester| *requester != peer);
+
+        let completed = if downloading.piece.is_complete() {
+            self.downloading
+                .remove(&block.piece)
+                .map(|downloading| downloading.piece)
+        } else {
+            None
+        };
+
+        return BlockResult::Accepted { cancel, completed };
+    }
+
+    /**
+     * The block will not be received from the peer (choke, cancel, disconnect),
+     * it goes back to missing if no other peer was asked for it
+     */
+    pub fn release_block(&mut self, peer: SocketAddr, block: &BlockRequest) {
+        let downloading = match self.downloading.get_mut(&block.piece) {
+            Some(downloading) => downloading,
+            None => return,
+        };
+
+        let block_index = (block.offset / REQUEST_PIECE_SIZE) as usize;
+        let requesters = match downloading.requesters.get_mut(block_index) {
+            Some(requesters) => requesters,
+            None => return,
+        };
+
+        requesters.retain(|requester| *requester != peer);
+
+        if requesters.is_empty() {
+            downloading.piece.reset_block(block.offset);
+        }
+    }
+
+    /**
+     * The piece download stopped before completion, it can be picked again from scratch
+     */
+    pub fn abort(&mut self, piece_index: usize) {
+        self.downloading.remove(&(piece_index as u32));
+
+        if self.states[piece_index] == PieceState::InProgress {
+            self.set_state(piece_index, PieceState::Needed);
+        }
+    }
+
+    /**
+     * A completed piece did not match its hash, it is downloaded again, from other peers if possible.
+     * Returns the peers to ban for sending data of too many failed pieces.
+     */
+    pub fn hash_failed(&mut self, piece: &Piece) -> Vec<IpAddr> {
+        self.abort(piece.index as usize);
+
+        return self.smart_ban.on_hash_failed(piece);
+    }
+
+    /**
+     * A completed piece matched its hash. Returns the peers to ban for sending corrupt blocks
+     * in a previous attempt of the piece.
+     */
+    pub fn hash_passed(&mut self, piece: &Piece) -> Vec<IpAddr> {
+        self.mark_have(piece.index as usize);
+
+        return self.smart_ban.on_hash_passed(piece);
+    }
+
+    pub fn mark_have(&mut self, piece_index: usize) {
+        self.downloading.remove(&(piece_index as u32));
+        self.set_state(piece_index, PieceState::Have);
+    }
+
+    pub fn has(&self, piece_index: usize) -> bool {
+        self.states[piece_index] == PieceState::Have
+    }
+
+    /**
+     * Amount of wanted pieces not downloaded yet, including the ones in progress
+     */
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+}
+
+impl DownloadingPiece {
+    fn request_block(&mut self, block_index: usize, peer: SocketAddr) -> BlockRequest {
+        self.piece.blocks[block_index] = BlockState::Requested;
+        self.requesters[block_index].push(peer);
+
+        return self.piece.block_request(block_index);
+    }
+}
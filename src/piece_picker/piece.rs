@@ -0,0 +1,111 @@
+use std::net::SocketAddr;
+
+pub const REQUEST_PIECE_SIZE: u32 = 16u32 * 1024u32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockState {
+    Missing,
+    Requested,
+    Received,
+}
+
+/**
+ * Piece being downloaded, split in blocks of REQUEST_PIECE_SIZE (the last one may be shorter)
+ */
+#[derive(Debug)]
+pub struct Piece {
+    pub index: u32,
+    pub data: Vec<u8>,
+    pub blocks: Vec<BlockState>,
+    pub missing_data: usize,
+
+    //peer each received block came from, to find who sent corrupt data
+    pub senders: Vec<Option<SocketAddr>>,
+}
+
+impl Piece {
+    pub fn new(index: u32, length: usize) -> Self {
+        let blocks_amount = length.div_ceil(REQUEST_PIECE_SIZE as usize);
+
+        Piece {
+            index,
+            data: vec![0u8; length],
+            blocks: vec![BlockState::Missing; blocks_amount],
+            missing_data: length,
+            senders: vec![None; blocks_amount],
+        }
+    }
+
+    pub fn block_request(&self, block_index: usize) -> BlockRequest {
+        let offset = block_index as u32 * REQUEST_PIECE_SIZE;
+        let length = (self.data.len() as u32 - offset).min(REQUEST_PIECE_SIZE);
+
+        BlockRequest {
+            piece: self.index,
+            offset,
+            length,
+        }
+    }
+
+    pub fn block_data(&self, block_index: usize) -> &[u8] {
+        let block = self.block_request(block_index);
+        let start = block.offset as usize;
+
+        return &self.data[start..start + block.length as usize];
+    }
+
+    pub fn next_missing_block(&self) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|state| *state == BlockState::Missing)
+    }
+
+    /**
+     * Store the block data, returns false if it does not belong to the piece or was already received
+     */
+    pub fn add_block(&mut self, offset: u32, block_data: &[u8], sender: SocketAddr) -> bool {
+        if !offset.is_multiple_of(REQUEST_PIECE_SIZE) {
+            return false;
+        }
+
+        let block_index = (offset / REQUEST_PIECE_SIZE) as usize;
+        if block_index >= self.blocks.len()
+            || self.blocks[block_index] == BlockState::Received
+            || block_data.len() != self.block_request(block_index).length as usize
+        {
+            return false;
+        }
+
+        let start = offset as usize;
+        self.data[start..start + block_data.len()].copy_from_slice(block_data);
+        self.blocks[block_index] = BlockState::Received;
+        self.senders[block_index] = Some(sender);
+        self.missing_data -= block_data.len();
+
+        return true;
+    }
+
+    /**
+     * A block request was dropped (choke, cancel...), it will be requested again
+     */
+    pub fn reset_block(&mut self, offset: u32) {
+        let block_index = (offset / REQUEST_PIECE_SIZE) as usize;
+
+        if let Some(state) = self.blocks.get_mut(block_index)
+            && *state == BlockState::Requested
+        {
+            *state = BlockState::Missing;
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing_data == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub piece: u32,
+    pub offset: u32,
+    pub length: u32,
+}
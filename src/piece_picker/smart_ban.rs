@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use log::{debug, warn};
+use sha1::{Digest, Sha1};
+
+use super::Piece;
+
+//failed pieces a peer can send data for before it is banned
+pub const MAX_HASH_FAILURES: u32 = 3;
+
+#[derive(Debug)]
+struct BlockRecord {
+    block_index: usize,
+    sender: IpAddr,
+    hash: [u8; 20],
+}
+
+/**
+ * Finds the peers sending corrupt data. The blocks of a piece failing its hash check are remembered
+ * with their sender, once the piece is downloaded again and passes, the senders of the blocks that
+ * differ from the good ones are the culprits. Peers taking part in too many failed pieces are banned
+ * too, for the cases the piece never passes.
+ */
+#[derive(Debug, Default)]
+pub(super) struct SmartBan {
+    //blocks of the failed attempts of pieces not verified yet
+    failed: BTreeMap<u32, Vec<BlockRecord>>,
+
+    //failed pieces each peer sent data for
+    strikes: HashMap<IpAddr, u32>,
+}
+
+fn block_hash(piece: &Piece, block_index: usize) -> [u8; 20] {
+    Sha1::digest(piece.block_data(block_index)).into()
+}
+
+impl SmartBan {
+    /**
+     * Returns the peers reaching MAX_HASH_FAILURES with this piece
+     */
+    pub(super) fn on_hash_failed(&mut self, piece: &Piece) -> Vec<IpAddr> {
+        let records = self.failed.entry(piece.index).or_default();
+        let mut contributors = Vec::new();
+
+        for (block_index, sender) in piece.senders.iter().enumerate() {
+            let sender = match sender {
+                Some(sender) => sender.ip(),
+                None => continue,
+            };
+
+            let hash = block_hash(piece, block_index);
+            let known = records.iter().any(|record| {
+                record.block_index == block_index && record.sender == sender && record.hash == hash
+            });
+            if !known {
+                records.push(BlockRecord {
+                    block_index,
+                    sender,
+                    hash,
+                });
+            }
+
+            if !contributors.contains(&sender) {
+                contributors.push(sender);
+            }
+        }
+
+        debug!(
+            "piece {} failed, data sent by {:?}",
+            piece.index, contributors
+        );
+
+        let mut banned = Vec::new();
+        for peer in contributors {
+            let strikes = self.strikes.entry(peer).or_insert(0);
+            *strikes += 1;
+
+            if *strikes >= MAX_HASH_FAILURES {
+                warn!("banning {peer}: sent data for {strikes} pieces failing their hash check");
+                banned.push(peer);
+            }
+        }
+
+        return banned;
+    }
+
+    /**
+     * Returns the peers whose blocks of a previously failed attempt differ from the verified piece
+     */
+    pub(super) fn on_hash_passed(&mut self, piece: &Piece) -> Vec<IpAddr> {
+        let records = match self.failed.remove(&piece.index) {
+            Some(records) => records,
+            None => return Vec::new(),
+        };
+
+        let mut banned = Vec::new();
+        let mut innocent = Vec::new();
+
+        for record in &records {
+            if record.hash == block_hash(piece, record.block_index) {
+                if !innocent.contains(&record.sender) {
+                    innocent.push(record.sender);
+                }
+            } else if !banned.contains(&record.sender) {
+                warn!(
+                    "banning {}: sent a corrupt block {} of piece {}",
+                    record.sender, record.block_index, piece.index
+                );
+                banned.push(record.sender);
+            }
+        }
+
+        //their strike for this piece was undeserved
+        for peer in innocent.iter().filter(|peer| !banned.contains(peer)) {
+            if let Some(strikes) = self.strikes.get_mut(peer) {
+                *strikes = strikes.saturating_sub(1);
+            }
+        }
+
+        return banned;
+    }
+
+    /**
+     * Peers that sent data for a failed attempt of the piece
+     */
+    pub(super) fn suspects(&self, piece_index: u32) -> Option<Vec<IpAddr>> {
+        let records = self.failed.get(&piece_index)?;
+
+        let mut suspects = Vec::new();
+        for record in records {
+            if !suspects.contains(&record.sender) {
+                suspects.push(record.sender);
+            }
+        }
+
+        return Some(suspects);
+    }
+}
@@ -0,0 +1,207 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+//largest chunk reserved at once while limited, connections sharing a limit take turns by chunks
+const MAX_GRANT: usize = 16 * 1024;
+const MIN_GRANT: usize = 1024;
+
+//tokens saved while idle, in time at the configured rate
+const BURST: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    //bytes per second, 0 when unlimited
+    rate: u64,
+    //negative when more than available was reserved
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn capacity(&self) -> f64 {
+        return (self.rate as f64 * BURST.as_secs_f64()).max(MIN_GRANT as f64);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity());
+        self.refilled_at = now;
+    }
+}
+
+/**
+ * Token bucket in bytes per second, 0 meaning unlimited. Amounts are reserved right away and the
+ * tokens can go negative: a reservation is usable once the bucket paid back the debt, so callers
+ * are served in the order they asked.
+ */
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        let mut bucket = Bucket {
+            rate,
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        };
+        bucket.tokens = bucket.capacity();
+
+        RateLimiter {
+            bucket: Mutex::new(bucket),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.rate() > 0
+    }
+
+    /**
+     * Takes effect for the next reservations, 0 removes the limit
+     */
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(bucket.capacity());
+    }
+
+    /**
+     * Chunk size reserved at once, about what the bucket can save
+     */
+    fn grant_size(&self) -> usize {
+        let bucket = self.bucket.lock().unwrap();
+        if bucket.rate == 0 {
+            return MAX_GRANT;
+        }
+
+        return (bucket.capacity() as usize).min(MAX_GRANT);
+    }
+
+    /**
+     * Instant from which the amount can be transferred
+     */
+    pub fn reserve(&self, amount: usize) -> Instant {
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate == 0 {
+            return now;
+        }
+
+        bucket.refill(now);
+        bucket.tokens -= amount as f64;
+
+        if bucket.tokens >= 0.0 {
+            return now;
+        }
+
+        return now + Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64);
+    }
+}
+
+/**
+ * Upload and download limits of one level: the whole client, a torrent or a single peer
+ */
+#[derive(Debug)]
+pub struct BandwidthLimit {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl BandwidthLimit {
+    pub fn new(upload_rate: u64, download_rate: u64) -> Self {
+        BandwidthLimit {
+            upload: RateLimiter::new(upload_rate),
+            download: RateLimiter::new(download_rate),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        BandwidthLimit::new(0, 0)
+    }
+
+    pub fn limiter(&self, direction: Direction) -> &RateLimiter {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+/**
+ * Bytes a connection may transfer in one direction, reserved by chunks on every level it goes
+ * through. A connection holds a single chunk at a time, so the ones sharing a limit are served
+ * one chunk each in turn.
+ */
+#[derive(Debug)]
+pub struct Allowance {
+    direction: Direction,
+    levels: Vec<Arc<BandwidthLimit>>,
+    bytes: usize,
+    ready_at: Instant,
+}
+
+impl Allowance {
+    pub fn new(direction: Direction, levels: Vec<Arc<BandwidthLimit>>) -> Self {
+        Allowance {
+            direction,
+            levels,
+            bytes: 0,
+            ready_at: Instant::now(),
+        }
+    }
+
+    /**
+     * Amount that can be transferred out of the wanted one, and from when.
+     * Without any limit everything can go right away.
+     */
+    pub fn next(&mut self, wanted: usize) -> (usize, Instant) {
+        let limiters: Vec<&RateLimiter> = self
+            .levels
+            .iter()
+            .map(|level| level.limiter(self.direction))
+            .filter(|limiter| limiter.is_limited())
+            .collect();
+
+        if limiters.is_empty() {
+            self.bytes = 0;
+            return (wanted, Instant::now());
+        }
+
+        if self.bytes == 0 && wanted > 0 {
+            let grant = limiters
+                .iter()
+                .map(|limiter| limiter.grant_size())
+                .min()
+                .unwrap_or(MAX_GRANT)
+                .min(wanted);
+
+            self.bytes = grant;
+            self.ready_at = limiters
+                .iter()
+                .map(|limiter| limiter.reserve(grant))
+                .max()
+                .unwrap_or_else(Instant::now);
+        }
+
+        return (self.bytes.min(wanted), self.ready_at);
+    }
+
+    /**
+     * Bytes actually transferred out of the last amount given by next
+     */
+    pub fn consume(&mut self, bytes: usize) {
+        self.bytes = self.bytes.saturating_sub(bytes);
+    }
+}
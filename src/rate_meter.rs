@@ -0,0 +1,74 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(5);
+
+/**
+ * Transfer rate over a sliding time window
+ */
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    window: Duration,
+    samples: VecDeque<(Instant, usize)>,
+    window_bytes: usize,
+    total_bytes: usize,
+    started_at: Instant,
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        RateMeter::new(DEFAULT_WINDOW)
+    }
+}
+
+impl RateMeter {
+    pub fn new(window: Duration) -> Self {
+        RateMeter {
+            window,
+            samples: VecDeque::new(),
+            window_bytes: 0,
+            total_bytes: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+        self.window_bytes += bytes;
+        self.total_bytes += bytes;
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, bytes)) = self.samples.front() {
+            if now.duration_since(*at) <= self.window {
+                break;
+            }
+
+            self.window_bytes -= bytes;
+            self.samples.pop_front();
+        }
+    }
+
+    /**
+     * Bytes per second over the window (or since creation for young meters)
+     */
+    pub fn rate(&mut self) -> f64 {
+        let now = Instant::now();
+        self.expire(now);
+
+        //young meters are averaged over at least a second to avoid huge spikes
+        let min_elapsed = Duration::from_secs(1).min(self.window);
+        let elapsed = now
+            .duration_since(self.started_at)
+            .min(self.window)
+            .max(min_elapsed);
+
+        return self.window_bytes as f64 / elapsed.as_secs_f64();
+    }
+
+    pub fn total(&self) -> usize {
+        self.total_bytes
+    }
+}
@@ -0,0 +1,37 @@
+use std::future::Future;
+use std::sync::OnceLock;
+
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::JoinHandle;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/**
+ * Runtime shared by every peer connection. A connection is a task waiting on its socket and
+ * its timers instead of a thread, so thousands of them only cost their buffers.
+ */
+pub fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("peer-io")
+            .enable_all()
+            .build()
+            .expect("cannot start the async runtime")
+    })
+}
+
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    runtime().spawn(future)
+}
+
+/**
+ * Blocking facade: run a future to completion from a regular thread.
+ * Must not be called from a task of the runtime itself.
+ */
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
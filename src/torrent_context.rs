@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+
+use crate::client::ClientConfig;
+use crate::dht::Dht;
+use crate::extension::metadata::UtMetadata;
+use crate::extension::pex::UtPex;
+use crate::extension::{ExtensionFactory, ExtensionHandler};
+use crate::file_handler::FileHandler;
+use crate::peer_pool::PeerPool;
+use crate::peer_registry::PeerRegistry;
+use crate::piece_picker::PiecePicker;
+use crate::rate_limiter::BandwidthLimit;
+use crate::torrent_file::TorrentFile;
+use crate::utp::UtpSocket;
+
+/**
+ * State of a torrent shared by every connection thread, cloning it only clones the handles
+ */
+#[derive(Clone)]
+pub struct TorrentContext {
+    pub torrent_file: Arc<TorrentFile>,
+    pub file_handler: Arc<Mutex<FileHandler>>,
+    pub piece_picker: Arc<Mutex<PiecePicker>>,
+    pub peer_pool: Arc<Mutex<PeerPool>>,
+    pub peer_registry: Arc<Mutex<PeerRegistry>>,
+    pub config: Arc<ClientConfig>,
+
+    //extension protocols every new connection supports
+    pub extensions: Vec<ExtensionFactory>,
+
+    //outgoing connections try uTP through it first when set
+    pub utp_socket: Option<Arc<UtpSocket>>,
+
+    //limits of this torrent's connections
+    pub bandwidth: Arc<BandwidthLimit>,
+
+    //limits of the whole client, torrents running together must share the same one
+    pub global_bandwidth: Arc<BandwidthLimit>,
+
+    //peers supporting the DHT get our node port, and give us theirs
+    pub dht: Option<Arc<Dht>>,
+}
+
+impl TorrentContext {
+    pub fn new(
+        torrent_file: Arc<TorrentFile>,
+        file_handler: FileHandler,
+        config: Arc<ClientConfig>,
+    ) -> Self {
+        let piece_picker = PiecePicker::new(
+            torrent_file.info.piece_length,
+            torrent_file.info.length,
+            torrent_file.pieces_amount,
+            &file_handler.bitfield,
+        );
+
+        let mut context = TorrentContext {
+            torrent_file,
+            file_handler: Arc::new(Mutex::new(file_handler)),
+            piece_picker: Arc::new(Mutex::new(piece_picker)),
+            peer_pool: Arc::new(Mutex::new(PeerPool::new())),
+            peer_registry: Arc::new(Mutex::new(PeerRegistry::new())),
+            bandwidth: Arc::new(BandwidthLimit::new(
+                config.per_torrent_upload_limit,
+                config.per_torrent_download_limit,
+            )),
+            global_bandwidth: Arc::new(BandwidthLimit::new(
+                config.upload_limit,
+                config.download_limit,
+            )),
+            config,
+            extensions: Vec::new(),
+            utp_socket: None,
+            dht: None,
+        };
+
+        context.register_extension(|| Box::new(UtMetadata::default()));
+
+        if context.allows_untracked_peers() {
+            context.register_extension(|| Box::new(UtPex::default()));
+        }
+
+        return context;
+    }
+
+    /**
+     * Support an extension protocol on the connections created from now on
+     */
+    pub fn register_extension<F>(&mut self, factory: F)
+    where
+        F: Fn() -> Box<dyn ExtensionHandler> + Send + Sync + 'static,
+    {
+        self.extensions.push(Arc::new(factory));
+    }
+
+    /**
+     * Whether peers may come from PEX, the DHT or LSD, private torrents only get their peers from their trackers
+     */
+    pub fn allows_untracked_peers(&self) -> bool {
+        return !self.torrent_file.info.private;
+    }
+
+    pub fn is_download_complete(&self) -> bool {
+        self.file_handler.lock().unwrap().written_bytes == self.torrent_file.info.length
+    }
+}
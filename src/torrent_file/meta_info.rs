@@ -1,4 +1,4 @@
-use std::{fmt, fs::File};
+use std::{fmt, io::Cursor};
 
 use crate::bencode::{BencodeKey, BencodeParsable};
 
@@ -11,6 +11,7 @@ pub enum MetaInfoKeys {
     Md5Sum,
     Sha256,
     Sha1,
+    Private,
 
     UnsupportedKey,
 }
@@ -23,11 +24,14 @@ pub struct MetaInfo {
     pub md5sum: Option<String>,
     pub sha1: Option<[u8; 20]>,
     pub sha256: Option<[u8; 32]>,
+
+    //BEP 27: peers only come from the trackers of the torrent
+    pub private: bool,
 }
 
 impl BencodeParsable for MetaInfo {
     type Key = MetaInfoKeys;
-    type R = File;
+    type R = Cursor<Vec<u8>>;
 
     fn key_from_str(s: &str) -> Self::Key {
         return MetaInfoKeys::from_str(s);
@@ -66,6 +70,7 @@ impl BencodeParsable for MetaInfo {
         match key {
             Self::Key::PieceLength => self.piece_length = value,
             Self::Key::Length => self.length = value,
+            Self::Key::Private => self.private = value == 1,
             _ => {}
         }
     }
@@ -80,6 +85,7 @@ impl fmt::Debug for MetaInfo {
             .field("md5sum", &self.md5sum)
             .field("sha1", &self.sha1)
             .field("sha256", &self.sha256)
+            .field("private", &self.private)
             .field(
                 "pieces",
                 &if self.pieces.len() > 40 {
@@ -109,6 +115,7 @@ impl BencodeKey for MetaInfoKeys {
             Self::Md5Sum => "md5sum",
             Self::Sha256 => "sha256",
             Self::Sha1 => "sha1",
+            Self::Private => "private",
             Self::UnsupportedKey => "unsupported-key",
         }
     }
@@ -122,6 +129,7 @@ impl BencodeKey for MetaInfoKeys {
             "md5sum" => Self::Md5Sum,
             "sha256" => Self::Sha256,
             "sha1" => Self::Sha1,
+            "private" => Self::Private,
             _ => Self::UnsupportedKey,
         }
     }
@@ -142,7 +150,7 @@ impl BencodeKey for MetaInfoKeys {
 
     fn is_integer_field(&self) -> bool {
         match self {
-            Self::Length | Self::PieceLength => true,
+            Self::Length | Self::PieceLength | Self::Private => true,
             _ => false,
         }
     }
@@ -1,6 +1,7 @@
 use std::{
     fs::File,
-    io::{BufRead, BufReader, Read, Seek},
+    io::{BufRead, BufReader, Cursor, Read, Seek},
+    path::{Component, Path},
 };
 
 use sha1::{Digest, Sha1};
@@ -9,7 +10,7 @@ mod meta_info;
 
 use meta_info::MetaInfo;
 
-use crate::bencode::{BencodeKey, BencodeParsable, decode_dictionary};
+use crate::bencode::{BencodeKey, BencodeParsable, BencodeValue, decode_dictionary, decode_value};
 
 #[derive(Debug)]
 pub struct TorrentFile {
@@ -22,10 +23,16 @@ pub struct TorrentFile {
     pub sources: Option<Vec<String>>,
     pub url_list: Option<Vec<String>>,
 
+    //DHT nodes given by trackerless torrents, as host:port
+    pub nodes: Vec<String>,
+
     //not part of the actual torrent BEP impl, but convenient to keep it here
     pub info_hash: [u8; 20],
     pub info_hash_str: String,
     pub pieces_amount: usize,
+
+    //bencoded info dictionary, served to peers downloading the metadata (BEP 9)
+    pub info_bytes: Vec<u8>,
 }
 
 impl Default for TorrentFile {
@@ -39,6 +46,7 @@ impl Default for TorrentFile {
                 md5sum: None,
                 sha1: None,
                 sha256: None,
+                private: false,
             },
             announce: String::new(),
             announce_list: None,
@@ -47,11 +55,122 @@ impl Default for TorrentFile {
             creation_date: 0,
             sources: None,
             url_list: None,
+            nodes: Vec::new(),
 
             info_hash: [0u8; 20],
             info_hash_str: String::new(),
             pieces_amount: 0,
+            info_bytes: Vec::new(),
+        }
+    }
+}
+
+impl TorrentFile {
+    /**
+     * Build the torrent from an info dictionary downloaded from peers (magnet links).
+     * The bytes are untrusted so they are checked before going through the torrent parser.
+     */
+    pub fn from_info_bytes(info_bytes: Vec<u8>, trackers: &[String]) -> Result<Self, String> {
+        let (info, used) = decode_value(&info_bytes)?;
+
+        if used != info_bytes.len() {
+            return Err(String::from("trailing data after the info dictionary"));
+        }
+
+        if info.as_dictionary().is_none() {
+            return Err(String::from("info is not a dictionary"));
+        }
+
+        if info.get("files").is_some() {
+            return Err(String::from("multi-file torrents are not supported"));
+        }
+
+        let positive = |key: &str| {
+            info.get(key)
+                .and_then(|value| value.as_integer())
+                .filter(|value| *value > 0)
+                .ok_or_else(|| format!("info has no valid {key}"))
+        };
+        let length = positive("length")? as usize;
+        let piece_length = positive("piece length")? as usize;
+
+        let pieces = info
+            .get("pieces")
+            .and_then(|pieces| pieces.as_bytes())
+            .ok_or_else(|| String::from("info has no pieces"))?;
+        if pieces.len() != length.div_ceil(piece_length) * 20 {
+            return Err(String::from("info pieces do not match the length"));
+        }
+
+        if !info
+            .get("name")
+            .and_then(|name| name.as_str())
+            .is_some_and(is_safe_file_name)
+        {
+            return Err(String::from("info has no valid name"));
+        }
+
+        let mut torrent_bytes = b"d4:info".to_vec();
+        torrent_bytes.extend_from_slice(&info_bytes);
+        torrent_bytes.push(b'e');
+
+        let mut torrent_file = Self::from(torrent_bytes);
+
+        if let Some(announce) = trackers.first() {
+            torrent_file.announce = announce.clone();
+            torrent_file.announce_list = Some(
+                trackers
+                    .iter()
+                    .map(|tracker| vec![tracker.clone()])
+                    .collect(),
+            );
+        }
+
+        return Ok(torrent_file);
+    }
+
+    /**
+     * Bencoded .torrent with the trackers and the info dictionary
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut entries = Vec::new();
+
+        if !self.announce.is_empty() {
+            entries.push(("announce", BencodeValue::from(self.announce.as_str())));
+        }
+        if let Some(announce_list) = &self.announce_list {
+            let tiers = announce_list
+                .iter()
+                .map(|tier| {
+                    BencodeValue::List(
+                        tier.iter()
+                            .map(|url| BencodeValue::from(url.as_str()))
+                            .collect(),
+                    )
+                })
+                .collect();
+            entries.push(("announce-list", BencodeValue::List(tiers)));
+        }
+
+        //the info dictionary is copied as is so the info hash does not change,
+        //it is the last key in sorted order
+        let mut bytes = BencodeValue::dictionary(entries).encode();
+        bytes.pop();
+        bytes.extend_from_slice(b"4:info");
+        bytes.extend_from_slice(&self.info_bytes);
+        bytes.push(b'e');
+
+        return bytes;
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)
+                .map_err(|e| format!("cannot create {}: {e}", directory.display()))?;
         }
+
+        std::fs::write(path, self.to_bytes())
+            .map_err(|e| format!("cannot save torrent file {}: {e}", path.display()))
     }
 }
 
@@ -99,6 +218,10 @@ impl BencodeKey for TorrentKeys {
         return *self == Self::AnnounceList;
     }
 
+    fn is_raw_value(&self) -> bool {
+        return *self == Self::Nodes;
+    }
+
     fn from_str(key: &str) -> Self {
         match key {
             "announce" => TorrentKeys::Announce,
@@ -110,6 +233,7 @@ impl BencodeKey for TorrentKeys {
 
             "sources" => TorrentKeys::Sources,
             "url-list" => TorrentKeys::UrlList,
+            "nodes" => TorrentKeys::Nodes,
             _ => TorrentKeys::UnsupportedKey,
         }
     }
@@ -125,15 +249,60 @@ impl BencodeKey for TorrentKeys {
 
             Self::Sources => "sources",
             Self::UrlList => "url-list",
+            Self::Nodes => "nodes",
             Self::UnsupportedKey => "unsupported-key",
         }
     }
 }
 
+/**
+ * The name becomes a file of the download directory: a single plain path component,
+ * so an absolute path or a .. cannot write elsewhere
+ */
+pub fn is_safe_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+
+    return !name.contains(['/', '\\'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none();
+}
+
+/**
+ * BEP 5: nodes = [["<host>", <port>], ...], malformed entries are skipped
+ */
+fn parse_nodes(raw: &[u8]) -> Vec<String> {
+    let nodes = match decode_value(raw) {
+        Ok((nodes, _)) => nodes,
+        Err(_) => return Vec::new(),
+    };
+
+    nodes
+        .as_list()
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|node| {
+            let node = node.as_list()?;
+            let host = node.first()?.as_str()?;
+            let port = u16::try_from(node.get(1)?.as_integer()?).ok()?;
+
+            Some(format!("{host}:{port}"))
+        })
+        .collect()
+}
+
 impl From<File> for TorrentFile {
-    fn from(source: File) -> Self {
+    fn from(mut source: File) -> Self {
+        let mut bytes = Vec::new();
+        source.read_to_end(&mut bytes).unwrap();
+
+        return Self::from(bytes);
+    }
+}
+
+impl From<Vec<u8>> for TorrentFile {
+    fn from(source: Vec<u8>) -> Self {
         let mut torrent_file = Self::default();
-        let mut buf_reader = BufReader::new(source);
+        let mut buf_reader = BufReader::new(Cursor::new(source));
 
         //making sure buffer is not empty as start
         buf_reader.fill_buf().unwrap();
@@ -144,7 +313,7 @@ impl From<File> for TorrentFile {
 
 impl BencodeParsable for TorrentFile {
     type Key = TorrentKeys;
-    type R = File;
+    type R = Cursor<Vec<u8>>;
 
     fn key_from_str(s: &str) -> Self::Key {
         TorrentKeys::from_str(s)
@@ -165,6 +334,12 @@ impl BencodeParsable for TorrentFile {
         }
     }
 
+    fn on_raw_value(&mut self, key: Self::Key, value: Vec<u8>) {
+        if key == Self::Key::Nodes {
+            self.nodes = parse_nodes(&value);
+        }
+    }
+
     fn on_integer(&mut self, key: Self::Key, value: usize) {
         match key {
             Self::Key::CreationDate => {
@@ -209,10 +384,11 @@ impl BencodeParsable for TorrentFile {
 
                 hasher.update(&info_raw_bytes);
 
-                self.info_hash = hasher.finalize().try_into().unwrap();
+                self.info_hash = hasher.finalize().into();
                 self.info_hash_str = hex::encode(self.info_hash);
 
                 self.pieces_amount = self.info.length.div_ceil(self.info.piece_length);
+                self.info_bytes = info_raw_bytes;
             }
             _ => {
                 decode_dictionary(&mut self.info, buf_reader);
@@ -232,6 +408,7 @@ pub enum TorrentKeys {
 
     Sources,
     UrlList,
+    Nodes,
 
     //We use this key whenever we meeta key in the torrent file that we don't support
     UnsupportedKey,
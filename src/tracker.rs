@@ -1,112 +1,291 @@
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
-use log::debug;
-use reqwest;
-use urlencoding::encode_binary;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
 
-use crate::client;
+use log::{debug, warn};
+use reqwest::Url;
+use reqwest::blocking::Client;
+use tokio::task::JoinHandle;
+use urlencoding::{encode, encode_binary};
+
+use crate::choker;
+use crate::client::ClientConfig;
 use crate::connection_handler::ConnectionHandler;
-use crate::file_handler::FileHandler;
+use crate::network;
+use crate::runtime;
+use crate::torrent_context::TorrentContext;
 use crate::torrent_file::TorrentFile;
 use crate::tracker_data::TrackerData;
 
-pub fn get_tracker_data(torrent_file: &TorrentFile) -> Result<TrackerData, String> {
-    println!("Announcing to tracker...");
-    let req = format!(
-        "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&event={}&compact=1",
-        torrent_file.announce,
-        encode_binary(torrent_file.info_hash.as_slice()),
-        client::PEER_ID,
-        6881,
-        0,
-        0,
-        torrent_file.info.length,
-        "started"
-    );
+const CONNECTIONS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Started => "started",
+            Self::Completed => "completed",
+            Self::Stopped => "stopped",
+        }
+    }
+}
 
-    let res = reqwest::blocking::get(req);
+/**
+ * Per announce values, the static ones come from ClientConfig
+ */
+#[derive(Debug, Clone)]
+pub struct AnnounceParams {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    pub event: Option<AnnounceEvent>,
 
-    if res.is_err() {
-        return Err(format!("server answered with: {:?}", res.unwrap_err()));
+    //BEP 7: our address in the family the announce is not made over, the tracker sees the other
+    pub local_ipv4: Option<IpAddr>,
+    pub local_ipv6: Option<IpAddr>,
+}
+
+impl AnnounceParams {
+    pub fn new(
+        announce: &str,
+        uploaded: usize,
+        downloaded: usize,
+        left: usize,
+        event: Option<AnnounceEvent>,
+    ) -> Self {
+        let over_ipv6 = announce_over_ipv6(announce);
+
+        AnnounceParams {
+            uploaded,
+            downloaded,
+            left,
+            event,
+            local_ipv4: over_ipv6
+                .filter(|ipv6| *ipv6)
+                .and_then(|_| network::local_ipv4()),
+            local_ipv6: over_ipv6
+                .filter(|ipv6| !*ipv6)
+                .and_then(|_| network::local_ipv6()),
+        }
     }
+}
+
+/**
+ * Whether the announce goes over IPv6: told by a literal host, otherwise by the first address
+ * resolved, the one the HTTP client tries first. None when the host cannot be resolved.
+ */
+fn announce_over_ipv6(announce: &str) -> Option<bool> {
+    let url = Url::parse(announce).ok()?;
+    let port = url.port_or_known_default()?;
+    let host = url
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
 
-    let res_data = res.unwrap();
+    let addr = (host, port).to_socket_addrs().ok()?.next()?;
 
-    println!("{:#?}", res_data);
+    return Some(addr.is_ipv6());
+}
 
-    let body = res_data.bytes().unwrap();
+/**
+ * Build the full announce URL. Announce URLs of private trackers often already carry
+ * a query string (passkey), in which case our parameters are appended to it.
+ */
+pub fn build_announce_url(
+    announce: &str,
+    info_hash: &[u8; 20],
+    config: &ClientConfig,
+    params: &AnnounceParams,
+) -> Result<String, String> {
+    let mut url = match Url::parse(announce) {
+        Ok(url) => url,
+        Err(e) => return Err(format!("invalid announce url {announce}: {e}")),
+    };
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("unsupported tracker scheme: {}", url.scheme()));
+    }
+
+    let mut query_params: Vec<(&str, String)> = vec![
+        ("info_hash", encode_binary(info_hash).into_owned()),
+        ("peer_id", encode_binary(&config.peer_id).into_owned()),
+        ("port", config.port.to_string()),
+        ("uploaded", params.uploaded.to_string()),
+        ("downloaded", params.downloaded.to_string()),
+        ("left", params.left.to_string()),
+        ("key", encode(&config.key).into_owned()),
+        (
+            "compact",
+            if config.compact { "1" } else { "0" }.to_string(),
+        ),
+    ];
+
+    if config.no_peer_id {
+        query_params.push(("no_peer_id", "1".to_string()));
+    }
+
+    if let Some(event) = params.event {
+        query_params.push(("event", event.as_str().to_string()));
+    }
+
+    if let Some(numwant) = config.numwant {
+        query_params.push(("numwant", numwant.to_string()));
+    }
+
+    if let Some(ip) = &config.ip {
+        query_params.push(("ip", encode(ip).into_owned()));
+    }
+
+    if let Some(ipv4) = params.local_ipv4 {
+        query_params.push(("ipv4", encode(&ipv4.to_string()).into_owned()));
+    }
+
+    if let Some(ipv6) = params.local_ipv6 {
+        query_params.push(("ipv6", encode(&ipv6.to_string()).into_owned()));
+    }
 
-    println!(
+    let mut query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{existing}&"),
+        _ => String::new(),
+    };
+
+    query.push_str(
+        query_params
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<String>>()
+            .join("&")
+            .as_str(),
+    );
+
+    //values are already percent encoded so they go through untouched
+    url.set_query(Some(query.as_str()));
+
+    return Ok(url.to_string());
+}
+
+/**
+ * HTTP client used for announces, with the configured timeouts so a dead tracker cannot block us
+ */
+pub fn http_client(config: &ClientConfig) -> Result<Client, String> {
+    Client::builder()
+        .connect_timeout(config.tracker_connect_timeout)
+        .timeout(config.tracker_timeout)
+        .build()
+        .map_err(|e| format!("cannot build http client: {e}"))
+}
+
+pub fn announce(
+    client: &Client,
+    announce: &str,
+    torrent_file: &TorrentFile,
+    config: &ClientConfig,
+    params: &AnnounceParams,
+) -> Result<TrackerData, String> {
+    let req = build_announce_url(announce, &torrent_file.info_hash, config, params)?;
+
+    debug!("announcing: {req}");
+
+    let res_data = match client.get(req).send() {
+        Ok(res) => res,
+        Err(e) => return Err(format!("server answered with: {:?}", e)),
+    };
+
+    debug!("{:#?}", res_data);
+
+    if !res_data.status().is_success() {
+        return Err(format!(
+            "tracker answered with status {}",
+            res_data.status()
+        ));
+    }
+
+    let body = match res_data.bytes() {
+        Ok(body) => body,
+        Err(e) => return Err(format!("cannot read tracker response: {e}")),
+    };
+
+    debug!(
         "{:?}",
         String::from_utf8_lossy(body.clone().to_vec().as_slice())
     );
 
-    let tracker_data = TrackerData::from(Vec::from(body));
+    let tracker_data =
+        TrackerData::parse(&body).map_err(|e| format!("invalid tracker response: {e}"))?;
+
+    if let Some(failure_reason) = &tracker_data.failure_reason {
+        return Err(format!("tracker failure: {failure_reason}"));
+    }
+
+    if let Some(warning_message) = &tracker_data.warning_message {
+        warn!("tracker warning for {announce}: {warning_message}");
+    }
 
     return Ok(tracker_data);
 }
 
-pub fn get_handshake_data(info_hash: &[u8; 20]) -> [u8; 68] {
-    /*
-    * Bytes	Content
-        1	Protocol string length = 19
-        19	BitTorrent protocol (literal ASCII)
-        8	Reserved (all zeros: 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00)
-        20	Info hash (your torrent’s 20-byte info hash)
-        20	Peer ID (your 20-byte peer id)
-    */
-    let mut buf = [0u8; 68];
-
-    buf[0] = 19;
-    buf[1..20].copy_from_slice(b"BitTorrent protocol");
-    //20 => 28 is alrweady 0;
-    buf[28..48].copy_from_slice(info_hash);
-    buf[48..68].copy_from_slice(client::PEER_ID.as_ref());
-    return buf;
+/**
+ * Connection to the peer as a task of the runtime
+ */
+pub fn spawn_connection(peer: SocketAddr, context: TorrentContext) -> JoinHandle<()> {
+    runtime::spawn(async move {
+        let mut connection_handler = ConnectionHandler::new(peer, &context);
+        if let Err(e) = connection_handler.connect_async().await {
+            debug!("[{peer}] connection failed: {e}");
+        }
+        drop(connection_handler);
+
+        context.peer_pool.lock().unwrap().release(peer);
+    })
 }
 
-pub fn get_connections_handler<'a>(
-    torrent_file: TorrentFile,
-    tracker_data: &'a TrackerData,
-    file_handler: FileHandler,
-    max_peers: Option<usize>,
-) -> Vec<JoinHandle<()>> {
+/**
+ * Keep up to max_peers connections alive with the peers found by the trackers (or any other source
+ * feeding the pool). Returns once the download is complete and every connection is closed.
+ */
+pub fn run_connections(context: &TorrentContext, max_peers: Option<usize>) {
     let mut connections_handles = Vec::<JoinHandle<()>>::new();
 
-    let max_peers = match max_peers {
-        Some(max) => max,
-        None => 5,
-    };
+    let max_peers = max_peers.unwrap_or(5);
+
+    let choker_stopped = Arc::new(AtomicBool::new(false));
+    let choker_handle = choker::spawn_choker(context.clone(), Arc::clone(&choker_stopped));
+
+    loop {
+        connections_handles.retain(|handle| !handle.is_finished());
 
-    let shared_torrent_file = Arc::new(torrent_file);
-    let shared_file_handler = Arc::new(Mutex::new(file_handler));
+        //incoming connections share the same limit
+        while context.peer_pool.lock().unwrap().connected_len() < max_peers {
+            let peer = match context.peer_pool.lock().unwrap().next_peer() {
+                Some(peer) => peer,
+                None => break,
+            };
+
+            connections_handles.push(spawn_connection(peer, context.clone()));
+
+            debug!(
+                "Spawned connection to {peer} [{}/{}]",
+                connections_handles.len(),
+                max_peers
+            )
+        }
 
-    for (peer_index, peer_str) in tracker_data.peers_str.iter().enumerate() {
-        if peer_index >= max_peers {
+        if context.is_download_complete() && connections_handles.is_empty() {
             break;
         }
 
-        let torrent_clone = Arc::clone(&shared_torrent_file);
-        let file_handler_clone = Arc::clone(&shared_file_handler);
-
-        let owned_peer_str = peer_str.clone();
-        connections_handles.push(thread::spawn(move || {
-            let mut connection_handler = ConnectionHandler::new(
-                //"127.0.0.1:57496",
-                owned_peer_str.as_str(),
-                torrent_clone,
-                file_handler_clone,
-            );
-            connection_handler.connect();
-        }));
-
-        debug!(
-            "Spawned thread for {peer_str} [{}/{}]",
-            connections_handles.len(),
-            max_peers
-        )
+        thread::sleep(CONNECTIONS_POLL_INTERVAL);
     }
 
-    return connections_handles;
+    choker_stopped.store(true, Ordering::Relaxed);
+    choker_handle.join().unwrap();
 }
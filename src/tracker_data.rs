@@ -1,145 +1,149 @@
-use std::io::{BufRead, BufReader, Cursor};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
-use crate::bencode::{BencodeKey, BencodeParsable, decode_dictionary};
+use log::warn;
 
-#[derive(Debug)]
+use crate::bencode::{BencodeValue, decode_value};
+
+#[derive(Debug, Default)]
 pub struct TrackerData {
     pub interval: usize,
     // pub complete: Option<u64>,
     // pub incomplete: Option<u64>,
-    pub peers: Vec<u8>,
+    pub peers: Vec<SocketAddr>,
 
-    //converted ip:port format
-    pub peers_str: Vec<String>,
-    // pub failure_reason: Option<String>,
-    // pub warning_message: Option<String>,
-    // pub min_interval: Option<u64>,
+    //only filled when the tracker answers with the dictionary model
+    pub peer_ids: HashMap<SocketAddr, [u8; 20]>,
+    pub failure_reason: Option<String>,
+    pub warning_message: Option<String>,
+    pub min_interval: Option<usize>,
     // pub tracker_id: Option<String>,
 }
 
-impl BencodeKey for TrackerDataKeys {
-    fn is_unsupported_key(&self) -> bool {
-        *self == TrackerDataKeys::UnsupportedKey
-    }
-
-    fn is_integer_field(&self) -> bool {
-        match self {
-            Self::Interval => true,
-            _ => false,
+impl TrackerData {
+    /**
+     * Tracker responses come from the network (or an HTML error page), nothing is trusted
+     */
+    pub fn parse(body: &[u8]) -> Result<Self, String> {
+        let (value, _) = decode_value(body)?;
+        if value.as_dictionary().is_none() {
+            return Err(String::from(
+                "tracker response is not a bencoded dictionary",
+            ));
         }
-    }
 
-    fn is_binary_field(&self) -> bool {
-        match self {
-            Self::Peers => true,
-            _ => false,
+        let mut tracker_data = TrackerData {
+            interval: optional_usize(&value, "interval")?.unwrap_or(0),
+            min_interval: optional_usize(&value, "min interval")?,
+            failure_reason: optional_string(&value, "failure reason"),
+            warning_message: optional_string(&value, "warning message"),
+            ..TrackerData::default()
+        };
+
+        match value.get("peers") {
+            Some(BencodeValue::Bytes(peers)) => tracker_data.on_compact_peers(peers),
+            Some(BencodeValue::List(peers)) => {
+                for peer in peers {
+                    tracker_data.on_dictionary_peer(peer);
+                }
+            }
+            Some(_) => return Err(String::from("invalid peers in tracker response")),
+            None => {}
         }
-    }
 
-    fn as_str(&self) -> &str {
-        match self {
-            TrackerDataKeys::Interval => "interval",
-            TrackerDataKeys::Peers => "peers",
-            TrackerDataKeys::UnsupportedKey => "unsupported-key",
+        if let Some(peers6) = value.get("peers6").and_then(|peers6| peers6.as_bytes()) {
+            tracker_data.on_compact_peers6(peers6);
         }
-    }
 
-    fn from_str(key: &str) -> Self {
-        match key {
-            "interval" => TrackerDataKeys::Interval,
-            "peers" => TrackerDataKeys::Peers,
-            _ => TrackerDataKeys::UnsupportedKey,
-        }
+        return Ok(tracker_data);
     }
-}
-
-impl From<Vec<u8>> for TrackerData {
-    fn from(source: Vec<u8>) -> Self {
-        let mut tracker_data = Self::default();
-        let mut buf_reader = BufReader::new(Cursor::new(source));
 
-        buf_reader.fill_buf().unwrap();
-        decode_dictionary(&mut tracker_data, &mut buf_reader);
+    fn on_compact_peers(&mut self, peers: &[u8]) {
+        //Parsing raw peers. 1 byte for each part of ip address + 2 bytes for port
+        self.peers.extend(peers.chunks_exact(6).map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes(chunk[4..6].try_into().unwrap());
 
-        return tracker_data;
+            SocketAddr::new(IpAddr::V4(ip), port)
+        }));
     }
-}
 
-impl BencodeParsable for TrackerData {
-    type Key = TrackerDataKeys;
-    type R = Cursor<Vec<u8>>;
+    fn on_compact_peers6(&mut self, peers: &[u8]) {
+        //BEP 7: 16 bytes of IPv6 address + 2 bytes for port
+        self.peers.extend(peers.chunks_exact(18).map(|chunk| {
+            let octets: [u8; 16] = chunk[0..16].try_into().unwrap();
+            let port = u16::from_be_bytes(chunk[16..18].try_into().unwrap());
 
-    fn key_from_str(s: &str) -> Self::Key {
-        TrackerDataKeys::from_str(s)
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)
+        }));
     }
 
-    fn on_integer(&mut self, key: Self::Key, value: usize) {
-        match key {
-            Self::Key::Interval => {
-                self.interval = value;
+    /**
+     * Single entry of a non-compact (dictionary model) peer list:
+     * d2:ip<ip or hostname>7:peer id20:<id>4:porti<port>ee
+     */
+    fn on_dictionary_peer(&mut self, peer: &BencodeValue) {
+        let ip = match peer.get("ip").and_then(|ip| ip.as_str()) {
+            Some(ip) => ip,
+            None => {
+                warn!("dropping peer without an ip");
+                return;
             }
-            _ => {}
-        }
-    }
-
-    fn on_string_or_bytes(&mut self, key: Self::Key, value: Vec<u8>) {
-        if key.is_binary_field() {
-            match key {
-                Self::Key::Peers => {
-                    self.peers = value;
-                    self.on_peers_updated();
-                }
-                _ => {}
+        };
+
+        let port = match peer
+            .get("port")
+            .and_then(|port| port.as_integer())
+            .and_then(|port| u16::try_from(port).ok())
+            .filter(|port| *port != 0)
+        {
+            Some(port) => port,
+            None => {
+                warn!("dropping peer {ip} without a valid port");
+                return;
+            }
+        };
+
+        //ip can either be an IPv4/IPv6 literal or a DNS name
+        let addr = match (ip, port).to_socket_addrs() {
+            Ok(mut addrs) => addrs.next(),
+            Err(e) => {
+                warn!("cannot resolve peer {}: {}", ip, e);
+                None
             }
+        };
+
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        let peer_id = peer
+            .get("peer id")
+            .and_then(|peer_id| peer_id.as_bytes())
+            .and_then(|peer_id| <[u8; 20]>::try_from(peer_id).ok());
+        if let Some(peer_id) = peer_id {
+            self.peer_ids.insert(addr, peer_id);
         }
-    }
-}
 
-impl TrackerData {
-    fn on_peers_updated(&mut self) {
-        self.peers_str = self
-            .peers
-            .chunks_exact(6)
-            .map(|chunk| {
-                //Parsing raw peers to string. 1 byte for each part of ip address + 2 bytes for port
-                let port = u16::from_be_bytes(chunk[4..6].try_into().unwrap());
-
-                format!(
-                    "{}.{}.{}.{}:{}",
-                    chunk[0], chunk[1], chunk[2], chunk[3], port
-                )
-            })
-            .collect();
+        self.peers.push(addr);
     }
 }
 
-impl Default for TrackerData {
-    fn default() -> Self {
-        TrackerData {
-            interval: 0,
-            // complete: None,
-            // incomplete: None,
-            peers: Vec::<u8>::new(),
-            peers_str: Vec::<String>::new(),
-            // failure_reason: None,
-            // warning_message: None,
-            // min_interval: None,
-            // tracker_id: None,
-        }
+fn optional_usize(value: &BencodeValue, key: &str) -> Result<Option<usize>, String> {
+    match value.get(key) {
+        None => Ok(None),
+        Some(integer) => integer
+            .as_integer()
+            .and_then(|integer| usize::try_from(integer).ok())
+            .map(Some)
+            .ok_or_else(|| format!("invalid {key} in tracker response")),
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum TrackerDataKeys {
-    Interval,
-    // Complete,
-    // Incomplete,
-    Peers,
-    // FailureReason,
-    // WarningMessages,
-    // MinInterval,
-    // TrackerId,
-    UnsupportedKey,
+fn optional_string(value: &BencodeValue, key: &str) -> Option<String> {
+    value
+        .get(key)
+        .and_then(|string| string.as_bytes())
+        .map(|string| String::from_utf8_lossy(string).into_owned())
 }
-
-impl TrackerDataKeys {}
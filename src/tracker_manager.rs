@@ -0,0 +1,370 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, info, warn};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use reqwest::blocking::Client;
+
+use crate::client::ClientConfig;
+use crate::file_handler::FileHandler;
+use crate::peer_pool::PeerPool;
+use crate::torrent_file::TorrentFile;
+use crate::tracker::{self, AnnounceEvent, AnnounceParams};
+
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const MIN_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+//the announce loop wakes up at least this often to notice a stop request
+const ANNOUNCE_LOOP_TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct TrackerStatus {
+    pub url: String,
+
+    //BEP 12 tier, 0 for the main announce url
+    pub tier: usize,
+    pub last_success: Option<SystemTime>,
+    pub last_error: Option<String>,
+    pub next_announce: Instant,
+
+    //amount of peers returned by the last successful announce
+    pub peers_returned: usize,
+    pub consecutive_failures: u32,
+
+    //false for trackers we cannot talk to (unsupported scheme)
+    pub enabled: bool,
+
+    started_sent: bool,
+    completed_sent: bool,
+}
+
+impl TrackerStatus {
+    fn new(url: String, tier: usize) -> Self {
+        let unsupported = !(url.starts_with("http://") || url.starts_with("https://"));
+
+        TrackerStatus {
+            last_error: if unsupported {
+                Some(String::from("unsupported tracker protocol"))
+            } else {
+                None
+            },
+            url,
+            tier,
+            last_success: None,
+            next_announce: Instant::now(),
+            peers_returned: 0,
+            consecutive_failures: 0,
+            enabled: !unsupported,
+            started_sent: false,
+            completed_sent: false,
+        }
+    }
+
+    fn on_success(&mut self, interval: Duration, peers_returned: usize) {
+        self.last_success = Some(SystemTime::now());
+        self.last_error = None;
+        self.consecutive_failures = 0;
+        self.peers_returned = peers_returned;
+        self.next_announce = Instant::now() + interval;
+    }
+
+    fn on_failure(&mut self, error: String) {
+        self.consecutive_failures += 1;
+        let backoff = backoff_delay(self.consecutive_failures);
+
+        warn!(
+            "announce to {} failed ({} in a row), retrying in {:?}: {error}",
+            self.url, self.consecutive_failures, backoff
+        );
+
+        self.last_error = Some(error);
+        self.next_announce = Instant::now() + backoff;
+    }
+}
+
+/**
+ * Exponential backoff starting at MIN_BACKOFF and capped at MAX_BACKOFF,
+ * with +/- 25% of jitter so clients do not hammer a recovering tracker at the same time
+ */
+pub fn backoff_delay(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    let backoff = MIN_BACKOFF.saturating_mul(1 << exponent).min(MAX_BACKOFF);
+
+    return backoff.mul_f64(rand::rng().random_range(0.75..1.25));
+}
+
+/**
+ * Transfer totals sent along every announce
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransferStats {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+}
+
+/**
+ * Announces to the trackers of the torrent with the failover of BEP 12, a single tracker
+ * answers each announce. A failing tracker is retried with backoff.
+ */
+pub struct TrackerManager {
+    torrent_file: Arc<TorrentFile>,
+    config: Arc<ClientConfig>,
+    http_client: Client,
+    //sorted by tier, the tracker that answered last first in its tier
+    trackers: Mutex<Vec<TrackerStatus>>,
+    next_cycle: Mutex<Instant>,
+    stopped: AtomicBool,
+}
+
+impl TrackerManager {
+    pub fn new(torrent_file: Arc<TorrentFile>, config: Arc<ClientConfig>) -> Result<Self, String> {
+        let http_client = tracker::http_client(&config)?;
+
+        let mut trackers = Vec::new();
+
+        match &torrent_file.announce_list {
+            Some(tiers) if !tiers.is_empty() => {
+                for (tier_index, tier) in tiers.iter().enumerate() {
+                    //BEP 12: trackers of a tier are shuffled once
+                    let mut tier = tier.clone();
+                    tier.shuffle(&mut rand::rng());
+
+                    for url in tier {
+                        if !trackers.iter().any(|t: &TrackerStatus| t.url == url) {
+                            trackers.push(TrackerStatus::new(url, tier_index));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if !torrent_file.announce.is_empty()
+            && !trackers.iter().any(|t| t.url == torrent_file.announce)
+        {
+            trackers.insert(0, TrackerStatus::new(torrent_file.announce.clone(), 0));
+        }
+
+        Ok(TrackerManager {
+            torrent_file,
+            config,
+            http_client,
+            trackers: Mutex::new(trackers),
+            next_cycle: Mutex::new(Instant::now()),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /**
+     * Snapshot of every tracker state
+     */
+    pub fn statuses(&self) -> Vec<TrackerStatus> {
+        self.trackers.lock().unwrap().clone()
+    }
+
+    /**
+     * When the next announce is due, None without any tracker we can talk to
+     */
+    pub fn next_announce(&self) -> Option<Instant> {
+        if !self.trackers.lock().unwrap().iter().any(|t| t.enabled) {
+            return None;
+        }
+
+        return Some(*self.next_cycle.lock().unwrap());
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /**
+     * BEP 12: once the announce is due, the tiers are tried in order and the trackers of a tier one
+     * after the other, until one answers. That tracker moves to the front of its tier so it is
+     * tried first next time. Trackers backing off from a failure are skipped.
+     * Returns the peers of the tracker that answered.
+     */
+    pub fn announce_due(&self, stats: TransferStats) -> Vec<SocketAddr> {
+        let now = Instant::now();
+
+        let candidates: Vec<TrackerStatus> = {
+            let trackers = self.trackers.lock().unwrap();
+
+            //completion is reported right away instead of waiting for the next interval
+            let completed_pending = stats.left == 0
+                && trackers.iter().any(|t| {
+                    t.enabled && t.started_sent && !t.completed_sent && t.consecutive_failures == 0
+                });
+            if *self.next_cycle.lock().unwrap() > now && !completed_pending {
+                return Vec::new();
+            }
+
+            //the list is kept sorted by tier, so this is the failover order
+            trackers
+                .iter()
+                .filter(|t| {
+                    let backing_off = t.next_announce > now
+                        && !(completed_pending && t.consecutive_failures == 0);
+                    t.enabled && !backing_off
+                })
+                .cloned()
+                .collect()
+        };
+
+        //the lock is not held during requests so statuses stay readable
+        for status in candidates {
+            let event = if !status.started_sent {
+                Some(AnnounceEvent::Started)
+            } else if stats.left == 0 && !status.completed_sent {
+                Some(AnnounceEvent::Completed)
+            } else {
+                None
+            };
+
+            let result = self.announce_one(&status.url, stats, event);
+
+            let mut trackers = self.trackers.lock().unwrap();
+            let index = match trackers.iter().position(|t| t.url == status.url) {
+                Some(index) => index,
+                None => continue,
+            };
+            let tracker_status = &mut trackers[index];
+
+            match result {
+                Ok((interval, tracker_peers)) => {
+                    info!(
+                        "{} returned {} peers, next announce in {:?}",
+                        tracker_status.url,
+                        tracker_peers.len(),
+                        interval
+                    );
+
+                    match event {
+                        Some(AnnounceEvent::Started) => {
+                            tracker_status.started_sent = true;
+                            //nothing to report as completed if we started as a seeder
+                            tracker_status.completed_sent = stats.left == 0;
+                        }
+                        Some(AnnounceEvent::Completed) => tracker_status.completed_sent = true,
+                        _ => {}
+                    }
+
+                    tracker_status.on_success(interval, tracker_peers.len());
+                    *self.next_cycle.lock().unwrap() = tracker_status.next_announce;
+
+                    let tier = tracker_status.tier;
+                    let tier_start = trackers.iter().position(|t| t.tier == tier).unwrap();
+                    let tracker_status = trackers.remove(index);
+                    trackers.insert(tier_start, tracker_status);
+
+                    return tracker_peers;
+                }
+                Err(e) => tracker_status.on_failure(e),
+            }
+        }
+
+        //every tracker failed, try again when the first one is done backing off
+        let trackers = self.trackers.lock().unwrap();
+        if let Some(retry_at) = trackers
+            .iter()
+            .filter(|t| t.enabled)
+            .map(|t| t.next_announce)
+            .min()
+        {
+            *self.next_cycle.lock().unwrap() = retry_at;
+        }
+
+        return Vec::new();
+    }
+
+    fn announce_one(
+        &self,
+        url: &str,
+        stats: TransferStats,
+        event: Option<AnnounceEvent>,
+    ) -> Result<(Duration, Vec<SocketAddr>), String> {
+        let params = AnnounceParams::new(url, stats.uploaded, stats.downloaded, stats.left, event);
+
+        let tracker_data = tracker::announce(
+            &self.http_client,
+            url,
+            &self.torrent_file,
+            &self.config,
+            &params,
+        )?;
+
+        let interval = if tracker_data.interval == 0 {
+            DEFAULT_ANNOUNCE_INTERVAL
+        } else {
+            let min_interval = tracker_data.min_interval.unwrap_or(0);
+            Duration::from_secs(tracker_data.interval.max(min_interval) as u64)
+        };
+
+        return Ok((interval, tracker_data.peers));
+    }
+
+    /**
+     * Send the stopped event to the trackers we started with, errors are only logged
+     */
+    pub fn stop(&self, stats: TransferStats) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        let started_urls: Vec<String> = self
+            .trackers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| t.started_sent)
+            .map(|t| t.url.clone())
+            .collect();
+
+        for url in started_urls {
+            if let Err(e) = self.announce_one(&url, stats, Some(AnnounceEvent::Stopped)) {
+                debug!("stopped announce to {url} failed: {e}");
+            }
+        }
+    }
+}
+
+/**
+ * Periodically announce in the background and feed the returned peers to the pool.
+ * Downloads keep going with the already known peers while every tracker is failing.
+ */
+pub fn spawn_announce_loop(
+    manager: Arc<TrackerManager>,
+    peer_pool: Arc<Mutex<PeerPool>>,
+    file_handler: Arc<Mutex<FileHandler>>,
+) -> JoinHandle<()> {
+    let initial_written_bytes = file_handler.lock().unwrap().written_bytes;
+    let total_length = manager.torrent_file.info.length;
+
+    thread::spawn(move || {
+        while !manager.is_stopped() {
+            let stats = {
+                let file_handler = file_handler.lock().unwrap();
+                TransferStats {
+                    uploaded: file_handler.uploaded_bytes,
+                    downloaded: file_handler.written_bytes - initial_written_bytes,
+                    left: total_length - file_handler.written_bytes,
+                }
+            };
+
+            let peers = manager.announce_due(stats);
+            peer_pool.lock().unwrap().add_peers(peers);
+
+            let sleep_duration = match manager.next_announce() {
+                Some(next) => next
+                    .saturating_duration_since(Instant::now())
+                    .min(ANNOUNCE_LOOP_TICK),
+                None => ANNOUNCE_LOOP_TICK,
+            };
+
+            thread::sleep(sleep_duration);
+        }
+    })
+}
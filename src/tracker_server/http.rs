@@ -0,0 +1,332 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, warn};
+use urlencoding::decode_binary;
+
+use super::TrackerServerConfig;
+use super::swarm::{AnnounceRequest, Swarm, SwarmPeer, Swarms};
+use crate::bencode::BencodeValue;
+use crate::network::canonical_peer_addr;
+use crate::tracker::AnnounceEvent;
+
+const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct HttpRequest {
+    path: String,
+    query: Vec<(String, Vec<u8>)>,
+}
+
+impl HttpRequest {
+    fn param(&self, name: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn params(&self, name: &str) -> Vec<&[u8]> {
+        self.query
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_slice())
+            .collect()
+    }
+
+    fn param_str(&self, name: &str) -> Option<String> {
+        self.param(name)
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+
+    fn param_parse<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.param_str(name)?.parse::<T>().ok()
+    }
+
+    fn param_flag(&self, name: &str) -> bool {
+        self.param_str(name).as_deref() == Some("1")
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, String> {
+    stream
+        .set_read_timeout(Some(REQUEST_READ_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).map_err(|e| e.to_string())?;
+
+        if read == 0 {
+            return Err(String::from("connection closed before end of request"));
+        }
+
+        head.extend_from_slice(&buf[..read]);
+
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(String::from("request head too large"));
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+
+    if parts.next() != Some("GET") {
+        return Err(format!("unsupported request: {request_line}"));
+    }
+
+    let target = parts.next().unwrap_or("/");
+    let (path, raw_query) = target.split_once('?').unwrap_or((target, ""));
+
+    let query = raw_query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                key.to_string(),
+                decode_binary(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+
+    return Ok(HttpRequest {
+        path: path.to_string(),
+        query,
+    });
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+
+    let res = stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .and_then(|_| stream.flush());
+
+    if let Err(e) = res {
+        debug!("cannot write tracker response: {e}");
+    }
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    BencodeValue::dictionary(vec![("failure reason", BencodeValue::from(reason))]).encode()
+}
+
+pub(super) fn handle_connection(
+    mut stream: TcpStream,
+    swarms: &Mutex<Swarms>,
+    config: &TrackerServerConfig,
+) {
+    let remote_addr = match stream.peer_addr() {
+        Ok(addr) => canonical_peer_addr(addr),
+        Err(_) => return,
+    };
+
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("[{remote_addr}] bad tracker request: {e}");
+            write_response(&mut stream, "400 Bad Request", &failure(&e));
+            return;
+        }
+    };
+
+    let body = if request.path.ends_with("/announce") {
+        handle_announce(&request, remote_addr, swarms, config)
+    } else if request.path.ends_with("/scrape") {
+        handle_scrape(&request, swarms)
+    } else {
+        write_response(&mut stream, "404 Not Found", &failure("not found"));
+        return;
+    };
+
+    let body = match body {
+        Ok(body) => body,
+        //trackers report errors with a 200 and a failure reason
+        Err(e) => failure(&e),
+    };
+
+    write_response(&mut stream, "200 OK", &body);
+}
+
+fn parse_hash(value: Option<&[u8]>, name: &str) -> Result<[u8; 20], String> {
+    match value {
+        Some(value) => value
+            .try_into()
+            .map_err(|_| format!("{name} must be 20 bytes long")),
+        None => Err(format!("missing {name}")),
+    }
+}
+
+fn handle_announce(
+    request: &HttpRequest,
+    remote_addr: SocketAddr,
+    swarms: &Mutex<Swarms>,
+    config: &TrackerServerConfig,
+) -> Result<Vec<u8>, String> {
+    let info_hash = parse_hash(request.param("info_hash"), "info_hash")?;
+    let peer_id = parse_hash(request.param("peer_id"), "peer_id")?;
+    let port: u16 = request
+        .param_parse("port")
+        .ok_or("missing or invalid port")?;
+    //without it the peer would be counted as a seeder
+    let left: u64 = request
+        .param_parse("left")
+        .ok_or("missing or invalid left")?;
+
+    //anyone could register third party addresses, the announced ip is opt-in
+    //and only trusted when it is an address, hostnames are ignored
+    let ip = match request.param_parse::<IpAddr>("ip") {
+        Some(ip) if config.trust_announced_ip => ip,
+        _ => remote_addr.ip(),
+    };
+
+    let event = match request.param_str("event").as_deref() {
+        Some("started") => Some(AnnounceEvent::Started),
+        Some("completed") => Some(AnnounceEvent::Completed),
+        Some("stopped") => Some(AnnounceEvent::Stopped),
+        _ => None,
+    };
+
+    let announce_request = AnnounceRequest {
+        info_hash,
+        peer_id,
+        addr: canonical_peer_addr(SocketAddr::new(ip, port)),
+        uploaded: request.param_parse("uploaded").unwrap_or(0),
+        downloaded: request.param_parse("downloaded").unwrap_or(0),
+        left,
+        event,
+        numwant: request.param_parse("numwant"),
+    };
+
+    let mut swarms = swarms.lock().unwrap();
+    let swarm = swarms.announce(&announce_request);
+
+    let numwant = announce_request
+        .numwant
+        .unwrap_or(config.default_numwant)
+        .min(config.max_numwant);
+    let peers = swarm.select_peers(&peer_id, numwant);
+
+    let reply = if request.param_str("compact").as_deref() == Some("0") {
+        non_compact_reply(swarm, &peers, request.param_flag("no_peer_id"), config)
+    } else {
+        compact_reply(swarm, &peers, config)
+    };
+
+    return Ok(reply.encode());
+}
+
+fn reply_entries(swarm: &Swarm, config: &TrackerServerConfig) -> Vec<(&'static str, BencodeValue)> {
+    vec![
+        ("complete", BencodeValue::from(swarm.seeders())),
+        ("incomplete", BencodeValue::from(swarm.leechers())),
+        (
+            "interval",
+            BencodeValue::from(config.announce_interval.as_secs() as usize),
+        ),
+        (
+            "min interval",
+            BencodeValue::from(config.min_announce_interval.as_secs() as usize),
+        ),
+    ]
+}
+
+fn compact_reply(swarm: &Swarm, peers: &[SwarmPeer], config: &TrackerServerConfig) -> BencodeValue {
+    let mut peers4 = Vec::new();
+    let mut peers6 = Vec::new();
+
+    for peer in peers {
+        match peer.addr.ip() {
+            IpAddr::V4(ip) => {
+                peers4.extend_from_slice(&ip.octets());
+                peers4.extend_from_slice(&peer.addr.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                peers6.extend_from_slice(&ip.octets());
+                peers6.extend_from_slice(&peer.addr.port().to_be_bytes());
+            }
+        }
+    }
+
+    let mut entries = reply_entries(swarm, config);
+    entries.push(("peers", BencodeValue::from(peers4)));
+
+    if !peers6.is_empty() {
+        entries.push(("peers6", BencodeValue::from(peers6)));
+    }
+
+    return BencodeValue::dictionary(entries);
+}
+
+fn non_compact_reply(
+    swarm: &Swarm,
+    peers: &[SwarmPeer],
+    no_peer_id: bool,
+    config: &TrackerServerConfig,
+) -> BencodeValue {
+    let peer_list = peers
+        .iter()
+        .map(|peer| {
+            let mut peer_entries = vec![
+                ("ip", BencodeValue::from(peer.addr.ip().to_string())),
+                ("port", BencodeValue::from(peer.addr.port() as usize)),
+            ];
+
+            if !no_peer_id {
+                peer_entries.push(("peer id", BencodeValue::from(peer.peer_id.as_slice())));
+            }
+
+            BencodeValue::dictionary(peer_entries)
+        })
+        .collect();
+
+    let mut entries = reply_entries(swarm, config);
+    entries.push(("peers", BencodeValue::List(peer_list)));
+
+    return BencodeValue::dictionary(entries);
+}
+
+fn handle_scrape(request: &HttpRequest, swarms: &Mutex<Swarms>) -> Result<Vec<u8>, String> {
+    let info_hashes = request
+        .params("info_hash")
+        .into_iter()
+        .map(|value| parse_hash(Some(value), "info_hash"))
+        .collect::<Result<Vec<[u8; 20]>, String>>()?;
+
+    if info_hashes.is_empty() {
+        return Err(String::from("full scrape is not supported"));
+    }
+
+    let swarms = swarms.lock().unwrap();
+
+    let files = info_hashes
+        .iter()
+        .map(|info_hash| {
+            let (complete, incomplete, downloaded) = match swarms.get(info_hash) {
+                Some(swarm) => (swarm.seeders(), swarm.leechers(), swarm.downloaded),
+                None => (0, 0, 0),
+            };
+
+            (
+                info_hash.to_vec(),
+                BencodeValue::dictionary(vec![
+                    ("complete", BencodeValue::from(complete)),
+                    ("downloaded", BencodeValue::from(downloaded)),
+                    ("incomplete", BencodeValue::from(incomplete)),
+                ]),
+            )
+        })
+        .collect();
+
+    return Ok(BencodeValue::dictionary(vec![("files", BencodeValue::Dictionary(files))]).encode());
+}
@@ -0,0 +1,216 @@
+mod http;
+mod swarm;
+pub mod udp;
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+pub use swarm::{Swarm, SwarmPeer, Swarms};
+
+#[derive(Debug, Clone)]
+pub struct TrackerServerConfig {
+    //HTTP announce/scrape, disabled when None
+    pub http_addr: Option<SocketAddr>,
+
+    //BEP 15 UDP tracker, disabled when None
+    pub udp_addr: Option<SocketAddr>,
+
+    pub announce_interval: Duration,
+    pub min_announce_interval: Duration,
+
+    //peers that did not announce for this long are dropped from their swarm
+    pub peer_timeout: Duration,
+
+    pub default_numwant: usize,
+    pub max_numwant: usize,
+
+    //use the address announced by the client (ip parameter, IP field of UDP announces) instead of
+    //the source address, only for trackers behind a proxy or on a trusted network
+    pub trust_announced_ip: bool,
+
+    //HTTP connections handled at once, the ones over the limit are closed right away
+    pub max_http_connections: usize,
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> Self {
+        TrackerServerConfig {
+            http_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            udp_addr: None,
+            announce_interval: Duration::from_secs(30 * 60),
+            min_announce_interval: Duration::from_secs(60),
+            peer_timeout: Duration::from_secs(2 * 30 * 60 + 60),
+            default_numwant: 50,
+            max_numwant: 200,
+            trust_announced_ip: false,
+            max_http_connections: 64,
+        }
+    }
+}
+
+/**
+ * In memory BitTorrent tracker, each enabled protocol is served from its own thread
+ * and HTTP requests are handled on a thread per connection, up to max_http_connections.
+ */
+pub struct TrackerServer {
+    swarms: Arc<Mutex<Swarms>>,
+    stopped: Arc<AtomicBool>,
+    http_addr: Option<SocketAddr>,
+    udp_addr: Option<SocketAddr>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl TrackerServer {
+    pub fn start(config: TrackerServerConfig) -> io::Result<Self> {
+        let config = Arc::new(config);
+        let swarms = Arc::new(Mutex::new(Swarms::new(config.peer_timeout)));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut handles = Vec::new();
+
+        let http_addr = match config.http_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                let local_addr = listener.local_addr()?;
+                info!("tracker listening for HTTP on {local_addr}");
+
+                let swarms = Arc::clone(&swarms);
+                let stopped = Arc::clone(&stopped);
+                let config = Arc::clone(&config);
+                handles.push(thread::spawn(move || {
+                    run_http(listener, swarms, config, stopped)
+                }));
+
+                Some(local_addr)
+            }
+            None => None,
+        };
+
+        let udp_addr = match config.udp_addr {
+            Some(addr) => {
+                let socket = UdpSocket::bind(addr)?;
+                let local_addr = socket.local_addr()?;
+                info!("tracker listening for UDP on {local_addr}");
+
+                let swarms = Arc::clone(&swarms);
+                let stopped = Arc::clone(&stopped);
+                let config = Arc::clone(&config);
+                handles.push(thread::spawn(move || {
+                    udp::run(socket, &swarms, &config, &stopped)
+                }));
+
+                Some(local_addr)
+            }
+            None => None,
+        };
+
+        {
+            let swarms = Arc::clone(&swarms);
+            let stopped = Arc::clone(&stopped);
+            handles.push(thread::spawn(move || run_peer_expiry(swarms, stopped)));
+        }
+
+        Ok(TrackerServer {
+            swarms,
+            stopped,
+            http_addr,
+            udp_addr,
+            handles,
+        })
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_addr
+    }
+
+    pub fn swarms(&self) -> Arc<Mutex<Swarms>> {
+        Arc::clone(&self.swarms)
+    }
+
+    /**
+     * Block until the server is stopped
+     */
+    pub fn wait(mut self) {
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        //wake up the blocking accept
+        if let Some(addr) = self.http_addr {
+            let _ = TcpStream::connect(addr);
+        }
+
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run_http(
+    listener: TcpListener,
+    swarms: Arc<Mutex<Swarms>>,
+    config: Arc<TrackerServerConfig>,
+    stopped: Arc<AtomicBool>,
+) {
+    let active_connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("tracker cannot accept connection: {e}");
+                continue;
+            }
+        };
+
+        if active_connections.fetch_add(1, Ordering::Relaxed) >= config.max_http_connections {
+            active_connections.fetch_sub(1, Ordering::Relaxed);
+            debug!(
+                "tracker busy, closing connection from {:?}",
+                stream.peer_addr()
+            );
+            continue;
+        }
+
+        let swarms = Arc::clone(&swarms);
+        let config = Arc::clone(&config);
+        let active_connections = Arc::clone(&active_connections);
+        thread::spawn(move || {
+            http::handle_connection(stream, &swarms, &config);
+            active_connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn run_peer_expiry(swarms: Arc<Mutex<Swarms>>, stopped: Arc<AtomicBool>) {
+    let mut elapsed = Duration::ZERO;
+
+    while !stopped.load(Ordering::Relaxed) {
+        thread::sleep(EXPIRY_CHECK_INTERVAL);
+        elapsed += EXPIRY_CHECK_INTERVAL;
+
+        if elapsed >= Duration::from_secs(60) {
+            swarms.lock().unwrap().expire_peers();
+            elapsed = Duration::ZERO;
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rand::seq::IteratorRandom;
+
+use crate::tracker::AnnounceEvent;
+
+#[derive(Debug, Clone)]
+pub struct SwarmPeer {
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub last_seen: Instant,
+}
+
+impl SwarmPeer {
+    pub fn is_seeder(&self) -> bool {
+        self.left == 0
+    }
+}
+
+/**
+ * Peers of a single torrent, keyed by peer id
+ */
+#[derive(Debug, Default)]
+pub struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+
+    //amount of completed events received
+    pub downloaded: usize,
+}
+
+impl Swarm {
+    pub fn seeders(&self) -> usize {
+        self.peers.values().filter(|p| p.is_seeder()).count()
+    }
+
+    pub fn leechers(&self) -> usize {
+        self.peers.len() - self.seeders()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /**
+     * Random selection of at most numwant peers, without the requesting one
+     */
+    pub fn select_peers(&self, requester: &[u8; 20], numwant: usize) -> Vec<SwarmPeer> {
+        self.peers
+            .values()
+            .filter(|p| &p.peer_id != requester)
+            .cloned()
+            .choose_multiple(&mut rand::rng(), numwant)
+    }
+
+    fn expire(&mut self, now: Instant, peer_timeout: Duration) {
+        self.peers
+            .retain(|_, p| now.saturating_duration_since(p.last_seen) < peer_timeout);
+    }
+}
+
+#[derive(Debug)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddr,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    pub numwant: Option<usize>,
+}
+
+/**
+ * Every torrent known by the tracker. Peers that did not announce within peer_timeout are dropped.
+ */
+#[derive(Debug)]
+pub struct Swarms {
+    swarms: HashMap<[u8; 20], Swarm>,
+    peer_timeout: Duration,
+}
+
+impl Swarms {
+    pub fn new(peer_timeout: Duration) -> Self {
+        Swarms {
+            swarms: HashMap::new(),
+            peer_timeout,
+        }
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<&Swarm> {
+        self.swarms.get(info_hash)
+    }
+
+    /**
+     * Update the swarm with the announcing peer, returns the swarm so a reply can be built
+     */
+    pub fn announce(&mut self, request: &AnnounceRequest) -> &Swarm {
+        let swarm = self.swarms.entry(request.info_hash).or_default();
+
+        match request.event {
+            Some(AnnounceEvent::Stopped) => {
+                swarm.peers.remove(&request.peer_id);
+            }
+            event => {
+                if event == Some(AnnounceEvent::Completed) {
+                    swarm.downloaded += 1;
+                }
+
+                swarm.peers.insert(
+                    request.peer_id,
+                    SwarmPeer {
+                        peer_id: request.peer_id,
+                        addr: request.addr,
+                        left: request.left,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        debug!(
+            "swarm {} has {} seeders and {} leechers",
+            hex::encode(request.info_hash),
+            swarm.seeders(),
+            swarm.leechers()
+        );
+
+        return swarm;
+    }
+
+    pub fn expire_peers(&mut self) {
+        let now = Instant::now();
+        let peer_timeout = self.peer_timeout;
+
+        self.swarms.values_mut().for_each(|swarm| {
+            swarm.expire(now, peer_timeout);
+        });
+        //anyone can announce random info hashes, only swarms with peers are kept
+        self.swarms.retain(|_, swarm| !swarm.is_empty());
+    }
+}
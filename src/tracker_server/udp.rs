@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use rand::Rng;
+
+use super::TrackerServerConfig;
+use super::swarm::{AnnounceRequest, Swarms};
+use crate::network::canonical_peer_addr;
+use crate::tracker::AnnounceEvent;
+
+/*
+ * BEP 15 UDP tracker protocol
+ * https://www.bittorrent.org/beps/bep_0015.html
+ */
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+//connection ids are valid for 2 minutes according to the BEP
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(120);
+const SOCKET_READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+//BEP 15 caps scrapes to about 74 torrents per request
+const MAX_SCRAPE_HASHES: usize = 74;
+
+struct UdpTracker<'a> {
+    swarms: &'a Mutex<Swarms>,
+    config: &'a TrackerServerConfig,
+    connection_ids: HashMap<u64, (SocketAddr, Instant)>,
+}
+
+pub(super) fn run(
+    socket: UdpSocket,
+    swarms: &Mutex<Swarms>,
+    config: &TrackerServerConfig,
+    stopped: &AtomicBool,
+) {
+    socket.set_read_timeout(Some(SOCKET_READ_TIMEOUT)).unwrap();
+
+    let mut tracker = UdpTracker {
+        swarms,
+        config,
+        connection_ids: HashMap::new(),
+    };
+
+    //big enough for a scrape with MAX_SCRAPE_HASHES hashes
+    let mut buf = [0u8; 2048];
+
+    while !stopped.load(Ordering::Relaxed) {
+        let (read, remote_addr) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue;
+            }
+            Err(e) => {
+                warn!("udp tracker socket error: {e}");
+                continue;
+            }
+        };
+
+        let response = match tracker.handle_packet(&buf[..read], remote_addr) {
+            Some(response) => response,
+            None => continue,
+        };
+
+        if let Err(e) = socket.send_to(&response, remote_addr) {
+            debug!("[{remote_addr}] cannot send udp tracker response: {e}");
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn error_response(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut response = Vec::with_capacity(8 + message.len());
+    response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+    response.extend_from_slice(&transaction_id.to_be_bytes());
+    response.extend_from_slice(message.as_bytes());
+
+    return response;
+}
+
+impl UdpTracker<'_> {
+    fn handle_packet(&mut self, data: &[u8], remote_addr: SocketAddr) -> Option<Vec<u8>> {
+        //every request starts with connection_id (8) + action (4) + transaction_id (4)
+        if data.len() < 16 {
+            return None;
+        }
+
+        let connection_id = read_u64(data, 0);
+        let action = read_u32(data, 8);
+        let transaction_id = read_u32(data, 12);
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            return Some(self.handle_connect(transaction_id, remote_addr));
+        }
+
+        if !self.is_valid_connection(connection_id, remote_addr) {
+            return Some(error_response(transaction_id, "invalid connection id"));
+        }
+
+        let response = match action {
+            ACTION_ANNOUNCE => self.handle_announce(data, transaction_id, remote_addr),
+            ACTION_SCRAPE => self.handle_scrape(data, transaction_id),
+            _ => Err(format!("unknown action {action}")),
+        };
+
+        return Some(response.unwrap_or_else(|e| error_response(transaction_id, &e)));
+    }
+
+    fn handle_connect(&mut self, transaction_id: u32, remote_addr: SocketAddr) -> Vec<u8> {
+        let now = Instant::now();
+        self.connection_ids
+            .retain(|_, (_, issued_at)| now.duration_since(*issued_at) < CONNECTION_ID_LIFETIME);
+
+        let connection_id = rand::rng().random::<u64>();
+        self.connection_ids
+            .insert(connection_id, (remote_addr, now));
+
+        let mut response = Vec::with_capacity(16);
+        response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        response.extend_from_slice(&transaction_id.to_be_bytes());
+        response.extend_from_slice(&connection_id.to_be_bytes());
+
+        return response;
+    }
+
+    fn is_valid_connection(&self, connection_id: u64, remote_addr: SocketAddr) -> bool {
+        match self.connection_ids.get(&connection_id) {
+            Some((addr, issued_at)) => {
+                addr.ip() == remote_addr.ip() && issued_at.elapsed() < CONNECTION_ID_LIFETIME
+            }
+            None => false,
+        }
+    }
+
+    fn handle_announce(
+        &self,
+        data: &[u8],
+        transaction_id: u32,
+        remote_addr: SocketAddr,
+    ) -> Result<Vec<u8>, String> {
+        /*
+         * Offset  Size
+         * 16      20   info_hash
+         * 36      20   peer_id
+         * 56      8    downloaded
+         * 64      8    left
+         * 72      8    uploaded
+         * 80      4    event (0: none, 1: completed, 2: started, 3: stopped)
+         * 84      4    IP address (0 = source address)
+         * 88      4    key
+         * 92      4    num_want (-1 = default)
+         * 96      2    port
+         */
+        if data.len() < 98 {
+            return Err(String::from("announce request too short"));
+        }
+
+        let event = match read_u32(data, 80) {
+            1 => Some(AnnounceEvent::Completed),
+            2 => Some(AnnounceEvent::Started),
+            3 => Some(AnnounceEvent::Stopped),
+            _ => None,
+        };
+
+        let remote_addr = canonical_peer_addr(remote_addr);
+        let announced_ip = read_u32(data, 84);
+        let ip = if self.config.trust_announced_ip && announced_ip != 0 && remote_addr.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::from(announced_ip))
+        } else {
+            remote_addr.ip()
+        };
+
+        let num_want = read_u32(data, 92) as i32;
+        let port = u16::from_be_bytes(data[96..98].try_into().unwrap());
+
+        let request = AnnounceRequest {
+            info_hash: data[16..36].try_into().unwrap(),
+            peer_id: data[36..56].try_into().unwrap(),
+            addr: SocketAddr::new(ip, port),
+            downloaded: read_u64(data, 56),
+            left: read_u64(data, 64),
+            uploaded: read_u64(data, 72),
+            event,
+            numwant: if num_want < 0 {
+                None
+            } else {
+                Some(num_want as usize)
+            },
+        };
+
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.announce(&request);
+
+        let numwant = request
+            .numwant
+            .unwrap_or(self.config.default_numwant)
+            .min(self.config.max_numwant);
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        response.extend_from_slice(&transaction_id.to_be_bytes());
+        response.extend_from_slice(&(self.config.announce_interval.as_secs() as u32).to_be_bytes());
+        response.extend_from_slice(&(swarm.leechers() as u32).to_be_bytes());
+        response.extend_from_slice(&(swarm.seeders() as u32).to_be_bytes());
+
+        //peers of the same family as the request, as the reply has no way to mix them
+        for peer in swarm.select_peers(&request.peer_id, numwant) {
+            match (peer.addr.ip(), remote_addr.ip()) {
+                (IpAddr::V4(peer_ip), IpAddr::V4(_)) => {
+                    response.extend_from_slice(&peer_ip.octets());
+                }
+                (IpAddr::V6(peer_ip), IpAddr::V6(_)) => {
+                    response.extend_from_slice(&peer_ip.octets());
+                }
+                _ => continue,
+            }
+            response.extend_from_slice(&peer.addr.port().to_be_bytes());
+        }
+
+        return Ok(response);
+    }
+
+    fn handle_scrape(&self, data: &[u8], transaction_id: u32) -> Result<Vec<u8>, String> {
+        let info_hashes: Vec<[u8; 20]> = data[16..]
+            .chunks_exact(20)
+            .take(MAX_SCRAPE_HASHES)
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect();
+
+        if info_hashes.is_empty() {
+            return Err(String::from("no info hash to scrape"));
+        }
+
+        let swarms = self.swarms.lock().unwrap();
+
+        let mut response = Vec::with_capacity(8 + info_hashes.len() * 12);
+        response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        response.extend_from_slice(&transaction_id.to_be_bytes());
+
+        for info_hash in info_hashes {
+            let (seeders, completed, leechers) = match swarms.get(&info_hash) {
+                Some(swarm) => (swarm.seeders(), swarm.downloaded, swarm.leechers()),
+                None => (0, 0, 0),
+            };
+
+            response.extend_from_slice(&(seeders as u32).to_be_bytes());
+            response.extend_from_slice(&(completed as u32).to_be_bytes());
+            response.extend_from_slice(&(leechers as u32).to_be_bytes());
+        }
+
+        return Ok(response);
+    }
+}
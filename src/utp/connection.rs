@@ -0,0 +1,674 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use super::ledbat::Ledbat;
+use super::packet::{HEADER_SIZE, Packet, PacketType, seq_after};
+
+//data accepted from the application but not acknowledged yet
+pub const MAX_SEND_BUFFER: usize = 1024 * 1024;
+
+//received data not read yet, the advertised window is what is left of it
+pub const MAX_RECEIVE_BUFFER: usize = 1024 * 1024;
+
+//packets further ahead than this are dropped instead of kept for reordering
+const MAX_REORDER_DISTANCE: u16 = 1024;
+
+//consecutive timeouts before the peer is considered gone
+const MAX_TIMEOUTS: u32 = 6;
+
+//duplicate acks (or packets selectively acked past a hole) meaning a packet was lost
+const DUPLICATE_ACKS_THRESHOLD: usize = 3;
+
+//IP + UDP headers, and the most the selective ack extension adds to the uTP header
+const IPV4_OVERHEAD: usize = 20 + 8;
+const IPV6_OVERHEAD: usize = 40 + 8;
+const EXTENSION_OVERHEAD: usize = 2 + 8;
+
+//Ethernet MTU to start with, lowered when a packet is rejected as too large
+pub const DEFAULT_MTU: usize = 1500;
+const MIN_IPV4_MTU: usize = 576;
+const MIN_IPV6_MTU: usize = 1280;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    Connected,
+    FinSent,
+    Closed,
+}
+
+#[derive(Debug)]
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+
+    //lost or timed out, waiting for room in the window to go out again
+    need_resend: bool,
+}
+
+/**
+ * Microseconds on a clock shared by every connection, wrapping like the uTP timestamps
+ */
+fn timestamp_micros(now: Instant) -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    let epoch = *EPOCH.get_or_init(Instant::now);
+
+    return now.saturating_duration_since(epoch).as_micros() as u32;
+}
+
+/**
+ * One uTP connection without any I/O: packets received and time go in, packets to send come out.
+ * The socket owning it sends the outbox after every call.
+ */
+#[derive(Debug)]
+pub struct Connection {
+    state: State,
+    peer: SocketAddr,
+
+    //we receive packets with recv_id and send them with send_id
+    recv_id: u16,
+    send_id: u16,
+
+    //next sequence number we send, last one received in order
+    seq_nr: u16,
+    ack_nr: u16,
+
+    send_buffer: VecDeque<u8>,
+    in_flight: VecDeque<SentPacket>,
+    peer_window: usize,
+    duplicate_acks: usize,
+    ledbat: Ledbat,
+    mtu: usize,
+    timeouts: u32,
+
+    receive_buffer: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+
+    //one way delay of the last packet of the peer, sent back as timestamp_difference
+    reply_micros: u32,
+    ack_needed: bool,
+
+    //the application closed its side, a FIN goes out once everything before it is sent
+    close_requested: bool,
+    fin_acked: bool,
+    peer_fin: Option<u16>,
+    eof: bool,
+
+    //the application dropped its stream
+    detached: bool,
+
+    error: Option<ErrorKind>,
+    outbox: Vec<Packet>,
+}
+
+impl Connection {
+    fn new(peer: SocketAddr, recv_id: u16, send_id: u16, seq_nr: u16) -> Self {
+        let mtu = DEFAULT_MTU;
+
+        Connection {
+            state: State::SynSent,
+            peer,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            send_buffer: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            peer_window: MAX_RECEIVE_BUFFER,
+            duplicate_acks: 0,
+            ledbat: Ledbat::new(packet_size(peer, mtu)),
+            mtu,
+            timeouts: 0,
+            receive_buffer: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            reply_micros: 0,
+            ack_needed: false,
+            close_requested: false,
+            fin_acked: false,
+            peer_fin: None,
+            eof: false,
+            detached: false,
+            error: None,
+            outbox: Vec::new(),
+        }
+    }
+
+    /**
+     * Open a connection: the SYN carries our receive id, the peer answers on recv_id + 1
+     */
+    pub fn connect(peer: SocketAddr, recv_id: u16, now: Instant) -> Self {
+        let mut connection = Connection::new(peer, recv_id, recv_id.wrapping_add(1), 1);
+
+        let syn = Packet::new(PacketType::Syn, recv_id);
+        connection.transmit_new(syn, now);
+
+        return connection;
+    }
+
+    /**
+     * Answer the SYN of a peer, the connection is usable right away
+     */
+    pub fn accept(peer: SocketAddr, syn: &Packet, seq_nr: u16, now: Instant) -> Self {
+        let mut connection = Connection::new(
+            peer,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            seq_nr,
+        );
+        connection.state = State::Connected;
+        connection.ack_nr = syn.seq_nr;
+        connection.reply_micros = timestamp_micros(now).wrapping_sub(syn.timestamp);
+        connection.ack_needed = true;
+        connection.flush(now);
+
+        return connection;
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    pub fn error(&self) -> Option<ErrorKind> {
+        self.error
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn window(&self) -> usize {
+        self.ledbat.window()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected || self.state == State::FinSent
+    }
+
+    /**
+     * The application can still queue data
+     */
+    pub fn is_writable(&self) -> bool {
+        !self.close_requested && self.state != State::Closed
+    }
+
+    /**
+     * Everything the peer sent was read and it will not send more
+     */
+    pub fn is_eof(&self) -> bool {
+        self.eof && self.receive_buffer.is_empty()
+    }
+
+    /**
+     * Nothing left to do, the socket can forget the connection
+     */
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Closed && (self.detached || self.error.is_some())
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn packet_size(&self) -> usize {
+        packet_size(self.peer, self.mtu)
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|sent| !sent.need_resend)
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    fn advertised_window(&self) -> u32 {
+        let buffered: usize = self.receive_buffer.len()
+            + self
+                .out_of_order
+                .values()
+                .map(|packet| packet.payload.len())
+                .sum::<usize>();
+
+        return MAX_RECEIVE_BUFFER.saturating_sub(buffered) as u32;
+    }
+
+    /**
+     * Selective ack of the packets received past the first missing one
+     */
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+
+        let mut bitmask = vec![0u8; 8];
+        for seq_nr in self.out_of_order.keys() {
+            let offset = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if offset < bitmask.len() * 8 {
+                bitmask[offset / 8] |= 1 << (offset % 8);
+            }
+        }
+
+        return Some(bitmask);
+    }
+
+    /**
+     * Fill the fields describing our side at the time the packet leaves
+     */
+    fn stamp(&self, packet: &mut Packet, now: Instant) {
+        packet.connection_id = match packet.packet_type {
+            PacketType::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        packet.timestamp = timestamp_micros(now);
+        packet.timestamp_difference = self.reply_micros;
+        packet.wnd_size = self.advertised_window();
+        packet.ack_nr = self.ack_nr;
+        packet.selective_ack = self.selective_ack();
+    }
+
+    /**
+     * Packet using a new sequence number, kept until the peer acknowledges it
+     */
+    fn transmit_new(&mut self, mut packet: Packet, now: Instant) {
+        packet.seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+
+        self.stamp(&mut packet, now);
+        self.outbox.push(packet.clone());
+        self.ack_needed = false;
+
+        self.in_flight.push_back(SentPacket {
+            packet,
+            sent_at: now,
+            transmissions: 1,
+            need_resend: false,
+        });
+    }
+
+    /**
+     * Send what the congestion window and the window of the peer allow
+     */
+    fn flush(&mut self, now: Instant) {
+        if self.error.is_some() {
+            self.ack_needed = false;
+            return;
+        }
+
+        let window = self.ledbat.window().min(self.peer_window);
+        let mut bytes_in_flight = self.bytes_in_flight();
+
+        //lost packets first, at least one even with a closed window so it cannot stall
+        for index in 0..self.in_flight.len() {
+            if !self.in_flight[index].need_resend {
+                continue;
+            }
+
+            let length = self.in_flight[index].packet.payload.len();
+            if bytes_in_flight > 0 && bytes_in_flight + length > window {
+                break;
+            }
+
+            let mut packet = self.in_flight[index].packet.clone();
+            self.stamp(&mut packet, now);
+            self.outbox.push(packet.clone());
+            self.ack_needed = false;
+
+            let sent = &mut self.in_flight[index];
+            sent.packet = packet;
+            sent.sent_at = now;
+            sent.transmissions += 1;
+            sent.need_resend = false;
+            bytes_in_flight += length;
+        }
+
+        if self.state == State::Connected {
+            while !self.send_buffer.is_empty() {
+                let length = self.packet_size().min(self.send_buffer.len());
+                if bytes_in_flight > 0 && bytes_in_flight + length > window {
+                    break;
+                }
+
+                let mut packet = Packet::new(PacketType::Data, self.send_id);
+                packet.payload = self.send_buffer.drain(..length).collect();
+                self.transmit_new(packet, now);
+                bytes_in_flight += length;
+            }
+
+            if self.close_requested && self.send_buffer.is_empty() {
+                self.transmit_new(Packet::new(PacketType::Fin, self.send_id), now);
+                self.state = State::FinSent;
+            }
+        }
+
+        if self.ack_needed {
+            let mut ack = Packet::new(PacketType::State, self.send_id);
+            ack.seq_nr = self.seq_nr;
+            self.stamp(&mut ack, now);
+            self.outbox.push(ack);
+            self.ack_needed = false;
+        }
+    }
+
+    /**
+     * Queue data for the peer, returns how much fitted in the send buffer
+     */
+    pub fn write(&mut self, data: &[u8], now: Instant) -> usize {
+        let buffered = self.send_buffer.len() + self.bytes_in_flight();
+        let accepted = data.len().min(MAX_SEND_BUFFER.saturating_sub(buffered));
+
+        self.send_buffer.extend(&data[..accepted]);
+        self.flush(now);
+
+        return accepted;
+    }
+
+    pub fn read(&mut self, buf: &mut [u8], now: Instant) -> usize {
+        let window_was_closed = (self.advertised_window() as usize) < self.packet_size();
+
+        let read = buf.len().min(self.receive_buffer.len());
+        for (index, byte) in self.receive_buffer.drain(..read).enumerate() {
+            buf[index] = byte;
+        }
+
+        //tell the peer it can send again
+        if window_was_closed && read > 0 {
+            self.ack_needed = true;
+            self.flush(now);
+        }
+
+        return read;
+    }
+
+    /**
+     * The application is done sending, the FIN follows the buffered data
+     */
+    pub fn close(&mut self, now: Instant) {
+        if self.close_requested {
+            return;
+        }
+
+        self.close_requested = true;
+
+        if self.state == State::SynSent {
+            self.state = State::Closed;
+            return;
+        }
+
+        self.flush(now);
+    }
+
+    /**
+     * The stream was dropped, the connection goes away once the FIN is acknowledged
+     */
+    pub fn detach(&mut self, now: Instant) {
+        self.detached = true;
+        self.close(now);
+        self.update_closed();
+    }
+
+    fn fail(&mut self, kind: ErrorKind) {
+        self.error = Some(kind);
+        self.state = State::Closed;
+        self.in_flight.clear();
+        self.send_buffer.clear();
+    }
+
+    fn update_closed(&mut self) {
+        let peer_done = self.peer_fin.is_some() || self.detached;
+
+        if self.state == State::FinSent && self.fin_acked && peer_done {
+            self.state = State::Closed;
+        }
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant) {
+        if self.state == State::Closed && self.error.is_some() {
+            return;
+        }
+
+        if packet.packet_type == PacketType::Reset {
+            self.fail(ErrorKind::ConnectionReset);
+            return;
+        }
+
+        self.reply_micros = timestamp_micros(now).wrapping_sub(packet.timestamp);
+        if packet.timestamp_difference != 0 {
+            self.ledbat.on_delay_sample(
+                Duration::from_micros(packet.timestamp_difference as u64),
+                now,
+            );
+        }
+        self.peer_window = packet.wnd_size as usize;
+
+        match packet.packet_type {
+            PacketType::Syn => {
+                //our answer to its SYN was lost
+                self.ack_needed = true;
+                self.flush(now);
+                return;
+            }
+            PacketType::State if self.state == State::SynSent => {
+                self.state = State::Connected;
+                //the first data packet of the peer uses the sequence number of this ack
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            _ => {}
+        }
+
+        if self.state == State::SynSent {
+            return;
+        }
+
+        self.on_ack(&packet, now);
+
+        if packet.packet_type == PacketType::Data || packet.packet_type == PacketType::Fin {
+            self.on_data(packet);
+            self.ack_needed = true;
+        }
+
+        self.flush(now);
+        self.update_closed();
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let selectively_acked = packet.selectively_acked();
+        let mut bytes_acked = 0;
+        let mut rtt_sample = None;
+        let mut fin_acked = false;
+
+        self.in_flight.retain(|sent| {
+            let seq_nr = sent.packet.seq_nr;
+            let acked = !seq_after(seq_nr, packet.ack_nr) || selectively_acked.contains(&seq_nr);
+
+            if acked {
+                bytes_acked += sent.packet.payload.len();
+
+                //Karn: a retransmitted packet says nothing about the round trip
+                if sent.transmissions == 1 {
+                    rtt_sample = Some(now.saturating_duration_since(sent.sent_at));
+                }
+                if sent.packet.packet_type == PacketType::Fin {
+                    fin_acked = true;
+                }
+            }
+
+            return !acked;
+        });
+
+        if fin_acked {
+            self.fin_acked = true;
+        }
+        if let Some(sample) = rtt_sample {
+            self.ledbat.on_rtt_sample(sample);
+        }
+
+        if bytes_acked > 0 || rtt_sample.is_some() {
+            self.ledbat.on_ack(bytes_acked);
+            self.timeouts = 0;
+            self.duplicate_acks = 0;
+        } else if packet.packet_type == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+        }
+
+        //packets acknowledged past the oldest one mean it was lost
+        let oldest = match self.in_flight.front() {
+            Some(oldest) => oldest.packet.seq_nr,
+            None => return,
+        };
+        let acked_past = selectively_acked
+            .iter()
+            .filter(|seq_nr| seq_after(**seq_nr, oldest))
+            .count();
+
+        if (self.duplicate_acks >= DUPLICATE_ACKS_THRESHOLD
+            || acked_past >= DUPLICATE_ACKS_THRESHOLD)
+            && !self.in_flight[0].need_resend
+            && self.in_flight[0].transmissions == 1
+        {
+            self.in_flight[0].need_resend = true;
+            self.duplicate_acks = 0;
+            self.ledbat.on_loss();
+        }
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        let expected = self.ack_nr.wrapping_add(1);
+
+        if packet.seq_nr != expected {
+            //ahead of a lost packet, kept until the hole is filled
+            let distance = packet.seq_nr.wrapping_sub(expected);
+            if seq_after(packet.seq_nr, expected) && distance < MAX_REORDER_DISTANCE {
+                self.out_of_order.insert(packet.seq_nr, packet);
+            }
+            return;
+        }
+
+        let mut next = Some(packet);
+        while let Some(packet) = next {
+            self.ack_nr = packet.seq_nr;
+
+            if packet.packet_type == PacketType::Fin {
+                self.peer_fin = Some(packet.seq_nr);
+                self.eof = true;
+                self.out_of_order.clear();
+                return;
+            }
+
+            self.receive_buffer.extend(&packet.payload);
+            next = self.out_of_order.remove(&self.ack_nr.wrapping_add(1));
+        }
+    }
+
+    /**
+     * Retransmission timer, called regularly by the socket
+     */
+    pub fn on_tick(&mut self, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+
+        let oldest_sent_at = self
+            .in_flight
+            .iter()
+            .filter(|sent| !sent.need_resend)
+            .map(|sent| sent.sent_at)
+            .min();
+
+        if let Some(sent_at) = oldest_sent_at
+            && now.saturating_duration_since(sent_at) >= self.ledbat.timeout()
+        {
+            self.timeouts += 1;
+            if self.timeouts > MAX_TIMEOUTS {
+                self.fail(ErrorKind::TimedOut);
+                return;
+            }
+
+            //everything in flight is sent again as the smaller window allows
+            self.ledbat.on_timeout();
+            for sent in self.in_flight.iter_mut() {
+                sent.need_resend = true;
+            }
+        }
+
+        self.flush(now);
+    }
+
+    /**
+     * The socket refused these packets as larger than the path MTU. The ones never sent before
+     * are the newest: their data goes back to the send buffer to be split into smaller packets.
+     */
+    pub fn on_packets_too_big(&mut self, packets: Vec<Packet>, now: Instant) {
+        let min_mtu = match self.peer {
+            SocketAddr::V4(_) => MIN_IPV4_MTU,
+            SocketAddr::V6(_) => MIN_IPV6_MTU,
+        };
+        if self.mtu <= min_mtu {
+            self.fail(ErrorKind::InvalidInput);
+            return;
+        }
+
+        self.mtu = (self.mtu * 3 / 4).max(min_mtu);
+        self.ledbat.set_packet_size(self.packet_size());
+
+        let mut unsent = Vec::new();
+        for packet in packets.iter().rev() {
+            let never_sent = self
+                .in_flight
+                .back()
+                .is_some_and(|sent| sent.packet.seq_nr == packet.seq_nr && sent.transmissions == 1);
+            if !never_sent {
+                continue;
+            }
+
+            let sent = self.in_flight.pop_back().unwrap();
+            self.seq_nr = sent.packet.seq_nr;
+            if sent.packet.packet_type == PacketType::Fin {
+                //sent again after the data by the next flush
+                self.state = State::Connected;
+            }
+            unsent.push(sent.packet.payload);
+        }
+
+        let data: Vec<u8> = unsent.into_iter().rev().flatten().collect();
+        for byte in data.into_iter().rev() {
+            self.send_buffer.push_front(byte);
+        }
+
+        //retransmissions too big for the new MTU stay lost until the timer resends them
+        for packet in packets {
+            if let Some(sent) = self
+                .in_flight
+                .iter_mut()
+                .find(|sent| sent.packet.seq_nr == packet.seq_nr)
+            {
+                sent.need_resend = true;
+            }
+        }
+
+        self.flush(now);
+    }
+}
+
+/**
+ * Payload of a packet for the given MTU, room is left for the selective ack
+ */
+pub fn packet_size(peer: SocketAddr, mtu: usize) -> usize {
+    let overhead = match peer {
+        SocketAddr::V4(_) => IPV4_OVERHEAD,
+        SocketAddr::V6(_) => IPV6_OVERHEAD,
+    };
+
+    return mtu - overhead - HEADER_SIZE - EXTENSION_OVERHEAD;
+}
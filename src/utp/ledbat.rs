@@ -0,0 +1,145 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+//queuing delay LEDBAT aims for, above it the window shrinks to leave room to other traffic
+pub const TARGET_DELAY: Duration = Duration::from_millis(100);
+
+//window growth per round trip when there is no queuing delay at all
+const MAX_CWND_INCREASE_BYTES_PER_RTT: f64 = 3000.0;
+
+//the base delay is the lowest delay of the last minutes, one sample per minute
+const BASE_DELAY_HISTORY: usize = 2;
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * LEDBAT congestion control: the window follows the one way delay measured by the peer,
+ * growing while the delay stays under the target and shrinking once packets queue up
+ */
+#[derive(Debug)]
+pub struct Ledbat {
+    //bytes allowed in flight
+    max_window: usize,
+    min_window: usize,
+
+    base_delays: VecDeque<(Instant, Duration)>,
+    current_delay: Duration,
+
+    //round trip estimation (RFC 6298)
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+}
+
+impl Ledbat {
+    /**
+     * The window never gets below a couple of packets
+     */
+    pub fn new(packet_size: usize) -> Self {
+        Ledbat {
+            max_window: packet_size * 2,
+            min_window: packet_size * 2,
+            base_delays: VecDeque::new(),
+            current_delay: Duration::ZERO,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.max_window
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_packet_size(&mut self, packet_size: usize) {
+        self.min_window = packet_size * 2;
+        self.max_window = self.max_window.max(self.min_window);
+    }
+
+    /**
+     * Queuing delay: the measured delay above the lowest one seen recently
+     */
+    pub fn queuing_delay(&self) -> Duration {
+        let base_delay = self
+            .base_delays
+            .iter()
+            .map(|(_, delay)| *delay)
+            .min()
+            .unwrap_or(self.current_delay);
+
+        return self.current_delay.saturating_sub(base_delay);
+    }
+
+    /**
+     * One way delay reported by the peer (timestamp_difference)
+     */
+    pub fn on_delay_sample(&mut self, delay: Duration, now: Instant) {
+        self.current_delay = delay;
+
+        match self.base_delays.back_mut() {
+            Some((started_at, base_delay))
+                if now.duration_since(*started_at) < BASE_DELAY_INTERVAL =>
+            {
+                *base_delay = (*base_delay).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+    }
+
+    pub fn on_rtt_sample(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+
+        self.timeout = (self.rtt.unwrap() + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /**
+     * Grow or shrink the window for newly acknowledged bytes
+     */
+    pub fn on_ack(&mut self, bytes_acked: usize) {
+        let target = TARGET_DELAY.as_secs_f64();
+        let off_target = (target - self.queuing_delay().as_secs_f64()) / target;
+
+        let window_factor = bytes_acked as f64 / self.max_window.max(1) as f64;
+        let gain = MAX_CWND_INCREASE_BYTES_PER_RTT * off_target.max(-1.0) * window_factor;
+
+        let window = (self.max_window as f64 + gain).max(self.min_window as f64);
+        self.max_window = window as usize;
+    }
+
+    /**
+     * A packet was lost but later ones arrived
+     */
+    pub fn on_loss(&mut self) {
+        self.max_window = (self.max_window / 2).max(self.min_window);
+    }
+
+    /**
+     * Nothing was acknowledged for a whole timeout, start over with the smallest window
+     */
+    pub fn on_timeout(&mut self) {
+        self.max_window = self.min_window;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+    }
+}
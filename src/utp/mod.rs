@@ -0,0 +1,13 @@
+/*
+ * uTP, the Micro Transport Protocol (BEP 29): reliable streams over UDP
+ * whose LEDBAT congestion control backs off as soon as other traffic builds up queues
+ * https://www.bittorrent.org/beps/bep_0029.html
+ */
+pub mod connection;
+pub mod ledbat;
+pub mod packet;
+pub mod socket;
+pub mod stream;
+
+pub use socket::UtpSocket;
+pub use stream::UtpStream;
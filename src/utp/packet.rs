@@ -0,0 +1,174 @@
+//BEP 29 header, followed by the extensions and the payload
+pub const HEADER_SIZE: usize = 20;
+pub const VERSION: u8 = 1;
+
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,  // ST_DATA: carries a payload
+    Fin = 1,   // ST_FIN: last packet of the connection
+    State = 2, // ST_STATE: ack without payload
+    Reset = 3, // ST_RESET: terminates the connection
+    Syn = 4,   // ST_SYN: opens a connection
+}
+
+impl PacketType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Data),
+            1 => Some(Self::Fin),
+            2 => Some(Self::State),
+            3 => Some(Self::Reset),
+            4 => Some(Self::Syn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+
+    //bit i acknowledges ack_nr + 2 + i
+    pub selective_ack: Option<Vec<u8>>,
+
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16) -> Self {
+        Packet {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+
+        bytes.push(((self.packet_type as u8) << 4) | VERSION);
+        bytes.push(match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => EXTENSION_NONE,
+        });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.wnd_size.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+
+        if let Some(bitmask) = &self.selective_ack {
+            bytes.push(EXTENSION_NONE);
+            bytes.push(bitmask.len() as u8);
+            bytes.extend_from_slice(bitmask);
+        }
+
+        bytes.extend_from_slice(&self.payload);
+        return bytes;
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(format!("packet of {} bytes is too short", bytes.len()));
+        }
+
+        if bytes[0] & 0x0F != VERSION {
+            return Err(format!("unsupported uTP version {}", bytes[0] & 0x0F));
+        }
+
+        let packet_type = PacketType::from_byte(bytes[0] >> 4)
+            .ok_or_else(|| format!("unknown packet type {}", bytes[0] >> 4))?;
+
+        let u16_at = |index: usize| u16::from_be_bytes([bytes[index], bytes[index + 1]]);
+        let u32_at = |index: usize| u32::from_be_bytes(bytes[index..index + 4].try_into().unwrap());
+
+        let mut packet = Packet {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack: None,
+            payload: Vec::new(),
+        };
+
+        //extensions are a linked list: type of the next one, length, data
+        let mut extension = bytes[1];
+        let mut index = HEADER_SIZE;
+
+        while extension != EXTENSION_NONE {
+            if index + 2 > bytes.len() {
+                return Err(String::from("truncated extension header"));
+            }
+
+            let next = bytes[index];
+            let length = bytes[index + 1] as usize;
+            index += 2;
+
+            if index + length > bytes.len() {
+                return Err(String::from("truncated extension"));
+            }
+
+            //unknown extensions are skipped
+            if extension == EXTENSION_SELECTIVE_ACK {
+                if length == 0 || !length.is_multiple_of(4) {
+                    return Err(format!("selective ack of {length} bytes"));
+                }
+                packet.selective_ack = Some(bytes[index..index + length].to_vec());
+            }
+
+            extension = next;
+            index += length;
+        }
+
+        packet.payload = bytes[index..].to_vec();
+        return Ok(packet);
+    }
+
+    /**
+     * Sequence numbers acknowledged by the selective ack extension
+     */
+    pub fn selectively_acked(&self) -> Vec<u16> {
+        let bitmask = match &self.selective_ack {
+            Some(bitmask) => bitmask,
+            None => return Vec::new(),
+        };
+
+        let mut acked = Vec::new();
+        for (byte_index, byte) in bitmask.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (1 << bit) != 0 {
+                    let offset = 2 + byte_index * 8 + bit;
+                    acked.push(self.ack_nr.wrapping_add(offset as u16));
+                }
+            }
+        }
+
+        return acked;
+    }
+}
+
+/**
+ * a comes after b, with sequence numbers wrapping around
+ */
+pub fn seq_after(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Waker;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use super::connection::Connection;
+use super::packet::{Packet, PacketType};
+use super::stream::UtpStream;
+use crate::network::canonical_peer_addr;
+
+//how often timers run when no packet arrives
+const TICK_INTERVAL: Duration = Duration::from_millis(20);
+
+//largest datagram accepted
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const EMSGSIZE: i32 = 90;
+#[cfg(windows)]
+const EMSGSIZE: i32 = 10040;
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+const EMSGSIZE: i32 = 40;
+
+/**
+ * Connection shared by its stream and the socket thread. Blocking streams wait on the condvar,
+ * async ones leave a waker, both are notified on every change.
+ */
+pub(super) struct Entry {
+    pub connection: Mutex<Connection>,
+    pub changed: Condvar,
+    pub wakers: Mutex<Vec<Waker>>,
+}
+
+impl Entry {
+    fn new(connection: Connection) -> Self {
+        Entry {
+            connection: Mutex::new(connection),
+            changed: Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn notify(&self) {
+        self.changed.notify_all();
+
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+pub(super) struct Inner {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    //never locked while holding the lock of a connection
+    connections: Mutex<HashMap<(SocketAddr, u16), Arc<Entry>>>,
+
+    //accepted connections, None until listen() is called
+    incoming: Mutex<Option<Sender<UtpStream>>>,
+    stopped: AtomicBool,
+}
+
+impl Inner {
+    /**
+     * Send the packets queued by a connection, splitting them again when the MTU is too large
+     */
+    pub(super) fn flush(&self, connection: &mut Connection) {
+        let peer = self.target_addr(connection.peer());
+        let now = Instant::now();
+
+        let mut packets = connection.take_outbox();
+        while !packets.is_empty() {
+            let mut unsent = Vec::new();
+
+            for (index, packet) in packets.iter().enumerate() {
+                match self.socket.send_to(&packet.to_bytes(), peer) {
+                    Ok(_) => {}
+                    Err(e) if e.raw_os_error() == Some(EMSGSIZE) => {
+                        unsent = packets.split_off(index);
+                        break;
+                    }
+                    //lost like any other packet, the retransmission timer takes care of it
+                    Err(e) => debug!("[{peer}] cannot send uTP packet: {e}"),
+                }
+            }
+
+            if unsent.is_empty() {
+                break;
+            }
+
+            connection.on_packets_too_big(unsent, now);
+            debug!("[{peer}] uTP MTU lowered to {}", connection.mtu());
+            packets = connection.take_outbox();
+        }
+    }
+
+    /**
+     * IPv4 peers are reached through v4-mapped addresses on dual stack sockets
+     */
+    fn target_addr(&self, peer: SocketAddr) -> SocketAddr {
+        match (self.local_addr.ip(), peer.ip()) {
+            (IpAddr::V6(_), IpAddr::V4(ip)) => {
+                SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), peer.port())
+            }
+            _ => peer,
+        }
+    }
+
+    pub(super) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub(super) fn remove(&self, peer: SocketAddr, recv_id: u16) {
+        self.connections.lock().unwrap().remove(&(peer, recv_id));
+    }
+
+    fn handle_datagram(self: &Arc<Self>, datagram: &[u8], from: SocketAddr) {
+        let packet = match Packet::parse(datagram) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("[{from}] invalid uTP packet: {e}");
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        let entry = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&(from, packet.connection_id))
+            .cloned();
+
+        if let Some(entry) = entry {
+            let mut connection = entry.connection.lock().unwrap();
+            connection.on_packet(packet, now);
+            self.flush(&mut connection);
+            entry.notify();
+            return;
+        }
+
+        if packet.packet_type == PacketType::Syn {
+            self.accept_syn(packet, from, now);
+        }
+    }
+
+    fn accept_syn(self: &Arc<Self>, syn: Packet, from: SocketAddr, now: Instant) {
+        let incoming = self.incoming.lock().unwrap();
+        let sender = match incoming.as_ref() {
+            Some(sender) => sender,
+            None => return,
+        };
+
+        let key = (from, syn.connection_id.wrapping_add(1));
+        let mut connections = self.connections.lock().unwrap();
+
+        //a retransmitted SYN is answered by the existing connection
+        if let Some(entry) = connections.get(&key).cloned() {
+            drop(connections);
+            let mut connection = entry.connection.lock().unwrap();
+            connection.on_packet(syn, now);
+            self.flush(&mut connection);
+            return;
+        }
+
+        let mut connection = Connection::accept(from, &syn, rand::random(), now);
+        self.flush(&mut connection);
+
+        let entry = Arc::new(Entry::new(connection));
+        connections.insert(key, Arc::clone(&entry));
+        drop(connections);
+
+        if sender
+            .send(UtpStream::new(Arc::clone(self), entry))
+            .is_err()
+        {
+            debug!("[{from}] dropping uTP connection, nobody accepts them");
+        }
+    }
+
+    fn tick(&self) {
+        let now = Instant::now();
+        let entries: Vec<Arc<Entry>> = self.connections.lock().unwrap().values().cloned().collect();
+
+        for entry in entries {
+            let mut connection = entry.connection.lock().unwrap();
+            connection.on_tick(now);
+            self.flush(&mut connection);
+
+            entry.notify();
+
+            if connection.is_finished() {
+                let key = (connection.peer(), connection.recv_id());
+                drop(connection);
+                self.remove(key.0, key.1);
+            }
+        }
+    }
+}
+
+/**
+ * uTP (BEP 29) endpoint over a single UDP socket. A thread receives the datagrams,
+ * hands them to their connection and runs the retransmission timers.
+ */
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+    accepted: Mutex<Option<Receiver<UtpStream>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl UtpSocket {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        UtpSocket::new(UdpSocket::bind(addr)?)
+    }
+
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_read_timeout(Some(TICK_INTERVAL))?;
+
+        let inner = Arc::new(Inner {
+            local_addr: socket.local_addr()?,
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            incoming: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        });
+
+        let handle = {
+            let inner = Arc::clone(&inner);
+            thread::spawn(move || run_socket(inner))
+        };
+
+        Ok(UtpSocket {
+            inner,
+            accepted: Mutex::new(None),
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    /**
+     * Accept incoming connections from now on, SYNs are ignored before
+     */
+    pub fn listen(&self) {
+        let mut incoming = self.inner.incoming.lock().unwrap();
+        if incoming.is_none() {
+            let (sender, receiver) = mpsc::channel();
+            *incoming = Some(sender);
+            *self.accepted.lock().unwrap() = Some(receiver);
+        }
+    }
+
+    /**
+     * Wait for an incoming connection, TimedOut when none arrives in time
+     */
+    pub fn accept(&self, timeout: Duration) -> io::Result<UtpStream> {
+        let accepted = self.accepted.lock().unwrap();
+        let receiver = match accepted.as_ref() {
+            Some(receiver) => receiver,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "uTP socket is not listening",
+                ));
+            }
+        };
+
+        match receiver.recv_timeout(timeout) {
+            Ok(stream) => Ok(stream),
+            Err(RecvTimeoutError::Timeout) => Err(ErrorKind::TimedOut.into()),
+            Err(RecvTimeoutError::Disconnected) => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+
+    pub fn connect(&self, peer: SocketAddr, timeout: Duration) -> io::Result<UtpStream> {
+        let peer = canonical_peer_addr(peer);
+        let deadline = Instant::now() + timeout;
+
+        let entry = {
+            let mut connections = self.inner.connections.lock().unwrap();
+
+            let mut recv_id: u16 = rand::random();
+            while connections.contains_key(&(peer, recv_id)) {
+                recv_id = rand::random();
+            }
+
+            let mut connection = Connection::connect(peer, recv_id, Instant::now());
+            self.inner.flush(&mut connection);
+
+            let entry = Arc::new(Entry::new(connection));
+            connections.insert((peer, recv_id), Arc::clone(&entry));
+            entry
+        };
+
+        let mut connection = entry.connection.lock().unwrap();
+        loop {
+            if connection.is_connected() {
+                drop(connection);
+                return Ok(UtpStream::new(Arc::clone(&self.inner), entry));
+            }
+
+            let now = Instant::now();
+            let error = match connection.error() {
+                Some(kind) => Some(io::Error::from(kind)),
+                None if now >= deadline => Some(io::Error::from(ErrorKind::TimedOut)),
+                None => None,
+            };
+
+            if let Some(error) = error {
+                let recv_id = connection.recv_id();
+                drop(connection);
+                self.inner.remove(peer, recv_id);
+                return Err(error);
+            }
+
+            connection = entry
+                .changed
+                .wait_timeout(connection, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.inner.stopped.store(true, Ordering::Relaxed);
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run_socket(inner: Arc<Inner>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut last_tick = Instant::now();
+
+    while !inner.stopped.load(Ordering::Relaxed) {
+        match inner.socket.recv_from(&mut buf) {
+            Ok((read, from)) => inner.handle_datagram(&buf[..read], canonical_peer_addr(from)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            //ICMP errors of earlier packets show up here on some systems
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            Err(e) => {
+                warn!("uTP socket error: {e}");
+                thread::sleep(TICK_INTERVAL);
+            }
+        }
+
+        if last_tick.elapsed() >= TICK_INTERVAL {
+            inner.tick();
+            last_tick = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::cell::Cell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::connection::Connection;
+use super::socket::{Entry, Inner};
+
+/**
+ * Reliable byte stream over a uTP connection, used like a TcpStream either blocking or from async tasks
+ */
+pub struct UtpStream {
+    inner: Arc<Inner>,
+    entry: Arc<Entry>,
+    read_timeout: Cell<Option<Duration>>,
+    nonblocking: Cell<bool>,
+}
+
+impl UtpStream {
+    pub(super) fn new(inner: Arc<Inner>, entry: Arc<Entry>) -> Self {
+        UtpStream {
+            inner,
+            entry,
+            read_timeout: Cell::new(None),
+            nonblocking: Cell::new(false),
+        }
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.entry.connection.lock().unwrap()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.connection().peer())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.local_addr())
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "zero read timeout"));
+        }
+
+        self.read_timeout.set(timeout);
+        Ok(())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.set(nonblocking);
+        Ok(())
+    }
+
+    /**
+     * Closing the write side sends a FIN once the buffered data is out, reading is not affected
+     */
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut connection = self.connection();
+            connection.close(Instant::now());
+            self.inner.flush(&mut connection);
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = self
+            .read_timeout
+            .get()
+            .map(|timeout| Instant::now() + timeout);
+        let mut connection = self.connection();
+
+        loop {
+            let read = connection.read(buf, Instant::now());
+            if read > 0 {
+                //the read may have reopened our receive window
+                self.inner.flush(&mut connection);
+                return Ok(read);
+            }
+
+            if let Some(kind) = connection.error() {
+                return Err(kind.into());
+            }
+            if connection.is_eof() {
+                return Ok(0);
+            }
+            if self.nonblocking.get() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+
+            connection = match deadline {
+                None => self.entry.changed.wait(connection).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ErrorKind::WouldBlock.into());
+                    }
+                    self.entry
+                        .changed
+                        .wait_timeout(connection, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+}
+
+impl Write for UtpStream {
+    /**
+     * Blocks while the send buffer is full, the socket thread empties it as acks arrive
+     */
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut connection = self.connection();
+
+        loop {
+            if let Some(kind) = connection.error() {
+                return Err(kind.into());
+            }
+            if !connection.is_writable() {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+
+            let written = connection.write(buf, Instant::now());
+            if written > 0 {
+                self.inner.flush(&mut connection);
+                return Ok(written);
+            }
+
+            if self.nonblocking.get() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            connection = self.entry.changed.wait(connection).unwrap();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl UtpStream {
+    /**
+     * Wake the task once the socket thread changes the connection
+     */
+    fn register_waker(&self, cx: &Context<'_>) {
+        let mut wakers = self.entry.wakers.lock().unwrap();
+
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut connection = self.connection();
+
+        let read = connection.read(buf.initialize_unfilled(), Instant::now());
+        if read > 0 {
+            buf.advance(read);
+            self.inner.flush(&mut connection);
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Some(kind) = connection.error() {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if connection.is_eof() {
+            return Poll::Ready(Ok(()));
+        }
+
+        //registered while the connection is locked, so no change can be missed
+        self.register_waker(cx);
+        return Poll::Pending;
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut connection = self.connection();
+
+        if let Some(kind) = connection.error() {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if !connection.is_writable() {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+
+        let written = connection.write(buf, Instant::now());
+        if written > 0 {
+            self.inner.flush(&mut connection);
+            return Poll::Ready(Ok(written));
+        }
+
+        self.register_waker(cx);
+        return Poll::Pending;
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.shutdown(Shutdown::Write))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut connection = self.connection();
+        connection.detach(Instant::now());
+        self.inner.flush(&mut connection);
+
+        if connection.is_finished() {
+            let key = (connection.peer(), connection.recv_id());
+            drop(connection);
+            self.inner.remove(key.0, key.1);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rust_torrent::choker::{Choker, OPTIMISTIC_UNCHOKE_INTERVAL};
+use rust_torrent::peer_registry::PeerStats;
+
+fn peer(
+    port: u16,
+    interested: bool,
+    download_rate: f64,
+    upload_rate: f64,
+) -> (SocketAddr, PeerStats) {
+    (
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        PeerStats {
+            peer_interested: interested,
+            download_rate,
+            upload_rate,
+            ..PeerStats::default()
+        },
+    )
+}
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn test_unchoke_fastest_interested_peers() {
+    let peers = vec![
+        peer(1, true, 100.0, 0.0),
+        peer(2, true, 500.0, 0.0),
+        peer(3, false, 9000.0, 0.0),
+        peer(4, true, 300.0, 0.0),
+        peer(5, true, 10.0, 0.0),
+    ];
+
+    let mut choker = Choker::new(3);
+    let unchoked = choker.rechoke(&peers, false, Instant::now());
+
+    //2 regular slots by download rate, the uninterested peer is ignored
+    assert_eq!(unchoked.len(), 3);
+    assert!(unchoked.contains(&addr(2)));
+    assert!(unchoked.contains(&addr(4)));
+    assert!(!unchoked.contains(&addr(3)));
+
+    let optimistic = choker.optimistic().unwrap();
+    assert!(optimistic == addr(1) || optimistic == addr(5));
+}
+
+#[test]
+fn test_snubbing_peers_are_ranked_last_while_downloading() {
+    let mut snubbing = peer(1, true, 900.0, 0.0);
+    snubbing.1.snubbed = true;
+    let peers = vec![
+        snubbing,
+        peer(2, true, 100.0, 0.0),
+        peer(3, true, 50.0, 0.0),
+    ];
+
+    //a single regular slot, and the optimistic one
+    let mut choker = Choker::new(2);
+    let unchoked = choker.rechoke(&peers, false, Instant::now());
+    assert!(unchoked.contains(&addr(2)));
+
+    //it still counts for seeding, where the download rate does not matter
+    let unchoked = Choker::new(2).rechoke(&peers, true, Instant::now());
+    assert_eq!(unchoked.len(), 2);
+}
+
+#[test]
+fn test_seeding_uses_upload_rate() {
+    let peers = vec![
+        peer(1, true, 0.0, 50.0),
+        peer(2, true, 900.0, 1.0),
+        peer(3, true, 0.0, 700.0),
+    ];
+
+    let mut choker = Choker::new(2);
+    let unchoked = choker.rechoke(&peers, true, Instant::now());
+
+    assert!(unchoked.contains(&addr(3)));
+    assert_eq!(unchoked.len(), 2);
+}
+
+#[test]
+fn test_optimistic_unchoke_rotates() {
+    let peers: Vec<_> = (1..=6).map(|port| peer(port, true, 0.0, 0.0)).collect();
+    let mut choker = Choker::new(2);
+    let start = Instant::now();
+
+    choker.rechoke(&peers, false, start);
+    let first = choker.optimistic().unwrap();
+
+    //kept between regular rechokes
+    choker.rechoke(&peers, false, start + Duration::from_secs(10));
+    assert_eq!(choker.optimistic(), Some(first));
+
+    //a different peer every 30 seconds
+    let mut previous = first;
+    let mut optimistic_peers = HashSet::from([first]);
+    for round in 1..=10 {
+        let unchoked = choker.rechoke(&peers, false, start + OPTIMISTIC_UNCHOKE_INTERVAL * round);
+        let optimistic = choker.optimistic().unwrap();
+
+        assert_ne!(optimistic, previous);
+        assert!(unchoked.contains(&optimistic));
+        previous = optimistic;
+        optimistic_peers.insert(optimistic);
+    }
+
+    assert!(optimistic_peers.len() > 2);
+}
+
+#[test]
+fn test_no_upload_slots() {
+    let peers = vec![peer(1, true, 100.0, 100.0)];
+    let mut choker = Choker::new(0);
+
+    assert!(choker.rechoke(&peers, false, Instant::now()).is_empty());
+}
@@ -0,0 +1,177 @@
+#![allow(dead_code)]
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use sha1::{Digest, Sha1};
+
+use rust_torrent::torrent_file::TorrentFile;
+
+static TEMP_DIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "rust-torrent-{name}-{}-{}",
+        std::process::id(),
+        TEMP_DIR_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn test_data(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+pub fn build_torrent(name: &str, data: &[u8], piece_length: usize) -> TorrentFile {
+    let mut torrent_file = TorrentFile::default();
+
+    torrent_file.info.name = name.to_string();
+    torrent_file.info.length = data.len();
+    torrent_file.info.piece_length = piece_length;
+    torrent_file.info.pieces = data
+        .chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    torrent_file.pieces_amount = data.len().div_ceil(piece_length);
+    torrent_file.info_hash = Sha1::digest(name.as_bytes()).into();
+
+    torrent_file
+}
+
+pub fn handshake(info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Vec<u8> {
+    let mut handshake = vec![19u8];
+    handshake.extend_from_slice(b"BitTorrent protocol");
+    handshake.extend_from_slice(&[0u8; 8]);
+    handshake.extend_from_slice(info_hash);
+    handshake.extend_from_slice(peer_id);
+    handshake
+}
+
+pub fn write_message(stream: &mut TcpStream, id: u8, payload: &[u8]) {
+    let mut msg = ((payload.len() + 1) as u32).to_be_bytes().to_vec();
+    msg.push(id);
+    msg.extend_from_slice(payload);
+    stream.write_all(&msg).unwrap();
+}
+
+/**
+ * Read the next message, None on timeout and Err when the connection is closed
+ */
+pub fn read_message(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, ()> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            return Ok(None);
+        }
+        Err(_) => return Err(()),
+    }
+
+    let mut msg = vec![0u8; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut msg).map_err(|_| ())?;
+    Ok(Some(msg))
+}
+
+#[derive(Debug, Default)]
+pub struct SeederReport {
+    //size of each request batch served
+    pub batches: Vec<usize>,
+    pub requests: usize,
+    pub cancels: usize,
+}
+
+/**
+ * Scripted seeder: answers requests in batches so the test can see how many were in flight
+ */
+pub struct FakeSeeder {
+    pub addr: SocketAddr,
+    handle: JoinHandle<SeederReport>,
+}
+
+impl FakeSeeder {
+    pub fn start(torrent_file: &TorrentFile, data: Vec<u8>) -> Self {
+        Self::start_with(torrent_file, data, true)
+    }
+
+    /**
+     * Seeder that unchokes and accepts requests but never answers them
+     */
+    pub fn start_stalled(torrent_file: &TorrentFile, data: Vec<u8>) -> Self {
+        Self::start_with(torrent_file, data, false)
+    }
+
+    fn start_with(torrent_file: &TorrentFile, data: Vec<u8>, answer_requests: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = torrent_file.info_hash;
+        let piece_length = torrent_file.info.piece_length;
+        let bitfield_length = torrent_file.pieces_amount.div_ceil(8);
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut their_handshake = [0u8; 68];
+            stream.read_exact(&mut their_handshake).unwrap();
+            assert_eq!(&their_handshake[28..48], &info_hash);
+            stream
+                .write_all(&handshake(&info_hash, b"-FS0001-seederseeder"))
+                .unwrap();
+
+            write_message(&mut stream, 5, &vec![0xFFu8; bitfield_length]);
+            write_message(&mut stream, 1, &[]);
+
+            stream
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+
+            let mut report = SeederReport::default();
+            let mut pending: Vec<(u32, u32, u32)> = Vec::new();
+
+            while let Ok(msg) = read_message(&mut stream) {
+                match msg {
+                    Some(msg) if msg.first() == Some(&6) => {
+                        let field =
+                            |i: usize| u32::from_be_bytes(msg[i..i + 4].try_into().unwrap());
+                        report.requests += 1;
+                        if answer_requests {
+                            pending.push((field(1), field(5), field(9)));
+                        }
+                    }
+                    Some(msg) if msg.first() == Some(&8) => report.cancels += 1,
+                    Some(_) => {}
+                    None => {
+                        if pending.is_empty() {
+                            continue;
+                        }
+
+                        report.batches.push(pending.len());
+                        for (piece, offset, length) in pending.drain(..) {
+                            let start = piece as usize * piece_length + offset as usize;
+                            let mut payload = piece.to_be_bytes().to_vec();
+                            payload.extend_from_slice(&offset.to_be_bytes());
+                            payload.extend_from_slice(&data[start..start + length as usize]);
+                            write_message(&mut stream, 7, &payload);
+                        }
+                    }
+                }
+            }
+
+            report
+        });
+
+        FakeSeeder { addr, handle }
+    }
+
+    /**
+     * Wait for the leecher to disconnect
+     */
+    pub fn join(self) -> SeederReport {
+        self.handle.join().unwrap()
+    }
+}
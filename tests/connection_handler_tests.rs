@@ -0,0 +1,386 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::choker;
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::ConnectionHandler;
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::torrent_context::TorrentContext;
+
+#[test]
+fn test_download_with_pipelined_requests() {
+    let data = common::test_data(10 * 32 * 1024 - 5000);
+    let torrent_file = Arc::new(common::build_torrent("pipelined.bin", &data, 32 * 1024));
+    let seeder = common::FakeSeeder::start(&torrent_file, data.clone());
+
+    let dir = common::temp_dir("pipelined");
+    let config = Arc::new(ClientConfig {
+        min_request_queue: 4,
+        max_request_queue: 8,
+        ..ClientConfig::default()
+    });
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        config,
+    );
+
+    let mut connection = ConnectionHandler::new(seeder.addr, &context);
+    connection.connect().unwrap();
+    drop(connection);
+
+    let batches = seeder.join().batches;
+    assert!(batches.iter().all(|batch| *batch <= 8));
+    assert!(batches.iter().any(|batch| *batch >= 4));
+
+    assert!(context.is_download_complete());
+    assert_eq!(context.piece_picker.lock().unwrap().remaining(), 0);
+    //the seeder is gone
+    assert_eq!(context.piece_picker.lock().unwrap().availability(0), 0);
+    assert_eq!(std::fs::read(dir.join("pipelined.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_endgame_does_not_stall_on_slow_peer() {
+    let data = common::test_data(6 * 32 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("endgame.bin", &data, 32 * 1024));
+    let stalled_seeder = common::FakeSeeder::start_stalled(&torrent_file, data.clone());
+    let seeder = common::FakeSeeder::start(&torrent_file, data.clone());
+
+    let dir = common::temp_dir("endgame");
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+
+    let stalled_addr = stalled_seeder.addr;
+    let stalled_context = context.clone();
+    let stalled_connection = thread::spawn(move || {
+        ConnectionHandler::new(stalled_addr, &stalled_context)
+            .connect()
+            .unwrap();
+    });
+
+    //let the stalled seeder get its requests first
+    thread::sleep(Duration::from_millis(300));
+    ConnectionHandler::new(seeder.addr, &context)
+        .connect()
+        .unwrap();
+    stalled_connection.join().unwrap();
+
+    let stalled_report = stalled_seeder.join();
+    assert!(stalled_report.requests > 0);
+    assert_eq!(stalled_report.cancels, stalled_report.requests);
+    seeder.join();
+
+    assert!(context.is_download_complete());
+    assert_eq!(std::fs::read(dir.join("endgame.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_cancel_drops_queued_uploads() {
+    let data = common::test_data(4 * 16 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("upload.bin", &data, 16 * 1024));
+
+    let dir = common::temp_dir("upload");
+    std::fs::write(dir.join("upload.bin"), &data).unwrap();
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent_file.info_hash;
+
+    let leecher = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut their_handshake = [0u8; 68];
+        stream.read_exact(&mut their_handshake).unwrap();
+        stream
+            .write_all(&common::handshake(&info_hash, b"-FL0001-leecherleech"))
+            .unwrap();
+
+        let request = |piece: u32, id: u8| {
+            let mut msg = 13u32.to_be_bytes().to_vec();
+            msg.push(id);
+            msg.extend_from_slice(&piece.to_be_bytes());
+            msg.extend_from_slice(&0u32.to_be_bytes());
+            msg.extend_from_slice(&(16 * 1024u32).to_be_bytes());
+            msg
+        };
+
+        //we are choked, this request must be refused
+        stream.write_all(&request(3, 6)).unwrap();
+
+        //interested, then wait for the choker to unchoke us
+        stream.write_all(&[0, 0, 0, 1, 2]).unwrap();
+        loop {
+            let msg = common::read_message(&mut stream).unwrap();
+            if msg.as_deref() == Some(&[1]) {
+                break;
+            }
+        }
+
+        //three requests and a cancel for the second one in a single write
+        let mut burst = Vec::new();
+        burst.extend(request(0, 6));
+        burst.extend(request(1, 6));
+        burst.extend(request(2, 6));
+        burst.extend(request(1, 8));
+        stream.write_all(&burst).unwrap();
+
+        stream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+
+        let mut pieces = Vec::new();
+        while let Ok(Some(msg)) = common::read_message(&mut stream) {
+            if msg.first() == Some(&7) {
+                pieces.push(u32::from_be_bytes(msg[1..5].try_into().unwrap()));
+            }
+        }
+        pieces
+    });
+
+    let choker_stopped = Arc::new(AtomicBool::new(false));
+    let choker = choker::spawn_choker(context.clone(), Arc::clone(&choker_stopped));
+
+    ConnectionHandler::new(addr, &context).connect().unwrap();
+    choker_stopped.store(true, Ordering::Relaxed);
+    choker.join().unwrap();
+
+    assert_eq!(leecher.join().unwrap(), vec![0, 2]);
+    assert_eq!(
+        context.file_handler.lock().unwrap().uploaded_bytes,
+        2 * 16 * 1024
+    );
+}
+
+#[test]
+fn test_peer_sending_corrupt_pieces_is_banned() {
+    let data = common::test_data(4 * 16 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("corrupt.bin", &data, 16 * 1024));
+    //serves data of the right size that does not match the piece hashes
+    let seeder = common::FakeSeeder::start(&torrent_file, vec![0xAAu8; data.len()]);
+
+    let dir = common::temp_dir("corrupt");
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+
+    ConnectionHandler::new(seeder.addr, &context)
+        .connect()
+        .unwrap();
+    seeder.join();
+
+    assert!(context.peer_pool.lock().unwrap().is_banned(seeder_ip()));
+    assert!(!context.is_download_complete());
+    //the failed pieces are downloaded again from other peers
+    assert_eq!(context.piece_picker.lock().unwrap().remaining(), 4);
+
+    //a banned peer is not queued again
+    assert_eq!(
+        context
+            .peer_pool
+            .lock()
+            .unwrap()
+            .add_peers([std::net::SocketAddr::new(seeder_ip(), 6881)]),
+        0
+    );
+}
+
+fn seeder_ip() -> std::net::IpAddr {
+    std::net::IpAddr::from([127, 0, 0, 1])
+}
+
+#[test]
+fn test_timed_out_requests_go_to_other_peers() {
+    let data = common::test_data(8 * 32 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("timeout.bin", &data, 32 * 1024));
+    let stalled_seeder = common::FakeSeeder::start_stalled(&torrent_file, data.clone());
+    let seeder = common::FakeSeeder::start(&torrent_file, data.clone());
+
+    let dir = common::temp_dir("timeout");
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig {
+            min_request_queue: 2,
+            max_request_queue: 2,
+            request_timeout: Duration::from_millis(500),
+            ..ClientConfig::default()
+        }),
+    );
+
+    let stalled_addr = stalled_seeder.addr;
+    let stalled_context = context.clone();
+    let stalled_connection = thread::spawn(move || {
+        ConnectionHandler::new(stalled_addr, &stalled_context)
+            .connect()
+            .unwrap();
+    });
+
+    //its requests expire before the other seeder shows up
+    thread::sleep(Duration::from_millis(1200));
+    let snubbed = context
+        .peer_registry
+        .lock()
+        .unwrap()
+        .stats()
+        .into_iter()
+        .any(|(peer, stats)| peer == stalled_addr && stats.snubbed);
+    assert!(snubbed);
+
+    ConnectionHandler::new(seeder.addr, &context)
+        .connect()
+        .unwrap();
+    stalled_connection.join().unwrap();
+
+    //the expired requests were cancelled, and it only got one request at a time since
+    let stalled_report = stalled_seeder.join();
+    assert!(stalled_report.requests >= 3);
+    assert_eq!(stalled_report.cancels, stalled_report.requests);
+    seeder.join();
+
+    assert!(context.is_download_complete());
+    assert_eq!(std::fs::read(dir.join("timeout.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_download_limit_slows_the_transfer() {
+    let data = common::test_data(4 * 32 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("limited.bin", &data, 32 * 1024));
+    let seeder = common::FakeSeeder::start(&torrent_file, data.clone());
+
+    let dir = common::temp_dir("limited");
+    let config = Arc::new(ClientConfig {
+        per_torrent_download_limit: 64 * 1024,
+        ..ClientConfig::default()
+    });
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        config,
+    );
+
+    let start = std::time::Instant::now();
+    let mut connection = ConnectionHandler::new(seeder.addr, &context);
+    connection.connect().unwrap();
+    drop(connection);
+    seeder.join();
+
+    //128KiB at 64KiB/s, minus what the bucket had saved
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+    assert!(context.is_download_complete());
+    assert_eq!(std::fs::read(dir.join("limited.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_peer_sending_a_short_bitfield_is_dropped() {
+    let data = common::test_data(20 * 16 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("short.bin", &data, 16 * 1024));
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &common::temp_dir("short-bitfield")),
+        Arc::new(ClientConfig::default()),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent_file.info_hash;
+
+    let peer = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut their_handshake = [0u8; 68];
+        stream.read_exact(&mut their_handshake).unwrap();
+        stream
+            .write_all(&common::handshake(&info_hash, b"-FL0001-shortbitfiel"))
+            .unwrap();
+
+        //20 pieces need 3 bytes, the have goes past the single byte sent
+        common::write_message(&mut stream, 5, &[0xff]);
+        common::write_message(&mut stream, 4, &19u32.to_be_bytes());
+
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let started = Instant::now();
+        while let Ok(Some(_)) = common::read_message(&mut stream) {}
+        started.elapsed()
+    });
+
+    ConnectionHandler::new(addr, &context).connect().unwrap();
+
+    assert!(peer.join().unwrap() < Duration::from_secs(5));
+    assert_eq!(context.piece_picker.lock().unwrap().availability(0), 0);
+}
+
+#[test]
+fn test_pieces_from_one_peer_are_announced_to_the_others() {
+    let data = common::test_data(4 * 16 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("broadcast.bin", &data, 16 * 1024));
+    let seeder = common::FakeSeeder::start(&torrent_file, data.clone());
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &common::temp_dir("broadcast")),
+        Arc::new(ClientConfig::default()),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let observer_addr = listener.local_addr().unwrap();
+    let info_hash = torrent_file.info_hash;
+
+    //a peer with nothing to give, it only hears about our pieces
+    let observer = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut their_handshake = [0u8; 68];
+        stream.read_exact(&mut their_handshake).unwrap();
+        stream
+            .write_all(&common::handshake(&info_hash, b"-FL0001-observerobse"))
+            .unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let mut haves = Vec::new();
+        let started = Instant::now();
+        while haves.len() < 4 && started.elapsed() < Duration::from_secs(5) {
+            if let Ok(Some(msg)) = common::read_message(&mut stream)
+                && msg.first() == Some(&4)
+            {
+                haves.push(u32::from_be_bytes(msg[1..5].try_into().unwrap()));
+            }
+        }
+        haves
+    });
+
+    let observer_context = context.clone();
+    let observer_connection = thread::spawn(move || {
+        let _ = ConnectionHandler::new(observer_addr, &observer_context).connect();
+    });
+    while context.peer_registry.lock().unwrap().peers().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    ConnectionHandler::new(seeder.addr, &context)
+        .connect()
+        .unwrap();
+    seeder.join();
+    assert!(context.is_download_complete());
+
+    let mut haves = observer.join().unwrap();
+    haves.sort();
+    assert_eq!(haves, vec![0, 1, 2, 3]);
+    observer_connection.join().unwrap();
+}
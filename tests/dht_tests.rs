@@ -0,0 +1,423 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::message::PeerMessage;
+use rust_torrent::dht::krpc::{
+    Body, METHOD_UNKNOWN, Message, NodeInfo, PROTOCOL_ERROR, Query, Response,
+};
+use rust_torrent::dht::routing_table::{K, RoutingTable};
+use rust_torrent::dht::{DHT_BIT, DHT_BYTE, Dht, MAX_STORED_TORRENTS};
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::peer_listener::PeerListener;
+use rust_torrent::torrent_context::TorrentContext;
+use rust_torrent::torrent_file::TorrentFile;
+
+fn node(first_byte: u8, last_byte: u8, port: u16) -> NodeInfo {
+    let mut id = [0u8; 20];
+    id[0] = first_byte;
+    id[19] = last_byte;
+
+    NodeInfo {
+        id,
+        addr: SocketAddr::from(([127, 0, 0, 1], port)),
+    }
+}
+
+fn start_nodes(amount: usize) -> Vec<Dht> {
+    (0..amount)
+        .map(|_| Dht::bind("127.0.0.1:0").unwrap())
+        .collect()
+}
+
+/**
+ * Every node joins through the first one
+ */
+fn bootstrap_network(nodes: &[Dht]) {
+    let entry = nodes[0].local_addr();
+    for node in &nodes[1..] {
+        assert!(node.bootstrap(&[entry]) > 0);
+    }
+}
+
+/**
+ * Answer of a single query sent from a raw socket
+ */
+fn raw_query(to: SocketAddr, query: Query) -> Message {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let message = Message {
+        transaction_id: b"aa".to_vec(),
+        body: Body::Query {
+            sender: [7u8; 20],
+            query,
+        },
+    };
+    socket.send_to(&message.encode(), to).unwrap();
+
+    let mut buf = [0u8; 4096];
+    let (read, _) = socket.recv_from(&mut buf).unwrap();
+    let answer = Message::parse(&buf[..read]).unwrap();
+    assert_eq!(answer.transaction_id, b"aa");
+
+    return answer;
+}
+
+#[test]
+fn test_krpc_messages_round_trip() {
+    let messages = vec![
+        Body::Query {
+            sender: [1u8; 20],
+            query: Query::Ping,
+        },
+        Body::Query {
+            sender: [1u8; 20],
+            query: Query::FindNode { target: [2u8; 20] },
+        },
+        Body::Query {
+            sender: [1u8; 20],
+            query: Query::GetPeers {
+                info_hash: [3u8; 20],
+            },
+        },
+        Body::Query {
+            sender: [1u8; 20],
+            query: Query::AnnouncePeer {
+                info_hash: [3u8; 20],
+                port: 6881,
+                implied_port: true,
+                token: b"secret".to_vec(),
+            },
+        },
+        Body::Response {
+            sender: [4u8; 20],
+            response: Response {
+                nodes: vec![node(1, 1, 1000), node(2, 2, 2000)],
+                values: vec![SocketAddr::from(([10, 0, 0, 1], 51413))],
+                token: Some(b"token".to_vec()),
+            },
+        },
+        Body::Error {
+            code: METHOD_UNKNOWN,
+            message: String::from("method unknown"),
+        },
+    ];
+
+    for body in messages {
+        let message = Message {
+            transaction_id: b"xy".to_vec(),
+            body,
+        };
+        assert_eq!(Message::parse(&message.encode()), Ok(message));
+    }
+
+    //the example of BEP 5
+    let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    assert_eq!(
+        Message::parse(ping),
+        Ok(Message {
+            transaction_id: b"aa".to_vec(),
+            body: Body::Query {
+                sender: *b"abcdefghij0123456789",
+                query: Query::Ping,
+            },
+        })
+    );
+
+    assert!(Message::parse(b"d1:t2:aa1:y1:qe").is_err());
+    assert!(Message::parse(b"d1:t2:aa1:y1:rd2:id3:abcee").is_err());
+    assert!(Message::parse(b"garbage").is_err());
+}
+
+#[test]
+fn test_routing_table_buckets() {
+    let mut table = RoutingTable::new([0u8; 20]);
+
+    //our own id is never stored
+    assert!(!table.insert(NodeInfo {
+        id: [0u8; 20],
+        addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+    }));
+
+    //all in the farthest bucket, it keeps the first K
+    for index in 0..K as u8 + 2 {
+        let inserted = table.insert(node(0x80, index, 1000 + index as u16));
+        assert_eq!(inserted, (index as usize) < K);
+    }
+    assert_eq!(table.len(), K);
+
+    //nodes not answering make room
+    let failing = node(0x80, 3, 1003);
+    table.mark_failed(failing.addr);
+    table.mark_failed(failing.addr);
+    assert!(!table.closest(&[0u8; 20], 20).contains(&failing));
+    assert!(table.insert(node(0x80, 100, 2000)));
+    assert_eq!(table.len(), K);
+
+    //closer buckets have their own room
+    assert!(table.insert(node(0x01, 0, 3000)));
+    assert!(table.insert(node(0x00, 1, 3001)));
+
+    let closest = table.closest(&[0u8; 20], 2);
+    assert_eq!(closest, vec![node(0x00, 1, 3001), node(0x01, 0, 3000)]);
+}
+
+#[test]
+fn test_routing_table_is_saved_and_loaded() {
+    let mut table = RoutingTable::new([9u8; 20]);
+    table.insert(node(1, 0, 1000));
+    table.insert(node(2, 0, 2000));
+
+    let path = common::temp_dir("dht_state").join("dht.dat");
+    table.save(&path).unwrap();
+
+    let loaded = RoutingTable::load(&path).unwrap();
+    assert_eq!(loaded.id(), [9u8; 20]);
+    let mut nodes = loaded.nodes();
+    nodes.sort_by_key(|node| node.addr.port());
+    assert_eq!(nodes, vec![node(1, 0, 1000), node(2, 0, 2000)]);
+
+    assert!(RoutingTable::from_bytes(b"d2:id3:abce").is_err());
+}
+
+#[test]
+fn test_nodes_find_each_other_and_share_peers() {
+    let nodes = start_nodes(10);
+    bootstrap_network(&nodes);
+
+    //the lookup reaches nodes the first one only knows about
+    let target = nodes[9].id();
+    let found = nodes[5].find_node(target);
+    assert_eq!(found.first().map(|node| node.id), Some(target));
+
+    let info_hash = [0x42u8; 20];
+    assert!(nodes[3].announce(info_hash, 6000).is_empty());
+
+    let peers = nodes[7].get_peers(info_hash);
+    let announced = SocketAddr::from(([127, 0, 0, 1], 6000));
+    assert_eq!(peers, vec![announced]);
+
+    //a later announce gets the peers too
+    assert_eq!(nodes[8].announce(info_hash, 7000), vec![announced]);
+}
+
+#[test]
+fn test_announce_needs_a_valid_token() {
+    let nodes = start_nodes(1);
+    let info_hash = [0x24u8; 20];
+
+    let answer = raw_query(
+        nodes[0].local_addr(),
+        Query::AnnouncePeer {
+            info_hash,
+            port: 6000,
+            implied_port: false,
+            token: b"made up".to_vec(),
+        },
+    );
+    assert!(matches!(
+        answer.body,
+        Body::Error {
+            code: PROTOCOL_ERROR,
+            ..
+        }
+    ));
+
+    let token = match raw_query(nodes[0].local_addr(), Query::GetPeers { info_hash }).body {
+        Body::Response { response, .. } => response.token.unwrap(),
+        body => panic!("unexpected answer {body:?}"),
+    };
+    let answer = raw_query(
+        nodes[0].local_addr(),
+        Query::AnnouncePeer {
+            info_hash,
+            port: 6000,
+            implied_port: false,
+            token,
+        },
+    );
+    assert!(matches!(answer.body, Body::Response { .. }));
+
+    let answer = raw_query(
+        nodes[0].local_addr(),
+        Query::Unknown {
+            method: String::from("vote"),
+        },
+    );
+    assert!(matches!(
+        answer.body,
+        Body::Error {
+            code: METHOD_UNKNOWN,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_stored_torrents_are_capped() {
+    let nodes = start_nodes(1);
+    let to = nodes[0].local_addr();
+
+    //tokens only depend on the address, one is enough for every info hash
+    let token = match raw_query(
+        to,
+        Query::GetPeers {
+            info_hash: [0u8; 20],
+        },
+    )
+    .body
+    {
+        Body::Response { response, .. } => response.token.unwrap(),
+        body => panic!("unexpected answer {body:?}"),
+    };
+
+    let info_hash = |index: usize| {
+        let mut info_hash = [0u8; 20];
+        info_hash[..8].copy_from_slice(&(index as u64).to_be_bytes());
+        info_hash
+    };
+    for index in 0..=MAX_STORED_TORRENTS {
+        let answer = raw_query(
+            to,
+            Query::AnnouncePeer {
+                info_hash: info_hash(index),
+                port: 6000,
+                implied_port: false,
+                token: token.clone(),
+            },
+        );
+        assert!(matches!(answer.body, Body::Response { .. }));
+    }
+
+    let values = |index: usize| match raw_query(
+        to,
+        Query::GetPeers {
+            info_hash: info_hash(index),
+        },
+    )
+    .body
+    {
+        Body::Response { response, .. } => response.values.len(),
+        body => panic!("unexpected answer {body:?}"),
+    };
+    assert_eq!(values(0), 1);
+    assert_eq!(values(MAX_STORED_TORRENTS - 1), 1);
+    assert_eq!(values(MAX_STORED_TORRENTS), 0);
+}
+
+#[test]
+fn test_unsolicited_responses_do_not_join_the_routing_table() {
+    let nodes = start_nodes(1);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let response = Message {
+        transaction_id: b"zz".to_vec(),
+        body: Body::Response {
+            sender: [9u8; 20],
+            response: Response::default(),
+        },
+    };
+    socket
+        .send_to(&response.encode(), nodes[0].local_addr())
+        .unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(nodes[0].nodes().is_empty());
+
+    //a query makes the sender known the regular way
+    raw_query(nodes[0].local_addr(), Query::Ping);
+    assert_eq!(nodes[0].nodes().len(), 1);
+}
+
+#[test]
+fn test_torrent_nodes_are_parsed() {
+    let mut bytes = b"d5:nodesll9:127.0.0.1i6881eel11:example.comi1234eel3:badee".to_vec();
+    bytes.extend_from_slice(b"4:infod6:lengthi10e4:name5:a.bin12:piece lengthi16384e6:pieces20:");
+    bytes.extend_from_slice(&[0u8; 20]);
+    bytes.extend_from_slice(b"ee");
+
+    let torrent = TorrentFile::from(bytes);
+    assert_eq!(
+        torrent.nodes,
+        vec![
+            String::from("127.0.0.1:6881"),
+            String::from("example.com:1234")
+        ]
+    );
+    assert_eq!(torrent.info.name, "a.bin");
+}
+
+#[test]
+fn test_port_message_adds_the_peer_node() {
+    let nodes = start_nodes(2);
+
+    let data = common::test_data(16 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("dht_port.bin", &data, 16 * 1024));
+    let dir = common::temp_dir("dht_port");
+    std::fs::write(dir.join("dht_port.bin"), &data).unwrap();
+    let mut seeder = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+    let seeder_dht = Arc::new(nodes.into_iter().next().unwrap());
+    seeder.dht = Some(Arc::clone(&seeder_dht));
+    let listener = PeerListener::start(
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap(),
+        vec![seeder],
+    )
+    .unwrap();
+
+    //our own DHT node the seeder should learn about
+    let peer_dht = Dht::bind("127.0.0.1:0").unwrap();
+
+    let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut handshake = common::handshake(&torrent_file.info_hash, b"-FL0001-dhtport00000");
+    handshake[20 + DHT_BYTE] |= DHT_BIT;
+    stream.write_all(&handshake).unwrap();
+
+    let mut peer_handshake = [0u8; 68];
+    stream.read_exact(&mut peer_handshake).unwrap();
+    assert_ne!(peer_handshake[20 + DHT_BYTE] & DHT_BIT, 0);
+
+    //the seeder tells us where its node is
+    let mut seeder_port = None;
+    while let Ok(Some(msg)) = common::read_message(&mut stream) {
+        if let Ok(PeerMessage::Port { port }) = PeerMessage::parse(&msg) {
+            seeder_port = Some(port);
+            break;
+        }
+    }
+    assert_eq!(seeder_port, Some(seeder_dht.local_addr().port()));
+
+    stream
+        .write_all(
+            &PeerMessage::Port {
+                port: peer_dht.local_addr().port(),
+            }
+            .to_bytes(),
+        )
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !seeder_dht
+        .nodes()
+        .iter()
+        .any(|node| node.id == peer_dht.id())
+    {
+        assert!(Instant::now() < deadline, "the peer node was not added");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    listener.stop();
+}
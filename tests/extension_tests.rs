@@ -0,0 +1,275 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::bencode::{BencodeValue, decode_value};
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::ConnectionHandler;
+use rust_torrent::connection_handler::handshake::Handshake;
+use rust_torrent::extension::{
+    ExtendedHandshake, ExtensionContext, ExtensionHandler, ExtensionRegistry, extended_message,
+};
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::torrent_context::TorrentContext;
+
+const FAKE_PEER_ID: &[u8; 20] = b"-FP0001-extensionpee";
+
+/**
+ * Sends every message back and remembers what it received
+ */
+struct EchoExtension {
+    received: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl ExtensionHandler for EchoExtension {
+    fn name(&self) -> &'static str {
+        "ut_echo"
+    }
+
+    fn on_message(&mut self, payload: &[u8], context: &mut ExtensionContext) -> Result<(), String> {
+        if payload == b"bad" {
+            return Err(String::from("bad payload"));
+        }
+
+        self.received.lock().unwrap().push(payload.to_vec());
+        context.send(payload.to_vec());
+        Ok(())
+    }
+}
+
+fn context(name: &str, received: &Arc<Mutex<Vec<Vec<u8>>>>) -> TorrentContext {
+    let data = common::test_data(1000);
+    let torrent_file = Arc::new(common::build_torrent(name, &data, 16 * 1024));
+    let dir = common::temp_dir("extension");
+    std::fs::write(dir.join(name), &data).unwrap();
+
+    let mut context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig {
+            port: 6999,
+            ..ClientConfig::default()
+        }),
+    );
+
+    let received = Arc::clone(received);
+    context.register_extension(move || {
+        Box::new(EchoExtension {
+            received: Arc::clone(&received),
+        })
+    });
+
+    context
+}
+
+#[test]
+fn test_extended_handshake_round_trip() {
+    let mut handshake = ExtendedHandshake {
+        v: Some(String::from("test 1.0")),
+        p: Some(6881),
+        reqq: Some(500),
+        yourip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+        metadata_size: Some(31235),
+        ..ExtendedHandshake::default()
+    };
+    handshake.m.insert(String::from("ut_metadata"), 3);
+    handshake.m.insert(String::from("ut_pex"), 1);
+
+    assert_eq!(
+        ExtendedHandshake::parse(&handshake.to_bytes()).unwrap(),
+        handshake
+    );
+
+    handshake.yourip = Some(IpAddr::V6(Ipv6Addr::LOCALHOST));
+    assert_eq!(
+        ExtendedHandshake::parse(&handshake.to_bytes()).unwrap(),
+        handshake
+    );
+}
+
+#[test]
+fn test_extended_handshake_ignores_invalid_keys() {
+    let payload = BencodeValue::dictionary(vec![
+        (
+            "m",
+            BencodeValue::dictionary(vec![
+                ("ut_metadata", BencodeValue::Integer(2)),
+                ("too_large", BencodeValue::Integer(300)),
+                ("not_an_id", BencodeValue::from("x")),
+            ]),
+        ),
+        ("p", BencodeValue::Integer(-1)),
+        ("yourip", BencodeValue::from(vec![1u8, 2, 3])),
+        ("unknown", BencodeValue::List(vec![])),
+    ])
+    .encode();
+
+    let handshake = ExtendedHandshake::parse(&payload).unwrap();
+    assert_eq!(handshake.m.len(), 1);
+    assert_eq!(handshake.m["ut_metadata"], 2);
+    assert_eq!(handshake.p, None);
+    assert_eq!(handshake.yourip, None);
+
+    //malformed input is an error, not a panic
+    assert!(ExtendedHandshake::parse(b"i42e").is_err());
+    assert!(ExtendedHandshake::parse(b"d1:mde").is_err());
+    assert!(ExtendedHandshake::parse(b"d1:m").is_err());
+    assert!(ExtendedHandshake::parse(b"d99999999999:m").is_err());
+    assert!(ExtendedHandshake::parse(&[b'l'; 1000]).is_err());
+}
+
+#[test]
+fn test_decode_value_returns_used_length() {
+    let mut data = b"d8:msg_typei1e5:piecei0ee".to_vec();
+    data.extend_from_slice(b"raw piece data");
+
+    let (value, used) = decode_value(&data).unwrap();
+    assert_eq!(value.get("msg_type").unwrap().as_integer(), Some(1));
+    assert_eq!(&data[used..], b"raw piece data");
+}
+
+#[test]
+fn test_registry_routes_by_negotiated_id() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let torrent = context("registry.bin", &received);
+    let peer: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+    let mut registry = ExtensionRegistry::new();
+    registry.register(Box::new(EchoExtension {
+        received: Arc::clone(&received),
+    }));
+
+    let our_handshake = registry.our_handshake(ExtendedHandshake::default(), &torrent);
+    let local_id = registry.local_id("ut_echo").unwrap();
+    assert_eq!(our_handshake.m["ut_echo"], local_id);
+
+    //the peer did not advertise the extension yet, the answer is dropped
+    let messages = registry
+        .on_message(local_id, b"one", peer, &torrent)
+        .unwrap();
+    assert!(messages.is_empty());
+
+    let mut peer_handshake = ExtendedHandshake::default();
+    peer_handshake.m.insert(String::from("ut_echo"), 9);
+    registry.on_peer_handshake(&peer_handshake, peer, &torrent);
+
+    let messages = registry
+        .on_message(local_id, b"two", peer, &torrent)
+        .unwrap();
+    assert_eq!(messages, vec![(9, b"two".to_vec())]);
+
+    //unknown ids are ignored, handler errors are reported
+    assert!(
+        registry
+            .on_message(42, b"three", peer, &torrent)
+            .unwrap()
+            .is_empty()
+    );
+    assert!(
+        registry
+            .on_message(local_id, b"bad", peer, &torrent)
+            .is_err()
+    );
+
+    //a later handshake with id 0 disables the extension
+    peer_handshake.m.insert(String::from("ut_echo"), 0);
+    registry.on_peer_handshake(&peer_handshake, peer, &torrent);
+    assert_eq!(registry.peer_id("ut_echo"), None);
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![b"one".to_vec(), b"two".to_vec()]
+    );
+}
+
+#[test]
+fn test_extension_messages_over_connection() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let context = context("extension.bin", &received);
+    let info_hash = context.torrent_file.info_hash;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let fake_peer = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut their_handshake = [0u8; 68];
+        stream.read_exact(&mut their_handshake).unwrap();
+        assert!(
+            Handshake::parse(&their_handshake)
+                .unwrap()
+                .supports_extension_protocol()
+        );
+
+        stream
+            .write_all(&Handshake::new(info_hash, *FAKE_PEER_ID).to_bytes())
+            .unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let mut their_extended_handshake = None;
+        let mut echoed = None;
+        let started_at = Instant::now();
+
+        while echoed.is_none() && started_at.elapsed() < Duration::from_secs(5) {
+            let msg = match common::read_message(&mut stream) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(()) => break,
+            };
+
+            match (msg.first(), msg.get(1)) {
+                (Some(20), Some(0)) => {
+                    let handshake = ExtendedHandshake::parse(&msg[2..]).unwrap();
+                    let local_id = handshake.m["ut_echo"];
+                    their_extended_handshake = Some(handshake);
+
+                    let mut ours = ExtendedHandshake::default();
+                    ours.m.insert(String::from("ut_echo"), 7);
+                    stream
+                        .write_all(&extended_message(0, &ours.to_bytes()))
+                        .unwrap();
+                    stream
+                        .write_all(&extended_message(local_id, b"ping"))
+                        .unwrap();
+                }
+                (Some(20), Some(7)) => echoed = Some(msg[2..].to_vec()),
+                _ => {}
+            }
+        }
+
+        (their_extended_handshake, echoed)
+    });
+
+    let mut connection = ConnectionHandler::new(addr, &context);
+    connection.connect().unwrap();
+
+    assert_eq!(
+        connection.peer_extended_handshake().unwrap().m["ut_echo"],
+        7
+    );
+
+    let (their_extended_handshake, echoed) = fake_peer.join().unwrap();
+    let their_extended_handshake = their_extended_handshake.unwrap();
+
+    assert!(
+        their_extended_handshake
+            .v
+            .unwrap()
+            .starts_with("rust-torrent")
+    );
+    assert_eq!(their_extended_handshake.p, Some(6999));
+    assert_eq!(their_extended_handshake.reqq, Some(250));
+    assert_eq!(
+        their_extended_handshake.yourip,
+        Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    );
+    assert_eq!(echoed, Some(b"ping".to_vec()));
+    assert_eq!(*received.lock().unwrap(), vec![b"ping".to_vec()]);
+}
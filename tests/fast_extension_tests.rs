@@ -0,0 +1,362 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::choker;
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::ConnectionHandler;
+use rust_torrent::connection_handler::fast::{ALLOWED_FAST_COUNT, allowed_fast_set};
+use rust_torrent::connection_handler::handshake::Handshake;
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::piece_picker::PiecePriority;
+use rust_torrent::torrent_context::TorrentContext;
+
+const FAKE_PEER_ID: &[u8; 20] = b"-FP0001-fastpeerfast";
+
+const HAVE_ALL: u8 = 14;
+const HAVE_NONE: u8 = 15;
+const REJECT_REQUEST: u8 = 16;
+const ALLOWED_FAST: u8 = 17;
+
+fn block_payload(piece: u32, offset: u32, length: u32) -> Vec<u8> {
+    [piece, offset, length]
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+/**
+ * Answer the handshake of the client with the fast extension bit set
+ */
+fn accept_fast_peer(listener: TcpListener, info_hash: [u8; 20]) -> TcpStream {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut their_handshake = [0u8; 68];
+    stream.read_exact(&mut their_handshake).unwrap();
+    assert!(
+        Handshake::parse(&their_handshake)
+            .unwrap()
+            .supports_fast_extension()
+    );
+
+    stream
+        .write_all(&Handshake::new(info_hash, *FAKE_PEER_ID).to_bytes())
+        .unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+
+    stream
+}
+
+#[test]
+fn test_allowed_fast_set_matches_bep6() {
+    let info_hash = [0xaa; 20];
+    let ip = IpAddr::V4(Ipv4Addr::new(80, 4, 4, 200));
+
+    assert_eq!(
+        allowed_fast_set(&info_hash, ip, 1313, 7),
+        vec![1059, 431, 808, 1217, 287, 376, 1188]
+    );
+    assert_eq!(
+        allowed_fast_set(&info_hash, ip, 1313, 9),
+        vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+    );
+
+    //same set for the whole /24
+    let neighbour = IpAddr::V4(Ipv4Addr::new(80, 4, 4, 1));
+    assert_eq!(
+        allowed_fast_set(&info_hash, neighbour, 1313, 7),
+        allowed_fast_set(&info_hash, ip, 1313, 7)
+    );
+
+    //never more pieces than the torrent has
+    assert_eq!(allowed_fast_set(&info_hash, ip, 3, 10).len(), 3);
+    assert!(allowed_fast_set(&info_hash, "::1".parse().unwrap(), 1313, 7).is_empty());
+}
+
+#[test]
+fn test_seeder_sends_have_all_and_rejects_choked_requests() {
+    let data = common::test_data(100 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("fast_seed.bin", &data, 1024));
+    let dir = common::temp_dir("fast_seed");
+    std::fs::write(dir.join("fast_seed.bin"), &data).unwrap();
+
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+    let info_hash = torrent_file.info_hash;
+
+    let allowed = allowed_fast_set(
+        &info_hash,
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        torrent_file.pieces_amount,
+        ALLOWED_FAST_COUNT,
+    );
+    let refused = (0..100).find(|piece| !allowed.contains(piece)).unwrap();
+    let first_allowed = allowed[0];
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let fake_peer = thread::spawn(move || {
+        let mut stream = accept_fast_peer(listener, info_hash);
+
+        common::write_message(&mut stream, HAVE_NONE, &[]);
+        //we are choked: only the allowed fast piece is served
+        common::write_message(&mut stream, 6, &block_payload(refused, 0, 1024));
+        common::write_message(&mut stream, 6, &block_payload(first_allowed, 0, 1024));
+
+        let mut first_message = None;
+        let mut allowed_fast = Vec::new();
+        let mut rejected = Vec::new();
+        let mut served = Vec::new();
+        let started_at = Instant::now();
+
+        while (rejected.is_empty() || served.is_empty())
+            && started_at.elapsed() < Duration::from_secs(5)
+        {
+            let msg = match common::read_message(&mut stream) {
+                Ok(Some(msg)) if !msg.is_empty() => msg,
+                Ok(_) => continue,
+                Err(()) => break,
+            };
+
+            first_message.get_or_insert(msg[0]);
+            let piece = msg
+                .get(1..5)
+                .map(|index| u32::from_be_bytes(index.try_into().unwrap()));
+
+            match msg[0] {
+                ALLOWED_FAST => allowed_fast.push(piece.unwrap()),
+                REJECT_REQUEST => rejected.push(msg[1..].to_vec()),
+                7 => served.push(piece.unwrap()),
+                _ => {}
+            }
+        }
+
+        (first_message, allowed_fast, rejected, served)
+    });
+
+    let mut connection = ConnectionHandler::new(addr, &context);
+    connection.connect().unwrap();
+
+    let (first_message, allowed_fast, rejected, served) = fake_peer.join().unwrap();
+    assert_eq!(first_message, Some(HAVE_ALL));
+    assert_eq!(allowed_fast, allowed);
+    assert_eq!(rejected, vec![block_payload(refused, 0, 1024)]);
+    assert_eq!(served, vec![first_allowed]);
+}
+
+#[test]
+fn test_requests_for_missing_pieces_or_past_the_piece_are_rejected() {
+    let data = common::test_data(100 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("fast_partial.bin", &data, 1024));
+    let info_hash = torrent_file.info_hash;
+    let (missing, kept) = (3u32, 4u32);
+
+    //piece 3 is corrupt on disk, so we do not have it
+    let mut on_disk = data.clone();
+    on_disk[missing as usize * 1024] ^= 0xff;
+    let dir = common::temp_dir("fast_partial");
+    std::fs::write(dir.join("fast_partial.bin"), &on_disk).unwrap();
+
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+    assert!(!context.piece_picker.lock().unwrap().has(missing as usize));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let fake_peer = thread::spawn(move || {
+        let mut stream = accept_fast_peer(listener, info_hash);
+
+        //interested, then wait for the choker to unchoke us
+        common::write_message(&mut stream, HAVE_NONE, &[]);
+        common::write_message(&mut stream, 2, &[]);
+        let started_at = Instant::now();
+        while started_at.elapsed() < Duration::from_secs(5) {
+            if let Ok(Some(msg)) = common::read_message(&mut stream)
+                && msg == [1]
+            {
+                break;
+            }
+        }
+
+        common::write_message(&mut stream, 6, &block_payload(missing, 0, 1024));
+        //would end inside the next piece
+        common::write_message(&mut stream, 6, &block_payload(kept, 512, 1024));
+        common::write_message(&mut stream, 6, &block_payload(kept, 0, 1024));
+
+        let mut rejected = Vec::new();
+        let mut served = Vec::new();
+
+        while (rejected.len() < 2 || served.is_empty())
+            && started_at.elapsed() < Duration::from_secs(10)
+        {
+            let msg = match common::read_message(&mut stream) {
+                Ok(Some(msg)) if !msg.is_empty() => msg,
+                Ok(_) => continue,
+                Err(()) => break,
+            };
+
+            match msg[0] {
+                REJECT_REQUEST => rejected.push(msg[1..].to_vec()),
+                7 => served.push(u32::from_be_bytes(msg[1..5].try_into().unwrap())),
+                _ => {}
+            }
+        }
+
+        (rejected, served)
+    });
+
+    let choker_stopped = Arc::new(AtomicBool::new(false));
+    let choker = choker::spawn_choker(context.clone(), Arc::clone(&choker_stopped));
+
+    ConnectionHandler::new(addr, &context).connect().unwrap();
+    choker_stopped.store(true, Ordering::Relaxed);
+    choker.join().unwrap();
+
+    let (rejected, served) = fake_peer.join().unwrap();
+    assert_eq!(
+        rejected,
+        vec![
+            block_payload(missing, 0, 1024),
+            block_payload(kept, 512, 1024)
+        ]
+    );
+    assert_eq!(served, vec![kept]);
+}
+
+#[test]
+fn test_skipped_missing_pieces_are_not_announced() {
+    let data = common::test_data(20 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("fast_skip.bin", &data, 1024));
+    let info_hash = torrent_file.info_hash;
+
+    //piece 3 is corrupt on disk and skipped, nothing remains to download
+    let mut on_disk = data.clone();
+    on_disk[3 * 1024] ^= 0xff;
+    let dir = common::temp_dir("fast_skip");
+    std::fs::write(dir.join("fast_skip.bin"), &on_disk).unwrap();
+
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+    context
+        .piece_picker
+        .lock()
+        .unwrap()
+        .set_priority(3, PiecePriority::Skip);
+    assert_eq!(context.piece_picker.lock().unwrap().remaining(), 0);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let fake_peer = thread::spawn(move || {
+        let mut stream = accept_fast_peer(listener, info_hash);
+
+        let started_at = Instant::now();
+        while started_at.elapsed() < Duration::from_secs(5) {
+            if let Ok(Some(msg)) = common::read_message(&mut stream)
+                && !msg.is_empty()
+            {
+                return msg;
+            }
+        }
+
+        Vec::new()
+    });
+
+    ConnectionHandler::new(addr, &context).connect().unwrap();
+
+    assert_eq!(
+        fake_peer.join().unwrap(),
+        vec![5, 0b1110_1111, 0xff, 0b1111_0000]
+    );
+}
+
+#[test]
+fn test_rejected_blocks_are_requested_again() {
+    let data = common::test_data(2 * 1024);
+    let torrent_file = Arc::new(common::build_torrent("fast_leech.bin", &data, 1024));
+    let dir = common::temp_dir("fast_leech");
+
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+    let info_hash = torrent_file.info_hash;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let fake_peer = thread::spawn(move || {
+        let mut stream = accept_fast_peer(listener, info_hash);
+
+        common::write_message(&mut stream, HAVE_ALL, &[]);
+        common::write_message(&mut stream, 1, &[]);
+
+        let mut first_message = None;
+        let mut requests = Vec::new();
+        let mut unchoked_again = false;
+        let started_at = Instant::now();
+
+        while requests.len() < 4 && started_at.elapsed() < Duration::from_secs(5) {
+            let msg = match common::read_message(&mut stream) {
+                Ok(Some(msg)) if !msg.is_empty() => msg,
+                Ok(_) => {
+                    //both pieces were rejected, the client waits for the next unchoke
+                    if requests.len() == 2 && !unchoked_again {
+                        unchoked_again = true;
+                        common::write_message(&mut stream, 1, &[]);
+                    }
+                    continue;
+                }
+                Err(()) => break,
+            };
+
+            first_message.get_or_insert(msg[0]);
+
+            if msg[0] == 6 {
+                requests.push(msg[1..].to_vec());
+                if !unchoked_again {
+                    common::write_message(&mut stream, REJECT_REQUEST, &msg[1..]);
+                }
+            }
+        }
+
+        (first_message, requests)
+    });
+
+    let mut connection = ConnectionHandler::new(addr, &context);
+    connection.connect().unwrap();
+
+    let (first_message, mut requests) = fake_peer.join().unwrap();
+    assert_eq!(first_message, Some(HAVE_NONE));
+
+    //the two rejected blocks went back to the picker and were asked again
+    requests.sort();
+    assert_eq!(
+        requests,
+        vec![
+            block_payload(0, 0, 1024),
+            block_payload(0, 0, 1024),
+            block_payload(1, 0, 1024),
+            block_payload(1, 0, 1024),
+        ]
+    );
+}
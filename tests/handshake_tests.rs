@@ -0,0 +1,155 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::ConnectionHandler;
+use rust_torrent::connection_handler::handshake::{Handshake, HandshakeError};
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::torrent_context::TorrentContext;
+
+const OUR_PEER_ID: &[u8; 20] = b"-TR3000-handshake000";
+
+#[test]
+fn test_handshake_round_trip() {
+    let mut handshake = Handshake::new([3u8; 20], *OUR_PEER_ID);
+    handshake.reserved[5] = 0x10;
+
+    let bytes = handshake.to_bytes();
+    assert_eq!(&bytes[0..20], b"\x13BitTorrent protocol");
+    assert_eq!(Handshake::parse(&bytes).unwrap(), handshake);
+
+    let mut invalid = bytes;
+    invalid[3] = b'X';
+    assert!(matches!(
+        Handshake::parse(&invalid),
+        Err(HandshakeError::InvalidProtocol)
+    ));
+}
+
+/**
+ * Fake peer answering with the given bytes in several writes, then closing the connection
+ * after hold_open
+ */
+fn fake_peer(chunks: Vec<Vec<u8>>, hold_open: Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut their_handshake = [0u8; 68];
+        stream.read_exact(&mut their_handshake).unwrap();
+
+        for chunk in chunks {
+            stream.write_all(&chunk).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        thread::sleep(hold_open);
+    });
+
+    addr
+}
+
+fn handshake_with(addr: SocketAddr) -> Result<(), HandshakeError> {
+    let data = common::test_data(1000);
+    let torrent_file = Arc::new(common::build_torrent("handshake.bin", &data, 16 * 1024));
+    let dir = common::temp_dir("handshake");
+    std::fs::write(dir.join("handshake.bin"), &data).unwrap();
+
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig {
+            peer_id: *OUR_PEER_ID,
+            handshake_timeout: Duration::from_millis(500),
+            ..ClientConfig::default()
+        }),
+    );
+
+    let mut connection = ConnectionHandler::new(addr, &context);
+    let result = connection.connect();
+
+    if result.is_ok() {
+        assert_eq!(connection.peer_id(), Some(*b"-FP0001-fakepeerfake"));
+        assert_eq!(connection.peer_reserved()[5], 0x10);
+    }
+
+    result
+}
+
+fn info_hash() -> [u8; 20] {
+    common::build_torrent("handshake.bin", &common::test_data(1000), 16 * 1024).info_hash
+}
+
+#[test]
+fn test_handshake_received_in_pieces() {
+    let mut handshake = Handshake::new(info_hash(), *b"-FP0001-fakepeerfake");
+    handshake.reserved[5] = 0x10;
+    let bytes = handshake.to_bytes().to_vec();
+
+    let addr = fake_peer(
+        vec![
+            bytes[0..10].to_vec(),
+            bytes[10..50].to_vec(),
+            bytes[50..].to_vec(),
+        ],
+        Duration::ZERO,
+    );
+
+    //the peer closing the connection afterwards ends the message loop, not the handshake
+    assert!(handshake_with(addr).is_ok());
+}
+
+#[test]
+fn test_handshake_rejects_wrong_info_hash() {
+    let handshake = Handshake::new([0xAB; 20], *b"-FP0001-fakepeerfake");
+    let addr = fake_peer(vec![handshake.to_bytes().to_vec()], Duration::ZERO);
+
+    assert!(matches!(
+        handshake_with(addr),
+        Err(HandshakeError::InfoHashMismatch)
+    ));
+}
+
+#[test]
+fn test_handshake_rejects_self_connection() {
+    let handshake = Handshake::new(info_hash(), *OUR_PEER_ID);
+    let addr = fake_peer(vec![handshake.to_bytes().to_vec()], Duration::ZERO);
+
+    assert!(matches!(
+        handshake_with(addr),
+        Err(HandshakeError::SelfConnection)
+    ));
+}
+
+#[test]
+fn test_handshake_times_out() {
+    //half a handshake, then nothing
+    let handshake = Handshake::new(info_hash(), *b"-FP0001-fakepeerfake");
+    let addr = fake_peer(
+        vec![handshake.to_bytes()[0..30].to_vec()],
+        Duration::from_secs(2),
+    );
+
+    let started_at = Instant::now();
+    assert!(matches!(handshake_with(addr), Err(HandshakeError::Timeout)));
+    assert!(started_at.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn test_handshake_with_closed_port() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    assert!(matches!(
+        handshake_with(addr),
+        Err(HandshakeError::Connect(_))
+    ));
+}
@@ -0,0 +1,169 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::lsd::{Announce, LSD_IPV4_GROUP, LSD_PORT, Lsd};
+use rust_torrent::peer_pool::PeerPool;
+
+/**
+ * The IPv4 group on a free port, so the tests do not hear the clients of the machine
+ */
+fn test_group() -> SocketAddr {
+    let port = UdpSocket::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    SocketAddr::new(IpAddr::V4(LSD_IPV4_GROUP), port)
+}
+
+fn wait_for_peers(pool: &Arc<Mutex<PeerPool>>, amount: usize) -> Vec<SocketAddr> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pool.lock().unwrap().queued_len() < amount && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let mut pool = pool.lock().unwrap();
+    let mut peers = Vec::new();
+    while let Some(peer) = pool.next_peer() {
+        peers.push(peer);
+    }
+
+    return peers;
+}
+
+#[test]
+fn test_lsd_announce_round_trip() {
+    let announce = Announce {
+        port: 6881,
+        info_hashes: vec![[0xab; 20], [0x01; 20]],
+        cookie: Some(String::from("c00k1e")),
+    };
+    let host = SocketAddr::new(IpAddr::V4(LSD_IPV4_GROUP), LSD_PORT);
+
+    let encoded = announce.encode(host);
+    let text = String::from_utf8(encoded.clone()).unwrap();
+    assert!(
+        text.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n")
+    );
+    assert!(text.contains(&format!("Infohash: {}\r\n", "ab".repeat(20))));
+    assert!(text.ends_with("cookie: c00k1e\r\n\r\n\r\n"));
+
+    assert_eq!(Announce::parse(&encoded).unwrap(), announce);
+}
+
+#[test]
+fn test_lsd_parse_is_lenient_with_headers_but_rejects_invalid_announces() {
+    let other_client = format!(
+        "BT-SEARCH * HTTP/1.1\r\nhost: [ff15::efc0:988f]:6771\r\nPORT: 51413\r\nINFOHASH: {}\r\nInfohash: not-a-hash\r\n\r\n\r\n",
+        "CD".repeat(20)
+    );
+    let announce = Announce::parse(other_client.as_bytes()).unwrap();
+    assert_eq!(announce.port, 51413);
+    assert_eq!(announce.info_hashes, vec![[0xcd; 20]]);
+    assert_eq!(announce.cookie, None);
+
+    let hash = "ab".repeat(20);
+    let invalid = [
+        format!("M-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: {hash}\r\n\r\n\r\n"),
+        format!("BT-SEARCH * HTTP/1.1\r\nInfohash: {hash}\r\n\r\n\r\n"),
+        format!("BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: {hash}\r\n\r\n\r\n"),
+        String::from("BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n\r\n"),
+        format!("BT-SEARCH * HTTP/1.1\r\nPort 6881\r\nInfohash: {hash}\r\n\r\n\r\n"),
+    ];
+    for message in invalid {
+        assert!(Announce::parse(message.as_bytes()).is_err(), "{message:?}");
+    }
+    assert!(Announce::parse(&[0xff, 0xfe, 0x00]).is_err());
+}
+
+#[test]
+fn test_clients_of_the_lan_find_each_other() {
+    let group = test_group();
+    let info_hash = [0x42; 20];
+
+    let first = Lsd::with_groups(&[group], 7001).unwrap();
+    let second = Lsd::with_groups(&[group], 7002).unwrap();
+    assert_ne!(first.cookie(), second.cookie());
+
+    let first_pool = Arc::new(Mutex::new(PeerPool::new()));
+    let second_pool = Arc::new(Mutex::new(PeerPool::new()));
+    first.add_torrent(info_hash, Arc::clone(&first_pool));
+    second.add_torrent(info_hash, Arc::clone(&second_pool));
+
+    //each one hears its own announce too, only the other one is a peer
+    let first_peers = wait_for_peers(&first_pool, 1);
+    let second_peers = wait_for_peers(&second_pool, 1);
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(first_peers.len(), 1);
+    assert_eq!(first_peers[0].port(), 7002);
+    assert_eq!(second_peers.len(), 1);
+    assert_eq!(second_peers[0].port(), 7001);
+    assert_eq!(first_pool.lock().unwrap().queued_len(), 0);
+    assert_eq!(second_pool.lock().unwrap().queued_len(), 0);
+}
+
+#[test]
+fn test_own_announces_and_unknown_torrents_are_ignored() {
+    let group = test_group();
+    let info_hash = [0x42; 20];
+
+    let lsd = Lsd::with_groups(&[group], 7001).unwrap();
+    let pool = Arc::new(Mutex::new(PeerPool::new()));
+    lsd.add_torrent(info_hash, Arc::clone(&pool));
+
+    let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let announces = [
+        //a copy of ours
+        Announce {
+            port: 7101,
+            info_hashes: vec![info_hash],
+            cookie: Some(lsd.cookie().to_string()),
+        },
+        Announce {
+            port: 7102,
+            info_hashes: vec![[0x43; 20]],
+            cookie: None,
+        },
+        Announce {
+            port: 7103,
+            info_hashes: vec![[0x43; 20], info_hash],
+            cookie: Some(String::from("someone else")),
+        },
+    ];
+    for announce in &announces {
+        sender.send_to(&announce.encode(group), group).unwrap();
+    }
+
+    let peers = wait_for_peers(&pool, 1);
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].port(), 7103);
+    assert_eq!(pool.lock().unwrap().queued_len(), 0);
+}
+
+#[test]
+fn test_removed_torrents_are_not_fed_anymore() {
+    let group = test_group();
+    let info_hash = [0x42; 20];
+
+    let lsd = Lsd::with_groups(&[group], 7001).unwrap();
+    let pool = Arc::new(Mutex::new(PeerPool::new()));
+    lsd.add_torrent(info_hash, Arc::clone(&pool));
+    lsd.remove_torrent(&info_hash);
+
+    let announce = Announce {
+        port: 7104,
+        info_hashes: vec![info_hash],
+        cookie: None,
+    };
+    let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+    sender.send_to(&announce.encode(group), group).unwrap();
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(pool.lock().unwrap().queued_len(), 0);
+}
@@ -0,0 +1,246 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::message::{MAX_MESSAGE_SIZE, MessageType, PeerMessage};
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::peer_listener::PeerListener;
+use rust_torrent::torrent_context::TorrentContext;
+
+#[test]
+fn test_messages_round_trip() {
+    let messages = vec![
+        PeerMessage::KeepAlive,
+        PeerMessage::Choke,
+        PeerMessage::Unchoke,
+        PeerMessage::Interested,
+        PeerMessage::NotInterested,
+        PeerMessage::Have { index: 7 },
+        PeerMessage::Bitfield {
+            bitfield: vec![0b1010_0000, 0xFF],
+        },
+        PeerMessage::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        },
+        PeerMessage::Piece {
+            index: 2,
+            begin: 0,
+            block: common::test_data(100),
+        },
+        PeerMessage::Cancel {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        },
+        PeerMessage::Port { port: 6881 },
+        PeerMessage::SuggestPiece { index: 3 },
+        PeerMessage::HaveAll,
+        PeerMessage::HaveNone,
+        PeerMessage::RejectRequest {
+            index: 4,
+            begin: 0,
+            length: 1024,
+        },
+        PeerMessage::AllowedFast { index: 5 },
+        PeerMessage::Extended {
+            id: 0,
+            payload: b"d1:md6:ut_pexi1eee".to_vec(),
+        },
+    ];
+
+    let mut buf = Vec::new();
+    for msg in &messages {
+        msg.encode(&mut buf);
+    }
+
+    for msg in &messages {
+        assert_eq!(
+            PeerMessage::decode(&mut buf, MAX_MESSAGE_SIZE),
+            Ok(Some(msg.clone()))
+        );
+    }
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_wire_layout() {
+    assert_eq!(PeerMessage::KeepAlive.to_bytes(), vec![0, 0, 0, 0]);
+    assert_eq!(PeerMessage::Interested.to_bytes(), vec![0, 0, 0, 1, 2]);
+    assert_eq!(
+        PeerMessage::Have { index: 258 }.to_bytes(),
+        vec![0, 0, 0, 5, 4, 0, 0, 1, 2]
+    );
+    assert_eq!(
+        PeerMessage::Request {
+            index: 1,
+            begin: 2,
+            length: 3,
+        }
+        .to_bytes(),
+        vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+    );
+    assert_eq!(
+        PeerMessage::Have { index: 1 }.message_type(),
+        Some(MessageType::Have)
+    );
+}
+
+#[test]
+fn test_decode_waits_for_full_message() {
+    let bytes = PeerMessage::Piece {
+        index: 9,
+        begin: 32,
+        block: vec![1, 2, 3],
+    }
+    .to_bytes();
+
+    let mut buf = Vec::new();
+    for byte in &bytes[..bytes.len() - 1] {
+        buf.push(*byte);
+        assert_eq!(PeerMessage::decode(&mut buf, MAX_MESSAGE_SIZE), Ok(None));
+    }
+
+    buf.push(bytes[bytes.len() - 1]);
+    buf.extend_from_slice(&PeerMessage::Choke.to_bytes()[..2]);
+
+    assert!(matches!(
+        PeerMessage::decode(&mut buf, MAX_MESSAGE_SIZE),
+        Ok(Some(PeerMessage::Piece { index: 9, .. }))
+    ));
+    //the start of the next message stays in the buffer
+    assert_eq!(buf, vec![0, 0]);
+}
+
+#[test]
+fn test_invalid_messages_are_rejected() {
+    //length above the maximum, refused before the payload arrives
+    let mut buf = (MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes().to_vec();
+    assert!(PeerMessage::decode(&mut buf, MAX_MESSAGE_SIZE).is_err());
+
+    //known ids with the wrong payload length
+    assert!(PeerMessage::parse(&[4, 0, 0, 1]).is_err());
+    assert!(PeerMessage::parse(&[6, 0, 0, 0, 1, 0, 0, 0, 2]).is_err());
+    assert!(PeerMessage::parse(&[7, 0, 0, 0, 1]).is_err());
+    assert!(PeerMessage::parse(&[1, 0]).is_err());
+    assert!(PeerMessage::parse(&[20]).is_err());
+}
+
+#[test]
+fn test_unknown_ids_are_kept_aside() {
+    assert_eq!(
+        PeerMessage::parse(&[42, 1, 2, 3]),
+        Ok(PeerMessage::Unknown {
+            id: 42,
+            payload: vec![1, 2, 3],
+        })
+    );
+    assert_eq!(PeerMessage::parse(&[]), Ok(PeerMessage::KeepAlive));
+}
+
+/**
+ * Seeder with the given timers and a raw stream past the handshake
+ */
+fn connect_to_seeder(name: &str, config: ClientConfig) -> (PeerListener, TcpStream) {
+    let data = common::test_data(16 * 1024);
+    let torrent_file = Arc::new(common::build_torrent(name, &data, 16 * 1024));
+    let dir = common::temp_dir(name);
+    std::fs::write(dir.join(name), &data).unwrap();
+    let seeder = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(config),
+    );
+    let listener = PeerListener::start(
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap(),
+        vec![seeder],
+    )
+    .unwrap();
+
+    let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(&common::handshake(
+            &torrent_file.info_hash,
+            b"-FL0001-timers000000",
+        ))
+        .unwrap();
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).unwrap();
+
+    return (listener, stream);
+}
+
+#[test]
+fn test_unknown_message_does_not_drop_the_connection() {
+    let (listener, mut stream) = connect_to_seeder(
+        "unknown_id.bin",
+        ClientConfig {
+            keep_alive_interval: Duration::from_millis(300),
+            ..ClientConfig::default()
+        },
+    );
+
+    common::write_message(&mut stream, 99, b"from the future");
+
+    //the seeder's keep-alives keep coming, which only happens if the unknown message was skipped
+    let mut answered = false;
+    while let Ok(Some(msg)) = common::read_message(&mut stream) {
+        if msg.is_empty() {
+            answered = true;
+            break;
+        }
+    }
+    assert!(answered);
+
+    listener.stop();
+}
+
+#[test]
+fn test_keep_alives_are_sent_on_quiet_connections() {
+    let (listener, mut stream) = connect_to_seeder(
+        "keep_alive.bin",
+        ClientConfig {
+            keep_alive_interval: Duration::from_millis(200),
+            ..ClientConfig::default()
+        },
+    );
+
+    //we stay silent, the seeder still reminds us it is there
+    let mut keep_alives = 0;
+    while keep_alives < 3 {
+        match common::read_message(&mut stream) {
+            Ok(Some(msg)) if msg.is_empty() => keep_alives += 1,
+            Ok(Some(_)) => {}
+            _ => break,
+        }
+    }
+    assert_eq!(keep_alives, 3);
+
+    listener.stop();
+}
+
+#[test]
+fn test_silent_peers_are_disconnected() {
+    let (listener, mut stream) = connect_to_seeder(
+        "idle_peer.bin",
+        ClientConfig {
+            peer_idle_timeout: Duration::from_millis(500),
+            ..ClientConfig::default()
+        },
+    );
+
+    let start = std::time::Instant::now();
+    //the connection ends with an EOF once the seeder gave up on us
+    while let Ok(Some(_)) = common::read_message(&mut stream) {}
+    assert!(start.elapsed() < Duration::from_secs(4));
+
+    listener.stop();
+}
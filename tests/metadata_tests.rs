@@ -0,0 +1,211 @@
+mod common;
+
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use sha1::{Digest, Sha1};
+
+use rust_torrent::bencode::BencodeValue;
+use rust_torrent::client::ClientConfig;
+use rust_torrent::extension::metadata::{METADATA_PIECE_SIZE, MetadataDownload, MetadataMessage};
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::magnet::{self, MagnetLink};
+use rust_torrent::peer_listener::PeerListener;
+use rust_torrent::torrent_context::TorrentContext;
+use rust_torrent::torrent_file::{TorrentFile, is_safe_file_name};
+
+/**
+ * Bencoded info dictionary of the data
+ */
+fn info_bytes(name: &str, data: &[u8], piece_length: usize) -> Vec<u8> {
+    let pieces: Vec<u8> = data
+        .chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+
+    BencodeValue::dictionary(vec![
+        ("name", BencodeValue::from(name)),
+        ("length", BencodeValue::from(data.len())),
+        ("piece length", BencodeValue::from(piece_length)),
+        ("pieces", BencodeValue::from(pieces)),
+    ])
+    .encode()
+}
+
+#[test]
+fn test_parse_magnet_link() {
+    let link = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+        &dn=some%20file.iso&tr=http%3A%2F%2Ftracker.example%3A6969%2Fannounce\
+        &tr=udp%3A%2F%2Ftracker.example%3A6969&x.pe=127.0.0.1%3A6881&x.pe=nope";
+    let magnet_link = MagnetLink::parse(link).unwrap();
+
+    assert_eq!(
+        hex::encode(magnet_link.info_hash),
+        "c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+    );
+    assert_eq!(magnet_link.display_name.as_deref(), Some("some file.iso"));
+    assert_eq!(
+        magnet_link.trackers,
+        vec![
+            "http://tracker.example:6969/announce",
+            "udp://tracker.example:6969"
+        ]
+    );
+    assert_eq!(magnet_link.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+    //same hash in base32
+    let base32 = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+    assert_eq!(base32.info_hash, magnet_link.info_hash);
+
+    assert!(MagnetLink::parse("magnet:?dn=nohash").is_err());
+    assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+    assert!(MagnetLink::parse("http://example.com").is_err());
+}
+
+#[test]
+fn test_metadata_message_round_trip() {
+    let messages = vec![
+        MetadataMessage::Request { piece: 2 },
+        MetadataMessage::Reject { piece: 0 },
+        MetadataMessage::Data {
+            piece: 1,
+            total_size: 20000,
+            data: b"d4:info".to_vec(),
+        },
+    ];
+
+    for message in messages {
+        assert_eq!(
+            MetadataMessage::parse(&message.to_bytes()).unwrap(),
+            message
+        );
+    }
+
+    assert!(MetadataMessage::parse(b"d8:msg_typei7e5:piecei0ee").is_err());
+    assert!(MetadataMessage::parse(b"d8:msg_typei0ee").is_err());
+}
+
+#[test]
+fn test_metadata_download_checks_pieces_and_hash() {
+    let metadata = vec![7u8; METADATA_PIECE_SIZE + 100];
+    let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+    let mut download = MetadataDownload::new(info_hash, metadata.len()).unwrap();
+    assert_eq!(download.missing_pieces(), vec![0, 1]);
+
+    assert!(download.add_piece(1, vec![7u8; 99]).is_err());
+    assert!(download.add_piece(2, vec![7u8; 100]).is_err());
+    download
+        .add_piece(1, metadata[METADATA_PIECE_SIZE..].to_vec())
+        .unwrap();
+    assert_eq!(download.missing_pieces(), vec![0]);
+
+    //corrupted data is dropped so it can be downloaded again
+    download
+        .add_piece(0, vec![8u8; METADATA_PIECE_SIZE])
+        .unwrap();
+    assert!(download.finish().is_err());
+    assert_eq!(download.missing_pieces(), vec![0, 1]);
+
+    download
+        .add_piece(0, metadata[..METADATA_PIECE_SIZE].to_vec())
+        .unwrap();
+    download
+        .add_piece(1, metadata[METADATA_PIECE_SIZE..].to_vec())
+        .unwrap();
+    assert_eq!(download.finish().unwrap(), metadata);
+
+    assert!(MetadataDownload::new(info_hash, 0).is_err());
+    assert!(MetadataDownload::new(info_hash, 100 * 1024 * 1024).is_err());
+}
+
+#[test]
+fn test_torrent_file_from_info_bytes() {
+    let data = common::test_data(5000);
+    let info = info_bytes("info.bin", &data, 1024);
+    let trackers = vec![String::from("http://tracker.example/announce")];
+
+    let torrent = TorrentFile::from_info_bytes(info.clone(), &trackers).unwrap();
+    assert_eq!(torrent.info.name, "info.bin");
+    assert_eq!(torrent.info.length, 5000);
+    assert_eq!(torrent.pieces_amount, 5);
+    assert_eq!(torrent.info_hash, <[u8; 20]>::from(Sha1::digest(&info)));
+    assert_eq!(torrent.info_bytes, info);
+    assert_eq!(torrent.announce, trackers[0]);
+
+    //saved and loaded back with the same info hash
+    let path = common::temp_dir("metadata").join("info.torrent");
+    torrent.save(&path).unwrap();
+    let loaded = TorrentFile::from(std::fs::File::open(&path).unwrap());
+    assert_eq!(loaded.info_hash, torrent.info_hash);
+    assert_eq!(loaded.announce, trackers[0]);
+
+    let mut trailing = info.clone();
+    trailing.push(b'x');
+    assert!(TorrentFile::from_info_bytes(trailing, &trackers).is_err());
+    assert!(TorrentFile::from_info_bytes(b"d4:name1:xe".to_vec(), &trackers).is_err());
+    assert!(
+        TorrentFile::from_info_bytes(
+            info_bytes("short.bin", &data[..1000], 1024)[..40].to_vec(),
+            &trackers
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_info_names_leaving_the_download_directory_are_rejected() {
+    let data = common::test_data(5000);
+
+    for name in ["../x", "/tmp/x", "a/b", "..", ".", ""] {
+        let info = info_bytes(name, &data, 1024);
+        assert!(TorrentFile::from_info_bytes(info, &[]).is_err(), "{name:?}");
+    }
+
+    assert!(is_safe_file_name("x..bin"));
+    assert!(!is_safe_file_name("..\\x"));
+}
+
+#[test]
+fn test_fetch_metadata_from_peer() {
+    //1000 pieces need more than one metadata piece
+    let data = common::test_data(1000 * 1024);
+    let info = info_bytes("magnet.bin", &data, 1024);
+    assert!(info.len() > METADATA_PIECE_SIZE);
+
+    let torrent_file = Arc::new(TorrentFile::from_info_bytes(info.clone(), &[]).unwrap());
+    let dir = common::temp_dir("metadata");
+    std::fs::write(dir.join("magnet.bin"), &data).unwrap();
+
+    let context = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    );
+    let listener =
+        PeerListener::start(TcpListener::bind("127.0.0.1:0").unwrap(), vec![context]).unwrap();
+
+    let config = ClientConfig {
+        peer_id: *b"-TR3000-magnetmagnet",
+        ..ClientConfig::default()
+    };
+
+    //a dead peer is skipped
+    let dead_peer = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let metadata = magnet::fetch_metadata(
+        torrent_file.info_hash,
+        &[dead_peer, listener.local_addr()],
+        &config,
+    )
+    .unwrap();
+    assert_eq!(metadata, info);
+
+    //nobody has the metadata of another torrent
+    assert!(magnet::fetch_metadata([1u8; 20], &[listener.local_addr()], &config).is_err());
+
+    listener.stop();
+}
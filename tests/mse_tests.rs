@@ -0,0 +1,228 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rust_torrent::choker;
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::ConnectionHandler;
+use rust_torrent::connection_handler::handshake::{self, Handshake, HandshakeError};
+use rust_torrent::connection_handler::mse::{
+    self, CRYPTO_PLAINTEXT, CRYPTO_RC4, EncryptionPolicy, Rc4,
+};
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::peer_listener::PeerListener;
+use rust_torrent::torrent_context::TorrentContext;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    Rc4::new(key).apply(&mut data);
+    data
+}
+
+#[test]
+fn test_rc4_vectors() {
+    assert_eq!(hex::encode(rc4(b"Key", b"Plaintext")), "bbf316e8d940af0ad3");
+    assert_eq!(hex::encode(rc4(b"Wiki", b"pedia")), "1021bf0420");
+    assert_eq!(
+        hex::encode(rc4(b"Secret", b"Attack at dawn")),
+        "45a01f645fc35b383552544b9bf5"
+    );
+}
+
+#[test]
+fn test_encryption_policy() {
+    assert_eq!(
+        "Forced".parse::<EncryptionPolicy>(),
+        Ok(EncryptionPolicy::Forced)
+    );
+    assert_eq!(
+        "disabled".parse::<EncryptionPolicy>(),
+        Ok(EncryptionPolicy::Disabled)
+    );
+    assert!("sometimes".parse::<EncryptionPolicy>().is_err());
+
+    assert_eq!(EncryptionPolicy::Forced.crypto_provide(), CRYPTO_RC4);
+    assert_eq!(
+        EncryptionPolicy::Preferred.crypto_provide(),
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    );
+
+    //RC4 whenever the initiator provides it
+    assert_eq!(
+        EncryptionPolicy::Preferred.crypto_select(CRYPTO_RC4 | CRYPTO_PLAINTEXT),
+        Some(CRYPTO_RC4)
+    );
+    assert_eq!(
+        EncryptionPolicy::Preferred.crypto_select(CRYPTO_PLAINTEXT),
+        Some(CRYPTO_PLAINTEXT)
+    );
+    assert_eq!(
+        EncryptionPolicy::Forced.crypto_select(CRYPTO_PLAINTEXT),
+        None
+    );
+}
+
+#[test]
+fn test_encrypted_stream_round_trip() {
+    let info_hashes = [[1u8; 20], [2u8; 20]];
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let receiver = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream =
+            mse::accept(stream, &info_hashes, EncryptionPolicy::Preferred, TIMEOUT).unwrap();
+        assert!(stream.is_encrypted());
+
+        let mut received = [0u8; 5];
+        stream.read_exact(&mut received).unwrap();
+        stream.write_all(b"world").unwrap();
+        received
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut stream = mse::initiate(stream, &[2u8; 20], EncryptionPolicy::Forced, TIMEOUT).unwrap();
+    assert!(stream.is_encrypted());
+
+    stream.write_all(b"hello").unwrap();
+    let mut answer = [0u8; 5];
+    stream.read_exact(&mut answer).unwrap();
+
+    assert_eq!(&receiver.join().unwrap(), b"hello");
+    assert_eq!(&answer, b"world");
+}
+
+#[test]
+fn test_encrypted_download_from_incoming_connection() {
+    let data = common::test_data(5 * 16 * 1024 + 123);
+    let torrent_file = Arc::new(common::build_torrent("encrypted.bin", &data, 16 * 1024));
+    let seeder_dir = common::temp_dir("mse_seeder");
+    std::fs::write(seeder_dir.join("encrypted.bin"), &data).unwrap();
+
+    let seeder = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &seeder_dir),
+        Arc::new(ClientConfig {
+            incoming_encryption: EncryptionPolicy::Forced,
+            ..ClientConfig::default()
+        }),
+    );
+    let listener = PeerListener::start(
+        TcpListener::bind("127.0.0.1:0").unwrap(),
+        vec![seeder.clone()],
+    )
+    .unwrap();
+
+    let choker_stopped = Arc::new(AtomicBool::new(false));
+    let choker = choker::spawn_choker(seeder.clone(), Arc::clone(&choker_stopped));
+
+    //plaintext peers are refused
+    let mut plaintext = TcpStream::connect(listener.local_addr()).unwrap();
+    plaintext.set_read_timeout(Some(TIMEOUT)).unwrap();
+    plaintext
+        .write_all(&common::handshake(
+            &torrent_file.info_hash,
+            b"-FL0001-plaintextpee",
+        ))
+        .unwrap();
+    assert!(matches!(plaintext.read(&mut [0u8; 68]), Ok(0) | Err(_)));
+
+    let leecher_dir = common::temp_dir("mse_leecher");
+    let leecher = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &leecher_dir),
+        Arc::new(ClientConfig {
+            peer_id: *b"-TR3000-leecher00000",
+            outgoing_encryption: EncryptionPolicy::Forced,
+            ..ClientConfig::default()
+        }),
+    );
+
+    ConnectionHandler::new(listener.local_addr(), &leecher)
+        .connect()
+        .unwrap();
+
+    assert!(leecher.is_download_complete());
+    assert_eq!(
+        std::fs::read(leecher_dir.join("encrypted.bin")).unwrap(),
+        data
+    );
+
+    choker_stopped.store(true, Ordering::Relaxed);
+    choker.join().unwrap();
+    listener.stop();
+}
+
+#[test]
+fn test_disabled_listener_refuses_encryption() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let receiver = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        mse::accept(stream, &[[1u8; 20]], EncryptionPolicy::Disabled, TIMEOUT).err()
+    });
+
+    let config = ClientConfig {
+        outgoing_encryption: EncryptionPolicy::Forced,
+        handshake_timeout: Duration::from_secs(2),
+        ..ClientConfig::default()
+    };
+    assert!(handshake::connect(addr, &[1u8; 20], &config, None).is_err());
+
+    assert!(matches!(
+        receiver.join().unwrap(),
+        Some(HandshakeError::InvalidProtocol)
+    ));
+}
+
+#[test]
+fn test_preferred_encryption_falls_back_to_plaintext() {
+    let info_hash = [3u8; 20];
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    //a peer without MSE support: drops what is not a BitTorrent handshake
+    let fake_peer = thread::spawn(move || {
+        let mut attempts = 0;
+
+        loop {
+            let (mut stream, _) = listener.accept().unwrap();
+            attempts += 1;
+
+            let mut start = [0u8; 20];
+            stream.read_exact(&mut start).unwrap();
+            if start[0] != 19 {
+                continue;
+            }
+
+            let mut rest = [0u8; 48];
+            stream.read_exact(&mut rest).unwrap();
+            stream
+                .write_all(&common::handshake(&info_hash, b"-FP0001-plaintextpee"))
+                .unwrap();
+            return attempts;
+        }
+    });
+
+    let config = ClientConfig {
+        outgoing_encryption: EncryptionPolicy::Preferred,
+        handshake_timeout: Duration::from_secs(2),
+        ..ClientConfig::default()
+    };
+    let mut stream = handshake::connect(addr, &info_hash, &config, None).unwrap();
+    assert!(!stream.is_encrypted());
+
+    handshake::send_handshake(&mut stream, &Handshake::new(info_hash, config.peer_id)).unwrap();
+    let peer_handshake = handshake::read_handshake(&mut stream, TIMEOUT).unwrap();
+    assert_eq!(&peer_handshake.peer_id, b"-FP0001-plaintextpee");
+
+    assert_eq!(fake_peer.join().unwrap(), 2);
+}
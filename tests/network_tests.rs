@@ -0,0 +1,35 @@
+use std::net::{SocketAddr, TcpStream};
+
+use rust_torrent::network::{bind_dual_stack_listener, canonical_peer_addr};
+
+#[test]
+fn test_dual_stack_listener_accepts_ipv4() {
+    let listener = bind_dual_stack_listener(0).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let _client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let (_stream, remote) = listener.accept().unwrap();
+
+    assert_eq!(
+        canonical_peer_addr(remote).ip(),
+        "127.0.0.1".parse::<std::net::IpAddr>().unwrap()
+    );
+
+    if listener.local_addr().unwrap().is_ipv6() {
+        let _client6 = TcpStream::connect(("::1", port)).unwrap();
+        let (_stream6, remote6) = listener.accept().unwrap();
+        assert!(remote6.is_ipv6());
+    }
+}
+
+#[test]
+fn test_canonical_peer_addr() {
+    let mapped: SocketAddr = "[::ffff:10.0.0.1]:6881".parse().unwrap();
+    let native: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+
+    assert_eq!(
+        canonical_peer_addr(mapped),
+        "10.0.0.1:6881".parse().unwrap()
+    );
+    assert_eq!(canonical_peer_addr(native), native);
+}
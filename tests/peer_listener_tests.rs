@@ -0,0 +1,230 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rust_torrent::choker;
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::ConnectionHandler;
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::peer_listener::PeerListener;
+use rust_torrent::torrent_context::TorrentContext;
+use rust_torrent::torrent_file::TorrentFile;
+
+fn seeding_context(name: &str, data: &[u8], config: ClientConfig) -> TorrentContext {
+    let torrent_file = Arc::new(common::build_torrent(name, data, 16 * 1024));
+    let dir = common::temp_dir("seeder");
+    std::fs::write(dir.join(name), data).unwrap();
+
+    TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(config),
+    )
+}
+
+fn start_listener(context: &TorrentContext) -> PeerListener {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    PeerListener::start(listener, vec![context.clone()]).unwrap()
+}
+
+#[test]
+fn test_download_from_incoming_connection() {
+    let data = common::test_data(5 * 16 * 1024 + 123);
+    let seeder = seeding_context("incoming.bin", &data, ClientConfig::default());
+    let listener = start_listener(&seeder);
+
+    let choker_stopped = Arc::new(AtomicBool::new(false));
+    let choker = choker::spawn_choker(seeder.clone(), Arc::clone(&choker_stopped));
+
+    let leecher_dir = common::temp_dir("leecher");
+    let leecher = TorrentContext::new(
+        Arc::clone(&seeder.torrent_file),
+        get_file_handler_in(&seeder.torrent_file, &leecher_dir),
+        Arc::new(ClientConfig {
+            peer_id: *b"-TR3000-leecher00000",
+            ..ClientConfig::default()
+        }),
+    );
+
+    ConnectionHandler::new(listener.local_addr(), &leecher)
+        .connect()
+        .unwrap();
+
+    assert!(leecher.is_download_complete());
+    assert_eq!(
+        std::fs::read(leecher_dir.join("incoming.bin")).unwrap(),
+        data
+    );
+    assert_eq!(
+        seeder.file_handler.lock().unwrap().uploaded_bytes,
+        data.len()
+    );
+
+    choker_stopped.store(true, Ordering::Relaxed);
+    choker.join().unwrap();
+    listener.stop();
+}
+
+#[test]
+fn test_two_default_clients_connect_to_each_other() {
+    let data = common::test_data(3 * 16 * 1024);
+    let seeder = seeding_context("default_ids.bin", &data, ClientConfig::default());
+    let listener = start_listener(&seeder);
+
+    let choker_stopped = Arc::new(AtomicBool::new(false));
+    let choker = choker::spawn_choker(seeder.clone(), Arc::clone(&choker_stopped));
+
+    let leecher_dir = common::temp_dir("default_ids");
+    let leecher = TorrentContext::new(
+        Arc::clone(&seeder.torrent_file),
+        get_file_handler_in(&seeder.torrent_file, &leecher_dir),
+        Arc::new(ClientConfig::default()),
+    );
+    assert_ne!(leecher.config.peer_id, seeder.config.peer_id);
+    assert!(leecher.config.peer_id.starts_with(b"-TR3000-"));
+
+    ConnectionHandler::new(listener.local_addr(), &leecher)
+        .connect()
+        .unwrap();
+
+    assert!(leecher.is_download_complete());
+
+    choker_stopped.store(true, Ordering::Relaxed);
+    choker.join().unwrap();
+    listener.stop();
+}
+
+fn connect_with_handshake(listener: &PeerListener, info_hash: &[u8; 20]) -> TcpStream {
+    let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(&common::handshake(info_hash, b"-FL0001-leecherleech"))
+        .unwrap();
+    stream
+}
+
+/**
+ * Whether the listener answered with a handshake (true) or closed the connection (false)
+ */
+fn receives_handshake(stream: &mut TcpStream) -> bool {
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).is_ok()
+}
+
+#[test]
+fn test_incoming_connection_for_unknown_torrent_is_dropped() {
+    let data = common::test_data(16 * 1024);
+    let seeder = seeding_context("unknown.bin", &data, ClientConfig::default());
+    let listener = start_listener(&seeder);
+
+    let mut stream = connect_with_handshake(&listener, &TorrentFile::default().info_hash);
+    assert!(!receives_handshake(&mut stream));
+
+    let mut stream = connect_with_handshake(&listener, &seeder.torrent_file.info_hash);
+    assert!(receives_handshake(&mut stream));
+
+    listener.stop();
+}
+
+#[test]
+fn test_incoming_connections_are_capped() {
+    let data = common::test_data(16 * 1024);
+    let seeder = seeding_context(
+        "capped.bin",
+        &data,
+        ClientConfig {
+            max_connections: 1,
+            ..ClientConfig::default()
+        },
+    );
+    let listener = start_listener(&seeder);
+    let info_hash = seeder.torrent_file.info_hash;
+
+    let mut first = connect_with_handshake(&listener, &info_hash);
+    assert!(receives_handshake(&mut first));
+
+    let mut second = connect_with_handshake(&listener, &info_hash);
+    assert!(!receives_handshake(&mut second));
+
+    drop(first);
+    listener.stop();
+}
+
+#[test]
+fn test_silent_connections_cannot_exceed_the_pending_handshakes() {
+    let data = common::test_data(16 * 1024);
+    let seeder = seeding_context(
+        "silent.bin",
+        &data,
+        ClientConfig {
+            max_connections: 2,
+            ..ClientConfig::default()
+        },
+    );
+    let listener = start_listener(&seeder);
+    let info_hash = seeder.torrent_file.info_hash;
+
+    //they never send their handshake and hold the two slots
+    let silent: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(listener.local_addr()).unwrap())
+        .collect();
+    thread::sleep(Duration::from_millis(200));
+
+    let mut refused = connect_with_handshake(&listener, &info_hash);
+    assert!(!receives_handshake(&mut refused));
+
+    //the slots are given back once their handshakes fail
+    drop(silent);
+    thread::sleep(Duration::from_millis(200));
+    let mut accepted = connect_with_handshake(&listener, &info_hash);
+    assert!(receives_handshake(&mut accepted));
+
+    listener.stop();
+}
+
+#[cfg(target_os = "linux")]
+fn thread_count() -> usize {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let threads = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .unwrap();
+    threads.trim().parse().unwrap()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_incoming_connections_do_not_take_a_thread_each() {
+    let data = common::test_data(16 * 1024);
+    let seeder = seeding_context(
+        "many.bin",
+        &data,
+        ClientConfig {
+            max_connections: 500,
+            ..ClientConfig::default()
+        },
+    );
+    let listener = start_listener(&seeder);
+    let info_hash = seeder.torrent_file.info_hash;
+
+    let threads_before = thread_count();
+    let mut streams = Vec::new();
+    for _ in 0..200 {
+        let mut stream = connect_with_handshake(&listener, &info_hash);
+        assert!(receives_handshake(&mut stream));
+        streams.push(stream);
+    }
+
+    assert_eq!(seeder.peer_pool.lock().unwrap().connected_len(), 200);
+    assert!(thread_count() < threads_before + 50);
+
+    drop(streams);
+    listener.stop();
+}
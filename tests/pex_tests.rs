@@ -0,0 +1,249 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rust_torrent::client::ClientConfig;
+use rust_torrent::extension::pex::{
+    PEX_FLAG_CONNECTABLE, PEX_FLAG_SEED, PexMessage, UT_PEX, UtPex,
+};
+use rust_torrent::extension::{ExtendedHandshake, ExtensionRegistry};
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::peer_registry::PeerStats;
+use rust_torrent::torrent_context::TorrentContext;
+use rust_torrent::torrent_file::TorrentFile;
+
+fn addr(value: &str) -> SocketAddr {
+    value.parse().unwrap()
+}
+
+fn context_for(torrent_file: TorrentFile) -> TorrentContext {
+    let torrent_file = Arc::new(torrent_file);
+    let dir = common::temp_dir("pex");
+
+    TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &dir),
+        Arc::new(ClientConfig::default()),
+    )
+}
+
+fn connect(
+    context: &TorrentContext,
+    peer: SocketAddr,
+    listen_addr: Option<SocketAddr>,
+    seed: bool,
+) {
+    let mut peer_registry = context.peer_registry.lock().unwrap();
+    let _ = peer_registry.register(peer);
+    peer_registry.update_stats(
+        &peer,
+        PeerStats {
+            listen_addr,
+            seed,
+            ..PeerStats::default()
+        },
+    );
+}
+
+/**
+ * Registry with ut_pex negotiated with the peer, which receives our messages with id 3
+ */
+fn negotiated_registry(
+    context: &TorrentContext,
+    peer: SocketAddr,
+    interval: Duration,
+) -> ExtensionRegistry {
+    let mut registry = ExtensionRegistry::new();
+    registry.register(Box::new(UtPex::new(interval)));
+
+    let mut peer_handshake = ExtendedHandshake::default();
+    peer_handshake.m.insert(UT_PEX.to_string(), 3);
+    registry.on_peer_handshake(&peer_handshake, peer, context);
+
+    registry
+}
+
+fn sent_message(messages: Vec<(u8, Vec<u8>)>) -> Option<PexMessage> {
+    assert!(messages.len() <= 1);
+    messages.into_iter().next().map(|(id, payload)| {
+        assert_eq!(id, 3);
+        PexMessage::parse(&payload).unwrap()
+    })
+}
+
+#[test]
+fn test_pex_message_round_trip() {
+    let message = PexMessage {
+        added: vec![
+            (addr("10.0.0.1:6881"), PEX_FLAG_SEED | PEX_FLAG_CONNECTABLE),
+            (addr("[2001:db8::1]:51413"), PEX_FLAG_CONNECTABLE),
+            (addr("10.0.0.2:6882"), 0),
+        ],
+        dropped: vec![addr("10.0.0.3:6883"), addr("[2001:db8::2]:6881")],
+    };
+
+    let mut parsed = PexMessage::parse(&message.to_bytes()).unwrap();
+    parsed.added.sort();
+    parsed.dropped.sort();
+
+    let mut expected = message.clone();
+    expected.added.sort();
+    expected.dropped.sort();
+    assert_eq!(parsed, expected);
+
+    //flags are optional
+    let parsed = PexMessage::parse(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+    assert_eq!(parsed.added, vec![(addr("10.0.0.1:6881"), 0)]);
+
+    assert!(PexMessage::parse(b"le").is_err());
+}
+
+#[test]
+fn test_pex_sends_added_and_dropped_peers() {
+    let context = context_for(common::build_torrent(
+        "pex.bin",
+        &common::test_data(1000),
+        1024,
+    ));
+    let receiver = addr("10.0.0.1:6881");
+
+    connect(&context, receiver, Some(receiver), false);
+    connect(
+        &context,
+        addr("10.0.0.2:6881"),
+        Some(addr("10.0.0.2:6881")),
+        true,
+    );
+    //incoming connection, announced with its listen port
+    connect(
+        &context,
+        addr("10.0.0.3:50000"),
+        Some(addr("10.0.0.3:6881")),
+        false,
+    );
+    //incoming connection without a known listen port
+    connect(&context, addr("10.0.0.4:50001"), None, false);
+
+    let mut registry = negotiated_registry(&context, receiver, Duration::ZERO);
+
+    let mut first = sent_message(registry.on_tick(receiver, &context)).unwrap();
+    first.added.sort();
+    assert_eq!(
+        first.added,
+        vec![
+            (addr("10.0.0.2:6881"), PEX_FLAG_SEED | PEX_FLAG_CONNECTABLE),
+            (addr("10.0.0.3:6881"), 0),
+        ]
+    );
+    assert!(first.dropped.is_empty());
+
+    //nothing changed
+    assert!(sent_message(registry.on_tick(receiver, &context)).is_none());
+
+    context
+        .peer_registry
+        .lock()
+        .unwrap()
+        .unregister(&addr("10.0.0.2:6881"));
+    connect(
+        &context,
+        addr("10.0.0.5:6881"),
+        Some(addr("10.0.0.5:6881")),
+        false,
+    );
+
+    let second = sent_message(registry.on_tick(receiver, &context)).unwrap();
+    assert_eq!(
+        second.added,
+        vec![(addr("10.0.0.5:6881"), PEX_FLAG_CONNECTABLE)]
+    );
+    assert_eq!(second.dropped, vec![addr("10.0.0.2:6881")]);
+}
+
+#[test]
+fn test_pex_is_rate_limited() {
+    let context = context_for(common::build_torrent(
+        "pex.bin",
+        &common::test_data(1000),
+        1024,
+    ));
+    let receiver = addr("10.0.0.1:6881");
+    connect(
+        &context,
+        addr("10.0.0.2:6881"),
+        Some(addr("10.0.0.2:6881")),
+        false,
+    );
+
+    let mut registry = negotiated_registry(&context, receiver, Duration::from_secs(60));
+    assert!(sent_message(registry.on_tick(receiver, &context)).is_some());
+
+    connect(
+        &context,
+        addr("10.0.0.3:6881"),
+        Some(addr("10.0.0.3:6881")),
+        false,
+    );
+    assert!(sent_message(registry.on_tick(receiver, &context)).is_none());
+
+    //nothing is sent to a peer without ut_pex
+    let mut registry = ExtensionRegistry::new();
+    registry.register(Box::new(UtPex::new(Duration::ZERO)));
+    registry.on_peer_handshake(&ExtendedHandshake::default(), receiver, &context);
+    assert!(registry.on_tick(receiver, &context).is_empty());
+}
+
+#[test]
+fn test_pex_peers_are_added_to_the_pool() {
+    let context = context_for(common::build_torrent(
+        "pex.bin",
+        &common::test_data(1000),
+        1024,
+    ));
+    let sender = addr("10.0.0.1:6881");
+    let mut registry = negotiated_registry(&context, sender, Duration::from_secs(60));
+    let local_id = registry.local_id(UT_PEX).unwrap();
+
+    let message = PexMessage {
+        added: (0..60)
+            .map(|i| (addr(&format!("10.1.0.{i}:6881")), 0))
+            .collect(),
+        dropped: vec![],
+    };
+    registry
+        .on_message(local_id, &message.to_bytes(), sender, &context)
+        .unwrap();
+
+    //at most 50 peers per message
+    assert_eq!(context.peer_pool.lock().unwrap().queued_len(), 50);
+
+    //a second message within the minute is ignored
+    let message = PexMessage {
+        added: vec![(addr("10.2.0.1:6881"), 0)],
+        dropped: vec![],
+    };
+    registry
+        .on_message(local_id, &message.to_bytes(), sender, &context)
+        .unwrap();
+    assert_eq!(context.peer_pool.lock().unwrap().queued_len(), 50);
+}
+
+#[test]
+fn test_pex_is_disabled_for_private_torrents() {
+    let extension_names = |context: &TorrentContext| -> Vec<&'static str> {
+        context
+            .extensions
+            .iter()
+            .map(|factory| factory().name())
+            .collect()
+    };
+
+    let public = common::build_torrent("public.bin", &common::test_data(1000), 1024);
+    assert!(extension_names(&context_for(public)).contains(&UT_PEX));
+
+    let mut private = common::build_torrent("private.bin", &common::test_data(1000), 1024);
+    private.info.private = true;
+    assert!(!extension_names(&context_for(private)).contains(&UT_PEX));
+}
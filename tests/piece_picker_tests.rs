@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+
+use std::net::SocketAddr;
+
+use rust_torrent::piece_picker::{
+    BlockRequest, BlockResult, MAX_HASH_FAILURES, Piece, PiecePicker, PiecePriority,
+    REQUEST_PIECE_SIZE, bitfield_has_piece,
+};
+
+#[test]
+fn test_bitfield_has_piece() {
+    let bitfield = [0b1000_0001, 0b0100_0000];
+    assert!(bitfield_has_piece(&bitfield, 0));
+    assert!(!bitfield_has_piece(&bitfield, 1));
+    assert!(bitfield_has_piece(&bitfield, 7));
+    assert!(bitfield_has_piece(&bitfield, 9));
+    assert!(!bitfield_has_piece(&bitfield, 42));
+}
+
+#[test]
+fn test_pick_rarest_first() {
+    //we already have piece 0
+    let mut picker = PiecePicker::new(32, 128, 4, &[0b1000_0000]);
+    let seeder = [0b1111_0000];
+
+    picker.add_peer_bitfield(&seeder);
+    picker.add_peer_bitfield(&seeder);
+    picker.add_peer_bitfield(&[0b0110_0000]);
+    picker.add_peer_bitfield(&[0b0100_0000]);
+
+    assert_eq!(picker.availability(1), 4);
+    assert_eq!(picker.availability(3), 2);
+    assert_eq!(picker.remaining(), 3);
+
+    assert_eq!(picker.pick(&seeder), Some(3));
+    assert_eq!(picker.pick(&seeder), Some(2));
+    assert_eq!(picker.pick(&seeder), Some(1));
+    assert_eq!(picker.pick(&seeder), None);
+
+    //in progress pieces are still remaining until they are verified
+    assert_eq!(picker.remaining(), 3);
+
+    picker.abort(2);
+    picker.mark_have(3);
+    assert_eq!(picker.remaining(), 2);
+    assert_eq!(picker.pick(&seeder), Some(2));
+    assert!(picker.has(3));
+}
+
+#[test]
+fn test_pick_only_pieces_the_peer_has() {
+    let mut picker = PiecePicker::new(32, 320, 10, &[0, 0]);
+    let peer = [0b0010_0000, 0];
+
+    picker.add_peer_bitfield(&peer);
+    assert!(picker.is_interesting(&peer));
+    assert!(!picker.is_interesting(&[0, 0]));
+
+    assert_eq!(picker.pick(&peer), Some(2));
+    assert_eq!(picker.pick(&peer), None);
+
+    picker.remove_peer_bitfield(&peer);
+    assert_eq!(picker.availability(2), 0);
+}
+
+#[test]
+fn test_pick_by_priority() {
+    let mut picker = PiecePicker::new(32, 128, 4, &[0]);
+    let seeder = [0b1111_0000];
+    picker.add_peer_bitfield(&seeder);
+    //piece 3 is the rarest but has a low priority
+    picker.add_peer_bitfield(&[0b1110_0000]);
+
+    picker.set_priority(0, PiecePriority::Skip);
+    picker.set_priority(2, PiecePriority::High);
+    picker.set_priority(3, PiecePriority::Low);
+
+    assert_eq!(picker.remaining(), 3);
+    assert_eq!(picker.pick(&seeder), Some(2));
+    assert_eq!(picker.pick(&seeder), Some(1));
+    assert_eq!(picker.pick(&seeder), Some(3));
+    assert_eq!(picker.pick(&seeder), None);
+}
+
+#[test]
+fn test_remaining_follows_priority_changes_of_any_piece() {
+    //we already have piece 0
+    let mut picker = PiecePicker::new(32, 128, 4, &[0b1000_0000]);
+    let seeder = [0b1111_0000];
+    picker.add_peer_bitfield(&seeder);
+    assert_eq!(picker.remaining(), 3);
+
+    //skipping or not a piece we have changes nothing
+    picker.set_priority(0, PiecePriority::Skip);
+    picker.set_priority(0, PiecePriority::High);
+    assert_eq!(picker.remaining(), 3);
+
+    picker.set_priority(1, PiecePriority::Skip);
+    picker.set_priority(1, PiecePriority::Skip);
+    assert_eq!(picker.remaining(), 2);
+
+    let picked = picker.pick(&seeder).unwrap();
+    picker.set_priority(picked, PiecePriority::Skip);
+    assert_eq!(picker.remaining(), 1);
+    picker.set_priority(picked, PiecePriority::Normal);
+    picker.abort(picked);
+    assert_eq!(picker.remaining(), 2);
+
+    picker.mark_have(2);
+    picker.mark_have(3);
+    assert_eq!(picker.remaining(), 0);
+    assert_eq!(picker.pick(&seeder), None);
+
+    picker.set_priority(1, PiecePriority::Low);
+    assert_eq!(picker.remaining(), 1);
+    assert_eq!(picker.pick(&seeder), Some(1));
+}
+
+#[test]
+fn test_pick_breaks_ties_randomly() {
+    let seeder = [0xFF, 0xFF];
+    let mut first_picks = HashSet::new();
+
+    for _ in 0..50 {
+        let mut picker = PiecePicker::new(32, 512, 16, &[0, 0]);
+        picker.add_peer_bitfield(&seeder);
+        first_picks.insert(picker.pick(&seeder).unwrap());
+    }
+
+    assert!(first_picks.len() > 1);
+}
+
+#[test]
+fn test_endgame_requests_blocks_twice_and_cancels() {
+    let block_size = REQUEST_PIECE_SIZE as usize;
+    //two pieces of two blocks, the last block is shorter
+    let mut picker = PiecePicker::new(2 * block_size, 4 * block_size - 10, 2, &[0]);
+    let seeder = [0b1100_0000];
+    let slow_peer: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+    let fast_peer: SocketAddr = "127.0.0.1:1002".parse().unwrap();
+
+    picker.add_peer_bitfield(&seeder);
+    //piece 0 is the rarest so it is picked first
+    picker.add_peer_bitfield(&[0b0100_0000]);
+
+    let mut slow_blocks = Vec::new();
+    while let Some(block) = picker.pick_block(slow_peer, &seeder) {
+        slow_blocks.push(block);
+    }
+    assert_eq!(slow_blocks.len(), 4);
+    assert_eq!(slow_blocks[3].length, (block_size - 10) as u32);
+    assert!(picker.is_endgame());
+
+    //the fast peer is asked for the same blocks, but only once each
+    let mut fast_blocks = Vec::new();
+    while let Some(block) = picker.pick_block(fast_peer, &seeder) {
+        fast_blocks.push(block);
+    }
+    assert_eq!(fast_blocks.len(), 4);
+
+    let block = fast_blocks[0];
+    let data = vec![1u8; block.length as usize];
+    match picker.on_block_received(fast_peer, &block, &data) {
+        BlockResult::Accepted { cancel, completed } => {
+            assert_eq!(cancel, vec![slow_peer]);
+            assert!(completed.is_none());
+        }
+        result => panic!("unexpected {:?}", result),
+    }
+
+    //the slow peer sent it anyway
+    assert!(matches!(
+        picker.on_block_received(slow_peer, &block, &data),
+        BlockResult::Duplicate
+    ));
+
+    let second = BlockRequest {
+        piece: block.piece,
+        offset: REQUEST_PIECE_SIZE,
+        length: REQUEST_PIECE_SIZE,
+    };
+    match picker.on_block_received(slow_peer, &second, &vec![2u8; block_size]) {
+        BlockResult::Accepted {
+            completed: Some(piece),
+            ..
+        } => {
+            assert_eq!(piece.data.len(), 2 * block_size);
+            picker.mark_have(piece.index as usize);
+        }
+        result => panic!("unexpected {:?}", result),
+    }
+    assert_eq!(picker.remaining(), 1);
+}
+
+#[test]
+fn test_released_blocks_are_requested_again() {
+    let mut picker = PiecePicker::new(REQUEST_PIECE_SIZE as usize, 100, 1, &[0]);
+    let seeder = [0b1000_0000];
+    let first_peer: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+    let second_peer: SocketAddr = "[::1]:1002".parse().unwrap();
+
+    let block = picker.pick_block(first_peer, &seeder).unwrap();
+    assert_eq!(block.length, 100);
+
+    //endgame, the second peer gets the block too
+    assert_eq!(picker.pick_block(second_peer, &seeder), Some(block));
+    picker.release_block(first_peer, &block);
+    picker.release_block(second_peer, &block);
+
+    //the piece data is kept, only the block is missing again
+    assert!(!picker.is_endgame());
+    assert_eq!(picker.pick_block(first_peer, &seeder), Some(block));
+
+    match picker.on_block_received(first_peer, &block, &[0u8; 100]) {
+        BlockResult::Accepted {
+            completed: Some(piece),
+            ..
+        } => assert!(picker.hash_failed(&piece).is_empty()),
+        result => panic!("unexpected {:?}", result),
+    }
+    assert_eq!(picker.pick_block(second_peer, &seeder), Some(block));
+}
+
+fn complete_piece(
+    picker: &mut PiecePicker,
+    blocks: &[(SocketAddr, BlockRequest, Vec<u8>)],
+) -> Piece {
+    let mut completed = None;
+
+    for (peer, block, data) in blocks {
+        match picker.on_block_received(*peer, block, data) {
+            BlockResult::Accepted {
+                completed: Some(piece),
+                ..
+            } => completed = Some(piece),
+            BlockResult::Accepted { .. } => {}
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    return completed.unwrap();
+}
+
+#[test]
+fn test_smart_ban_finds_the_corrupt_block_sender() {
+    let block_size = REQUEST_PIECE_SIZE as usize;
+    let mut picker = PiecePicker::new(2 * block_size, 2 * block_size, 1, &[0]);
+    let seeder = [0b1000_0000];
+    let liar: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let honest: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let other: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+    for _ in 0..3 {
+        picker.add_peer_bitfield(&seeder);
+    }
+
+    let first = picker.pick_block(liar, &seeder).unwrap();
+    let second = picker.pick_block(honest, &seeder).unwrap();
+    let good: Vec<u8> = (0..2 * block_size).map(|i| (i % 251) as u8).collect();
+
+    let failed = complete_piece(
+        &mut picker,
+        &[
+            (liar, first, vec![0u8; block_size]),
+            (honest, second, good[block_size..].to_vec()),
+        ],
+    );
+    //one failure is not enough to know who lied
+    assert!(picker.hash_failed(&failed).is_empty());
+    assert_eq!(picker.remaining(), 1);
+
+    //the piece goes to a peer that did not take part in the failed attempt
+    assert_eq!(picker.pick_block(liar, &seeder), None);
+    assert_eq!(picker.pick_block(honest, &seeder), None);
+    let first = picker.pick_block(other, &seeder).unwrap();
+    let second = picker.pick_block(other, &seeder).unwrap();
+
+    let passed = complete_piece(
+        &mut picker,
+        &[
+            (other, first, good[..block_size].to_vec()),
+            (other, second, good[block_size..].to_vec()),
+        ],
+    );
+    assert_eq!(picker.hash_passed(&passed), vec![liar.ip()]);
+    assert_eq!(picker.remaining(), 0);
+}
+
+#[test]
+fn test_repeated_hash_failures_ban_the_peer() {
+    let mut picker = PiecePicker::new(100, 100 * MAX_HASH_FAILURES as usize, 3, &[0]);
+    let seeder = [0b1110_0000];
+    let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    picker.add_peer_bitfield(&seeder);
+
+    for failure in 1..=MAX_HASH_FAILURES {
+        let block = picker.pick_block(peer, &seeder).unwrap();
+        let failed = complete_piece(&mut picker, &[(peer, block, vec![0u8; 100])]);
+
+        let banned = picker.hash_failed(&failed);
+        if failure < MAX_HASH_FAILURES {
+            assert!(banned.is_empty());
+        } else {
+            assert_eq!(banned, vec![peer.ip()]);
+        }
+    }
+
+    //nothing was lost, every piece is still needed
+    assert_eq!(picker.remaining(), 3);
+}
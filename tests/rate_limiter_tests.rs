@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rust_torrent::rate_limiter::{Allowance, BandwidthLimit, Direction, RateLimiter};
+
+fn levels(limits: &[&Arc<BandwidthLimit>]) -> Vec<Arc<BandwidthLimit>> {
+    limits.iter().map(|limit| Arc::clone(limit)).collect()
+}
+
+#[test]
+fn test_unlimited_transfers_are_not_delayed() {
+    let limit = Arc::new(BandwidthLimit::unlimited());
+    let mut allowance = Allowance::new(Direction::Upload, levels(&[&limit, &limit]));
+
+    for _ in 0..10 {
+        let (bytes, ready_at) = allowance.next(1024 * 1024);
+        assert_eq!(bytes, 1024 * 1024);
+        assert!(ready_at <= Instant::now());
+        allowance.consume(bytes);
+    }
+}
+
+#[test]
+fn test_reservations_are_paced_by_the_rate() {
+    let limiter = RateLimiter::new(10_000);
+    let start = Instant::now();
+
+    //the saved burst goes right away
+    assert!(limiter.reserve(2_500) <= Instant::now());
+
+    //then a second worth of tokens takes a second
+    let ready_at = limiter.reserve(10_000);
+    let wait = ready_at.duration_since(start);
+    assert!(wait >= Duration::from_millis(900), "{wait:?}");
+    assert!(wait <= Duration::from_millis(1100), "{wait:?}");
+}
+
+#[test]
+fn test_connections_sharing_a_limit_take_turns() {
+    let torrent = Arc::new(BandwidthLimit::new(64 * 1024, 0));
+    let mut first = Allowance::new(Direction::Upload, levels(&[&torrent]));
+    let mut second = Allowance::new(Direction::Upload, levels(&[&torrent]));
+
+    //a connection with a lot to send only gets a chunk
+    let (first_bytes, first_at) = first.next(1024 * 1024);
+    assert!(first_bytes <= 16 * 1024);
+    first.consume(first_bytes);
+
+    let (second_bytes, second_at) = second.next(1024 * 1024);
+    second.consume(second_bytes);
+
+    //its next chunk comes after the other connection got its own
+    let (_, first_again_at) = first.next(1024 * 1024);
+    assert!(first_at <= second_at);
+    assert!(second_at < first_again_at);
+}
+
+#[test]
+fn test_every_level_applies() {
+    let peer = Arc::new(BandwidthLimit::new(0, 4 * 1024));
+    let torrent = Arc::new(BandwidthLimit::unlimited());
+    let global = Arc::new(BandwidthLimit::unlimited());
+    let mut allowance = Allowance::new(Direction::Download, levels(&[&peer, &torrent, &global]));
+
+    let mut bytes = 0;
+    let mut ready_at = Instant::now();
+    while bytes < 8 * 1024 {
+        let (granted, at) = allowance.next(32 * 1024);
+        allowance.consume(granted);
+        bytes += granted;
+        ready_at = at;
+    }
+    //the peer limit alone holds the transfer back, minus the saved burst
+    assert!(ready_at.duration_since(Instant::now()) >= Duration::from_millis(1500));
+
+    //limits change at runtime
+    peer.download.set_rate(0);
+    let (granted, at) = allowance.next(32 * 1024);
+    assert_eq!(granted, 32 * 1024);
+    assert!(at <= Instant::now());
+}
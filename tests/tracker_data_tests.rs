@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+
+use rust_torrent::tracker_data::TrackerData;
+
+#[test]
+fn test_compact_peers() {
+    let mut body = b"d8:intervali1800e5:peers12:".to_vec();
+    body.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2, 0x1A, 0xE2]);
+    body.push(b'e');
+
+    let tracker_data = TrackerData::parse(&body).unwrap();
+
+    assert_eq!(tracker_data.interval, 1800);
+    assert_eq!(
+        tracker_data.peers,
+        vec![
+            "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse::<SocketAddr>().unwrap()
+        ]
+    );
+    assert!(tracker_data.peer_ids.is_empty());
+}
+
+#[test]
+fn test_dictionary_model_peers() {
+    let body = b"d8:completei3e8:intervali900e5:peersl\
+d2:ip9:127.0.0.17:peer id20:-TR3000-aaaaaaaaaaaa4:porti6881ee\
+d2:ip3:::14:porti6882ee\
+d2:ip9:localhost4:porti6883eee\
+e"
+    .to_vec();
+
+    let tracker_data = TrackerData::parse(&body).unwrap();
+
+    assert_eq!(tracker_data.interval, 900);
+    assert_eq!(tracker_data.peers.len(), 3);
+
+    let first: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+    assert_eq!(tracker_data.peers[0], first);
+    assert_eq!(
+        tracker_data.peer_ids.get(&first),
+        Some(b"-TR3000-aaaaaaaaaaaa")
+    );
+
+    assert_eq!(tracker_data.peers[1], "[::1]:6882".parse().unwrap());
+    assert!(tracker_data.peers[2].ip().is_loopback());
+    assert_eq!(tracker_data.peers[2].port(), 6883);
+    assert_eq!(tracker_data.peer_ids.len(), 1);
+}
+
+#[test]
+fn test_dictionary_peers_with_invalid_ports_are_dropped() {
+    let body = b"d8:intervali900e5:peersl\
+d2:ip8:10.0.0.14:porti70000ee\
+d2:ip8:10.0.0.24:porti0ee\
+d2:ip8:10.0.0.3e\
+d2:ip8:10.0.0.44:porti6881ee\
+ee"
+    .to_vec();
+
+    let tracker_data = TrackerData::parse(&body).unwrap();
+
+    assert_eq!(
+        tracker_data.peers,
+        vec!["10.0.0.4:6881".parse::<SocketAddr>().unwrap()]
+    );
+}
+
+#[test]
+fn test_compact_peers6() {
+    let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+    body.extend_from_slice(&[192, 168, 1, 10, 0x1A, 0xE1]);
+    body.extend_from_slice(b"6:peers618:");
+    let mut ipv6 = [0u8; 16];
+    ipv6[15] = 1;
+    body.extend_from_slice(&ipv6);
+    body.extend_from_slice(&6881u16.to_be_bytes());
+    body.push(b'e');
+
+    let tracker_data = TrackerData::parse(&body).unwrap();
+
+    assert_eq!(
+        tracker_data.peers,
+        vec![
+            "192.168.1.10:6881".parse::<SocketAddr>().unwrap(),
+            "[::1]:6881".parse::<SocketAddr>().unwrap()
+        ]
+    );
+}
+
+#[test]
+fn test_malformed_responses_are_errors() {
+    let bodies: [&[u8]; 6] = [
+        b"d8:intervali1800e5:peers12:abc",
+        b"d8:intervali-5ee",
+        b"d8:intervali1800e5:peersi3ee",
+        b"<html><body>502 Bad Gateway</body></html>",
+        b"li1ee",
+        b"",
+    ];
+
+    for body in bodies {
+        assert!(
+            TrackerData::parse(body).is_err(),
+            "{}",
+            String::from_utf8_lossy(body)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rust_torrent::client::ClientConfig;
+use rust_torrent::torrent_file::TorrentFile;
+use rust_torrent::tracker_manager::{TrackerManager, TransferStats, backoff_delay};
+use rust_torrent::tracker_server::{TrackerServer, TrackerServerConfig};
+
+#[test]
+fn test_backoff_grows_and_is_capped() {
+    for failures in 1..40 {
+        let delay = backoff_delay(failures);
+        let base = Duration::from_secs(15)
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(Duration::from_secs(30 * 60));
+
+        assert!(delay >= base.mul_f64(0.75) && delay <= base.mul_f64(1.25));
+    }
+}
+
+#[test]
+fn test_unresponsive_tracker_times_out() {
+    //accepts connections but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let torrent_file = TorrentFile {
+        announce: format!("http://127.0.0.1:{port}/announce"),
+        announce_list: Some(vec![vec![String::from("udp://tracker.example.org:80")]]),
+        ..TorrentFile::default()
+    };
+
+    let config = ClientConfig {
+        tracker_connect_timeout: Duration::from_millis(200),
+        tracker_timeout: Duration::from_millis(500),
+        ..ClientConfig::default()
+    };
+
+    let manager = TrackerManager::new(Arc::new(torrent_file), Arc::new(config)).unwrap();
+
+    let started_at = Instant::now();
+    let peers = manager.announce_due(TransferStats {
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+    });
+
+    assert!(peers.is_empty());
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+
+    let statuses = manager.statuses();
+    assert_eq!(statuses.len(), 2);
+
+    let http_status = &statuses[0];
+    assert!(http_status.enabled);
+    assert_eq!(http_status.consecutive_failures, 1);
+    assert!(http_status.last_error.is_some());
+    assert!(http_status.last_success.is_none());
+    assert!(http_status.next_announce > Instant::now());
+
+    assert!(!statuses[1].enabled);
+
+    //not due yet, nothing happens
+    manager.announce_due(TransferStats::default());
+    assert_eq!(manager.statuses()[0].consecutive_failures, 1);
+}
+
+fn start_tracker() -> TrackerServer {
+    TrackerServer::start(TrackerServerConfig {
+        http_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..TrackerServerConfig::default()
+    })
+    .unwrap()
+}
+
+fn announce_url(server: &TrackerServer) -> String {
+    format!("http://{}/announce", server.http_addr().unwrap())
+}
+
+/**
+ * Nothing listens on it, connections are refused right away
+ */
+fn dead_tracker_url() -> String {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    format!("http://127.0.0.1:{port}/announce")
+}
+
+fn swarm_size(server: &TrackerServer) -> usize {
+    server
+        .swarms()
+        .lock()
+        .unwrap()
+        .get(&[0u8; 20])
+        .map_or(0, |swarm| swarm.len())
+}
+
+#[test]
+fn test_announces_stop_at_the_first_tracker_answering() {
+    let first_tier = start_tracker();
+    let second_tier = start_tracker();
+    let dead = dead_tracker_url();
+
+    let torrent_file = TorrentFile {
+        announce_list: Some(vec![
+            vec![dead.clone(), announce_url(&first_tier)],
+            vec![announce_url(&second_tier)],
+        ]),
+        ..TorrentFile::default()
+    };
+    let manager =
+        TrackerManager::new(Arc::new(torrent_file), Arc::new(ClientConfig::default())).unwrap();
+
+    manager.announce_due(TransferStats::default());
+
+    assert_eq!(swarm_size(&first_tier), 1);
+    assert_eq!(swarm_size(&second_tier), 0);
+
+    //the tracker that answered is tried first from now on
+    let statuses = manager.statuses();
+    assert_eq!(statuses[0].url, announce_url(&first_tier));
+    assert!(statuses[0].last_success.is_some());
+    assert_eq!(statuses[1].url, dead);
+    assert!(statuses[2].last_success.is_none());
+    assert_eq!(statuses[2].consecutive_failures, 0);
+
+    //nothing is due before the interval
+    assert!(manager.next_announce().unwrap() > Instant::now() + Duration::from_secs(60));
+    manager.announce_due(TransferStats::default());
+    assert!(manager.statuses()[2].last_success.is_none());
+
+    first_tier.stop();
+    second_tier.stop();
+}
+
+#[test]
+fn test_next_tier_is_used_when_a_whole_tier_fails() {
+    let second_tier = start_tracker();
+    let dead = [dead_tracker_url(), dead_tracker_url()];
+
+    let torrent_file = TorrentFile {
+        announce_list: Some(vec![dead.to_vec(), vec![announce_url(&second_tier)]]),
+        ..TorrentFile::default()
+    };
+    let manager =
+        TrackerManager::new(Arc::new(torrent_file), Arc::new(ClientConfig::default())).unwrap();
+
+    manager.announce_due(TransferStats::default());
+
+    assert_eq!(swarm_size(&second_tier), 1);
+    let statuses = manager.statuses();
+    assert!(statuses[..2].iter().all(|t| t.consecutive_failures == 1));
+    assert!(statuses[2].last_success.is_some());
+
+    second_tier.stop();
+}
@@ -0,0 +1,333 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::client::ClientConfig;
+use rust_torrent::torrent_file::TorrentFile;
+use rust_torrent::tracker::{self, AnnounceEvent, AnnounceParams};
+use rust_torrent::tracker_server::udp::{ACTION_ANNOUNCE, ACTION_CONNECT, PROTOCOL_ID};
+use rust_torrent::tracker_server::{TrackerServer, TrackerServerConfig};
+
+fn start_server() -> TrackerServer {
+    TrackerServer::start(TrackerServerConfig {
+        http_addr: Some("127.0.0.1:0".parse().unwrap()),
+        udp_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..TrackerServerConfig::default()
+    })
+    .unwrap()
+}
+
+fn client_config(peer_id: &[u8; 20], port: u16, compact: bool) -> ClientConfig {
+    ClientConfig {
+        peer_id: *peer_id,
+        port,
+        compact,
+        no_peer_id: false,
+        ..ClientConfig::default()
+    }
+}
+
+fn params() -> AnnounceParams {
+    AnnounceParams {
+        uploaded: 0,
+        downloaded: 0,
+        left: 1000,
+        event: Some(AnnounceEvent::Started),
+        local_ipv4: None,
+        local_ipv6: None,
+    }
+}
+
+#[test]
+fn test_http_announce_compact_and_non_compact() {
+    let server = start_server();
+    let announce_url = format!("http://{}/announce", server.http_addr().unwrap());
+
+    let torrent_file = TorrentFile {
+        info_hash: [7u8; 20],
+        ..TorrentFile::default()
+    };
+
+    let config_a = client_config(b"-TR3000-aaaaaaaaaaaa", 7001, true);
+    let http_client = tracker::http_client(&config_a).unwrap();
+
+    let first = tracker::announce(
+        &http_client,
+        &announce_url,
+        &torrent_file,
+        &config_a,
+        &params(),
+    )
+    .unwrap();
+    assert!(first.peers.is_empty());
+    assert_eq!(first.interval, 30 * 60);
+
+    let config_b = client_config(b"-TR3000-bbbbbbbbbbbb", 7002, true);
+    let compact = tracker::announce(
+        &http_client,
+        &announce_url,
+        &torrent_file,
+        &config_b,
+        &params(),
+    )
+    .unwrap();
+    assert_eq!(
+        compact.peers,
+        vec!["127.0.0.1:7001".parse::<SocketAddr>().unwrap()]
+    );
+
+    let config_c = client_config(b"-TR3000-cccccccccccc", 7003, false);
+    let non_compact = tracker::announce(
+        &http_client,
+        &announce_url,
+        &torrent_file,
+        &config_c,
+        &params(),
+    )
+    .unwrap();
+    assert_eq!(non_compact.peers.len(), 2);
+
+    let peer_a: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+    assert_eq!(
+        non_compact.peer_ids.get(&peer_a),
+        Some(b"-TR3000-aaaaaaaaaaaa")
+    );
+
+    let scrape = http_client
+        .get(format!(
+            "http://{}/scrape?info_hash=%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07",
+            server.http_addr().unwrap()
+        ))
+        .send()
+        .unwrap()
+        .bytes()
+        .unwrap();
+    let expected = b"d5:filesd20:\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07d8:completei0e10:downloadedi0e10:incompletei3eeee";
+    assert_eq!(scrape.as_ref(), expected.as_slice());
+
+    server.stop();
+}
+
+#[test]
+fn test_http_announce_failure_reason() {
+    let server = start_server();
+    let announce_url = format!("http://{}/announce", server.http_addr().unwrap());
+
+    //missing port and peer id
+    let body = reqwest::blocking::get(format!(
+        "{announce_url}?info_hash=%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07"
+    ))
+    .unwrap()
+    .bytes()
+    .unwrap();
+
+    let tracker_data = rust_torrent::tracker_data::TrackerData::parse(&body).unwrap();
+    assert_eq!(
+        tracker_data.failure_reason.as_deref(),
+        Some("missing peer_id")
+    );
+
+    let body = reqwest::blocking::get(format!(
+        "{announce_url}?info_hash={hash}&peer_id={hash}&port=6881",
+        hash = "%07".repeat(20)
+    ))
+    .unwrap()
+    .bytes()
+    .unwrap();
+
+    let tracker_data = rust_torrent::tracker_data::TrackerData::parse(&body).unwrap();
+    assert_eq!(
+        tracker_data.failure_reason.as_deref(),
+        Some("missing or invalid left")
+    );
+    assert!(server.swarms().lock().unwrap().get(&[7u8; 20]).is_none());
+
+    server.stop();
+}
+
+#[test]
+fn test_swarms_without_peers_are_dropped() {
+    let server = TrackerServer::start(TrackerServerConfig {
+        http_addr: Some("127.0.0.1:0".parse().unwrap()),
+        peer_timeout: Duration::from_millis(100),
+        ..TrackerServerConfig::default()
+    })
+    .unwrap();
+    let announce_url = format!("http://{}/announce", server.http_addr().unwrap());
+
+    let torrent_file = TorrentFile {
+        info_hash: [8u8; 20],
+        ..TorrentFile::default()
+    };
+    let config = client_config(b"-TR3000-completedddd", 7001, true);
+    let completed = AnnounceParams {
+        left: 0,
+        event: Some(AnnounceEvent::Completed),
+        ..params()
+    };
+    tracker::announce(
+        &tracker::http_client(&config).unwrap(),
+        &announce_url,
+        &torrent_file,
+        &config,
+        &completed,
+    )
+    .unwrap();
+
+    let swarms = server.swarms();
+    assert_eq!(
+        swarms.lock().unwrap().get(&[8u8; 20]).unwrap().downloaded,
+        1
+    );
+
+    thread::sleep(Duration::from_millis(200));
+    swarms.lock().unwrap().expire_peers();
+    assert!(swarms.lock().unwrap().get(&[8u8; 20]).is_none());
+
+    server.stop();
+}
+
+#[test]
+fn test_udp_announce() {
+    let server = start_server();
+    let tracker_addr = server.udp_addr().unwrap();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    let mut connect = Vec::new();
+    connect.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    connect.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    connect.extend_from_slice(&42u32.to_be_bytes());
+    socket.send_to(&connect, tracker_addr).unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (read, _) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(read, 16);
+    assert_eq!(&buf[0..4], &ACTION_CONNECT.to_be_bytes());
+    assert_eq!(&buf[4..8], &42u32.to_be_bytes());
+    let connection_id: [u8; 8] = buf[8..16].try_into().unwrap();
+
+    let announce = |peer_id: &[u8; 20], port: u16| {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&connection_id);
+        packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&43u32.to_be_bytes());
+        packet.extend_from_slice(&[9u8; 20]);
+        packet.extend_from_slice(peer_id);
+        packet.extend_from_slice(&0u64.to_be_bytes()); //downloaded
+        packet.extend_from_slice(&0u64.to_be_bytes()); //left, seeder
+        packet.extend_from_slice(&0u64.to_be_bytes()); //uploaded
+        packet.extend_from_slice(&2u32.to_be_bytes()); //started
+        packet.extend_from_slice(&0u32.to_be_bytes()); //ip
+        packet.extend_from_slice(&0u32.to_be_bytes()); //key
+        packet.extend_from_slice(&(-1i32).to_be_bytes());
+        packet.extend_from_slice(&port.to_be_bytes());
+        socket.send_to(&packet, tracker_addr).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let (read, _) = socket.recv_from(&mut buf).unwrap();
+        buf[..read].to_vec()
+    };
+
+    let first = announce(b"-TR3000-aaaaaaaaaaaa", 7001);
+    assert_eq!(first.len(), 20);
+    assert_eq!(&first[0..4], &ACTION_ANNOUNCE.to_be_bytes());
+
+    let second = announce(b"-TR3000-bbbbbbbbbbbb", 7002);
+    assert_eq!(second.len(), 26);
+    //seeders
+    assert_eq!(&second[16..20], &2u32.to_be_bytes());
+    assert_eq!(&second[20..26], &[127, 0, 0, 1, 0x1B, 0x59]);
+
+    server.stop();
+}
+
+/**
+ * Address the swarm registered for a peer announcing ip=10.9.9.9
+ */
+fn announced_address(trust_announced_ip: bool) -> SocketAddr {
+    let server = TrackerServer::start(TrackerServerConfig {
+        http_addr: Some("127.0.0.1:0".parse().unwrap()),
+        trust_announced_ip,
+        ..TrackerServerConfig::default()
+    })
+    .unwrap();
+
+    let body = reqwest::blocking::get(format!(
+        "http://{}/announce?info_hash=%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07%07\
+&peer_id=-TR3000-aaaaaaaaaaaa&port=7001&left=0&ip=10.9.9.9",
+        server.http_addr().unwrap()
+    ))
+    .unwrap()
+    .bytes()
+    .unwrap();
+    assert!(
+        rust_torrent::tracker_data::TrackerData::parse(&body)
+            .unwrap()
+            .failure_reason
+            .is_none()
+    );
+
+    let swarms = server.swarms();
+    let addr = swarms
+        .lock()
+        .unwrap()
+        .get(&[7u8; 20])
+        .unwrap()
+        .select_peers(&[0u8; 20], 1)[0]
+        .addr;
+    server.stop();
+
+    return addr;
+}
+
+#[test]
+fn test_announced_ip_is_only_used_when_trusted() {
+    assert_eq!(
+        announced_address(false),
+        "127.0.0.1:7001".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(
+        announced_address(true),
+        "10.9.9.9:7001".parse::<SocketAddr>().unwrap()
+    );
+}
+
+#[test]
+fn test_http_connections_over_the_limit_are_closed() {
+    let server = TrackerServer::start(TrackerServerConfig {
+        http_addr: Some("127.0.0.1:0".parse().unwrap()),
+        max_http_connections: 1,
+        ..TrackerServerConfig::default()
+    })
+    .unwrap();
+    let http_addr = server.http_addr().unwrap();
+
+    //holds the only slot without sending a request
+    let idle = TcpStream::connect(http_addr).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let mut rejected = TcpStream::connect(http_addr).unwrap();
+    rejected
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    rejected.write_all(b"GET /scrape HTTP/1.1\r\n\r\n").unwrap();
+    let mut buf = [0u8; 64];
+    assert!(matches!(rejected.read(&mut buf), Ok(0) | Err(_)));
+
+    //the slot is given back once the idle connection is gone
+    drop(idle);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut answered = false;
+    while !answered && Instant::now() < deadline {
+        answered = reqwest::blocking::get(format!("http://{http_addr}/scrape")).is_ok();
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(answered);
+
+    server.stop();
+}
@@ -0,0 +1,86 @@
+use rust_torrent::client::ClientConfig;
+use rust_torrent::tracker::{AnnounceEvent, AnnounceParams, build_announce_url};
+
+fn test_params() -> AnnounceParams {
+    AnnounceParams {
+        uploaded: 10,
+        downloaded: 20,
+        left: 30,
+        event: Some(AnnounceEvent::Started),
+        local_ipv4: None,
+        local_ipv6: Some("2001:db8::1".parse().unwrap()),
+    }
+}
+
+fn test_config() -> ClientConfig {
+    ClientConfig {
+        peer_id: *b"-TR3000-abcdefghijkl",
+        port: 51413,
+        numwant: Some(80),
+        key: String::from("DEADBEEF"),
+        ip: Some(String::from("tracker.example.org")),
+        ..ClientConfig::default()
+    }
+}
+
+#[test]
+fn test_announce_url_parameters() {
+    let info_hash = [0xABu8; 20];
+    let url = build_announce_url(
+        "http://tracker.example.org:8080/announce",
+        &info_hash,
+        &test_config(),
+        &test_params(),
+    )
+    .unwrap();
+
+    assert!(url.starts_with("http://tracker.example.org:8080/announce?info_hash=%AB%AB"));
+    assert!(url.contains("&peer_id=-TR3000-abcdefghijkl&"));
+    assert!(url.contains("&port=51413&"));
+    assert!(url.contains("&uploaded=10&downloaded=20&left=30&"));
+    assert!(url.contains("&key=DEADBEEF&"));
+    assert!(url.contains("&compact=1&"));
+    assert!(url.contains("&no_peer_id=1&"));
+    assert!(url.contains("&event=started&"));
+    assert!(url.contains("&numwant=80&"));
+    assert!(url.contains("&ip=tracker.example.org&"));
+    assert!(url.ends_with("&ipv6=2001%3Adb8%3A%3A1"));
+}
+
+#[test]
+fn test_announce_url_keeps_existing_query() {
+    let url = build_announce_url(
+        "https://private.example.org/announce.php?passkey=0123456789abcdef",
+        &[0u8; 20],
+        &ClientConfig::default(),
+        &test_params(),
+    )
+    .unwrap();
+
+    assert!(url.starts_with(
+        "https://private.example.org/announce.php?passkey=0123456789abcdef&info_hash=%00%00"
+    ));
+    assert_eq!(url.matches('?').count(), 1);
+}
+
+#[test]
+fn test_announce_url_rejects_invalid() {
+    let params = test_params();
+    let config = ClientConfig::default();
+
+    assert!(build_announce_url("not an url", &[0u8; 20], &config, &params).is_err());
+    assert!(build_announce_url("udp://tracker:80", &[0u8; 20], &config, &params).is_err());
+}
+
+#[test]
+fn test_announce_params_only_give_the_other_address_family() {
+    let over_ipv4 = AnnounceParams::new("http://127.0.0.1:6969/announce", 0, 0, 0, None);
+    assert_eq!(over_ipv4.local_ipv4, None);
+
+    let over_ipv6 = AnnounceParams::new("http://[::1]:6969/announce", 0, 0, 0, None);
+    assert_eq!(over_ipv6.local_ipv6, None);
+
+    let unresolved = AnnounceParams::new("http://tracker.invalid/announce", 0, 0, 0, None);
+    assert_eq!(unresolved.local_ipv4, None);
+    assert_eq!(unresolved.local_ipv6, None);
+}
@@ -0,0 +1,328 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_torrent::choker;
+use rust_torrent::client::ClientConfig;
+use rust_torrent::connection_handler::ConnectionHandler;
+use rust_torrent::file_handler::get_file_handler_in;
+use rust_torrent::peer_listener::PeerListener;
+use rust_torrent::torrent_context::TorrentContext;
+use rust_torrent::utp::UtpSocket;
+use rust_torrent::utp::connection::{Connection, DEFAULT_MTU, packet_size};
+use rust_torrent::utp::ledbat::{Ledbat, TARGET_DELAY};
+use rust_torrent::utp::packet::{Packet, PacketType, seq_after};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/**
+ * Deliver the packets of both connections to each other until they have nothing left to say,
+ * the filter decides which ones get lost
+ */
+fn exchange(
+    a: &mut Connection,
+    b: &mut Connection,
+    now: Instant,
+    mut delivered: impl FnMut(&Packet) -> bool,
+) {
+    loop {
+        let from_a = a.take_outbox();
+        let from_b = b.take_outbox();
+        if from_a.is_empty() && from_b.is_empty() {
+            return;
+        }
+
+        for packet in from_a.into_iter().filter(|packet| delivered(packet)) {
+            b.on_packet(packet, now);
+        }
+        for packet in from_b.into_iter().filter(|packet| delivered(packet)) {
+            a.on_packet(packet, now);
+        }
+    }
+}
+
+fn connected_pair(now: Instant) -> (Connection, Connection) {
+    let mut initiator = Connection::connect(addr(2), 1000, now);
+    let syn = initiator.take_outbox().remove(0);
+    assert_eq!(syn.packet_type, PacketType::Syn);
+
+    let mut acceptor = Connection::accept(addr(1), &syn, 500, now);
+    exchange(&mut initiator, &mut acceptor, now, |_| true);
+
+    assert!(initiator.is_connected());
+    assert!(acceptor.is_connected());
+    (initiator, acceptor)
+}
+
+fn read_all(connection: &mut Connection, now: Instant) -> Vec<u8> {
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let read = connection.read(&mut buf, now);
+        if read == 0 {
+            return received;
+        }
+        received.extend_from_slice(&buf[..read]);
+    }
+}
+
+#[test]
+fn test_packet_round_trip() {
+    let mut packet = Packet::new(PacketType::Data, 42);
+    packet.timestamp = 123456;
+    packet.timestamp_difference = 789;
+    packet.wnd_size = 65536;
+    packet.seq_nr = 7;
+    packet.ack_nr = 65534;
+    packet.selective_ack = Some(vec![0b0000_0101, 0, 0, 0b1000_0000]);
+    packet.payload = b"payload".to_vec();
+
+    let bytes = packet.to_bytes();
+    assert_eq!(bytes[0], 0x01);
+    assert_eq!(Packet::parse(&bytes), Ok(packet.clone()));
+
+    //bit i is ack_nr + 2 + i, wrapping
+    assert_eq!(packet.selectively_acked(), vec![0, 2, 31]);
+
+    assert!(Packet::parse(&bytes[..19]).is_err());
+    let mut invalid_sack = Packet::new(PacketType::State, 1);
+    invalid_sack.selective_ack = Some(vec![1, 2, 3]);
+    assert!(Packet::parse(&invalid_sack.to_bytes()).is_err());
+}
+
+#[test]
+fn test_sequence_numbers_wrap() {
+    assert!(seq_after(2, 1));
+    assert!(!seq_after(1, 2));
+    assert!(!seq_after(5, 5));
+    assert!(seq_after(0, 65535));
+    assert!(seq_after(10, 65530));
+    assert!(!seq_after(65530, 10));
+}
+
+#[test]
+fn test_ledbat_follows_queuing_delay() {
+    let now = Instant::now();
+    let mut ledbat = Ledbat::new(1000);
+    let initial = ledbat.window();
+
+    //no queuing: the window grows
+    for _ in 0..50 {
+        ledbat.on_delay_sample(Duration::from_millis(20), now);
+        ledbat.on_ack(1000);
+    }
+    let grown = ledbat.window();
+    assert!(grown > initial);
+
+    //delay above the base delay plus the target: the window shrinks
+    for _ in 0..10 {
+        ledbat.on_delay_sample(Duration::from_millis(20) + TARGET_DELAY * 2, now);
+        ledbat.on_ack(1000);
+    }
+    let shrunk = ledbat.window();
+    assert!(shrunk < grown);
+
+    ledbat.on_loss();
+    assert!(ledbat.window() <= shrunk / 2 + 1);
+
+    //never below two packets
+    for _ in 0..20 {
+        ledbat.on_loss();
+    }
+    assert_eq!(ledbat.window(), 2000);
+
+    let timeout = ledbat.timeout();
+    ledbat.on_timeout();
+    assert_eq!(ledbat.timeout(), timeout * 2);
+}
+
+#[test]
+fn test_connection_recovers_lost_packets() {
+    let now = Instant::now();
+    let (mut initiator, mut acceptor) = connected_pair(now);
+
+    let data = common::test_data(20 * 1024);
+    assert_eq!(initiator.write(&data, now), data.len());
+
+    //the second data packet is lost once, the ones after it are selectively acked
+    let mut lost = None;
+    exchange(&mut initiator, &mut acceptor, now, |packet| {
+        if packet.packet_type == PacketType::Data && lost.is_none() && packet.seq_nr == 3 {
+            lost = Some(packet.seq_nr);
+            return false;
+        }
+        return true;
+    });
+
+    //resent by the retransmission timer at the latest
+    let later = now + Duration::from_secs(2);
+    initiator.on_tick(later);
+    exchange(&mut initiator, &mut acceptor, later, |_| true);
+
+    assert_eq!(lost, Some(3));
+    assert_eq!(read_all(&mut acceptor, later), data);
+
+    //FIN in both directions ends the connection
+    initiator.close(later);
+    exchange(&mut initiator, &mut acceptor, later, |_| true);
+    assert!(acceptor.is_eof());
+
+    acceptor.detach(later);
+    exchange(&mut initiator, &mut acceptor, later, |_| true);
+    initiator.detach(later);
+    assert!(acceptor.is_finished());
+    assert!(initiator.is_finished());
+}
+
+#[test]
+fn test_connection_times_out_without_answer() {
+    let mut now = Instant::now();
+    let mut connection = Connection::connect(addr(2), 1, now);
+    connection.take_outbox();
+
+    for _ in 0..20 {
+        now += Duration::from_secs(31);
+        connection.on_tick(now);
+    }
+
+    assert_eq!(connection.error(), Some(std::io::ErrorKind::TimedOut));
+}
+
+#[test]
+fn test_packets_too_big_are_split_again() {
+    let now = Instant::now();
+    let (mut initiator, mut acceptor) = connected_pair(now);
+
+    let data = common::test_data(3000);
+    initiator.write(&data, now);
+    let packets = initiator.take_outbox();
+    assert!(
+        packets
+            .iter()
+            .all(|packet| packet.payload.len() <= packet_size(addr(2), DEFAULT_MTU))
+    );
+
+    //none of them left: the data goes out again in smaller packets
+    initiator.on_packets_too_big(packets, now);
+    assert!(initiator.mtu() < DEFAULT_MTU);
+
+    let smaller = packet_size(addr(2), initiator.mtu());
+    exchange(&mut initiator, &mut acceptor, now, |packet| {
+        assert!(packet.payload.len() <= smaller);
+        return true;
+    });
+
+    assert_eq!(read_all(&mut acceptor, now), data);
+}
+
+#[test]
+fn test_stream_over_loopback() {
+    let server = UtpSocket::bind("127.0.0.1:0").unwrap();
+    server.listen();
+    let client = UtpSocket::bind("127.0.0.1:0").unwrap();
+
+    let data = common::test_data(2 * 1024 * 1024 + 17);
+    let expected = data.clone();
+    let server_addr = server.local_addr();
+
+    let receiver = thread::spawn(move || {
+        let mut stream = server.accept(TIMEOUT).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        let mut received = vec![0u8; expected.len()];
+        stream.read_exact(&mut received).unwrap();
+        assert!(received == expected);
+
+        //and back the other way
+        stream.write_all(&received).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        rest
+    });
+
+    let mut stream = client.connect(server_addr, TIMEOUT).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), server_addr);
+
+    stream.write_all(&data).unwrap();
+    let mut echoed = vec![0u8; data.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    assert!(echoed == data);
+
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+    drop(stream);
+
+    assert!(receiver.join().unwrap().is_empty());
+}
+
+#[test]
+fn test_connect_to_silent_port_times_out() {
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0").unwrap();
+
+    let started_at = Instant::now();
+    assert!(
+        client
+            .connect(silent.local_addr().unwrap(), Duration::from_millis(500))
+            .is_err()
+    );
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_download_over_utp() {
+    let data = common::test_data(6 * 16 * 1024 + 321);
+    let torrent_file = Arc::new(common::build_torrent("utp.bin", &data, 16 * 1024));
+    let seeder_dir = common::temp_dir("utp_seeder");
+    std::fs::write(seeder_dir.join("utp.bin"), &data).unwrap();
+
+    let seeder = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &seeder_dir),
+        Arc::new(ClientConfig::default()),
+    );
+    let listener = PeerListener::start_utp(
+        Arc::new(UtpSocket::bind("127.0.0.1:0").unwrap()),
+        vec![seeder.clone()],
+    )
+    .unwrap();
+
+    let choker_stopped = Arc::new(AtomicBool::new(false));
+    let choker = choker::spawn_choker(seeder.clone(), Arc::clone(&choker_stopped));
+
+    let leecher_dir = common::temp_dir("utp_leecher");
+    let mut leecher = TorrentContext::new(
+        Arc::clone(&torrent_file),
+        get_file_handler_in(&torrent_file, &leecher_dir),
+        Arc::new(ClientConfig {
+            peer_id: *b"-TR3000-utpleecher00",
+            ..ClientConfig::default()
+        }),
+    );
+    leecher.utp_socket = Some(Arc::new(UtpSocket::bind("127.0.0.1:0").unwrap()));
+
+    //nothing listens on TCP at this port, the download can only go through uTP
+    ConnectionHandler::new(listener.local_addr(), &leecher)
+        .connect()
+        .unwrap();
+
+    assert!(leecher.is_download_complete());
+    assert_eq!(std::fs::read(leecher_dir.join("utp.bin")).unwrap(), data);
+
+    choker_stopped.store(true, Ordering::Relaxed);
+    choker.join().unwrap();
+    listener.stop();
+}